@@ -0,0 +1,92 @@
+//! Mount a RAM disk through the `embassy` `BlockDriver` adapter.
+//!
+//! On a board the driver would be embassy-stm32's `Sdmmc` or an `sdspi` card,
+//! and the embassy executor would replace `block_on`; the filesystem calls stay
+//! the same.
+//!
+//! ```text
+//! cargo run --example embassy --features embassy
+//! ```
+
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use aligned::{Aligned, A4};
+use fat32::asynch::AsyncFat32;
+use fat32::device::BlockDevice;
+use fat32::embassy::BlockDriver;
+use fat32::mkfs::{self, FormatOptions};
+use fat32::{Error, Result};
+
+const SECTORS: usize = 200;
+
+/// Sectors kept in memory, standing in for an SD card driver.
+struct RamDisk(Vec<[u8; 512]>);
+
+/// Lets `mkfs` format the disk before it is handed to the async driver.
+impl BlockDevice for RamDisk {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        *buf = *self.0.get(lba as usize).ok_or(Error::Io)?;
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        *self.0.get_mut(lba as usize).ok_or(Error::Io)? = *buf;
+        Ok(())
+    }
+}
+
+impl block_device_driver::BlockDevice<512> for RamDisk {
+    type Error = ();
+    type Align = A4;
+
+    async fn read(&mut self, addr: u32, data: &mut [Aligned<A4, [u8; 512]>]) -> core::result::Result<(), ()> {
+        for (i, block) in data.iter_mut().enumerate() {
+            **block = *self.0.get(addr as usize + i).ok_or(())?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, addr: u32, data: &[Aligned<A4, [u8; 512]>]) -> core::result::Result<(), ()> {
+        for (i, block) in data.iter().enumerate() {
+            *self.0.get_mut(addr as usize + i).ok_or(())? = **block;
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> core::result::Result<u64, ()> {
+        Ok(self.0.len() as u64 * 512)
+    }
+}
+
+/// Poll `f` to completion; the RAM disk never returns `Pending`.
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let mut disk = RamDisk(vec![[0u8; 512]; SECTORS]);
+    let options = FormatOptions {
+        sectors_per_cluster: Some(1),
+        allow_small: true,
+        ..FormatOptions::default()
+    };
+    mkfs::format_with(&mut disk, SECTORS as u32, &options)?;
+
+    let mut fs = block_on(AsyncFat32::mount(BlockDriver::new(disk)))?;
+    block_on(fs.write_file_root("LOG.TXT", b"hello from embassy\n"))?;
+    let data = block_on(fs.read_file_root("LOG.TXT"))?;
+    print!("{}", String::from_utf8_lossy(&data));
+    for e in block_on(fs.list_root())? {
+        println!("{:12} {:6}", e.name(), e.file_size);
+    }
+    block_on(fs.unmount())?;
+    Ok(())
+}
@@ -0,0 +1,39 @@
+#!/bin/sh
+# Build the reference images checked by `compat::tests::reference_images_pass`.
+#
+# Needs dosfstools (mkfs.fat) and mtools; no root. Every image holds the same
+# files, which the test knows:
+#   HELLO.TXT       "hello\n"
+#   SUB/DATA.BIN    4096 bytes, byte i = i % 251
+#   LONG NAME.TXT   "long\n" (long file name, 8.3 alias LONGNA~1.TXT)
+#
+# Windows images: format a 40 MiB VHD as FAT32 in Disk Management, copy the
+# same three files in with Explorer, detach it, then extract the partition
+# (e.g. `dd if=disk.vhd of=windows-fat32.img bs=512 skip=<partition start>
+# count=<partition sectors>`) and gzip it into testdata/compat/ as below.
+set -eu
+
+OUT=$(dirname "$0")/../testdata/compat
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+printf 'hello\n' >"$TMP/hello"
+printf 'long\n' >"$TMP/long"
+python3 -c 'import sys; sys.stdout.buffer.write(bytes(i % 251 for i in range(4096)))' >"$TMP/data"
+
+# name, size in KiB, extra mkfs.fat arguments
+make_image() {
+	img="$TMP/$1.img"
+	rm -f "$img"
+	mkfs.fat -C -F 32 -n COMPAT -i 12345678 $3 "$img" "$2"
+	mcopy -i "$img" "$TMP/hello" ::/HELLO.TXT
+	mmd -i "$img" ::/SUB
+	mcopy -i "$img" "$TMP/data" ::/SUB/DATA.BIN
+	mcopy -i "$img" "$TMP/long" "::/LONG NAME.TXT"
+	gzip -9n <"$img" >"$OUT/$1.img.gz"
+}
+
+mkdir -p "$OUT"
+make_image mkfs-fat-s1 34000 "-s 1"
+make_image mkfs-fat-s8 300000 "-s 8"
+make_image mkfs-fat-1fat 34000 "-s 1 -f 1"
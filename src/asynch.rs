@@ -0,0 +1,456 @@
+//! Async block device and filesystem API (`async` feature).
+//!
+//! Mirrors the root-directory subset of `Fat32` for executors such as embassy,
+//! where a blocking SDMMC/SPI driver would stall every other task.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::bpb::{Bpb, BOOT_FLAGS_OFFSET, BOOT_FLAG_DIRTY};
+use crate::dir::{nt_case_flags, to_short_name_83, DirEntry, ATTR_DIRECTORY, ATTR_LFN};
+use crate::error::{Error, Result};
+use crate::fat::{cluster_to_lba, fat_start_lba, max_cluster, EOC_MIN, FAT1_CLEAN_SHUTDOWN};
+use crate::metadata::ATTR_READ_ONLY;
+
+/// Async counterpart of `BlockDevice`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockDevice {
+    /// Read a 512-byte sector at `lba` into `buf`.
+    async fn read_sector(&mut self, lba: u64, buf: &mut [u8; 512]) -> Result<()>;
+
+    /// Write a 512-byte sector at `lba` from `buf`.
+    async fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()>;
+
+    /// Make every completed write durable; the default does nothing.
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: AsyncBlockDevice> AsyncBlockDevice for &mut T {
+    async fn read_sector(&mut self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        T::read_sector(self, lba, buf).await
+    }
+
+    async fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        T::write_sector(self, lba, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        T::flush(self).await
+    }
+}
+
+/// Async FAT32 filesystem handle (root directory, 8.3 names, FAT #0).
+pub struct AsyncFat32<D: AsyncBlockDevice> {
+    dev: D,
+    bpb: Bpb,
+    dirty: bool,
+}
+
+impl<D: AsyncBlockDevice> AsyncFat32<D> {
+    /// Mount a FAT32 volume by reading and parsing sector 0.
+    pub async fn mount(mut dev: D) -> Result<Self> {
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot).await?;
+        let bpb = Bpb::parse(&boot)?;
+        fs_debug!("fat32: async mount root={}", bpb.root_cluster);
+        Ok(Self {
+            dev,
+            bpb,
+            dirty: false,
+        })
+    }
+
+    /// Return parsed BPB info.
+    pub fn bpb(&self) -> &Bpb {
+        &self.bpb
+    }
+
+    /// Read the root directory entries (8.3 only, skipping deleted and LFN records).
+    #[cfg(feature = "alloc")]
+    pub async fn list_root(&mut self) -> Result<Vec<DirEntry>> {
+        let mut out = Vec::new();
+        let mut cluster = self.bpb.root_cluster;
+        loop {
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.dev.read_sector(base_lba + s, &mut buf).await?;
+                for i in 0..16 {
+                    let mut rec = [0u8; 32];
+                    rec.copy_from_slice(&buf[i * 32..i * 32 + 32]);
+                    if rec[0] == 0x00 {
+                        return Ok(out);
+                    }
+                    if rec[0] == 0xE5 || rec[11] == ATTR_LFN {
+                        continue;
+                    }
+                    if let Some(e) = DirEntry::parse(&rec)? {
+                        out.push(e.located(cluster, (s * 512) as u32 + i as u32 * 32));
+                    }
+                }
+            }
+            cluster = match self.next_cluster(cluster).await? {
+                Some(next) => next,
+                None => return Ok(out),
+            };
+        }
+    }
+
+    /// Read a file by short name (8.3 only) from root directory.
+    #[cfg(feature = "alloc")]
+    pub async fn read_file_root(&mut self, name: &str) -> Result<Vec<u8>> {
+        let target = to_short_name_83(name)?;
+        let e = self
+            .list_root()
+            .await?
+            .into_iter()
+            .find(|e| e.raw_name == target)
+            .ok_or(Error::NotFound)?;
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+
+        let mut remaining = e.file_size as usize;
+        let mut data = Vec::with_capacity(remaining);
+        let mut cluster = e.first_cluster;
+        while remaining > 0 {
+            if cluster < 2 {
+                return Err(Error::Corrupt);
+            }
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.dev.read_sector(base_lba + s, &mut buf).await?;
+                let take = remaining.min(512);
+                data.extend_from_slice(&buf[..take]);
+                remaining -= take;
+                if remaining == 0 {
+                    return Ok(data);
+                }
+            }
+            cluster = self
+                .next_cluster(cluster)
+                .await?
+                .ok_or(Error::UnexpectedEof)?;
+        }
+        Ok(data)
+    }
+
+    /// Create or replace a root file (8.3) holding `content`, like
+    /// `Fat32::write_file_root`: an existing file gets the new chain in its
+    /// entry, and its old chain is freed last.
+    pub async fn write_file_root(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        let short = to_short_name_83(name)?;
+        if content.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        let existing = self.find_root_entry(&short).await?;
+        if let Some((e, _, _)) = &existing {
+            if e.attr & ATTR_DIRECTORY != 0 {
+                return Err(Error::IsADirectory);
+            }
+            if e.attr & ATTR_READ_ONLY != 0 {
+                return Err(Error::ReadOnlyFile);
+            }
+        }
+        self.mark_dirty().await?;
+
+        let bytes_per_cluster = (self.bpb.sectors_per_cluster as usize) * 512;
+        let mut first = 0u32;
+        let mut prev = None;
+        for chunk in content.chunks(bytes_per_cluster) {
+            let cluster = self.alloc_cluster(prev).await?;
+            if first == 0 {
+                first = cluster;
+            }
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as usize) {
+                let mut sector = [0u8; 512];
+                let start = (s * 512).min(chunk.len());
+                let end = (start + 512).min(chunk.len());
+                sector[..end - start].copy_from_slice(&chunk[start..end]);
+                self.dev.write_sector(base_lba + s as u64, &sector).await?;
+            }
+            prev = Some(cluster);
+        }
+
+        match existing {
+            Some((old, lba, slot)) => {
+                let mut buf = [0u8; 512];
+                self.dev.read_sector(lba, &mut buf).await?;
+                let rec = &mut buf[slot * 32..slot * 32 + 32];
+                rec[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+                rec[26..28].copy_from_slice(&((first & 0xFFFF) as u16).to_le_bytes());
+                rec[28..32].copy_from_slice(&(content.len() as u32).to_le_bytes());
+                self.dev.write_sector(lba, &buf).await?;
+                self.free_chain(old.first_cluster).await
+            }
+            None => {
+                let mut rec = DirEntry::build_short_file(short, first, content.len() as u32);
+                rec[12] = nt_case_flags(name);
+                self.write_root_entry(&rec).await
+            }
+        }
+    }
+
+    /// Clear the "volume dirty" flags set by the first write of this session.
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            self.set_dirty_flags(false).await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// `flush`, then flush the device's write cache (see `Fat32::sync`).
+    pub async fn sync(&mut self) -> Result<()> {
+        self.flush().await?;
+        self.dev.flush().await
+    }
+
+    /// Sync and return the underlying device.
+    pub async fn unmount(mut self) -> Result<D> {
+        self.sync().await?;
+        Ok(self.dev)
+    }
+
+    async fn mark_dirty(&mut self) -> Result<()> {
+        if !self.dirty {
+            self.set_dirty_flags(true).await?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    async fn set_dirty_flags(&mut self, dirty: bool) -> Result<()> {
+        let fat1 = self.read_fat(1).await?;
+        let fat1 = if dirty {
+            fat1 & !FAT1_CLEAN_SHUTDOWN
+        } else {
+            fat1 | FAT1_CLEAN_SHUTDOWN
+        };
+        self.write_fat(1, fat1).await?;
+
+        let mut boot = [0u8; 512];
+        self.dev.read_sector(0, &mut boot).await?;
+        if dirty {
+            boot[BOOT_FLAGS_OFFSET] |= BOOT_FLAG_DIRTY;
+        } else {
+            boot[BOOT_FLAGS_OFFSET] &= !BOOT_FLAG_DIRTY;
+        }
+        self.dev.write_sector(0, &boot).await
+    }
+
+    /// Root entry named `short`, with the LBA and slot holding it.
+    async fn find_root_entry(&mut self, short: &[u8; 11]) -> Result<Option<(DirEntry, u64, usize)>> {
+        let mut cluster = self.bpb.root_cluster;
+        loop {
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.dev.read_sector(base_lba + s, &mut buf).await?;
+                for i in 0..16 {
+                    let rec = &buf[i * 32..i * 32 + 32];
+                    if rec[0] == 0x00 {
+                        return Ok(None);
+                    }
+                    if rec[0] == 0xE5 || rec[11] == ATTR_LFN || rec[..11] != short[..] {
+                        continue;
+                    }
+                    let mut raw = [0u8; 32];
+                    raw.copy_from_slice(rec);
+                    if let Some(e) = DirEntry::parse(&raw)? {
+                        return Ok(Some((e, base_lba + s, i)));
+                    }
+                }
+            }
+            cluster = match self.next_cluster(cluster).await? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+        }
+    }
+
+    /// Store `rec` in the first free root slot, growing the root by one cluster if needed.
+    async fn write_root_entry(&mut self, rec: &[u8; 32]) -> Result<()> {
+        let mut cluster = self.bpb.root_cluster;
+        loop {
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.dev.read_sector(base_lba + s, &mut buf).await?;
+                for i in 0..16 {
+                    let first = buf[i * 32];
+                    if first == 0x00 || first == 0xE5 {
+                        if first == 0x00 {
+                            // Keep the directory ending right after the new entry.
+                            buf[(i + 1) * 32..].fill(0);
+                            if i == 15 {
+                                self.clear_dir_sector_after(cluster, base_lba + s).await?;
+                            }
+                        }
+                        buf[i * 32..i * 32 + 32].copy_from_slice(rec);
+                        return self.dev.write_sector(base_lba + s, &buf).await;
+                    }
+                }
+            }
+            match self.next_cluster(cluster).await? {
+                Some(next) => cluster = next,
+                None => {
+                    let new = self.alloc_cluster(None).await?;
+                    let base_lba = cluster_to_lba(&self.bpb, new)?;
+                    let mut buf = [0u8; 512];
+                    buf[0..32].copy_from_slice(rec);
+                    self.dev.write_sector(base_lba, &buf).await?;
+                    buf = [0u8; 512];
+                    for s in 1..(self.bpb.sectors_per_cluster as u64) {
+                        self.dev.write_sector(base_lba + s, &buf).await?;
+                    }
+                    return self.write_fat(cluster, new).await;
+                }
+            }
+        }
+    }
+
+    /// Zero the directory sector following `lba` unless it already starts with an end marker.
+    async fn clear_dir_sector_after(&mut self, cluster: u32, lba: u64) -> Result<()> {
+        let next = if lba + 1 < cluster_to_lba(&self.bpb, cluster)? + self.bpb.sectors_per_cluster as u64 {
+            lba + 1
+        } else {
+            match self.next_cluster(cluster).await? {
+                Some(c) => cluster_to_lba(&self.bpb, c)?,
+                None => return Ok(()),
+            }
+        };
+        let mut buf = [0u8; 512];
+        self.dev.read_sector(next, &mut buf).await?;
+        if buf[0] != 0x00 {
+            self.dev.write_sector(next, &[0u8; 512]).await?;
+        }
+        Ok(())
+    }
+
+    /// Next cluster of a chain, `None` at end of chain.
+    async fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>> {
+        let next = self.read_fat(cluster).await?;
+        if next >= EOC_MIN {
+            return Ok(None);
+        }
+        if next < 2 {
+            return Err(Error::Corrupt);
+        }
+        Ok(Some(next))
+    }
+
+    /// Mark every cluster of the chain starting at `first` free.
+    async fn free_chain(&mut self, first: u32) -> Result<()> {
+        let mut c = first;
+        while (2..=max_cluster(&self.bpb)).contains(&c) {
+            let next = self.read_fat(c).await?;
+            self.write_fat(c, 0).await?;
+            c = next;
+        }
+        Ok(())
+    }
+
+    async fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32> {
+        let start = prev.map_or(2, |p| p + 1);
+        for c in start..=max_cluster(&self.bpb) {
+            if self.read_fat(c).await? == 0 {
+                fs_trace!("fat32: allocate cluster {}", c);
+                self.write_fat(c, 0x0FFFFFFF).await?;
+                if let Some(p) = prev {
+                    self.write_fat(p, c).await?;
+                }
+                return Ok(c);
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    async fn read_fat(&mut self, cluster: u32) -> Result<u32> {
+        let (lba, off) = fat_entry_pos(&self.bpb, cluster);
+        let mut buf = [0u8; 512];
+        self.dev.read_sector(lba, &mut buf).await?;
+        Ok(u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) & 0x0FFFFFFF)
+    }
+
+    async fn write_fat(&mut self, cluster: u32, value: u32) -> Result<()> {
+        let (lba, off) = fat_entry_pos(&self.bpb, cluster);
+        let mut buf = [0u8; 512];
+        self.dev.read_sector(lba, &mut buf).await?;
+        buf[off..off + 4].copy_from_slice(&(value & 0x0FFFFFFF).to_le_bytes());
+        self.dev.write_sector(lba, &buf).await
+    }
+}
+
+fn fat_entry_pos(bpb: &Bpb, cluster: u32) -> (u64, usize) {
+    let fat_offset = cluster as u64 * 4;
+    (
+        fat_start_lba(bpb) + fat_offset / 512,
+        (fat_offset % 512) as usize,
+    )
+}
+
+#[cfg(all(test, feature = "alloc"))]
+pub(crate) mod tests {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use super::*;
+    use crate::device::{BlockDevice, MemDevice};
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    impl AsyncBlockDevice for MemDevice {
+        async fn read_sector(&mut self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+            BlockDevice::read_sector(self, lba, buf)
+        }
+
+        async fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+            BlockDevice::write_sector(self, lba, buf)
+        }
+    }
+
+    /// Poll a future that never actually waits.
+    pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = pin!(f);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                return v;
+            }
+        }
+    }
+
+    #[test]
+    fn async_write_then_read() {
+        let mut fs =
+            block_on(AsyncFat32::mount(MemDevice::new(make_tiny_fat32_image()))).expect("mount");
+        let big = [7u8; 1500];
+        block_on(fs.write_file_root("BIG.BIN", &big)).expect("write");
+        assert_eq!(block_on(fs.read_file_root("BIG.BIN")).unwrap(), big);
+
+        // The sync API sees the same volume.
+        let dev = block_on(fs.unmount()).unwrap();
+        let sync = crate::fs::Fat32::mount(dev).expect("mount");
+        assert_eq!(sync.read_file_root("BIG.BIN").unwrap(), big);
+    }
+
+    #[test]
+    fn async_write_replaces_existing_file() {
+        let mut fs =
+            block_on(AsyncFat32::mount(MemDevice::new(make_tiny_fat32_image()))).expect("mount");
+        block_on(fs.write_file_root("A.TXT", &[1u8; 1500])).expect("write");
+        block_on(fs.write_file_root("a.txt", b"second")).expect("replace");
+        assert_eq!(block_on(fs.read_file_root("A.TXT")).unwrap(), b"second");
+        assert_eq!(block_on(fs.list_root()).unwrap().len(), 1);
+
+        // The old chain went back to the free pool.
+        let dev = block_on(fs.unmount()).unwrap();
+        let sync = crate::fs::Fat32::mount(dev).expect("mount");
+        assert!(sync.check().unwrap().is_clean());
+    }
+}
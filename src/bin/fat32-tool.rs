@@ -0,0 +1,179 @@
+//! `fat32-tool`: inspect and change FAT32 image files (`cli` feature).
+//!
+//! ```text
+//! fat32-tool IMAGE ls [DIR]
+//! fat32-tool IMAGE cat PATH
+//! fat32-tool IMAGE cp-in HOST_FILE PATH
+//! fat32-tool IMAGE cp-out PATH HOST_FILE
+//! fat32-tool IMAGE mkdir PATH
+//! fat32-tool IMAGE rm PATH
+//! fat32-tool IMAGE format SIZE_MIB [SECTORS_PER_CLUSTER]
+//! fat32-tool IMAGE fsck [--repair]
+//! ```
+//!
+//! Paths inside the image are `/`-separated 8.3 names.
+
+use std::io::Write;
+use std::process::ExitCode;
+
+use fat32::file_device::FileDevice;
+use fat32::fsck::LostChainAction;
+use fat32::mkfs::{self, FormatOptions};
+use fat32::{Fat32, MountOptions};
+
+const USAGE: &str = "usage: fat32-tool IMAGE COMMAND [ARGS]
+
+commands:
+  ls [DIR]                       list a directory (default /)
+  cat PATH                       print a file
+  cp-in HOST_FILE PATH           copy a host file into the image
+  cp-out PATH HOST_FILE          copy a file out of the image
+  mkdir PATH                     create a directory and missing parents
+  rm PATH                        remove a file or an empty directory
+  format SIZE_MIB [SPC]          create IMAGE and format it
+  fsck [--repair]                check the volume, freeing lost chains with --repair";
+
+type CmdResult = Result<(), Box<dyn std::error::Error>>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        [image, "ls"] => ls(image, "/"),
+        [image, "ls", dir] => ls(image, dir),
+        [image, "cat", path] => cat(image, path),
+        [image, "cp-in", host, path] => copy_in(image, host, path),
+        [image, "cp-out", path, host] => copy_out(image, path, host),
+        [image, "mkdir", path] => edit(image, |fs| Ok(fs.create_dir_all(path)?)),
+        [image, "rm", path] => edit(image, |fs| remove(fs, path)),
+        [image, "format", mib] => format(image, mib, None),
+        [image, "format", mib, spc] => format(image, mib, Some(spc)),
+        [image, "fsck"] => fsck(image, false),
+        [image, "fsck", "--repair"] => fsck(image, true),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fat32-tool: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Mount `image` read-only.
+fn open(image: &str) -> Result<Fat32<FileDevice>, Box<dyn std::error::Error>> {
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    Ok(Fat32::mount_with(FileDevice::open_read_only(image)?, options)?)
+}
+
+/// Mount `image` for writing, run `f` and unmount cleanly.
+fn edit(image: &str, f: impl FnOnce(&mut Fat32<FileDevice>) -> CmdResult) -> CmdResult {
+    let mut fs = Fat32::mount(FileDevice::open(image)?)?;
+    f(&mut fs)?;
+    fs.unmount()?;
+    Ok(())
+}
+
+/// Split `path` into its directory (`/` for the root) and file name.
+fn split(path: &str) -> (&str, &str) {
+    let path = path.trim_start_matches('/');
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("/", path),
+    }
+}
+
+fn ls(image: &str, dir: &str) -> CmdResult {
+    let fs = open(image)?;
+    for e in fs.read_dir(dir)? {
+        let e = e?;
+        let m = fs.metadata(&format!("{}/{}", dir.trim_end_matches('/'), e.name()))?;
+        let t = m.modified;
+        let date = match t.date {
+            0 => String::new(),
+            _ => format!("{:04}-{:02}-{:02} {:02}:{:02}", t.year(), t.month(), t.day(), t.hour(), t.minute()),
+        };
+        let size = if e.is_dir() { String::from("<DIR>") } else { m.size.to_string() };
+        println!("{:16} {:>10}  {}", date, size, e.name());
+    }
+    Ok(())
+}
+
+fn cat(image: &str, path: &str) -> CmdResult {
+    let fs = open(image)?;
+    let mut out = std::io::stdout().lock();
+    let mut result = Ok(());
+    fs.read_file_with(path, |d| {
+        if result.is_ok() {
+            result = out.write_all(d);
+        }
+    })?;
+    result?;
+    Ok(out.flush()?)
+}
+
+fn copy_in(image: &str, host: &str, path: &str) -> CmdResult {
+    let content = std::fs::read(host)?;
+    edit(image, |fs| {
+        let (dir, name) = split(path);
+        let mut dir = fs.open_dir(dir)?;
+        let mut file = dir.create_file(name)?;
+        file.write(&content)?;
+        Ok(file.close()?)
+    })
+}
+
+fn copy_out(image: &str, path: &str, host: &str) -> CmdResult {
+    let fs = open(image)?;
+    let mut content = Vec::new();
+    fs.read_file_with(path, |d| content.extend_from_slice(d))?;
+    Ok(std::fs::write(host, content)?)
+}
+
+fn remove(fs: &mut Fat32<FileDevice>, path: &str) -> CmdResult {
+    let (dir, name) = split(path);
+    Ok(fs.open_dir(dir)?.remove(name)?)
+}
+
+fn format(image: &str, mib: &str, spc: Option<&str>) -> CmdResult {
+    let total_sectors = mib.parse::<u32>()?.checked_mul(2048).ok_or("image too large")?;
+    let options = FormatOptions {
+        sectors_per_cluster: spc.map(str::parse).transpose()?,
+        ..FormatOptions::default()
+    };
+    let file = std::fs::File::create(image)?;
+    file.set_len(total_sectors as u64 * 512)?;
+    let mut dev = FileDevice::new(file);
+    mkfs::format_with(&mut dev, total_sectors, &options)?;
+    Ok(())
+}
+
+fn fsck(image: &str, repair: bool) -> CmdResult {
+    let report = if repair {
+        let mut fs = Fat32::mount(FileDevice::open(image)?)?;
+        let report = fs.repair(LostChainAction::Free)?;
+        fs.unmount()?;
+        report
+    } else {
+        open(image)?.check()?
+    };
+    println!("{} directories, {} files", report.directories, report.files);
+    println!("broken chains: {}", report.broken_chains);
+    println!("cross-links: {}", report.cross_links);
+    println!("lost chains: {}", report.lost_chains.len());
+    if repair {
+        println!("chains freed: {}", report.chains_freed);
+    }
+    if report.is_clean() || repair {
+        Ok(())
+    } else {
+        Err("volume has errors; run with --repair to free lost chains".into())
+    }
+}
@@ -0,0 +1,78 @@
+//! Fixed-capacity names and listings backed by `heapless` (`heapless` feature).
+//!
+//! For firmware that forbids dynamic allocation but still wants owned
+//! return values. Nothing here needs the `alloc` feature: paths are resolved
+//! by walking the string in place (`path::Components`).
+
+use crate::codepage::{Ascii, OemCodepage};
+use crate::device::BlockDevice;
+use crate::dir::DirEntry;
+use crate::error::{Error, Result};
+use crate::fs::Fat32;
+
+/// Bytes needed for any decoded short name: 12 characters of up to 3 UTF-8 bytes.
+pub const SHORT_NAME_BYTES: usize = 36;
+
+/// A decoded short name that lives on the stack.
+pub type ShortName = heapless::String<SHORT_NAME_BYTES>;
+
+impl DirEntry {
+    /// `name` without allocating.
+    pub fn name_heapless(&self) -> ShortName {
+        self.name_heapless_with(&Ascii)
+    }
+
+    /// `name_with` without allocating.
+    pub fn name_heapless_with<C: OemCodepage + ?Sized>(&self, cp: &C) -> ShortName {
+        let mut out = ShortName::new();
+        // Cannot overflow: SHORT_NAME_BYTES covers the longest name.
+        self.decode_name(cp, |c| {
+            let _ = out.push(c);
+        });
+        out
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// The entries of directory `path`, as `read_dir` yields them, in a
+    /// vector of capacity `N`.
+    ///
+    /// Fails with `BufferTooSmall` if the directory holds more than `N`
+    /// entries; iterate with `read_dir` instead for directories of unknown size.
+    pub fn read_dir_heapless<const N: usize>(&self, path: &str) -> Result<heapless::Vec<DirEntry, N>> {
+        let mut out = heapless::Vec::new();
+        for e in self.read_dir(path)? {
+            out.push(e?).map_err(|_| Error::BufferTooSmall)?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn names_and_listings_without_alloc() {
+        let img = ImageBuilder::new(200)
+            .file("readme.txt", b"r")
+            .file("DATA.BIN", b"d")
+            .file("LOGS/A.LOG", b"a")
+            .build()
+            .unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let root = fs.read_dir_heapless::<4>("/").unwrap();
+        let names: heapless::Vec<ShortName, 4> = root.iter().map(DirEntry::name_heapless).collect();
+        assert_eq!(names, ["readme.txt", "DATA.BIN", "LOGS"]);
+        assert_eq!(fs.read_dir_heapless::<2>("/").err(), Some(Error::BufferTooSmall));
+        let logs = fs.read_dir_heapless::<4>("/DATA.BIN/../LOGS/.").unwrap();
+        let names: heapless::Vec<ShortName, 4> = logs.iter().map(DirEntry::name_heapless).collect();
+        assert_eq!(names, ["A.LOG"]);
+
+        let mut raw = DirEntry::parse(&DirEntry::build_short_file(*b"\xE9T\xE9     TXT", 3, 1)).unwrap().unwrap();
+        raw.nt_case = 0;
+        assert_eq!(raw.name_heapless(), "\u{FFFD}T\u{FFFD}.TXT");
+    }
+}
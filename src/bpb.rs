@@ -1,84 +1,466 @@
-//! FAT32 BPB / boot sector parsing.
-
-use crate::error::{Error, Result};
-
-/// Parsed FAT32 BPB (Boot Parameter Block) fields required by this MVP.
-#[derive(Debug, Clone, Copy)]
-pub struct Bpb {
-    /// Bytes per sector (usually 512).
-    pub bytes_per_sector: u16,
-    /// Sectors per cluster (power of two).
-    pub sectors_per_cluster: u8,
-    /// Reserved sectors before the FAT region.
-    pub reserved_sectors: u16,
-    /// Number of FATs (usually 2).
-    pub num_fats: u8,
-    /// Total sectors (FAT32 uses 32-bit field).
-    pub total_sectors_32: u32,
-    /// FAT size in sectors (FAT32 field).
-    pub fat_size_32: u32,
-    /// Root directory first cluster.
-    pub root_cluster: u32,
-    /// FSInfo sector (optional, not used in MVP).
-    pub fsinfo_sector: u16,
-}
-
-fn le_u16(x: &[u8]) -> u16 {
-    u16::from_le_bytes([x[0], x[1]])
-}
-fn le_u32(x: &[u8]) -> u32 {
-    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
-}
-
-impl Bpb {
-    /// Parse FAT32 BPB from a 512-byte boot sector.
-    pub fn parse(boot: &[u8; 512]) -> Result<Self> {
-        // Signature check (0x55AA at the end)
-        if boot[510] != 0x55 || boot[511] != 0xAA {
-            return Err(Error::InvalidBootSector);
-        }
-
-        let bytes_per_sector = le_u16(&boot[11..13]);
-        let sectors_per_cluster = boot[13];
-        let reserved_sectors = le_u16(&boot[14..16]);
-        let num_fats = boot[16];
-        let root_entry_count = le_u16(&boot[17..19]); // must be 0 for FAT32
-        let fat_size_16 = le_u16(&boot[22..24]);
-
-        let total_sectors_32 = le_u32(&boot[32..36]);
-        let fat_size_32 = le_u32(&boot[36..40]);
-        let root_cluster = le_u32(&boot[44..48]);
-        let fsinfo_sector = le_u16(&boot[48..50]);
-
-        // Minimal validation for FAT32.
-        if bytes_per_sector != 512 {
-            return Err(Error::InvalidBootSector);
-        }
-        if root_entry_count != 0 {
-            return Err(Error::NotFat32);
-        }
-        if fat_size_16 != 0 {
-            return Err(Error::NotFat32);
-        }
-        if fat_size_32 == 0 || root_cluster < 2 {
-            return Err(Error::InvalidBootSector);
-        }
-        if sectors_per_cluster == 0 || (sectors_per_cluster & (sectors_per_cluster - 1)) != 0 {
-            return Err(Error::InvalidBootSector);
-        }
-        if reserved_sectors == 0 || num_fats == 0 {
-            return Err(Error::InvalidBootSector);
-        }
-
-        Ok(Self {
-            bytes_per_sector,
-            sectors_per_cluster,
-            reserved_sectors,
-            num_fats,
-            total_sectors_32,
-            fat_size_32,
-            root_cluster,
-            fsinfo_sector,
-        })
-    }
-}
+//! FAT32 BPB / boot sector parsing.
+
+use crate::error::{Error, Result};
+
+/// Offset of the reserved byte Windows uses for volume flags (FAT32 `BS_Reserved1`).
+pub const BOOT_FLAGS_OFFSET: usize = 0x41;
+/// Volume flag: the volume was not cleanly unmounted.
+pub const BOOT_FLAG_DIRTY: u8 = 0x01;
+/// Conventional location of the backup boot sector.
+pub const DEFAULT_BACKUP_BOOT_SECTOR: u16 = 6;
+/// Bytes of a FAT32 boot sector holding x86 boot code (after the BPB, before the signature).
+pub const BOOT_CODE: core::ops::Range<usize> = 0x5A..0x1FE;
+
+/// Parsed FAT32 BPB (Boot Parameter Block) fields required by this MVP.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Bpb {
+    /// Bytes per sector (usually 512).
+    pub bytes_per_sector: u16,
+    /// Sectors per cluster (power of two).
+    pub sectors_per_cluster: u8,
+    /// Reserved sectors before the FAT region.
+    pub reserved_sectors: u16,
+    /// Number of FATs (usually 2).
+    pub num_fats: u8,
+    /// Total sectors: the 32-bit field, or the 16-bit one if that is zero.
+    pub total_sectors_32: u32,
+    /// The 16-bit total sectors field as stored (0 on a conforming FAT32 volume).
+    pub total_sectors_16: u16,
+    /// FAT size in sectors (FAT32 field).
+    pub fat_size_32: u32,
+    /// Root directory first cluster.
+    pub root_cluster: u32,
+    /// FSInfo sector (optional, not used in MVP).
+    pub fsinfo_sector: u16,
+    /// Sector holding the backup copy of the boot sector (0 or 0xFFFF if none).
+    pub backup_boot_sector: u16,
+    /// Sectors preceding the volume on the disk: the partition start, or 0
+    /// for an unpartitioned device.
+    pub hidden_sectors: u32,
+    /// Media descriptor (0xF8 for fixed disks).
+    pub media: u8,
+    /// BIOS drive number (0x80 for the first hard disk).
+    pub drive_number: u8,
+    /// Extended boot signature: 0x29 if the volume ID, label and type
+    /// string are present, 0x28 if only the volume ID is.
+    pub boot_signature: u8,
+    /// Volume serial number (0 if `boot_signature` is neither 0x28 nor 0x29).
+    pub volume_id: u32,
+    /// Volume label as stored in the boot sector, space-padded.
+    pub volume_label: [u8; 11],
+    /// File system type string, normally `FAT32   ` (informational only).
+    pub fs_type: [u8; 8],
+}
+
+fn le_u16(x: &[u8]) -> u16 {
+    u16::from_le_bytes([x[0], x[1]])
+}
+fn le_u32(x: &[u8]) -> u32 {
+    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
+}
+
+impl Bpb {
+    /// Parse FAT32 BPB from a 512-byte boot sector.
+    pub fn parse(boot: &[u8; 512]) -> Result<Self> {
+        Self::parse_with(boot, true)
+    }
+
+    /// `parse_with` on the first 512 bytes of `data`, for fuzzers and other
+    /// callers holding an unchecked slice; shorter input is `InvalidBootSector`.
+    ///
+    /// Like every parser in this crate, it never panics, whatever the input.
+    pub fn parse_bytes(data: &[u8], strict: bool) -> Result<Self> {
+        let boot = data.first_chunk::<512>().ok_or(Error::InvalidBootSector)?;
+        Self::parse_with(boot, strict)
+    }
+
+    /// Parse FAT32 BPB in lenient mode (see `parse_with`).
+    pub fn parse_lenient(boot: &[u8; 512]) -> Result<Self> {
+        Self::parse_with(boot, false)
+    }
+
+    /// Parse FAT32 BPB, optionally tolerating recoverable deviations.
+    ///
+    /// The size is taken from `total_sectors_32`, or from `total_sectors_16`
+    /// when the 32-bit field is zero; a volume with neither is rejected.
+    ///
+    /// Strict mode rejects anything unusual. Lenient mode (`strict == false`),
+    /// for boot sectors written by cameras and cheap tools, additionally:
+    /// - ignores a missing 0x55AA signature
+    /// - ignores leftover FAT12/16 fields (root entry count, 16-bit FAT size)
+    /// - accepts a `total_sectors_16` that disagrees with `total_sectors_32`
+    ///   (see `sizes_disagree`), keeping the 32-bit value
+    /// - accepts any media descriptor, and a type string other than
+    ///   `FAT32   ` after an 0x29 extended boot signature
+    /// - drops FSInfo / backup boot sector pointers that lie outside the reserved area
+    pub fn parse_with(boot: &[u8; 512], strict: bool) -> Result<Self> {
+        // Signature check (0x55AA at the end)
+        if strict && (boot[510] != 0x55 || boot[511] != 0xAA) {
+            return Err(Error::InvalidBootSector);
+        }
+
+        let bytes_per_sector = le_u16(&boot[11..13]);
+        let sectors_per_cluster = boot[13];
+        let reserved_sectors = le_u16(&boot[14..16]);
+        let num_fats = boot[16];
+        let root_entry_count = le_u16(&boot[17..19]); // must be 0 for FAT32
+        let fat_size_16 = le_u16(&boot[22..24]);
+
+        let total_sectors_16 = le_u16(&boot[19..21]);
+
+        let mut total_sectors_32 = le_u32(&boot[32..36]);
+        let fat_size_32 = le_u32(&boot[36..40]);
+        let root_cluster = le_u32(&boot[44..48]);
+        let hidden_sectors = le_u32(&boot[28..32]);
+        let media = boot[21];
+        let boot_signature = boot[66];
+        let has_volume_id = matches!(boot_signature, 0x28 | 0x29);
+        let volume_id = if has_volume_id { le_u32(&boot[67..71]) } else { 0 };
+        let mut volume_label = [b' '; 11];
+        let mut fs_type = [b' '; 8];
+        if boot_signature == 0x29 {
+            volume_label.copy_from_slice(&boot[71..82]);
+            fs_type.copy_from_slice(&boot[82..90]);
+        }
+        let mut fsinfo_sector = le_u16(&boot[48..50]);
+        let mut backup_boot_sector = le_u16(&boot[50..52]);
+
+        if total_sectors_32 == 0 {
+            total_sectors_32 = total_sectors_16 as u32;
+        }
+        if !strict {
+            if fsinfo_sector == 0 || fsinfo_sector >= reserved_sectors {
+                fsinfo_sector = 0xFFFF;
+            }
+            if backup_boot_sector >= reserved_sectors {
+                backup_boot_sector = 0;
+            }
+        }
+
+        // Minimal validation for FAT32.
+        if bytes_per_sector != 512 {
+            return Err(match bytes_per_sector {
+                1024 | 2048 | 4096 => Error::UnsupportedSectorSize,
+                _ => Error::InvalidBootSector,
+            });
+        }
+        if strict && root_entry_count != 0 {
+            return Err(Error::NotFat32);
+        }
+        if strict && fat_size_16 != 0 {
+            return Err(Error::NotFat32);
+        }
+        if strict && boot_signature == 0x29 && fs_type != *b"FAT32   " {
+            return Err(Error::NotFat32);
+        }
+        if strict && media != 0xF0 && media < 0xF8 {
+            return Err(Error::InvalidBootSector);
+        }
+        if total_sectors_32 == 0 {
+            return Err(Error::InvalidBootSector);
+        }
+        if strict && total_sectors_16 != 0 && total_sectors_16 as u32 != total_sectors_32 {
+            return Err(Error::InvalidBootSector);
+        }
+        if fat_size_32 == 0 || root_cluster < 2 {
+            return Err(Error::InvalidBootSector);
+        }
+        if sectors_per_cluster == 0 || (sectors_per_cluster & (sectors_per_cluster - 1)) != 0 {
+            return Err(Error::InvalidBootSector);
+        }
+        if reserved_sectors == 0 || num_fats == 0 {
+            return Err(Error::InvalidBootSector);
+        }
+
+        Ok(Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            total_sectors_32,
+            total_sectors_16,
+            fat_size_32,
+            root_cluster,
+            fsinfo_sector,
+            backup_boot_sector,
+            hidden_sectors,
+            media,
+            drive_number: boot[64],
+            boot_signature,
+            volume_id,
+            volume_label,
+            fs_type,
+        })
+    }
+
+    /// True if the 16-bit and 32-bit total sectors fields both hold a size
+    /// and the sizes differ (only possible after a lenient parse).
+    pub fn sizes_disagree(&self) -> bool {
+        self.total_sectors_16 != 0 && self.total_sectors_16 as u32 != self.total_sectors_32
+    }
+
+    /// Disk-absolute LBA of volume-relative `lba`, using `hidden_sectors`.
+    pub fn absolute_lba(&self, lba: u64) -> u64 {
+        lba + self.hidden_sectors as u64
+    }
+
+    /// True if the volume declares a backup boot sector.
+    pub fn has_backup_boot_sector(&self) -> bool {
+        self.backup_boot_sector != 0 && self.backup_boot_sector != 0xFFFF
+    }
+}
+
+/// Assembles a FAT32 boot sector (jump, OEM name, BPB, extended fields,
+/// signature) from typed fields.
+///
+/// Start from `new` for a fresh volume, or from `from_sector` to patch
+/// selected fields of an existing boot sector while keeping its boot code.
+///
+/// ```ignore
+/// let boot = BootSectorBuilder::new(total_sectors, fat_size)
+///     .sectors_per_cluster(8)
+///     .volume_label(*b"DATA       ")
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct BootSectorBuilder {
+    sector: [u8; 512],
+}
+
+impl BootSectorBuilder {
+    /// A boot sector for `total_sectors` with `fat_size`-sector FATs, with the
+    /// defaults `format` uses: one sector per cluster, 32 reserved sectors,
+    /// two FATs, root in cluster 2, FSInfo at 1, backup at 6, label "NO NAME".
+    pub fn new(total_sectors: u32, fat_size: u32) -> Self {
+        Self { sector: [0u8; 512] }
+            .oem_name(*b"MSWIN4.1")
+            .sectors_per_cluster(1)
+            .reserved_sectors(32)
+            .num_fats(2)
+            .media(0xF8)
+            .geometry(63, 255)
+            .total_sectors(total_sectors)
+            .fat_size(fat_size)
+            .root_cluster(2)
+            .fsinfo_sector(1)
+            .backup_boot_sector(DEFAULT_BACKUP_BOOT_SECTOR)
+            .drive_number(0x80)
+            .volume_label(*b"NO NAME    ")
+    }
+
+    /// Start from an existing boot sector; fields not set are kept as they are.
+    pub fn from_sector(boot: &[u8; 512]) -> Self {
+        Self { sector: *boot }
+    }
+
+    fn put(mut self, offset: usize, bytes: &[u8]) -> Self {
+        self.sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    /// OEM name (bytes 3..11).
+    pub fn oem_name(self, name: [u8; 8]) -> Self {
+        self.put(3, &name)
+    }
+
+    /// Cluster size in sectors (power of two).
+    pub fn sectors_per_cluster(self, n: u8) -> Self {
+        self.put(13, &[n])
+    }
+
+    /// Sectors before the first FAT.
+    pub fn reserved_sectors(self, n: u16) -> Self {
+        self.put(14, &n.to_le_bytes())
+    }
+
+    /// Number of FAT copies.
+    pub fn num_fats(self, n: u8) -> Self {
+        self.put(16, &[n])
+    }
+
+    /// Media descriptor (0xF8 for fixed disks).
+    pub fn media(self, media: u8) -> Self {
+        self.put(21, &[media])
+    }
+
+    /// CHS geometry: sectors per track and number of heads.
+    pub fn geometry(self, sectors_per_track: u16, heads: u16) -> Self {
+        self.put(24, &sectors_per_track.to_le_bytes()).put(26, &heads.to_le_bytes())
+    }
+
+    /// Sectors preceding the volume on the disk (partition start).
+    pub fn hidden_sectors(self, n: u32) -> Self {
+        self.put(28, &n.to_le_bytes())
+    }
+
+    /// Volume size in sectors.
+    pub fn total_sectors(self, n: u32) -> Self {
+        self.put(32, &n.to_le_bytes())
+    }
+
+    /// Sectors per FAT.
+    pub fn fat_size(self, n: u32) -> Self {
+        self.put(36, &n.to_le_bytes())
+    }
+
+    /// First cluster of the root directory.
+    pub fn root_cluster(self, cluster: u32) -> Self {
+        self.put(44, &cluster.to_le_bytes())
+    }
+
+    /// FSInfo sector (0xFFFF for none).
+    pub fn fsinfo_sector(self, lba: u16) -> Self {
+        self.put(48, &lba.to_le_bytes())
+    }
+
+    /// Backup boot sector (0 for none).
+    pub fn backup_boot_sector(self, lba: u16) -> Self {
+        self.put(50, &lba.to_le_bytes())
+    }
+
+    /// BIOS drive number (0x80 for the first hard disk).
+    pub fn drive_number(self, n: u8) -> Self {
+        self.put(64, &[n])
+    }
+
+    /// Volume serial number.
+    pub fn volume_id(self, id: u32) -> Self {
+        self.put(67, &id.to_le_bytes())
+    }
+
+    /// Volume label, space-padded.
+    pub fn volume_label(self, label: [u8; 11]) -> Self {
+        self.put(71, &label)
+    }
+
+    /// Boot code placed in the `BOOT_CODE` bytes, where the jump lands.
+    pub fn boot_code(self, code: &[u8; 420]) -> Self {
+        self.put(BOOT_CODE.start, code)
+    }
+
+    /// The boot sector, after checking that it parses as FAT32.
+    ///
+    /// Fills in what every FAT32 boot sector has: a jump instruction (unless
+    /// one is present), 512 bytes per sector, zeroed FAT12/16 fields, the
+    /// extended boot signature, the "FAT32" type string and 0x55AA.
+    pub fn build(self) -> Result<[u8; 512]> {
+        let mut s = self;
+        if !matches!(s.sector[0], 0xEB | 0xE9) {
+            s = s.put(0, &[0xEB, 0x58, 0x90]);
+        }
+        let s = s
+            .put(11, &512u16.to_le_bytes())
+            .put(17, &[0; 4]) // root entry count, 16-bit total sectors
+            .put(22, &[0; 2]) // 16-bit FAT size
+            .put(66, &[0x29])
+            .put(82, b"FAT32   ")
+            .put(510, &[0x55, 0xAA]);
+        Bpb::parse(&s.sector)?;
+        Ok(s.sector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_round_trips_through_parse() {
+        let boot = BootSectorBuilder::new(100_000, 777)
+            .sectors_per_cluster(8)
+            .reserved_sectors(16)
+            .num_fats(1)
+            .root_cluster(5)
+            .volume_id(0xCAFE_F00D)
+            .build()
+            .unwrap();
+        let bpb = Bpb::parse(&boot).unwrap();
+        assert_eq!(
+            (bpb.sectors_per_cluster, bpb.reserved_sectors, bpb.num_fats, bpb.total_sectors_32),
+            (8, 16, 1, 100_000)
+        );
+        assert_eq!((bpb.fat_size_32, bpb.root_cluster, bpb.fsinfo_sector), (777, 5, 1));
+        assert_eq!(&boot[0..11], b"\xEB\x58\x90MSWIN4.1");
+        assert_eq!(&boot[67..90], b"\x0D\xF0\xFE\xCANO NAME    FAT32   ");
+        assert_eq!((bpb.media, bpb.drive_number, bpb.boot_signature, bpb.volume_id), (0xF8, 0x80, 0x29, 0xCAFE_F00D));
+        assert_eq!((&bpb.volume_label, &bpb.fs_type), (b"NO NAME    ", b"FAT32   "));
+
+        let mut patched = boot;
+        patched[100] = 0xAB; // boot code
+        let patched = BootSectorBuilder::from_sector(&patched).fat_size(800).build().unwrap();
+        assert_eq!(Bpb::parse(&patched).unwrap().fat_size_32, 800);
+        assert_eq!((patched[13], patched[100]), (8, 0xAB));
+
+        assert_eq!(BootSectorBuilder::new(100_000, 0).build(), Err(Error::InvalidBootSector));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn parsers_accept_any_bytes() {
+        use crate::dir::{long_name, DirEntry};
+        use crate::fsinfo::FsInfo;
+
+        let valid = BootSectorBuilder::new(4000, 8).build().unwrap();
+        assert!(Bpb::parse_bytes(&valid, true).is_ok());
+        assert_eq!(Bpb::parse_bytes(&valid[..511], true).err(), Some(Error::InvalidBootSector));
+        assert_eq!(FsInfo::parse_bytes(&FsInfo::UNKNOWN.serialize()[..100]), Err(Error::InvalidFsInfo));
+        assert!(matches!(DirEntry::parse_bytes(&[b'A'; 31]), Err(Error::Corrupt)));
+
+        // Random bytes, and the valid sector with random bytes spliced in.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        };
+        for round in 0..2000 {
+            let len = round % 700;
+            let mut data: alloc::vec::Vec<u8> = (0..len).map(|_| next()).collect();
+            if round % 2 == 0 && len >= 512 {
+                data[..512].copy_from_slice(&valid);
+                let at = next() as usize % 90;
+                data[at] = next();
+            }
+            let _ = Bpb::parse_bytes(&data, round % 3 == 0);
+            let _ = FsInfo::parse_bytes(&data);
+            let _ = DirEntry::parse_bytes(&data);
+            let _ = long_name(&data[..len / 32 * 32]);
+        }
+    }
+
+    #[test]
+    fn total_sectors_fields() {
+        let mut boot = BootSectorBuilder::new(4000, 8).build().unwrap();
+        boot[32..36].fill(0);
+        assert_eq!(Bpb::parse(&boot).err(), Some(Error::InvalidBootSector));
+        boot[19..21].copy_from_slice(&4000u16.to_le_bytes());
+        let bpb = Bpb::parse(&boot).unwrap();
+        assert_eq!((bpb.total_sectors_32, bpb.sizes_disagree()), (4000, false));
+
+        boot[32..36].copy_from_slice(&4000u32.to_le_bytes());
+        assert!(Bpb::parse(&boot).is_ok());
+        boot[32..36].copy_from_slice(&5000u32.to_le_bytes());
+        assert_eq!(Bpb::parse(&boot).err(), Some(Error::InvalidBootSector));
+        let bpb = Bpb::parse_lenient(&boot).unwrap();
+        assert_eq!((bpb.total_sectors_32, bpb.sizes_disagree()), (5000, true));
+    }
+
+    #[test]
+    fn strict_rejects_non_fat32_type_and_media() {
+        let boot = BootSectorBuilder::new(4000, 8).build().unwrap();
+        let mut fat16 = boot;
+        fat16[82..90].copy_from_slice(b"FAT16   ");
+        assert_eq!(Bpb::parse(&fat16).err(), Some(Error::NotFat32));
+        assert!(Bpb::parse_lenient(&fat16).is_ok());
+        fat16[66] = 0; // no extended fields: the type string is not looked at
+        assert_eq!(Bpb::parse(&fat16).unwrap().fs_type, *b"        ");
+
+        let mut media = boot;
+        media[21] = 0x12;
+        assert_eq!(Bpb::parse(&media).err(), Some(Error::InvalidBootSector));
+        assert_eq!(Bpb::parse_lenient(&media).unwrap().media, 0x12);
+    }
+}
@@ -0,0 +1,37 @@
+//! Cooperative cancellation of long-running operations.
+
+use crate::error::{Error, Result};
+
+/// Abort request polled by formatting, checking, copying and wiping between sectors.
+///
+/// ```ignore
+/// let stop = core::sync::atomic::AtomicBool::new(false);
+/// let poll = || stop.load(Ordering::Relaxed);
+/// fs.wipe_free_space_cancellable(true, Cancel::new(&poll))?;
+/// ```
+#[derive(Clone, Copy, Default)]
+pub struct Cancel<'a>(Option<&'a dyn Fn() -> bool>);
+
+impl<'a> Cancel<'a> {
+    /// Never cancels.
+    pub const NEVER: Cancel<'static> = Cancel(None);
+
+    /// Cancel once `poll` returns true.
+    pub fn new(poll: &'a dyn Fn() -> bool) -> Self {
+        Self(Some(poll))
+    }
+
+    /// `Err(Cancelled)` if cancellation was requested.
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.0 {
+            Some(poll) if poll() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl core::fmt::Debug for Cancel<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(if self.0.is_some() { "Cancel(..)" } else { "Cancel::NEVER" })
+    }
+}
@@ -0,0 +1,182 @@
+//! Streaming file checksums, e.g. to verify a firmware image before flashing it.
+//!
+//! `Fat32::checksum_file` feeds a file through any `Digest` one sector at a
+//! time, and `File::hashing` hashes the bytes of an ordinary read as they
+//! go by, so verifying data that is read anyway costs no second pass.
+//! `Crc32` is built in; for SHA-256 and the like, implement `Digest` on a
+//! wrapper around the hasher of your choice.
+//!
+//! ```ignore
+//! let crc = fs.checksum_file("FW/APP.BIN", Crc32::new())?;
+//! if crc != expected { return Err(UpdateError::Corrupt); }
+//! ```
+
+use crate::device::BlockDevice;
+use crate::error::Result;
+use crate::file::File;
+use crate::fs::Fat32;
+
+/// A hash or checksum computed incrementally.
+pub trait Digest {
+    /// The final value, e.g. `u32` or `[u8; 32]`.
+    type Output;
+
+    /// Feed the next bytes.
+    fn update(&mut self, data: &[u8]);
+
+    /// Consume the hasher and return the value.
+    fn finalize(self) -> Self::Output;
+}
+
+/// CRC-32 as used by zlib, PNG and Ethernet (reflected polynomial 0xEDB88320).
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    /// A CRC over no bytes yet.
+    pub fn new() -> Self {
+        Self { state: !0 }
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digest for Crc32 {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state = CRC32_TABLE[((self.state ^ b as u32) & 0xFF) as usize] ^ self.state >> 8;
+        }
+    }
+
+    fn finalize(self) -> u32 {
+        !self.state
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Run file `path` (`/`-separated 8.3 components) through `digest` and
+    /// return the result, reading one sector at a time.
+    pub fn checksum_file<H: Digest>(&self, path: &str, mut digest: H) -> Result<H::Output> {
+        self.read_file_with(path, |data| digest.update(data))?;
+        Ok(digest.finalize())
+    }
+}
+
+/// Reads a `File` while feeding every byte returned to a `Digest`, from `File::hashing`.
+pub struct HashingReader<'f, 'a, D: BlockDevice, H: Digest> {
+    file: &'f mut File<'a, D>,
+    digest: H,
+}
+
+impl<'a, D: BlockDevice> File<'a, D> {
+    /// Read through `digest` from the current position on.
+    pub fn hashing<H: Digest>(&mut self, digest: H) -> HashingReader<'_, 'a, D, H> {
+        HashingReader { file: self, digest }
+    }
+}
+
+impl<D: BlockDevice, H: Digest> HashingReader<'_, '_, D, H> {
+    /// `File::read`, then hash the bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.file.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    /// The digest of the bytes read so far, e.g. to check a partial result.
+    pub fn digest(&self) -> &H {
+        &self.digest
+    }
+
+    /// Stop hashing and return the digest value; the file stays where reading stopped.
+    pub fn finalize(self) -> H::Output {
+        self.digest.finalize()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::error::Error;
+    use crate::image::ImageBuilder;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn crc32_of_files() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finalize(), 0xCBF4_3926);
+
+        /// Byte sum, standing in for a caller-supplied hash.
+        struct Sum(u64);
+        impl Digest for Sum {
+            type Output = u64;
+            fn update(&mut self, data: &[u8]) {
+                self.0 += data.iter().map(|&b| b as u64).sum::<u64>();
+            }
+            fn finalize(self) -> u64 {
+                self.0
+            }
+        }
+
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 13) as u8).collect();
+        let img = ImageBuilder::new(200).file("FW/APP.BIN", &data).file("EMPTY.TXT", b"").build().unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let mut whole = Crc32::new();
+        whole.update(&data);
+        assert_eq!(fs.checksum_file("FW/APP.BIN", Crc32::new()), Ok(whole.finalize()));
+        assert_eq!(fs.checksum_file("EMPTY.TXT", Crc32::new()), Ok(0));
+        let sum = data.iter().map(|&b| b as u64).sum();
+        assert_eq!(fs.checksum_file("FW/APP.BIN", Sum(0)), Ok(sum));
+        assert_eq!(fs.checksum_file("FW", Crc32::new()), Err(Error::IsADirectory));
+    }
+
+    #[test]
+    fn hash_while_reading() {
+        let data: Vec<u8> = (0..2500u32).map(|i| (i * 31) as u8).collect();
+        let img = ImageBuilder::new(200).file("DL.BIN", &data).build().unwrap();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let expected = fs.checksum_file("DL.BIN", Crc32::new()).unwrap();
+
+        let mut f = fs.open_file_root("DL.BIN").unwrap();
+        let mut reader = f.hashing(Crc32::new());
+        let mut out = Vec::new();
+        let mut buf = [0u8; 700];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(reader.finalize(), expected);
+        assert_eq!(out, data);
+        assert_eq!(f.position(), 2500);
+    }
+}
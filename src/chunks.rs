@@ -0,0 +1,147 @@
+//! Reading files in fixed-size pieces with bounded memory.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, EOC_MIN};
+use crate::fs::Fat32;
+
+/// Iterator over a file in chunks of at most `chunk_size` bytes, from `Fat32::read_chunks`.
+///
+/// Only one sector is buffered, so memory use is the chunk plus 512 bytes
+/// whatever the file size. `read_into` fills a caller buffer instead of
+/// allocating. Iteration stops after the first error.
+///
+/// The `Iterator` impl needs the `alloc` feature; `read_into` does not.
+#[cfg_attr(not(feature = "alloc"), allow(dead_code))]
+pub struct ReadChunks<'a, D: BlockDevice> {
+    fs: &'a Fat32<D>,
+    chunk_size: usize,
+    cluster: u32,
+    /// Next sector to load within `cluster`.
+    sector: u8,
+    /// File bytes not yet handed out.
+    left: u32,
+    buf: [u8; 512],
+    buf_pos: usize,
+    buf_len: usize,
+    failed: bool,
+}
+
+impl<D: BlockDevice> ReadChunks<'_, D> {
+    /// Copy the next `out.len()` bytes of the file (fewer at the end) into
+    /// `out`, returning how many; 0 once the file is exhausted.
+    pub fn read_into(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut n = 0;
+        while n < out.len() && self.left > 0 {
+            if self.buf_pos == self.buf_len {
+                self.load_sector()?;
+            }
+            let take = (out.len() - n).min(self.buf_len - self.buf_pos);
+            out[n..n + take].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + take]);
+            self.buf_pos += take;
+            self.left -= take as u32;
+            n += take;
+        }
+        Ok(n)
+    }
+
+    /// Bytes of the file not read yet.
+    pub fn remaining(&self) -> u32 {
+        self.left
+    }
+
+    fn load_sector(&mut self) -> Result<()> {
+        if self.sector == self.fs.bpb.sectors_per_cluster {
+            self.cluster = self.fs.read_fat(self.cluster)?;
+            self.sector = 0;
+        }
+        if !(2..EOC_MIN).contains(&self.cluster) {
+            return Err(self.fs.record(Operation::ReadData, None, Some(self.cluster), Error::UnexpectedEof));
+        }
+        let lba = cluster_to_lba(&self.fs.bpb, self.cluster)? + self.sector as u64;
+        self.fs.read_sector(Operation::ReadData, lba, &mut self.buf)?;
+        self.sector += 1;
+        self.buf_pos = 0;
+        self.buf_len = (self.left as usize).min(512);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<D: BlockDevice> Iterator for ReadChunks<'_, D> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.left == 0 || self.failed {
+            return None;
+        }
+        let mut chunk = alloc::vec![0u8; self.chunk_size.min(self.left as usize)];
+        match self.read_into(&mut chunk) {
+            Ok(_) => Some(Ok(chunk)),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Read file `path` (`/`-separated 8.3 components) in chunks of `chunk_size` bytes.
+    ///
+    /// The last chunk holds whatever is left; an empty file yields none. A
+    /// `chunk_size` of 0 fails with `InvalidInput`.
+    pub fn read_chunks(&self, path: &str, chunk_size: usize) -> Result<ReadChunks<'_, D>> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidInput);
+        }
+        let (e, _, _) = self.find_path(path)?;
+        if e.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        Ok(ReadChunks {
+            fs: self,
+            chunk_size,
+            cluster: e.first_cluster,
+            sector: 0,
+            left: e.file_size,
+            buf: [0u8; 512],
+            buf_pos: 0,
+            buf_len: 0,
+            failed: false,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn chunks_cover_the_file() {
+        let data: Vec<u8> = (0..1300u32).map(|i| (i * 7) as u8).collect();
+        let img = ImageBuilder::new(200).file("DATA.BIN", &data).file("EMPTY.TXT", b"").build().unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+
+        let chunks: Vec<Vec<u8>> = fs.read_chunks("DATA.BIN", 300).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [300, 300, 300, 300, 100]);
+        assert_eq!(chunks.concat(), data);
+
+        let mut reader = fs.read_chunks("DATA.BIN", 1).unwrap();
+        let mut buf = [0u8; 1000];
+        assert_eq!(reader.read_into(&mut buf), Ok(1000));
+        assert_eq!(reader.remaining(), 300);
+        assert_eq!(reader.read_into(&mut buf), Ok(300));
+        assert_eq!(&buf[..300], &data[1000..]);
+        assert_eq!(reader.read_into(&mut buf), Ok(0));
+
+        assert_eq!(fs.read_chunks("EMPTY.TXT", 16).unwrap().count(), 0);
+        assert!(matches!(fs.read_chunks("NONE.TXT", 16), Err(Error::NotFound)));
+        assert!(matches!(fs.read_chunks("DATA.BIN", 0), Err(Error::InvalidInput)));
+    }
+}
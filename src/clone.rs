@@ -0,0 +1,97 @@
+//! Sector-level volume backup that skips free space: `Fat32::clone_to`.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::device::BlockDevice;
+use crate::error::{Operation, Result};
+use crate::fat::{cluster_to_lba, data_start_lba};
+use crate::fs::Fat32;
+use crate::stats::ClusterState;
+
+/// Sectors moved per device call.
+const CHUNK_SECTORS: u64 = 64;
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Copy the volume to the same sectors of `dst`, skipping free clusters.
+    ///
+    /// Copied are the reserved area, every FAT copy and each cluster in use;
+    /// free and bad clusters are left as they are on `dst`, so the copy holds
+    /// the same files but not deleted data. `dst` must be at least as large as
+    /// the volume. Returns the number of sectors copied.
+    pub fn clone_to<T: BlockDevice>(&self, dst: &mut T) -> Result<u64> {
+        self.clone_range_to(dst, 0..self.bpb.total_sectors_32 as u64)
+    }
+
+    /// `clone_to` limited to the sectors in `lbas`, e.g. to back up a large
+    /// volume in pieces. Cloning adjacent ranges equals cloning the whole.
+    pub fn clone_range_to<T: BlockDevice>(&self, dst: &mut T, lbas: Range<u64>) -> Result<u64> {
+        let end = lbas.end.min(self.bpb.total_sectors_32 as u64);
+        let data_start = data_start_lba(&self.bpb);
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let mut buf = Vec::new();
+        let mut copied = 0;
+
+        let mut copy = |from: u64, to: u64| -> Result<()> {
+            let (from, to) = (from.max(lbas.start), to.min(end));
+            let mut lba = from;
+            while lba < to {
+                let n = (to - lba).min(CHUNK_SECTORS);
+                buf.resize(n as usize * 512, 0);
+                self.read_sectors(Operation::ReadData, lba, &mut buf)?;
+                dst.write_sectors(lba, &buf)?;
+                lba += n;
+                copied += n;
+            }
+            Ok(())
+        };
+
+        copy(0, data_start)?;
+        for run in self.cluster_map()? {
+            if run.state == ClusterState::Used {
+                let first = cluster_to_lba(&self.bpb, run.first)?;
+                copy(first, first + run.count as u64 * spc)?;
+            }
+        }
+        dst.flush()?;
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn clone_skips_free_clusters() {
+        let img = ImageBuilder::new(4096)
+            .sectors_per_cluster(2)
+            .file("KEEP.BIN", &[7u8; 3000])
+            .file("GONE.BIN", &[9u8; 1024])
+            .file("DIR/NOTE.TXT", b"note")
+            .build()
+            .unwrap();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        fs.remove_file_root("GONE.BIN").unwrap();
+        let data_start = data_start_lba(fs.bpb());
+
+        let mut dst = MemDevice::zeroed(4096);
+        // Root, KEEP.BIN (3 clusters), DIR and NOTE.TXT.
+        assert_eq!(fs.clone_to(&mut dst), Ok(data_start + 6 * 2));
+        assert_eq!(dst.as_slice().windows(1024).position(|w| w == [9u8; 1024]), None);
+
+        let mut pieces = MemDevice::zeroed(4096);
+        let mid = data_start + 3;
+        let n = fs.clone_range_to(&mut pieces, 0..mid).unwrap() + fs.clone_range_to(&mut pieces, mid..u64::MAX).unwrap();
+        assert_eq!(n, data_start + 12);
+        assert!(pieces.as_slice() == dst.as_slice());
+
+        let copy = Fat32::mount(dst).expect("mount clone");
+        assert!(copy.check().unwrap().is_clean());
+        assert_eq!(copy.read_file_root("KEEP.BIN").unwrap(), [7u8; 3000]);
+        let mut buf = [0u8; 8];
+        assert_eq!(copy.read_file_into("DIR/NOTE.TXT", &mut buf), Ok(4));
+    }
+}
@@ -0,0 +1,133 @@
+//! OEM code pages for short (8.3) names.
+//!
+//! Short names store one byte per character in the OEM code page of the
+//! system that wrote them. `Ascii` is always available; `Cp437` (the US
+//! DOS code page) needs the `cp437` feature.
+
+/// Maps short-name bytes to characters and back.
+pub trait OemCodepage {
+    /// Character for `byte` (0x20..=0xFF).
+    fn decode(&self, byte: u8) -> char;
+
+    /// Byte for `c`, or `None` if the code page has no such character.
+    fn encode(&self, c: char) -> Option<u8>;
+}
+
+/// 7-bit ASCII only: bytes 0x80 and up decode as U+FFFD.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ascii;
+
+impl OemCodepage for Ascii {
+    fn decode(&self, byte: u8) -> char {
+        if byte.is_ascii() {
+            byte as char
+        } else {
+            char::REPLACEMENT_CHARACTER
+        }
+    }
+
+    fn encode(&self, c: char) -> Option<u8> {
+        c.is_ascii().then_some(c as u8)
+    }
+}
+
+/// Code page 437, the original IBM PC / US DOS code page.
+#[cfg(feature = "cp437")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cp437;
+
+#[cfg(feature = "cp437")]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+#[cfg(feature = "cp437")]
+impl OemCodepage for Cp437 {
+    fn decode(&self, byte: u8) -> char {
+        match byte {
+            0x80.. => CP437_HIGH[(byte - 0x80) as usize],
+            _ => byte as char,
+        }
+    }
+
+    fn encode(&self, c: char) -> Option<u8> {
+        if c.is_ascii() {
+            return Some(c as u8);
+        }
+        CP437_HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8)
+    }
+}
+
+/// Code page choice for `MountOptions::codepage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Codepage {
+    /// 7-bit ASCII, see `Ascii`.
+    #[default]
+    Ascii,
+    /// US DOS code page 437, see `Cp437`.
+    #[cfg(feature = "cp437")]
+    Cp437,
+}
+
+impl OemCodepage for Codepage {
+    fn decode(&self, byte: u8) -> char {
+        match self {
+            Codepage::Ascii => Ascii.decode(byte),
+            #[cfg(feature = "cp437")]
+            Codepage::Cp437 => Cp437.decode(byte),
+        }
+    }
+
+    fn encode(&self, c: char) -> Option<u8> {
+        match self {
+            Codepage::Ascii => Ascii.encode(c),
+            #[cfg(feature = "cp437")]
+            Codepage::Cp437 => Cp437.encode(c),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc", feature = "cp437"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::dir::{to_short_name_83_with, DirEntry, ATTR_ARCHIVE};
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+    use crate::options::MountOptions;
+
+    #[test]
+    fn cp437_round_trips_names() {
+        let short = to_short_name_83_with("CAFÉ.TXT", &Cp437).unwrap();
+        assert_eq!(&short, b"CAF\x90    TXT");
+        let e = DirEntry::parse(&DirEntry::build_short_file(short, 0, 0)).unwrap().unwrap();
+        assert_eq!(e.name_with(&Cp437), "CAFÉ.TXT");
+        assert_eq!(e.name(), "CAF\u{fffd}.TXT");
+
+        // A leading 0xE5 ('σ') is stored as 0x05.
+        let short = to_short_name_83_with("σ.TXT", &Cp437).unwrap();
+        assert_eq!(short[0], 0x05);
+        let e = DirEntry::parse(&DirEntry::build_short_entry(short, ATTR_ARCHIVE, 0, 0)).unwrap().unwrap();
+        assert_eq!(e.name_with(&Cp437), "σ.TXT");
+        assert!(to_short_name_83_with("€.TXT", &Cp437).is_err());
+    }
+
+    #[test]
+    fn mount_codepage_applies_to_lookups() {
+        let options = MountOptions { codepage: Codepage::Cp437, ..MountOptions::default() };
+        let mut fs = Fat32::mount_with(MemDevice::new(make_tiny_fat32_image()), options).unwrap();
+        fs.write_file_root("CAFÉ.TXT", b"cafe").unwrap();
+        assert_eq!(fs.read_file_root("CAFÉ.TXT").unwrap(), b"cafe");
+        fs.open_dir("/").unwrap().rename("CAFÉ.TXT", "ÇA.TXT").unwrap();
+        assert_eq!(fs.find_path("ÇA.TXT").unwrap().0.raw_name, *b"\x80A      TXT");
+        fs.remove_file_root("ÇA.TXT").unwrap();
+    }
+}
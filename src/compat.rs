@@ -0,0 +1,206 @@
+//! Compatibility harness for images made by other formatters (`test-util` feature).
+//!
+//! Feed it a disk image produced by `mkfs.vfat`, Windows `format`, a camera,
+//! ... (e.g. via `include_bytes!`) together with the files it is known to
+//! contain, and it runs the standard battery of read-only checks against it.
+//!
+//! The crate's own tests run it on the gzipped images in `testdata/compat`,
+//! built by `scripts/make-compat-images.sh`.
+
+use alloc::vec::Vec;
+
+use crate::device::MemDevice;
+use crate::dir::ATTR_DIRECTORY;
+use crate::fs::Fat32;
+use crate::options::MountOptions;
+
+/// A file the image is expected to hold, by `/`-separated 8.3 path.
+#[derive(Debug, Clone, Copy)]
+pub struct ExpectedFile<'a> {
+    pub path: &'a str,
+    pub content: &'a [u8],
+}
+
+/// Run every compatibility check on `image`, panicking with a description of
+/// the first one that fails.
+///
+/// The image is mounted read-only on a write-protected device, so nothing is
+/// modified. Checked:
+/// - the boot sector parses in strict mode and the FSInfo signatures are valid
+/// - the backup boot sector (if any) matches sector 0
+/// - all FAT copies agree
+/// - `check` finds no broken, cross-linked or lost chains
+/// - the root directory lists without error and holds no free-cluster references
+/// - every `expected` file is found by path with the exact content
+pub fn assert_image_compatible(image: &[u8], expected: &[ExpectedFile<'_>]) {
+    let options = MountOptions {
+        read_only: true,
+        verify_fsinfo: true,
+        ..MountOptions::default()
+    };
+    let fs = Fat32::mount_with(MemDevice::read_only(image.to_vec()), options)
+        .unwrap_or_else(|e| panic!("mount failed: {}", e));
+
+    if fs.bpb().has_backup_boot_sector() {
+        assert_eq!(fs.verify_backup_boot_sector(), Ok(true), "backup boot sector differs");
+    }
+    let mismatches = fs.compare_fat_copies().expect("compare FAT copies");
+    assert!(mismatches.is_empty(), "FAT copies differ: {:?}", mismatches);
+
+    let report = fs.check().expect("check");
+    assert!(report.is_clean(), "check found problems: {:?}", report);
+
+    let root = fs.list_root().expect("list root");
+    for e in &root {
+        assert!(e.file_size == 0 || e.first_cluster >= 2, "root entry {:?} has no cluster", e);
+    }
+
+    for file in expected {
+        let (entry, _, _) = fs.find_path(file.path).unwrap_or_else(|e| panic!("{}: lookup failed: {}", file.path, e));
+        assert_eq!(entry.attr & ATTR_DIRECTORY, 0, "{}: is a directory", file.path);
+        assert_eq!(entry.file_size as usize, file.content.len(), "{}: size", file.path);
+        let data = if file.content.is_empty() {
+            Vec::new()
+        } else {
+            fs.read_entry_data(&entry).unwrap_or_else(|e| panic!("{}: read failed: {}", file.path, e))
+        };
+        assert!(data == file.content, "{}: content differs", file.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::BlockDevice;
+    use crate::dir::{DirEntry, ATTR_ARCHIVE, ATTR_LFN, ATTR_VOLUME_ID};
+    use crate::fat::{cluster_to_lba, fat_copy_lba};
+    use crate::image::ImageBuilder;
+
+    /// Recreates the on-disk quirks of a `mkfs.fat 4.2` volume with a label
+    /// and a file copied in by Linux: "mkfs.fat" OEM name, a volume label
+    /// entry first in the root, and a long-name record before the short entry.
+    fn mkfs_vfat_like() -> Vec<u8> {
+        let mut dev = ImageBuilder::new(4096).build_device().unwrap();
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot).unwrap();
+        boot[3..11].copy_from_slice(b"mkfs.fat");
+        boot[71..82].copy_from_slice(b"GOLDEN     ");
+        dev.write_sector(0, &boot).unwrap();
+        dev.write_sector(6, &boot).unwrap();
+        let bpb = crate::bpb::Bpb::parse(&boot).unwrap();
+
+        let mut root = [0u8; 512];
+        root[0..32].copy_from_slice(&DirEntry::build_short_entry(*b"GOLDEN     ", ATTR_VOLUME_ID, 0, 0));
+        let mut lfn = [0xFFu8; 32];
+        lfn[0] = 0x41;
+        for (i, &c) in b"hello.txt\0".iter().enumerate() {
+            let off = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22][i];
+            lfn[off] = c;
+            lfn[off + 1] = 0;
+        }
+        lfn[11] = ATTR_LFN;
+        lfn[12] = 0;
+        lfn[26] = 0;
+        lfn[27] = 0;
+        root[32..64].copy_from_slice(&lfn);
+        root[64..96].copy_from_slice(&DirEntry::build_short_entry(*b"HELLO   TXT", ATTR_ARCHIVE, 3, 6));
+        dev.write_sector(cluster_to_lba(&bpb, 2).unwrap(), &root).unwrap();
+
+        let mut data = [0u8; 512];
+        data[..6].copy_from_slice(b"hello\n");
+        dev.write_sector(cluster_to_lba(&bpb, 3).unwrap(), &data).unwrap();
+        for fat in 0..bpb.num_fats {
+            let lba = fat_copy_lba(&bpb, fat);
+            let mut sector = [0u8; 512];
+            dev.read_sector(lba, &mut sector).unwrap();
+            sector[12..16].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+            dev.write_sector(lba, &sector).unwrap();
+        }
+        dev.into_inner()
+    }
+
+    #[test]
+    fn mkfs_vfat_style_image_passes() {
+        let image = mkfs_vfat_like();
+        assert_image_compatible(
+            &image,
+            &[ExpectedFile {
+                path: "HELLO.TXT",
+                content: b"hello\n",
+            }],
+        );
+    }
+
+    /// Images made by real formatters. Run `scripts/make-compat-images.sh` first, then
+    /// `cargo test -- --ignored reference_images_pass`.
+    #[test]
+    #[ignore = "needs testdata/compat from scripts/make-compat-images.sh"]
+    fn reference_images_pass() {
+        use std::io::Read;
+
+        let data: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let files = [
+            ExpectedFile {
+                path: "HELLO.TXT",
+                content: b"hello\n",
+            },
+            ExpectedFile {
+                path: "SUB/DATA.BIN",
+                content: &data,
+            },
+            ExpectedFile {
+                path: "LONGNA~1.TXT",
+                content: b"long\n",
+            },
+        ];
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/compat");
+        let entries = std::fs::read_dir(dir)
+            .unwrap_or_else(|e| panic!("no reference images in {dir} ({e}); run scripts/make-compat-images.sh"));
+        let mut checked = 0;
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "gz") {
+                let mut image = Vec::new();
+                let file = std::fs::File::open(&path).unwrap();
+                flate2::read::GzDecoder::new(file).read_to_end(&mut image).unwrap();
+                assert_image_compatible(&image, &files);
+                checked += 1;
+            }
+        }
+        assert!(checked > 0, "no .gz images in {dir}");
+    }
+
+    #[test]
+    fn own_images_pass() {
+        let image = ImageBuilder::new(4096)
+            .sectors_per_cluster(2)
+            .file("SUB/DATA.BIN", &[5u8; 3000])
+            .file("EMPTY.TXT", b"")
+            .build()
+            .unwrap();
+        let files = [
+            ExpectedFile {
+                path: "SUB/DATA.BIN",
+                content: &[5u8; 3000],
+            },
+            ExpectedFile {
+                path: "EMPTY.TXT",
+                content: b"",
+            },
+        ];
+        assert_image_compatible(&image, &files);
+    }
+
+    #[test]
+    #[should_panic(expected = "content differs")]
+    fn reports_wrong_content() {
+        let image = mkfs_vfat_like();
+        assert_image_compatible(
+            &image,
+            &[ExpectedFile {
+                path: "HELLO.TXT",
+                content: b"HELLO\n",
+            }],
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Copying files without buffering them whole.
+
+use crate::cancel::Cancel;
+use crate::device::BlockDevice;
+use crate::dir::{DirEntry, ATTR_DIRECTORY};
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, EOC_MIN};
+use crate::fs::{clusters_for_len, Fat32};
+use crate::path::Path;
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Copy file `src` to a new file `dst` (paths of 8.3 names, see `Path`),
+    /// keeping its attributes and timestamps.
+    ///
+    /// Data is streamed through a one-sector buffer, so any file size works
+    /// with constant memory. `progress`, if given, is called after each sector
+    /// with the bytes copied so far and the total. Fails with `AlreadyExists`
+    /// if `dst` exists; its parent directory must exist.
+    pub fn copy_file(&mut self, src: &str, dst: &str, progress: Option<&mut dyn FnMut(u32, u32)>) -> Result<()> {
+        self.copy_file_cancellable(src, dst, progress, Cancel::NEVER)
+    }
+
+    /// `copy_file`, polling `cancel` before each sector.
+    ///
+    /// A cancelled copy leaves no trace: `dst` is only linked in once all data is written.
+    pub fn copy_file_cancellable(
+        &mut self,
+        src: &str,
+        dst: &str,
+        progress: Option<&mut dyn FnMut(u32, u32)>,
+        cancel: Cancel<'_>,
+    ) -> Result<()> {
+        let src = self.find_path(src)?;
+        let (parent, name) = Path::new(dst).split_last()?;
+        let dst_dir = self.resolve_dir(parent)?;
+        let dst = self.short_name(name)?;
+        self.atomic(|fs| fs.copy_entry(&src, dst_dir, dst, progress, cancel))
+    }
+
+    /// Copy the file found by `find_path` as `src` to `dst` in the directory
+    /// at `dir_cluster`.
+    fn copy_entry(
+        &mut self,
+        src: &(DirEntry, u64, usize),
+        dir_cluster: u32,
+        dst: [u8; 11],
+        mut progress: Option<&mut dyn FnMut(u32, u32)>,
+        cancel: Cancel<'_>,
+    ) -> Result<()> {
+        let (e, lba, slot) = (&src.0, src.1, src.2);
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        if self.find_entry(dir_cluster, &dst)?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        self.mark_dirty()?;
+
+        // The source record carries the attributes and timestamps to keep.
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let mut rec = [0u8; 32];
+        rec.copy_from_slice(&buf[slot * 32..slot * 32 + 32]);
+
+        let total = e.file_size;
+        let chain = self.pick_free_clusters(clusters_for_len(&self.bpb, total as usize))?;
+        let mut src_cluster = e.first_cluster;
+        let mut copied = 0u32;
+        for (i, &dst_cluster) in chain.iter().enumerate() {
+            if i > 0 {
+                let next = self.read_fat(src_cluster)?;
+                if !(2..EOC_MIN).contains(&next) {
+                    return Err(self.record(Operation::ReadData, None, Some(src_cluster), Error::UnexpectedEof));
+                }
+                src_cluster = next;
+            }
+            let src_lba = cluster_to_lba(&self.bpb, src_cluster)?;
+            let dst_lba = cluster_to_lba(&self.bpb, dst_cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                if copied == total {
+                    break;
+                }
+                cancel.check()?;
+                self.read_sector(Operation::ReadData, src_lba + s, &mut buf)?;
+                self.write_sector(Operation::WriteData, dst_lba + s, &buf)?;
+                copied = copied.saturating_add(512).min(total);
+                if let Some(p) = progress.as_mut() {
+                    p(copied, total);
+                }
+            }
+        }
+        let first_cluster = chain.first().copied().unwrap_or(0);
+        rec[0..11].copy_from_slice(&dst);
+        rec[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        rec[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        fs_debug!("fat32: copied {:?} to {:?} ({} bytes)", e.raw_name, dst, total);
+        let linked = self
+            .link_chain(&chain)
+            .and_then(|()| self.write_dir_entry_first_free(dir_cluster, &rec).map(|_| ()));
+        if linked.is_err() {
+            self.release_clusters(&chain);
+        }
+        linked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    #[test]
+    fn copy_streams_data_and_keeps_metadata() {
+        let mut img = make_tiny_fat32_image();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let content: std::vec::Vec<u8> = (0..1300u32).map(|i| i as u8).collect();
+        fs.write_file_root("SRC.BIN", &content).unwrap();
+        img = fs.unmount().unwrap().into_inner();
+        // Read-only + hidden, with a modification time and date.
+        let root = 34 * 512;
+        img[root + 11] = 0x03;
+        img[root + 22..root + 26].copy_from_slice(&[0x20, 0x5A, 0x4F, 0x59]);
+
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("remount");
+        let mut calls = std::vec::Vec::new();
+        let mut progress = |done: u32, total: u32| calls.push((done, total));
+        fs.copy_file("SRC.BIN", "/DST.BIN", Some(&mut progress)).unwrap();
+        assert_eq!(calls, [(512, 1300), (1024, 1300), (1300, 1300)]);
+        assert_eq!(fs.read_file_root("DST.BIN").unwrap(), content);
+        assert_eq!(fs.copy_file("SRC.BIN", "DST.BIN", None), Err(Error::AlreadyExists));
+        assert!(fs.check().unwrap().is_clean());
+
+        let img = fs.unmount().unwrap().into_inner();
+        let dst = &img[root + 32..root + 64];
+        assert_eq!(&dst[0..11], b"DST     BIN");
+        assert_eq!(dst[11], 0x03);
+        assert_eq!(&dst[22..26], &[0x20, 0x5A, 0x4F, 0x59]);
+
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("remount");
+        fs.create_dir_all("A/B").unwrap();
+        fs.copy_file("DST.BIN", "A/B/COPY.BIN", None).unwrap();
+        fs.copy_file("A/B/COPY.BIN", "A/BACK.BIN", None).unwrap();
+        assert_eq!(fs.read_file_into("A/BACK.BIN", &mut [0u8; 2048]), Ok(1300));
+        assert!(fs.metadata("A/B/COPY.BIN").unwrap().is_read_only());
+        assert_eq!(fs.copy_file("A", "A/C.BIN", None), Err(Error::IsADirectory));
+        assert_eq!(fs.copy_file("DST.BIN", "NONE/C.BIN", None), Err(Error::NotFound));
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn cancelled_copy_leaves_no_trace() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("SRC.BIN", &[3u8; 2000]).unwrap();
+        let next_free = fs.find_free_cluster(2).unwrap();
+        let polls = core::cell::Cell::new(0);
+        let poll = || {
+            polls.set(polls.get() + 1);
+            polls.get() > 2
+        };
+        let r = fs.copy_file_cancellable("SRC.BIN", "DST.BIN", None, Cancel::new(&poll));
+        assert_eq!(r, Err(Error::Cancelled));
+        assert_eq!(polls.get(), 3);
+        assert_eq!(fs.find_path("DST.BIN").err(), Some(Error::NotFound));
+        assert_eq!(fs.find_free_cluster(2).unwrap(), next_free);
+        assert!(fs.check().unwrap().is_clean());
+    }
+}
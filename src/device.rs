@@ -1,65 +1,423 @@
-//! Block device abstraction.
-//!
-//! FAT32 is built on top of a sector-based device (usually 512 bytes per sector).
-
-use crate::error::Result;
-
-/// A minimal sector-based device.
-///
-/// Implementations must be able to read/write 512-byte sectors.
-///
-/// In `no_std`, you typically implement this trait for:
-/// - a memory-mapped block device
-/// - a driver
-/// - an in-memory disk image (for tests)
-pub trait BlockDevice {
-    /// Read a 512-byte sector at `lba` into `buf`.
-    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()>;
-
-    /// Write a 512-byte sector at `lba` from `buf`.
-    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()>;
-}
-
-#[cfg(test)]
-use crate::error::Error;
-
-/// Simple in-memory block device for tests.
-///
-/// Stores a full disk image inside a `Vec<u8>` (sector-aligned).
-#[cfg(test)]
-pub struct MemDevice {
-    data: std::vec::Vec<u8>,
-}
-
-#[cfg(test)]
-impl MemDevice {
-    pub fn new(data: std::vec::Vec<u8>) -> Self {
-        assert!(data.len() % 512 == 0);
-        Self { data }
-    }
-
-    pub fn into_inner(self) -> std::vec::Vec<u8> {
-        self.data
-    }
-}
-
-#[cfg(test)]
-impl BlockDevice for MemDevice {
-    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
-        let off = (lba as usize) * 512;
-        if off + 512 > self.data.len() {
-            return Err(Error::Io);
-        }
-        buf.copy_from_slice(&self.data[off..off + 512]);
-        Ok(())
-    }
-
-    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
-        let off = (lba as usize) * 512;
-        if off + 512 > self.data.len() {
-            return Err(Error::Io);
-        }
-        self.data[off..off + 512].copy_from_slice(buf);
-        Ok(())
-    }
-}
+//! Block device abstraction.
+//!
+//! FAT32 is built on top of a sector-based device (usually 512 bytes per sector).
+//!
+//! `BlockDevice` is object safe: to pick the medium at runtime without a
+//! `Fat32` per device type, mount a `&mut dyn BlockDevice` or (with `alloc`) a
+//! `Box<dyn BlockDevice>`.
+//!
+//! ```ignore
+//! let dev: &mut dyn BlockDevice = if card_present { &mut sd } else { &mut qspi };
+//! let mut fs = Fat32::mount(dev)?;
+//! ```
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::vec::Vec;
+
+use crate::error::Result;
+
+/// A minimal sector-based device.
+///
+/// Implementations must be able to read/write 512-byte sectors.
+///
+/// In `no_std`, you typically implement this trait for:
+/// - a memory-mapped block device
+/// - a driver
+/// - an in-memory disk image (for tests)
+///
+/// Report failures as `Error::Device` when the driver can classify them
+/// (CRC error, timeout, card removed), or as `Error::Io` otherwise.
+pub trait BlockDevice {
+    /// Read a 512-byte sector at `lba` into `buf`.
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()>;
+
+    /// Write a 512-byte sector at `lba` from `buf`.
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()>;
+
+    /// Read `buf.len() / 512` consecutive sectors starting at `lba`.
+    ///
+    /// `buf.len()` is a multiple of 512. The default reads one sector at a
+    /// time; override it with a multi-block command (e.g. SD `CMD18`) where
+    /// the hardware has one.
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        for (i, chunk) in buf.chunks_exact_mut(512).enumerate() {
+            let sector: &mut [u8; 512] = chunk.try_into().expect("512-byte chunk");
+            self.read_sector(lba + i as u64, sector)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf.len() / 512` consecutive sectors starting at `lba`.
+    ///
+    /// `buf.len()` is a multiple of 512. The default writes one sector at a
+    /// time; override it with a multi-block command (e.g. SD `CMD25`) where
+    /// the hardware has one.
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        for (i, chunk) in buf.chunks_exact(512).enumerate() {
+            let sector: &[u8; 512] = chunk.try_into().expect("512-byte chunk");
+            self.write_sector(lba + i as u64, sector)?;
+        }
+        Ok(())
+    }
+
+    /// Make every completed `write_sector` durable (e.g. drain a write cache).
+    ///
+    /// The default does nothing, for devices that write through.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Tell the device that `count` sectors from `lba` hold no data (TRIM / discard).
+    ///
+    /// Their content is undefined afterwards. Called by `Fat32::wipe_free_space`
+    /// and, under `MountOptions::discard`, whenever clusters are freed. The
+    /// default does nothing.
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        let _ = (lba, count);
+        Ok(())
+    }
+
+    /// Alignment in bytes (a power of two) that buffers need for the device to
+    /// transfer them directly, e.g. 4 or 32 for an SDMMC DMA engine.
+    ///
+    /// A mounted `Fat32` only passes buffers with this alignment, bouncing
+    /// misaligned ones through `SectorBuf` or `AlignedBuf`; other callers such
+    /// as `mkfs` may not, so a driver should still accept them. Without the
+    /// `alloc` feature there is no `AlignedBuf`, and misaligned transfers to a
+    /// device aligned above `SECTOR_BUF_ALIGN` fail with `Error::InvalidInput`.
+    /// The default is 1.
+    fn alignment(&self) -> usize {
+        1
+    }
+
+    /// Whether writes are currently refused, e.g. by an SD card's lock switch.
+    ///
+    /// `Fat32` checks it before every mutating operation and fails with
+    /// `Error::WriteProtected` without touching the device. The default is false.
+    fn is_write_protected(&self) -> bool {
+        false
+    }
+}
+
+/// Lets `Fat32::mount(&mut dev)` borrow a device owned elsewhere (e.g. a HAL singleton);
+/// the device is usable again once the filesystem is dropped.
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        (**self).read_sector(lba, buf)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        (**self).write_sector(lba, buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        (**self).read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        (**self).write_sectors(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        (**self).trim(lba, count)
+    }
+
+    fn alignment(&self) -> usize {
+        (**self).alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        (**self).is_write_protected()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        (**self).read_sector(lba, buf)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        (**self).write_sector(lba, buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        (**self).read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        (**self).write_sectors(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        (**self).trim(lba, count)
+    }
+
+    fn alignment(&self) -> usize {
+        (**self).alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        (**self).is_write_protected()
+    }
+}
+
+/// Largest `BlockDevice::alignment` that `SectorBuf` satisfies.
+pub const SECTOR_BUF_ALIGN: usize = 64;
+
+/// One sector, aligned for any device whose alignment is at most `SECTOR_BUF_ALIGN`.
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct SectorBuf(pub [u8; 512]);
+
+impl Default for SectorBuf {
+    fn default() -> Self {
+        Self([0; 512])
+    }
+}
+
+impl core::ops::Deref for SectorBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl core::ops::DerefMut for SectorBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Whether `buf` starts at a multiple of `align`.
+pub fn is_aligned(buf: &[u8], align: usize) -> bool {
+    (buf.as_ptr() as usize).is_multiple_of(align)
+}
+
+/// A heap buffer whose first byte is aligned to a chosen power of two, for
+/// multi-sector transfers to devices with a `BlockDevice::alignment` above 1.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+pub struct AlignedBuf {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl AlignedBuf {
+    /// `len` zero bytes starting at a multiple of `align`.
+    pub fn new(len: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two());
+        let data = alloc::vec![0u8; len + align - 1];
+        let start = (data.as_ptr() as usize).wrapping_neg() & (align - 1);
+        Self { data, start, len }
+    }
+
+    /// Change the length to `len` and the alignment to `align`, reusing the
+    /// allocation when it is large enough; the contents are unspecified.
+    pub fn resize(&mut self, len: usize, align: usize) {
+        assert!(align.is_power_of_two());
+        let start = (self.data.as_ptr() as usize).wrapping_neg() & (align - 1);
+        if start + len <= self.data.len() {
+            self.start = start;
+            self.len = len;
+        } else {
+            *self = Self::new(len, align);
+        }
+    }
+
+    /// Set the length to 0, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl core::ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl core::ops::DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.start + self.len]
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+use crate::error::Error;
+
+/// In-memory block device for tests (`test-util` feature).
+///
+/// Stores a full disk image inside a `Vec<u8>` (sector-aligned). Accesses past
+/// the end fail with `Error::Io`, or panic once `strict` is set.
+#[cfg(any(test, feature = "test-util"))]
+pub struct MemDevice {
+    data: Vec<u8>,
+    read_only: bool,
+    strict: bool,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MemDevice {
+    /// Wrap a disk image; its length must be a multiple of 512.
+    pub fn new(data: Vec<u8>) -> Self {
+        assert!(data.len().is_multiple_of(512));
+        Self {
+            data,
+            read_only: false,
+            strict: false,
+        }
+    }
+
+    /// A zero-filled device of `sectors` sectors.
+    pub fn zeroed(sectors: usize) -> Self {
+        Self::new(alloc::vec![0u8; sectors * 512])
+    }
+
+    /// Wrap a disk image whose writes fail with `Error::WriteProtected`.
+    pub fn read_only(data: Vec<u8>) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(data)
+        }
+    }
+
+    /// Panic on out-of-range accesses instead of returning `Error::Io`,
+    /// to catch filesystem bugs at the offending call.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// The current image.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the image.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>> {
+        let start = usize::try_from(lba).ok().and_then(|l| l.checked_mul(512));
+        match start.and_then(|start| Some(start..start.checked_add(len)?)) {
+            Some(range) if range.end <= self.data.len() => Ok(range),
+            _ if self.strict => panic!("sector {} out of range ({} sectors)", lba, self.data.len() / 512),
+            _ => Err(Error::Io),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl BlockDevice for MemDevice {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        let range = self.range(lba, 512)?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.write_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let range = self.range(lba, buf.len())?;
+        if self.read_only {
+            return Err(Error::WriteProtected);
+        }
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_device_variants() {
+        let mut dev = MemDevice::read_only(alloc::vec![7u8; 1024]);
+        let mut buf = [0u8; 512];
+        dev.read_sector(1, &mut buf).unwrap();
+        assert_eq!(buf, [7u8; 512]);
+        assert_eq!(dev.write_sector(0, &buf), Err(Error::WriteProtected));
+        assert_eq!(dev.read_sector(2, &mut buf), Err(Error::Io));
+        assert_eq!(dev.read_sector(u64::MAX, &mut buf), Err(Error::Io));
+        assert_eq!(dev.read_sector(usize::MAX as u64 / 512, &mut buf), Err(Error::Io));
+
+        let strict = MemDevice::zeroed(2).strict();
+        let r = std::panic::catch_unwind(|| strict.read_sector(2, &mut [0u8; 512]));
+        assert!(r.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn aligned_buffers() {
+        for align in [1, 4, 32, 4096] {
+            let mut buf = AlignedBuf::new(1024, align);
+            assert!(is_aligned(&buf, align));
+            assert_eq!(buf.len(), 1024);
+            buf.resize(512, align);
+            assert!(is_aligned(&buf, align));
+            assert_eq!(buf.len(), 512);
+        }
+        assert!(is_aligned(&SectorBuf::default().0, SECTOR_BUF_ALIGN));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    #[should_panic(expected = "align.is_power_of_two()")]
+    fn resize_rejects_zero_alignment() {
+        AlignedBuf::new(512, 8).resize(512, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn mount_device_chosen_at_runtime() {
+        use crate::fs::fixtures::make_tiny_fat32_image;
+        use crate::fs::Fat32;
+        use crate::trace_device::TraceDevice;
+
+        let mut mem = MemDevice::new(make_tiny_fat32_image());
+        let mut traced = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        for traced_first in [true, false] {
+            let dev: &mut dyn BlockDevice = if traced_first { &mut traced } else { &mut mem };
+            let mut fs = Fat32::mount(dev).expect("mount");
+            fs.write_file_root("A.TXT", b"dyn").unwrap();
+            assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"dyn");
+        }
+        assert!(!traced.writes().is_empty());
+
+        let boxed: Box<dyn BlockDevice> = Box::new(mem);
+        let fs = Fat32::mount(boxed).expect("mount boxed");
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"dyn");
+    }
+}
@@ -1,121 +1,423 @@
-//! Directory entry parsing (8.3 only in this MVP).
-
-use crate::error::{Error, Result};
-
-/// A parsed 8.3 directory entry (short name only).
-#[derive(Debug, Clone)]
-pub struct DirEntry {
-    /// 11 bytes name (8 + 3) as stored on disk.
-    pub raw_name: [u8; 11],
-    pub attr: u8,
-    pub first_cluster: u32,
-    pub file_size: u32,
-}
-
-fn le_u16(x: &[u8]) -> u16 {
-    u16::from_le_bytes([x[0], x[1]])
-}
-fn le_u32(x: &[u8]) -> u32 {
-    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
-}
-
-impl DirEntry {
-    /// Parse a directory entry from a 32-byte record.
-    pub fn parse(rec: &[u8; 32]) -> Result<Option<Self>> {
-        let first = rec[0];
-        if first == 0x00 {
-            // End of directory.
-            return Ok(None);
-        }
-        if first == 0xE5 {
-            // Deleted (skip)
-            return Ok(Some(Self {
-                raw_name: [0; 11],
-                attr: 0,
-                first_cluster: 0,
-                file_size: 0,
-            }));
-        }
-
-        let attr = rec[11];
-        // Skip LFN entries (attr == 0x0F).
-        if attr == 0x0F {
-            return Ok(Some(Self {
-                raw_name: [0; 11],
-                attr,
-                first_cluster: 0,
-                file_size: 0,
-            }));
-        }
-
-        let mut raw_name = [0u8; 11];
-        raw_name.copy_from_slice(&rec[0..11]);
-
-        let hi = le_u16(&rec[20..22]) as u32;
-        let lo = le_u16(&rec[26..28]) as u32;
-        let first_cluster = (hi << 16) | lo;
-        let file_size = le_u32(&rec[28..32]);
-
-        Ok(Some(Self {
-            raw_name,
-            attr,
-            first_cluster,
-            file_size,
-        }))
-    }
-
-    /// Build an on-disk 32-byte entry for a short name file (minimal fields).
-    pub fn build_short_file(name_83: [u8; 11], first_cluster: u32, file_size: u32) -> [u8; 32] {
-        let mut rec = [0u8; 32];
-        rec[0..11].copy_from_slice(&name_83);
-        rec[11] = 0x20; // archive
-
-        let hi = ((first_cluster >> 16) as u16).to_le_bytes();
-        let lo = ((first_cluster & 0xFFFF) as u16).to_le_bytes();
-        rec[20..22].copy_from_slice(&hi);
-        rec[26..28].copy_from_slice(&lo);
-
-        rec[28..32].copy_from_slice(&file_size.to_le_bytes());
-        rec
-    }
-}
-
-/// Convert a human name like "HELLO.TXT" to FAT 8.3 (11 bytes).
-///
-/// This MVP supports only ASCII uppercase letters, digits, '_' and '-'.
-pub fn to_short_name_83(s: &str) -> Result<[u8; 11]> {
-    let mut out = [b' '; 11];
-
-    let (name, ext) = match s.split_once('.') {
-        Some((a, b)) => (a, b),
-        None => (s, ""),
-    };
-
-    if name.is_empty() || name.len() > 8 || ext.len() > 3 {
-        return Err(Error::InvalidName);
-    }
-
-    fn ok_char(c: u8) -> bool {
-        (b'A'..=b'Z').contains(&c)
-            || (b'0'..=b'9').contains(&c)
-            || c == b'_'
-            || c == b'-'
-    }
-
-    for (i, ch) in name.bytes().enumerate() {
-        let up = ch.to_ascii_uppercase();
-        if !ok_char(up) {
-            return Err(Error::InvalidName);
-        }
-        out[i] = up;
-    }
-    for (i, ch) in ext.bytes().enumerate() {
-        let up = ch.to_ascii_uppercase();
-        if !ok_char(up) {
-            return Err(Error::InvalidName);
-        }
-        out[8 + i] = up;
-    }
-
-    Ok(out)
-}
+//! Directory entry parsing (8.3 only in this MVP).
+
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+
+use crate::codepage::{Ascii, OemCodepage};
+use crate::error::{Error, Result};
+
+/// Attribute bit: volume label entry.
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+/// Attribute bit: subdirectory.
+pub const ATTR_DIRECTORY: u8 = 0x10;
+/// Attribute bit: archive (set on newly written files).
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Attribute value marking a long file name (LFN) record.
+pub const ATTR_LFN: u8 = 0x0F;
+/// NT reserved-byte flag: the base name is displayed in lowercase.
+pub const NT_LOWER_BASE: u8 = 0x08;
+/// NT reserved-byte flag: the extension is displayed in lowercase.
+pub const NT_LOWER_EXT: u8 = 0x10;
+
+/// A parsed 8.3 directory entry (short name only).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirEntry {
+    /// 11 bytes name (8 + 3) as stored on disk.
+    pub raw_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub file_size: u32,
+    /// Windows NT case flags (`NT_LOWER_BASE`, `NT_LOWER_EXT`) from the reserved byte.
+    pub nt_case: u8,
+    /// Cluster of the containing directory's chain in which this record lives; not necessarily
+    /// the directory's first cluster (0 if not read from a directory).
+    pub entry_cluster: u32,
+    /// Byte offset of the 32-byte record within its cluster.
+    pub entry_offset: u32,
+    /// The entry is deleted (only listed with `ListOptions::include_deleted`).
+    pub deleted: bool,
+}
+
+fn le_u16(x: &[u8]) -> u16 {
+    u16::from_le_bytes([x[0], x[1]])
+}
+fn le_u32(x: &[u8]) -> u32 {
+    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
+}
+
+impl DirEntry {
+    /// Parse a directory entry from a 32-byte record.
+    pub fn parse(rec: &[u8; 32]) -> Result<Option<Self>> {
+        let first = rec[0];
+        if first == 0x00 {
+            // End of directory.
+            return Ok(None);
+        }
+        if first == 0xE5 {
+            // Deleted (skip)
+            return Ok(Some(Self {
+                raw_name: [0; 11],
+                attr: 0,
+                first_cluster: 0,
+                file_size: 0,
+                nt_case: 0,
+                entry_cluster: 0,
+                entry_offset: 0,
+                deleted: false,
+            }));
+        }
+
+        let attr = rec[11];
+        // Skip LFN entries (attr == 0x0F).
+        if attr == 0x0F {
+            return Ok(Some(Self {
+                raw_name: [0; 11],
+                attr,
+                first_cluster: 0,
+                file_size: 0,
+                nt_case: 0,
+                entry_cluster: 0,
+                entry_offset: 0,
+                deleted: false,
+            }));
+        }
+
+        let mut raw_name = [0u8; 11];
+        raw_name.copy_from_slice(&rec[0..11]);
+
+        let hi = le_u16(&rec[20..22]) as u32;
+        let lo = le_u16(&rec[26..28]) as u32;
+        let first_cluster = (hi << 16) | lo;
+        let file_size = le_u32(&rec[28..32]);
+
+        Ok(Some(Self {
+            raw_name,
+            attr,
+            first_cluster,
+            file_size,
+            nt_case: rec[12] & (NT_LOWER_BASE | NT_LOWER_EXT),
+            entry_cluster: 0,
+            entry_offset: 0,
+            deleted: false,
+        }))
+    }
+
+    /// `parse` on the first 32 bytes of `data`; shorter input is `Corrupt`.
+    pub fn parse_bytes(data: &[u8]) -> Result<Option<Self>> {
+        Self::parse(data.first_chunk::<32>().ok_or(Error::Corrupt)?)
+    }
+
+    /// Record where the entry was read from.
+    pub(crate) fn located(self, entry_cluster: u32, entry_offset: u32) -> Self {
+        Self {
+            entry_cluster,
+            entry_offset,
+            ..self
+        }
+    }
+
+    /// True for a subdirectory (including `.` and `..`).
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0 && self.attr != ATTR_LFN
+    }
+
+    /// True for the `.` and `..` records at the start of a subdirectory.
+    pub fn is_dot_entry(&self) -> bool {
+        self.is_dir() && (self.raw_name == *b".          " || self.raw_name == *b"..         ")
+    }
+
+    /// True for the volume label record.
+    pub fn is_volume_label(&self) -> bool {
+        self.attr & ATTR_VOLUME_ID != 0 && self.attr != ATTR_LFN
+    }
+
+    /// The name as text: `README.TXT` for `"README  TXT"`, padding trimmed.
+    ///
+    /// The NT case flags are applied, so `hello.txt` created by Windows reads
+    /// back in lowercase. Volume labels are returned whole, without a dot. Bytes outside ASCII
+    /// decode as U+FFFD; use `name_with` to decode them in an OEM code page.
+    #[cfg(feature = "alloc")]
+    pub fn name(&self) -> String {
+        self.name_with(&Ascii)
+    }
+
+    /// `name`, decoding bytes with the code page `cp`.
+    #[cfg(feature = "alloc")]
+    pub fn name_with<C: OemCodepage + ?Sized>(&self, cp: &C) -> String {
+        let mut out = String::with_capacity(12);
+        self.decode_name(cp, |c| out.push(c));
+        out
+    }
+
+    /// Feed the characters of `name_with(cp)` to `push`.
+    #[cfg(any(feature = "alloc", feature = "heapless"))]
+    pub(crate) fn decode_name<C: OemCodepage + ?Sized>(&self, cp: &C, mut push: impl FnMut(char)) {
+        let mut raw = self.raw_name;
+        // 0x05 stands for a leading 0xE5 byte, which would mean "deleted".
+        if raw[0] == 0x05 {
+            raw[0] = 0xE5;
+        }
+        let trim = |b: &[u8]| b.len() - b.iter().rev().take_while(|&&c| c == b' ').count();
+        if self.is_volume_label() {
+            raw[..trim(&raw)].iter().for_each(|&c| push(cp.decode(c)));
+            return;
+        }
+        let lower = |c: char, flag: u8| if self.nt_case & flag != 0 { c.to_ascii_lowercase() } else { c };
+        raw[..trim(&raw[..8])].iter().for_each(|&c| push(lower(cp.decode(c), NT_LOWER_BASE)));
+        let ext = &raw[8..8 + trim(&raw[8..])];
+        if !ext.is_empty() {
+            push('.');
+            ext.iter().for_each(|&c| push(lower(cp.decode(c), NT_LOWER_EXT)));
+        }
+    }
+
+    /// Build an on-disk 32-byte entry for a short name file (minimal fields).
+    pub fn build_short_file(name_83: [u8; 11], first_cluster: u32, file_size: u32) -> [u8; 32] {
+        Self::build_short_entry(name_83, ATTR_ARCHIVE, first_cluster, file_size)
+    }
+
+    /// Build an on-disk 32-byte entry with an explicit attribute byte.
+    pub fn build_short_entry(name_83: [u8; 11], attr: u8, first_cluster: u32, file_size: u32) -> [u8; 32] {
+        let mut rec = [0u8; 32];
+        rec[0..11].copy_from_slice(&name_83);
+        rec[11] = attr;
+
+        let hi = ((first_cluster >> 16) as u16).to_le_bytes();
+        let lo = ((first_cluster & 0xFFFF) as u16).to_le_bytes();
+        rec[20..22].copy_from_slice(&hi);
+        rec[26..28].copy_from_slice(&lo);
+
+        rec[28..32].copy_from_slice(&file_size.to_le_bytes());
+        rec
+    }
+}
+
+/// Convert a human name like "HELLO.TXT" to FAT 8.3 (11 bytes).
+///
+/// Lowercase letters are upper-cased. Besides letters and digits, the
+/// characters ``! # $ % & ' ( ) - @ ^ _ ` { } ~`` are allowed; spaces, `.`
+/// (other than the one separator) and ``" * + , / : ; < = > ? [ \ ] |`` are
+/// not.
+pub fn to_short_name_83(s: &str) -> Result<[u8; 11]> {
+    to_short_name_83_with(s, &Ascii)
+}
+
+/// `to_short_name_83`, additionally accepting characters of the code page `cp`
+/// (stored as their bytes 0x80..=0xFF, not case-converted).
+pub fn to_short_name_83_with<C: OemCodepage + ?Sized>(s: &str, cp: &C) -> Result<[u8; 11]> {
+    let mut out = [b' '; 11];
+
+    let (name, ext) = match s.split_once('.') {
+        Some((a, b)) => (a, b),
+        None => (s, ""),
+    };
+
+    fn encode<C: OemCodepage + ?Sized>(part: &str, dst: &mut [u8], cp: &C) -> Result<usize> {
+        let mut len = 0;
+        for ch in part.chars() {
+            let b = if ch.is_ascii() {
+                let up = ch.to_ascii_uppercase() as u8;
+                if !is_short_name_char(up) {
+                    return Err(Error::InvalidName);
+                }
+                up
+            } else {
+                cp.encode(ch).filter(|&b| b >= 0x80).ok_or(Error::InvalidName)?
+            };
+            *dst.get_mut(len).ok_or(Error::InvalidName)? = b;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    if encode(name, &mut out[..8], cp)? == 0 {
+        return Err(Error::InvalidName);
+    }
+    encode(ext, &mut out[8..], cp)?;
+    // A leading 0xE5 would mark the entry deleted; FAT stores it as 0x05.
+    if out[0] == 0xE5 {
+        out[0] = 0x05;
+    }
+
+    Ok(out)
+}
+
+/// NT case flags that make the short name of `s` display as `s`.
+///
+/// A part (base or extension) written entirely in lowercase gets its flag;
+/// mixed-case parts cannot be represented and are left uppercase.
+pub fn nt_case_flags(s: &str) -> u8 {
+    let (name, ext) = s.split_once('.').unwrap_or((s, ""));
+    let lower = |p: &str| p.bytes().any(|c| c.is_ascii_lowercase()) && !p.bytes().any(|c| c.is_ascii_uppercase());
+    let mut flags = 0;
+    if lower(name) {
+        flags |= NT_LOWER_BASE;
+    }
+    if lower(ext) {
+        flags |= NT_LOWER_EXT;
+    }
+    flags
+}
+
+/// True for ASCII bytes allowed in a short name (after upper-casing).
+pub fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Checksum of a short name stored in each LFN record that belongs to it.
+pub fn lfn_checksum(name_83: &[u8; 11]) -> u8 {
+    name_83.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Byte offsets of the 13 UCS-2 name characters in an LFN record.
+#[cfg(feature = "alloc")]
+const LFN_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Assemble the long name from `records`: the LFN records of one entry as
+/// they appear in the directory (highest sequence number first) followed by
+/// its short entry, 32 bytes each.
+///
+/// Returns `None` unless the set is complete and consistent: sequence numbers
+/// counting down to 1, the last-record flag on the first, every checksum
+/// matching the short name, and valid UTF-16. Accepts any input without
+/// panicking.
+#[cfg(feature = "alloc")]
+pub fn long_name(records: &[u8]) -> Option<String> {
+    if !records.len().is_multiple_of(32) || records.len() < 64 {
+        return None;
+    }
+    let (lfns, short) = records.split_at(records.len() - 32);
+    if short[0] == 0 || short[0] == 0xE5 || short[11] == ATTR_LFN {
+        return None;
+    }
+    let count = lfns.len() / 32;
+    if count > 20 {
+        return None;
+    }
+    let checksum = lfn_checksum(short.first_chunk()?);
+    let mut units = [0u16; 20 * 13];
+    for (i, rec) in lfns.chunks_exact(32).enumerate() {
+        let seq = (count - i) as u8;
+        let expected = if i == 0 { seq | 0x40 } else { seq };
+        if rec[0] != expected || rec[11] != ATTR_LFN || rec[12] != 0 || rec[13] != checksum || rec[26..28] != [0, 0] {
+            return None;
+        }
+        let base = (seq as usize - 1) * 13;
+        for (j, &off) in LFN_CHARS.iter().enumerate() {
+            units[base + j] = u16::from_le_bytes([rec[off], rec[off + 1]]);
+        }
+    }
+    let units = &units[..count * 13];
+    let len = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+    if len == 0 {
+        return None;
+    }
+    char::decode_utf16(units[..len].iter().copied()).collect::<core::result::Result<String, _>>().ok()
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+
+    fn entry(name: &[u8; 11], attr: u8) -> DirEntry {
+        DirEntry::parse(&DirEntry::build_short_entry(*name, attr, 0, 0)).unwrap().unwrap()
+    }
+
+    /// The LFN records for `name` followed by the short entry `short`, as stored.
+    fn lfn_set(name: &str, short: &[u8; 11]) -> alloc::vec::Vec<u8> {
+        let mut units: alloc::vec::Vec<u16> = name.encode_utf16().collect();
+        let count = units.len().div_ceil(13);
+        if units.len() < count * 13 {
+            units.push(0);
+        }
+        units.resize(count * 13, 0xFFFF);
+        let mut out = alloc::vec::Vec::new();
+        for seq in (1..=count).rev() {
+            let mut rec = [0u8; 32];
+            rec[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+            rec[11] = ATTR_LFN;
+            rec[13] = lfn_checksum(short);
+            for (j, &off) in LFN_CHARS.iter().enumerate() {
+                rec[off..off + 2].copy_from_slice(&units[(seq - 1) * 13 + j].to_le_bytes());
+            }
+            out.extend_from_slice(&rec);
+        }
+        out.extend_from_slice(&DirEntry::build_short_file(*short, 0, 0));
+        out
+    }
+
+    #[test]
+    fn long_names_assemble_and_reject_damage() {
+        let short = b"LONGFI~1TXT";
+        let set = lfn_set("Long file name.txt", short);
+        assert_eq!(set.len(), 3 * 32);
+        assert_eq!(long_name(&set).as_deref(), Some("Long file name.txt"));
+        assert_eq!(long_name(&lfn_set("exactly13.txt", short)).as_deref(), Some("exactly13.txt"));
+        assert_eq!(long_name(&lfn_set("größe €.txt", short)).as_deref(), Some("größe €.txt"));
+
+        let mut bad = set.clone();
+        bad[13] ^= 1; // checksum
+        assert_eq!(long_name(&bad), None);
+        let mut bad = set.clone();
+        bad[0] = 2; // missing last-record flag
+        assert_eq!(long_name(&bad), None);
+        assert_eq!(long_name(&set[32..]), None); // first record lost
+        assert_eq!(long_name(&set[..64]), None); // no short entry
+        assert_eq!(long_name(&set[..95]), None);
+        assert_eq!(long_name(&[]), None);
+    }
+
+    #[test]
+    fn names_and_kinds() {
+        assert_eq!(entry(b"README  TXT", ATTR_ARCHIVE).name(), "README.TXT");
+        assert_eq!(entry(b"MAKEFILE   ", ATTR_ARCHIVE).name(), "MAKEFILE");
+        assert_eq!(entry(b"\x05BC     TXT", ATTR_ARCHIVE).name(), "\u{fffd}BC.TXT");
+        let label = entry(b"MY DISK    ", ATTR_VOLUME_ID);
+        assert_eq!(label.name(), "MY DISK");
+        assert!(label.is_volume_label() && !label.is_dir());
+        assert!(entry(b"LOGS       ", ATTR_DIRECTORY).is_dir());
+        assert!(entry(b"..         ", ATTR_DIRECTORY).is_dot_entry());
+        assert!(!entry(b"LOGS       ", ATTR_DIRECTORY).is_dot_entry());
+    }
+
+    #[test]
+    fn short_name_character_set() {
+        assert_eq!(&to_short_name_83("a!#$%&'(.)-@").unwrap(), b"A!#$%&'()-@");
+        assert_eq!(&to_short_name_83("^_`{}~").unwrap(), b"^_`{}~     ");
+        for bad in ["A B", "A+B", "A,B", "A;B", "A=B", "A[B", "A]B", "A*", "A?", "A\\B", "A|B", "A.B.C", ".", "..", ".TXT"] {
+            assert_eq!(to_short_name_83(bad), Err(Error::InvalidName), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn lowercase_names_round_trip() {
+        assert_eq!(nt_case_flags("hello.txt"), NT_LOWER_BASE | NT_LOWER_EXT);
+        assert_eq!(nt_case_flags("README.md"), NT_LOWER_EXT);
+        assert_eq!(nt_case_flags("Hello.TXT"), 0);
+        assert_eq!(nt_case_flags("2024.log"), NT_LOWER_EXT);
+
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("hello.txt", b"x").unwrap();
+        fs.write_file_root("Mixed.TXT", b"x").unwrap();
+        fs.create_file_root("notes").unwrap().close().unwrap();
+        fs.create_dir_all("logs").unwrap();
+        let names: std::vec::Vec<_> = fs.list_root().unwrap().iter().map(DirEntry::name).collect();
+        assert_eq!(names, ["hello.txt", "MIXED.TXT", "notes", "logs"]);
+        assert_eq!(fs.read_file_root("HELLO.TXT").unwrap(), b"x");
+    }
+
+    #[test]
+    fn listed_entries_know_their_location() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        for name in ["A.TXT", "B.TXT"] {
+            fs.write_file_root(name, b"x").unwrap();
+        }
+        let root = fs.bpb().root_cluster;
+        let b = &fs.list_root().unwrap()[1];
+        assert_eq!((b.name().as_str(), b.entry_cluster, b.entry_offset), ("B.TXT", root, 32));
+    }
+}
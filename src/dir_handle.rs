@@ -0,0 +1,191 @@
+//! Directory handles: operations relative to one open directory.
+
+use crate::device::BlockDevice;
+use crate::dir::nt_case_flags;
+use crate::error::{Error, Operation, Result};
+use crate::file::File;
+use crate::fs::Fat32;
+use crate::options::{CreateOptions, ListOptions, OpenMode};
+use crate::path::Path;
+use crate::read_dir::ReadDir;
+
+/// An open directory, from `Fat32::open_dir`.
+///
+/// The path is resolved once; names passed to the methods are single 8.3
+/// components inside this directory.
+pub struct Dir<'a, D: BlockDevice> {
+    fs: &'a mut Fat32<D>,
+    cluster: u32,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Open directory `path` (the root for `/`).
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir<'_, D>> {
+        let cluster = self.resolve_dir(Path::new(path).components()?)?;
+        Ok(Dir { fs: self, cluster })
+    }
+
+    /// Handle on the directory whose first cluster is `cluster`.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn dir_at(&mut self, cluster: u32) -> Dir<'_, D> {
+        Dir { fs: self, cluster }
+    }
+
+    /// Give entry `from` of the directory at `dir_cluster` the name `to`.
+    pub(crate) fn rename_in(&mut self, dir_cluster: u32, from: &str, to: &str) -> Result<()> {
+        let src = self.short_name(from)?;
+        let dst = self.short_name(to)?;
+        let (_, lba, slot) = self.find_entry(dir_cluster, &src)?.ok_or(Error::NotFound)?;
+        if src != dst && self.find_entry(dir_cluster, &dst)?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        self.mark_dirty()?;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        buf[slot * 32..slot * 32 + 11].copy_from_slice(&dst);
+        buf[slot * 32 + 12] = nt_case_flags(to);
+        self.write_sector(Operation::WriteDir, lba, &buf)
+    }
+}
+
+impl<D: BlockDevice> Dir<'_, D> {
+    /// First cluster of the directory.
+    pub fn cluster(&self) -> u32 {
+        self.cluster
+    }
+
+    /// Iterate over the entries with the default `ListOptions`.
+    pub fn iter(&self) -> ReadDir<'_, D> {
+        ReadDir::new(self.fs, self.cluster, ListOptions::default())
+    }
+
+    /// Open existing file `name` for reading and writing.
+    pub fn open_file(&mut self, name: &str) -> Result<File<'_, D>> {
+        let st = self.fs.open_state(self.cluster, name)?;
+        Ok(File::new(self.fs, st, None))
+    }
+
+    /// Open file `name` as `mode` prescribes.
+    pub fn open_file_with(&mut self, name: &str, mode: OpenMode) -> Result<File<'_, D>> {
+        let st = self.fs.open_state_with(self.cluster, name, mode)?;
+        Ok(File::new(self.fs, st, None))
+    }
+
+    /// Create an empty file `name`, truncating it if it already exists.
+    pub fn create_file(&mut self, name: &str) -> Result<File<'_, D>> {
+        self.create_file_with(name, &CreateOptions::default())
+    }
+
+    /// Like `create_file`, giving a newly created file the attributes in `options`.
+    pub fn create_file_with(&mut self, name: &str, options: &CreateOptions) -> Result<File<'_, D>> {
+        let st = self.fs.create_state(self.cluster, name, options)?;
+        Ok(File::new(self.fs, st, None))
+    }
+
+    /// Create subdirectory `name`, returning its first cluster.
+    ///
+    /// Fails with `AlreadyExists` if an entry of that name exists.
+    pub fn create_dir(&mut self, name: &str) -> Result<u32> {
+        let short = self.fs.short_name(name)?;
+        if self.fs.find_entry(self.cluster, &short)?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        self.fs.mark_dirty()?;
+        let cluster = self.cluster;
+        self.fs.atomic(|fs| fs.create_dir_in(cluster, short, nt_case_flags(name)))
+    }
+
+    /// Remove file `name`, or subdirectory `name` if it is empty.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        let short = self.fs.short_name(name)?;
+        let (e, lba, slot) = self.fs.find_entry(self.cluster, &short)?.ok_or(Error::NotFound)?;
+        if e.is_dir() {
+            return self.fs.remove_empty_dir(&e, lba, slot);
+        }
+        let cluster = self.cluster;
+        self.fs.atomic(|fs| fs.remove_file_in(cluster, name))
+    }
+
+    /// Rename entry `from` to `to` within this directory.
+    ///
+    /// Fails with `AlreadyExists` if `to` names another entry.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let cluster = self.cluster;
+        self.fs.atomic(|fs| fs.rename_in(cluster, from, to))
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    #[test]
+    fn operations_relative_to_directory() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.create_dir_all("DATA/LOGS").unwrap();
+
+        let mut dir = fs.open_dir("DATA").unwrap();
+        let mut f = dir.create_file("a.txt").unwrap();
+        f.write(b"hello").unwrap();
+        f.close().unwrap();
+        dir.create_dir("OLD").unwrap();
+        assert_eq!(dir.create_dir("old"), Err(Error::AlreadyExists));
+        dir.rename("A.TXT", "B.TXT").unwrap();
+        assert_eq!(dir.rename("B.TXT", "OLD"), Err(Error::AlreadyExists));
+        let names: std::vec::Vec<_> = dir.iter().map(|e| e.unwrap().name()).collect();
+        assert_eq!(names, ["LOGS", "B.TXT", "OLD"]);
+        let mut buf = [0u8; 8];
+        assert_eq!(dir.open_file("b.txt").unwrap().read(&mut buf), Ok(5));
+
+        assert_eq!(dir.remove("LOGS"), Ok(()));
+        assert_eq!(dir.remove("B.TXT"), Ok(()));
+        assert_eq!(dir.remove("B.TXT"), Err(Error::NotFound));
+        assert_eq!(fs.read_dir("DATA").unwrap().count(), 1);
+        assert!(fs.check().unwrap().is_clean());
+        assert!(matches!(fs.open_dir("DATA/OLD/X"), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn create_with_attributes() {
+        use crate::dir::ATTR_ARCHIVE;
+        use crate::metadata::{ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM};
+
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let boot = CreateOptions {
+            hidden: true,
+            system: true,
+            ..CreateOptions::default()
+        };
+        let mut dir = fs.open_dir("/").unwrap();
+        let mut f = dir.create_file_with("BOOTCFG.SYS", &boot).unwrap();
+        f.write(b"boot=1").unwrap();
+        f.close().unwrap();
+        let m = fs.metadata("BOOTCFG.SYS").unwrap();
+        assert_eq!((m.attr, m.size), (ATTR_ARCHIVE | ATTR_HIDDEN | ATTR_SYSTEM, 6));
+        let visible = ListOptions {
+            include_hidden: false,
+            ..ListOptions::default()
+        };
+        assert!(fs.list_root_with(&visible).unwrap().is_empty());
+
+        let locked = CreateOptions {
+            read_only: true,
+            ..CreateOptions::default()
+        };
+        fs.write_file_root_with("KEY.BIN", b"k", &locked).unwrap();
+        assert_eq!(fs.metadata("KEY.BIN").unwrap().attr, ATTR_ARCHIVE | ATTR_READ_ONLY);
+        assert_eq!(fs.write_file_root("KEY.BIN", b"x"), Err(Error::ReadOnlyFile));
+        // An existing file keeps its attributes.
+        fs.write_file_root_with("BOOTCFG.SYS", b"boot=2", &CreateOptions::default()).unwrap();
+        assert_eq!(fs.metadata("BOOTCFG.SYS").unwrap().attr, ATTR_ARCHIVE | ATTR_HIDDEN | ATTR_SYSTEM);
+
+        fs.create_file_root_with("SWAP.SYS", &boot).unwrap().close().unwrap();
+        assert_eq!(fs.metadata("SWAP.SYS").unwrap().attr, ATTR_ARCHIVE | ATTR_HIDDEN | ATTR_SYSTEM);
+        fs.write_file_atomic_with("CFG.INI", b"", &locked).unwrap();
+        assert_eq!(fs.metadata("CFG.INI").unwrap().attr, ATTR_ARCHIVE | ATTR_READ_ONLY);
+        fs.write_file_atomic_with("SWAP.SYS", b"s", &CreateOptions::default()).unwrap();
+        assert_eq!(fs.metadata("SWAP.SYS").unwrap().attr, ATTR_ARCHIVE | ATTR_HIDDEN | ATTR_SYSTEM);
+    }
+}
@@ -0,0 +1,127 @@
+//! Adapter for embassy block device drivers (`embassy` feature).
+//!
+//! embassy-stm32's `Sdmmc` and the `sdspi` SPI SD card driver (over any
+//! `embedded-hal-async` SPI bus) both implement
+//! `block_device_driver::BlockDevice<512>`; wrap them in `BlockDriver` to get
+//! an `AsyncBlockDevice`.
+//!
+//! ```ignore
+//! let spi = SpiDevice::new(&spi_bus, cs);
+//! let mut sd = sdspi::SdSpi::<_, _, aligned::A1>::new(spi, Delay);
+//! sd.init().await?;
+//! let mut fs = AsyncFat32::mount(BlockDriver::new(sd)).await?;
+//! fs.write_file_root("LOG.TXT", b"hello").await?;
+//! let sd = fs.unmount().await?.into_inner();
+//! ```
+
+use aligned::Aligned;
+use block_device_driver::BlockDevice;
+
+use crate::asynch::AsyncBlockDevice;
+use crate::error::{Error, Result};
+
+/// `AsyncBlockDevice` over a `block_device_driver::BlockDevice<512>`.
+pub struct BlockDriver<T> {
+    inner: T,
+}
+
+impl<T: BlockDevice<512>> BlockDriver<T> {
+    /// Wrap a 512-byte block driver.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Return the wrapped driver.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+fn block_address(lba: u64) -> Result<u32> {
+    u32::try_from(lba).map_err(|_| Error::Io)
+}
+
+impl<T: BlockDevice<512>> AsyncBlockDevice for BlockDriver<T> {
+    async fn read_sector(&mut self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        let mut block = [Aligned([0u8; 512])];
+        self.inner
+            .read(block_address(lba)?, &mut block)
+            .await
+            .map_err(|e| {
+                fs_warn!("fat32: block read {} failed: {:?}", lba, e);
+                Error::Io
+            })?;
+        buf.copy_from_slice(&*block[0]);
+        Ok(())
+    }
+
+    async fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        let block = [Aligned(*buf)];
+        self.inner
+            .write(block_address(lba)?, &block)
+            .await
+            .map_err(|e| {
+                fs_warn!("fat32: block write {} failed: {:?}", lba, e);
+                Error::Io
+            })
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::asynch::tests::block_on;
+    use crate::asynch::AsyncFat32;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    struct RamDisk(Vec<[u8; 512]>);
+
+    impl BlockDevice<512> for RamDisk {
+        type Error = ();
+        type Align = aligned::A4;
+
+        async fn read(
+            &mut self,
+            addr: u32,
+            data: &mut [Aligned<aligned::A4, [u8; 512]>],
+        ) -> core::result::Result<(), ()> {
+            for (i, b) in data.iter_mut().enumerate() {
+                **b = *self.0.get(addr as usize + i).ok_or(())?;
+            }
+            Ok(())
+        }
+
+        async fn write(
+            &mut self,
+            addr: u32,
+            data: &[Aligned<aligned::A4, [u8; 512]>],
+        ) -> core::result::Result<(), ()> {
+            for (i, b) in data.iter().enumerate() {
+                *self.0.get_mut(addr as usize + i).ok_or(())? = **b;
+            }
+            Ok(())
+        }
+
+        async fn size(&mut self) -> core::result::Result<u64, ()> {
+            Ok(self.0.len() as u64 * 512)
+        }
+    }
+
+    #[test]
+    fn mount_through_block_driver() {
+        let img = make_tiny_fat32_image();
+        let disk = RamDisk(img.chunks(512).map(|c| c.try_into().unwrap()).collect());
+        let mut fs = block_on(AsyncFat32::mount(BlockDriver::new(disk))).expect("mount");
+        block_on(fs.write_file_root("A.TXT", b"embassy")).expect("write");
+        assert_eq!(block_on(fs.read_file_root("A.TXT")).unwrap(), b"embassy");
+
+        let mut dev = block_on(fs.unmount()).unwrap();
+        let past_u32 = u32::MAX as u64 + 1;
+        assert_eq!(
+            block_on(dev.read_sector(past_u32, &mut [0u8; 512])),
+            Err(Error::Io)
+        );
+    }
+}
@@ -1,25 +1,252 @@
-//! Errors for the FAT32 library.
-
-/// Result alias used by this crate.
-pub type Result<T> = core::result::Result<T, Error>;
-
-/// Errors returned by the FAT32 parser / filesystem.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Error {
-    /// Underlying device I/O error.
-    Io,
-    /// The boot sector is invalid or unsupported.
-    InvalidBootSector,
-    /// Not a FAT32 volume (or fields not supported).
-    NotFat32,
-    /// The requested file was not found.
-    NotFound,
-    /// Directory is full (no free entry).
-    DirFull,
-    /// No free cluster available.
-    NoSpace,
-    /// The provided name is invalid (only 8.3 supported in this MVP).
-    InvalidName,
-    /// Internal corruption (FAT chain, cluster values).
-    Corrupt,
-}
+//! Errors for the FAT32 library.
+
+use core::fmt;
+
+/// Result alias used by this crate.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Errors returned by the FAT32 parser / filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Underlying device I/O error.
+    Io,
+    /// Device error classified by the driver.
+    Device(DeviceError),
+    /// The boot sector is invalid or unsupported.
+    InvalidBootSector,
+    /// Not a FAT32 volume (or fields not supported).
+    NotFat32,
+    /// The requested file was not found.
+    NotFound,
+    /// Directory is full (no free entry).
+    DirFull,
+    /// No free cluster available.
+    NoSpace,
+    /// The provided name is invalid (only 8.3 supported in this MVP).
+    InvalidName,
+    /// Internal corruption (FAT chain, cluster values).
+    Corrupt,
+    /// The requested FAT copy does not exist on this volume.
+    InvalidFatIndex,
+    /// The FSInfo sector signatures are missing or wrong.
+    InvalidFsInfo,
+    /// A mutating operation was attempted on a volume mounted read-only.
+    ReadOnlyVolume,
+    /// The device refuses writes (e.g. SD card lock switch engaged).
+    WriteProtected,
+    /// An entry with this name already exists.
+    AlreadyExists,
+    /// A path component that must be a directory is a file.
+    NotADirectory,
+    /// A file operation was attempted on a directory.
+    IsADirectory,
+    /// A cluster chain ended before the size recorded in the directory entry,
+    /// or a device read ran past the end of its medium.
+    UnexpectedEof,
+    /// The volume uses a valid sector size other than 512 bytes.
+    UnsupportedSectorSize,
+    /// A seek targeted a negative position or one beyond the 4 GiB file limit.
+    InvalidSeek,
+    /// The file already has a handle in the open-file table.
+    AlreadyOpen,
+    /// The reserved area has no room for the journal, holds other data where
+    /// the journal would go, or an operation changes more metadata sectors
+    /// than the journal holds.
+    NoJournalSpace,
+    /// A sector read back after writing differs from what was written.
+    VerifyFailed,
+    /// A directory to remove still has entries.
+    DirectoryNotEmpty,
+    /// The operation was aborted through its `Cancel` token.
+    Cancelled,
+    /// A fixed-capacity output buffer cannot hold the result.
+    BufferTooSmall,
+    /// A write was attempted through a file opened read-only, or a write or
+    /// delete on an entry with the read-only attribute.
+    ReadOnlyFile,
+    /// An argument is out of range, such as a zero chunk size, a mount
+    /// option needs the `alloc` feature, or the call cannot run inside a
+    /// transaction.
+    InvalidInput,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Error::Io => "device I/O error",
+            Error::Device(e) => return write!(f, "device error: {}", e),
+            Error::InvalidBootSector => "invalid or unsupported boot sector",
+            Error::NotFat32 => "not a FAT32 volume",
+            Error::NotFound => "file not found",
+            Error::DirFull => "directory is full",
+            Error::NoSpace => "no free cluster available",
+            Error::InvalidName => "invalid file name",
+            Error::Corrupt => "filesystem structure is corrupt",
+            Error::InvalidFatIndex => "FAT copy does not exist",
+            Error::InvalidFsInfo => "invalid FSInfo sector",
+            Error::ReadOnlyVolume => "volume is mounted read-only",
+            Error::WriteProtected => "device is write-protected",
+            Error::AlreadyExists => "entry already exists",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::UnexpectedEof => "unexpected end of cluster chain or medium",
+            Error::UnsupportedSectorSize => "unsupported sector size",
+            Error::InvalidSeek => "invalid seek position",
+            Error::AlreadyOpen => "file is already open",
+            Error::NoJournalSpace => "not enough journal space",
+            Error::VerifyFailed => "sector read back differs from data written",
+            Error::DirectoryNotEmpty => "directory is not empty",
+            Error::Cancelled => "operation cancelled",
+            Error::BufferTooSmall => "output buffer too small",
+            Error::ReadOnlyFile => "file is read-only",
+            Error::InvalidInput => "invalid argument",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// What kind of failure a driver reported, for `Error::Device`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceErrorKind {
+    /// Data or command CRC mismatch; usually worth a retry.
+    Crc,
+    /// The device did not answer in time; usually worth a retry.
+    Timeout,
+    /// The medium is gone (e.g. SD card pulled).
+    Removed,
+    /// A host I/O call failed; `code` is the OS error number, 0 if there was none.
+    Host,
+    /// Anything else.
+    Other,
+}
+
+/// Driver-level error carried by `Error::Device`.
+///
+/// `BlockDevice` implementations return `Err(Error::Device(..))` instead of
+/// `Error::Io` to let callers tell a CRC glitch from a removed card. `code`
+/// is free for the driver's own status (e.g. an SD R1 response).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceError {
+    /// What went wrong, as far as the driver can tell.
+    pub kind: DeviceErrorKind,
+    /// Driver-specific status; 0 when the driver has none to report.
+    pub code: u32,
+}
+
+impl DeviceError {
+    /// A `kind` error with driver status `code`.
+    pub const fn new(kind: DeviceErrorKind, code: u32) -> Self {
+        Self { kind, code }
+    }
+
+    /// True for errors that may go away when the access is repeated.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.kind, DeviceErrorKind::Crc | DeviceErrorKind::Timeout)
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            DeviceErrorKind::Crc => "CRC error",
+            DeviceErrorKind::Timeout => "timeout",
+            DeviceErrorKind::Removed => "medium removed",
+            DeviceErrorKind::Host => "host I/O error",
+            DeviceErrorKind::Other => "failure",
+        };
+        write!(f, "{} (code {:#x})", kind, self.code)
+    }
+}
+
+impl core::error::Error for DeviceError {}
+
+/// Operation in progress when an error was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Reading the boot sector or its backup.
+    ReadBoot,
+    /// Writing the boot sector.
+    WriteBoot,
+    /// Reading the FSInfo sector.
+    ReadFsInfo,
+    /// Writing the FSInfo sector.
+    WriteFsInfo,
+    /// Reading a FAT entry.
+    ReadFat,
+    /// Writing a FAT entry.
+    WriteFat,
+    /// Reading a directory cluster.
+    ReadDir,
+    /// Writing a directory cluster.
+    WriteDir,
+    /// Reading file data.
+    ReadData,
+    /// Writing file data.
+    WriteData,
+    /// Reading the metadata journal.
+    ReadJournal,
+    /// Writing the metadata journal or applying it.
+    WriteJournal,
+    /// Flushing the device's write cache.
+    Flush,
+    /// Discarding free sectors (TRIM).
+    Trim,
+}
+
+/// Where an error happened, for diagnosing failures on deployed devices.
+///
+/// Retrieved with `Fat32::last_error` after an operation returned `Err`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The error that was returned.
+    pub error: Error,
+    /// What the filesystem was doing.
+    pub op: Operation,
+    /// Sector involved, if known.
+    pub lba: Option<u64>,
+    /// Cluster involved, if known.
+    pub cluster: Option<u32>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Operation::ReadBoot => "reading boot sector",
+            Operation::WriteBoot => "writing boot sector",
+            Operation::ReadFsInfo => "reading FSInfo",
+            Operation::WriteFsInfo => "writing FSInfo",
+            Operation::ReadFat => "reading FAT",
+            Operation::WriteFat => "writing FAT",
+            Operation::ReadDir => "reading directory",
+            Operation::WriteDir => "writing directory",
+            Operation::ReadData => "reading file data",
+            Operation::WriteData => "writing file data",
+            Operation::ReadJournal => "reading journal",
+            Operation::WriteJournal => "writing journal",
+            Operation::Flush => "flushing device",
+            Operation::Trim => "trimming free sectors",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} while {}", self.error, self.op)?;
+        if let Some(lba) = self.lba {
+            write!(f, " (lba {})", lba)?;
+        }
+        if let Some(cluster) = self.cluster {
+            write!(f, " (cluster {})", cluster)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ErrorContext {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
@@ -1,80 +1,259 @@
-//! FAT table helpers (FAT32).
-
-use crate::bpb::Bpb;
-use crate::device::BlockDevice;
-use crate::error::{Error, Result};
-
-/// FAT32 end-of-chain marker threshold.
-pub const EOC_MIN: u32 = 0x0FFFFFF8;
-
-fn le_u32(x: &[u8]) -> u32 {
-    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
-}
-fn write_le_u32(dst: &mut [u8], v: u32) {
-    let b = v.to_le_bytes();
-    dst[0..4].copy_from_slice(&b);
-}
-
-/// Compute LBA of FAT region start.
-pub fn fat_start_lba(bpb: &Bpb) -> u64 {
-    bpb.reserved_sectors as u64
-}
-
-/// Compute LBA of data region start.
-pub fn data_start_lba(bpb: &Bpb) -> u64 {
-    fat_start_lba(bpb) + (bpb.num_fats as u64) * (bpb.fat_size_32 as u64)
-}
-
-/// Convert cluster number to first sector LBA.
-pub fn cluster_to_lba(bpb: &Bpb, cluster: u32) -> u64 {
-    // Cluster numbers start at 2.
-    let first_data = data_start_lba(bpb);
-    first_data + ((cluster - 2) as u64) * (bpb.sectors_per_cluster as u64)
-}
-
-/// Read FAT entry (next cluster) for `cluster`.
-pub fn read_fat_entry<D: BlockDevice>(dev: &D, bpb: &Bpb, cluster: u32) -> Result<u32> {
-    let fat_offset = cluster as u64 * 4;
-    let sector = fat_start_lba(bpb) + (fat_offset / 512);
-    let off = (fat_offset % 512) as usize;
-
-    let mut buf = [0u8; 512];
-    dev.read_sector(sector, &mut buf)?;
-    let v = le_u32(&buf[off..off + 4]) & 0x0FFFFFFF;
-    Ok(v)
-}
-
-/// Write FAT entry for `cluster` (updates only FAT #0 in this MVP).
-///
-/// For a “proper” implementation, you should mirror to all FATs.
-pub fn write_fat_entry<D: BlockDevice>(
-    dev: &mut D,
-    bpb: &Bpb,
-    cluster: u32,
-    value: u32,
-) -> Result<()> {
-    let fat_offset = cluster as u64 * 4;
-    let sector = fat_start_lba(bpb) + (fat_offset / 512);
-    let off = (fat_offset % 512) as usize;
-
-    let mut buf = [0u8; 512];
-    dev.read_sector(sector, &mut buf)?;
-    write_le_u32(&mut buf[off..off + 4], value & 0x0FFFFFFF);
-    dev.write_sector(sector, &buf)?;
-    Ok(())
-}
-
-/// Find a free cluster by scanning the FAT (very naive).
-pub fn find_free_cluster<D: BlockDevice>(dev: &D, bpb: &Bpb, start_from: u32) -> Result<u32> {
-    let mut c = if start_from < 2 { 2 } else { start_from };
-    let max_iters = 1_000_000u32;
-
-    for _ in 0..max_iters {
-        let v = read_fat_entry(dev, bpb, c)?;
-        if v == 0 {
-            return Ok(c);
-        }
-        c += 1;
-    }
-    Err(Error::NoSpace)
-}
+//! FAT table helpers (FAT32).
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::bpb::Bpb;
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+pub use crate::layout::{cluster_to_lba, data_start_lba, fat_copy_lba, fat_start_lba, max_cluster};
+
+/// FAT32 end-of-chain marker threshold.
+pub const EOC_MIN: u32 = 0x0FFFFFF8;
+/// FAT32 bad cluster marker.
+pub const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+/// FAT[1] bit set when the volume was cleanly unmounted.
+pub const FAT1_CLEAN_SHUTDOWN: u32 = 0x08000000;
+
+fn le_u32(x: &[u8]) -> u32 {
+    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
+}
+fn write_le_u32(dst: &mut [u8], v: u32) {
+    let b = v.to_le_bytes();
+    dst[0..4].copy_from_slice(&b);
+}
+
+/// Read FAT entry (next cluster) for `cluster`.
+pub fn read_fat_entry<D: BlockDevice>(dev: &D, bpb: &Bpb, cluster: u32) -> Result<u32> {
+    read_fat_entry_in(dev, bpb, 0, cluster)
+}
+
+/// Read FAT entry for `cluster` from FAT copy `fat`.
+pub fn read_fat_entry_in<D: BlockDevice>(dev: &D, bpb: &Bpb, fat: u8, cluster: u32) -> Result<u32> {
+    let fat_offset = cluster as u64 * 4;
+    let sector = fat_copy_lba(bpb, fat) + (fat_offset / 512);
+    let off = (fat_offset % 512) as usize;
+
+    let mut buf = [0u8; 512];
+    dev.read_sector(sector, &mut buf)?;
+    let v = le_u32(&buf[off..off + 4]) & 0x0FFFFFFF;
+    Ok(v)
+}
+
+/// Iterator over the clusters of a chain, first cluster included.
+///
+/// Stops after the cluster marked end-of-chain. A link to a free, reserved,
+/// bad or out-of-range cluster, or a chain longer than the volume (a loop),
+/// yields `Err(Corrupt)` and ends the iteration.
+pub struct ChainIter<'a, D: BlockDevice> {
+    dev: &'a D,
+    bpb: &'a Bpb,
+    fat: u8,
+    next: Option<u32>,
+    count: u32,
+}
+
+impl<'a, D: BlockDevice> ChainIter<'a, D> {
+    /// Walk the chain starting at `first` in FAT #0.
+    pub fn new(dev: &'a D, bpb: &'a Bpb, first: u32) -> Self {
+        Self::in_fat(dev, bpb, 0, first)
+    }
+
+    /// Walk the chain starting at `first` in FAT copy `fat`.
+    pub fn in_fat(dev: &'a D, bpb: &'a Bpb, fat: u8, first: u32) -> Self {
+        Self {
+            dev,
+            bpb,
+            fat,
+            next: Some(first),
+            count: 0,
+        }
+    }
+}
+
+impl<D: BlockDevice> Iterator for ChainIter<'_, D> {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Result<u32>> {
+        let cluster = self.next.take()?;
+        let max = max_cluster(self.bpb);
+        self.count += 1;
+        if !(2..=max).contains(&cluster) || self.count > max {
+            return Some(Err(Error::Corrupt));
+        }
+        match read_fat_entry_in(self.dev, self.bpb, self.fat, cluster) {
+            Ok(v) if v >= EOC_MIN => {}
+            Ok(v) => self.next = Some(v),
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(cluster))
+    }
+}
+
+/// Write FAT entry for `cluster` (updates only FAT #0 in this MVP).
+///
+/// For a “proper” implementation, you should mirror to all FATs.
+pub fn write_fat_entry<D: BlockDevice>(
+    dev: &mut D,
+    bpb: &Bpb,
+    cluster: u32,
+    value: u32,
+) -> Result<()> {
+    write_fat_entry_in(dev, bpb, 0, cluster, value)
+}
+
+/// Write FAT entry for `cluster` into FAT copy `fat` only.
+pub fn write_fat_entry_in<D: BlockDevice>(
+    dev: &mut D,
+    bpb: &Bpb,
+    fat: u8,
+    cluster: u32,
+    value: u32,
+) -> Result<()> {
+    let fat_offset = cluster as u64 * 4;
+    let sector = fat_copy_lba(bpb, fat) + (fat_offset / 512);
+    let off = (fat_offset % 512) as usize;
+
+    let mut buf = [0u8; 512];
+    dev.read_sector(sector, &mut buf)?;
+    write_le_u32(&mut buf[off..off + 4], value & 0x0FFFFFFF);
+    dev.write_sector(sector, &buf)?;
+    Ok(())
+}
+
+/// Find a free cluster by scanning the FAT (very naive).
+pub fn find_free_cluster<D: BlockDevice>(dev: &D, bpb: &Bpb, start_from: u32) -> Result<u32> {
+    find_free_cluster_in(dev, bpb, 0, start_from)
+}
+
+/// Find a free cluster by scanning FAT copy `fat` from `start_from` up to `max_cluster`.
+pub fn find_free_cluster_in<D: BlockDevice>(dev: &D, bpb: &Bpb, fat: u8, start_from: u32) -> Result<u32> {
+    let first = if start_from < 2 { 2 } else { start_from };
+
+    for c in first..=max_cluster(bpb) {
+        let v = read_fat_entry_in(dev, bpb, fat, c)?;
+        if v == 0 {
+            return Ok(c);
+        }
+    }
+    Err(Error::NoSpace)
+}
+
+/// A run of FAT sectors where a copy differs from the reference FAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatMismatch {
+    /// FAT copy that differs.
+    pub fat_index: u8,
+    /// First differing sector, relative to the start of the FAT.
+    pub first_sector: u32,
+    /// Number of consecutive differing sectors.
+    pub sector_count: u32,
+}
+
+/// Compare every FAT copy against FAT `reference`, sector by sector.
+#[cfg(feature = "alloc")]
+pub fn compare_fats<D: BlockDevice>(dev: &D, bpb: &Bpb, reference: u8) -> Result<Vec<FatMismatch>> {
+    if reference >= bpb.num_fats {
+        return Err(Error::InvalidFatIndex);
+    }
+    let mut out: Vec<FatMismatch> = Vec::new();
+    let mut a = [0u8; 512];
+    let mut b = [0u8; 512];
+
+    for copy in (0..bpb.num_fats).filter(|&i| i != reference) {
+        for s in 0..bpb.fat_size_32 {
+            dev.read_sector(fat_copy_lba(bpb, reference) + s as u64, &mut a)?;
+            dev.read_sector(fat_copy_lba(bpb, copy) + s as u64, &mut b)?;
+            if a == b {
+                continue;
+            }
+            // Extend the previous run when contiguous.
+            match out.last_mut() {
+                Some(m) if m.fat_index == copy && m.first_sector + m.sector_count == s => {
+                    m.sector_count += 1;
+                }
+                _ => out.push(FatMismatch {
+                    fat_index: copy,
+                    first_sector: s,
+                    sector_count: 1,
+                }),
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Copy FAT `source` over every other FAT copy, rewriting only sectors that differ.
+pub fn sync_fats<D: BlockDevice>(dev: &mut D, bpb: &Bpb, source: u8) -> Result<()> {
+    if source >= bpb.num_fats {
+        return Err(Error::InvalidFatIndex);
+    }
+    let mut a = [0u8; 512];
+    let mut b = [0u8; 512];
+
+    for copy in (0..bpb.num_fats).filter(|&i| i != source) {
+        for s in 0..bpb.fat_size_32 {
+            dev.read_sector(fat_copy_lba(bpb, source) + s as u64, &mut a)?;
+            dev.read_sector(fat_copy_lba(bpb, copy) + s as u64, &mut b)?;
+            if a != b {
+                fs_trace!("fat32: resync FAT{} sector {} from FAT{}", copy, s, source);
+                dev.write_sector(fat_copy_lba(bpb, copy) + s as u64, &a)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn chain_iter_follows_and_checks_links() {
+        let mut dev = ImageBuilder::new(200).fats(1).file("A.BIN", &[1u8; 1500]).build_device().unwrap();
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot).unwrap();
+        let bpb = Bpb::parse(&boot).unwrap();
+
+        let chain: Result<Vec<u32>> = ChainIter::new(&dev, &bpb, 3).collect();
+        assert_eq!(chain, Ok(alloc::vec![3, 4, 5]));
+        assert_eq!(ChainIter::new(&dev, &bpb, 2).count(), 1);
+        let fs = crate::fs::Fat32::mount(&mut dev).unwrap();
+        assert_eq!(fs.chain(3).filter_map(Result::ok).last(), Some(5));
+        drop(fs);
+
+        // 5 -> 3 closes a loop; 4 -> 0 points at a free cluster.
+        write_fat_entry(&mut dev, &bpb, 5, 3).unwrap();
+        let looped: Vec<_> = ChainIter::new(&dev, &bpb, 3).collect();
+        assert_eq!(looped.last(), Some(&Err(Error::Corrupt)));
+        assert_eq!(looped.len() as u32, max_cluster(&bpb) + 1);
+        write_fat_entry(&mut dev, &bpb, 4, 0).unwrap();
+        let broken: Vec<_> = ChainIter::new(&dev, &bpb, 3).collect();
+        assert_eq!(broken, [Ok(3), Ok(4), Err(Error::Corrupt)]);
+    }
+
+    #[test]
+    fn detect_and_resync_fat_mirror() {
+        let mut dev = ImageBuilder::new(200).fats(2).build_device().unwrap();
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot).unwrap();
+        let bpb = Bpb::parse(&boot).unwrap();
+        dev.write_sector(fat_copy_lba(&bpb, 1), &[0u8; 512]).unwrap(); // blank FAT #1
+
+        let diff = compare_fats(&dev, &bpb, 0).unwrap();
+        assert_eq!(
+            diff,
+            [FatMismatch {
+                fat_index: 1,
+                first_sector: 0,
+                sector_count: 1
+            }]
+        );
+        assert_eq!(compare_fats(&dev, &bpb, 2), Err(Error::InvalidFatIndex));
+
+        sync_fats(&mut dev, &bpb, 0).unwrap();
+        assert!(compare_fats(&dev, &bpb, 0).unwrap().is_empty());
+    }
+}
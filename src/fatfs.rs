@@ -0,0 +1,513 @@
+//! `fatfs`-style API (`std` feature).
+//!
+//! Mirrors the `FileSystem` / `Dir` / `File` types of the `fatfs` crate on
+//! top of `Fat32`, so a host tool written against `fatfs` can share one
+//! implementation with firmware:
+//!
+//! ```ignore
+//! let img = OpenOptions::new().read(true).write(true).open("fat.img")?;
+//! let fs = FileSystem::new(img, FsOptions::new())?;
+//! let root = fs.root_dir();
+//! let mut file = root.create_file("logs/boot.txt")?;
+//! file.truncate()?;
+//! file.write_all(b"hello")?;
+//! for entry in root.iter() {
+//!     let entry = entry?;
+//!     println!("{} {}", entry.file_name(), entry.len());
+//! }
+//! ```
+//!
+//! Differences: names are 8.3, errors carry this crate's `Error`, `iter`
+//! skips `.` and `..`, and `rename` only renames within one directory.
+
+use alloc::string::String;
+use core::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::device::BlockDevice;
+use crate::dir::DirEntry as RawEntry;
+use crate::error::{Error, Result};
+use crate::file::FileHandle;
+use crate::fs::Fat32;
+use crate::metadata::{Metadata, Timestamp};
+use crate::options::{MountOptions, OpenMode};
+use crate::path::Path;
+
+/// Streams a filesystem image can live on.
+pub trait ReadWriteSeek: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> ReadWriteSeek for T {}
+
+/// A `BlockDevice` over a seekable stream; stream errors keep their kind and
+/// OS error number, as with `FileDevice`.
+struct StreamDevice<T> {
+    io: RefCell<T>,
+}
+
+impl<T: ReadWriteSeek> BlockDevice for StreamDevice<T> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        self.read_sectors(lba, buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let mut io = self.io.borrow_mut();
+        io.seek(SeekFrom::Start(lba * 512))?;
+        io.read_exact(buf).map_err(Error::from)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.write_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let io = self.io.get_mut();
+        io.seek(SeekFrom::Start(lba * 512))?;
+        io.write_all(buf).map_err(Error::from)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.io.get_mut().flush().map_err(Error::from)
+    }
+}
+
+/// Options for `FileSystem::new`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsOptions {
+    mount: MountOptions,
+}
+
+impl FsOptions {
+    /// The default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mount with `options` (read-only, strictness, caching, ...).
+    pub fn mount_options(mut self, options: MountOptions) -> Self {
+        self.mount = options;
+        self
+    }
+}
+
+/// Space usage, from `FileSystem::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSystemStats {
+    cluster_size: u32,
+    total_clusters: u32,
+    free_clusters: u32,
+}
+
+impl FileSystemStats {
+    /// Cluster size in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    /// Number of data clusters.
+    pub fn total_clusters(&self) -> u32 {
+        self.total_clusters
+    }
+
+    /// Number of free clusters.
+    pub fn free_clusters(&self) -> u32 {
+        self.free_clusters
+    }
+}
+
+/// A mounted volume, shared by the `Dir`s and `File`s borrowed from it.
+pub struct FileSystem<IO: ReadWriteSeek> {
+    fs: RefCell<Fat32<StreamDevice<IO>>>,
+}
+
+impl<IO: ReadWriteSeek> FileSystem<IO> {
+    /// Mount the volume stored in `disk`.
+    pub fn new(disk: IO, options: FsOptions) -> io::Result<Self> {
+        let dev = StreamDevice { io: RefCell::new(disk) };
+        Ok(Self {
+            fs: RefCell::new(Fat32::mount_with(dev, options.mount)?),
+        })
+    }
+
+    /// The root directory.
+    pub fn root_dir(&self) -> Dir<'_, IO> {
+        Dir {
+            fs: self,
+            path: String::from("/"),
+        }
+    }
+
+    /// Cluster size and counts.
+    pub fn stats(&self) -> io::Result<FileSystemStats> {
+        let stats = self.fs.borrow().stats()?;
+        Ok(FileSystemStats {
+            cluster_size: self.fs.borrow().bpb().sectors_per_cluster as u32 * 512,
+            total_clusters: stats.total_clusters,
+            free_clusters: stats.free_clusters,
+        })
+    }
+
+    /// Volume serial number from the boot sector.
+    pub fn volume_id(&self) -> u32 {
+        self.fs.borrow().bpb().volume_id
+    }
+
+    /// Volume label from the boot sector, without trailing spaces.
+    pub fn volume_label(&self) -> String {
+        let label = self.fs.borrow().bpb().volume_label;
+        String::from_utf8_lossy(&label).trim_end().into()
+    }
+
+    /// Write everything back and mark the volume clean.
+    pub fn unmount(self) -> io::Result<()> {
+        self.fs.into_inner().unmount()?;
+        Ok(())
+    }
+}
+
+/// A directory of a `FileSystem`. Paths passed to its methods are relative
+/// to it, `/`-separated.
+pub struct Dir<'a, IO: ReadWriteSeek> {
+    fs: &'a FileSystem<IO>,
+    /// Path from the root (`/` for the root itself).
+    path: String,
+}
+
+impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+    /// Path from the root of `path` relative to this directory.
+    fn join(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        match self.path.as_str() {
+            "/" => path.into(),
+            dir => alloc::format!("{}/{}", dir, path),
+        }
+    }
+
+    /// Open subdirectory `path`.
+    pub fn open_dir(&self, path: &str) -> io::Result<Dir<'a, IO>> {
+        let path = self.join(path);
+        if !self.fs.fs.borrow().metadata(&path)?.is_dir() {
+            return Err(Error::NotADirectory.into());
+        }
+        Ok(Dir { fs: self.fs, path })
+    }
+
+    /// Create subdirectory `path`, or open it if it exists.
+    pub fn create_dir(&self, path: &str) -> io::Result<Dir<'a, IO>> {
+        let full = self.join(path);
+        let (parent, name) = Path::new(&full).split_last()?;
+        let mut fs = self.fs.fs.borrow_mut();
+        let dir = fs.resolve_dir(parent)?;
+        match fs.dir_at(dir).create_dir(name) {
+            Ok(_) => {}
+            Err(Error::AlreadyExists) if fs.metadata(&full)?.is_dir() => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Dir { fs: self.fs, path: full })
+    }
+
+    /// Open existing file `path` at its start.
+    pub fn open_file(&self, path: &str) -> io::Result<File<'a, IO>> {
+        self.file(path, false)
+    }
+
+    /// Create file `path`, or open it without truncating if it exists.
+    pub fn create_file(&self, path: &str) -> io::Result<File<'a, IO>> {
+        self.file(path, true)
+    }
+
+    fn file(&self, path: &str, create: bool) -> io::Result<File<'a, IO>> {
+        let full = self.join(path);
+        let (parent, name) = Path::new(&full).split_last()?;
+        let mut fs = self.fs.fs.borrow_mut();
+        let dir = fs.resolve_dir(parent)?;
+        let mode = match fs.exists(&full)? {
+            false if create => OpenMode::CreateNew,
+            _ => OpenMode::Open,
+        };
+        let handle = fs.open_handle_in(dir, name, mode)?;
+        Ok(File {
+            fs: self.fs,
+            handle: Some(handle),
+        })
+    }
+
+    /// Remove file `path`, or directory `path` if it is empty.
+    pub fn remove(&self, path: &str) -> io::Result<()> {
+        let full = self.join(path);
+        let (parent, name) = Path::new(&full).split_last()?;
+        let mut fs = self.fs.fs.borrow_mut();
+        let dir = fs.resolve_dir(parent)?;
+        Ok(fs.dir_at(dir).remove(name)?)
+    }
+
+    /// Rename `src_path` to `dst_path` in `dst_dir`; both must name the
+    /// same directory, moves fail with `Unsupported`.
+    pub fn rename(&self, src_path: &str, dst_dir: &Dir<'_, IO>, dst_path: &str) -> io::Result<()> {
+        let (src, dst) = (self.join(src_path), dst_dir.join(dst_path));
+        let (src_parent, from) = Path::new(&src).split_last()?;
+        let (dst_parent, to) = Path::new(&dst).split_last()?;
+        let mut fs = self.fs.fs.borrow_mut();
+        let dir = fs.resolve_dir(src_parent)?;
+        if fs.resolve_dir(dst_parent)? != dir {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "rename across directories"));
+        }
+        Ok(fs.dir_at(dir).rename(from, to)?)
+    }
+
+    /// The entries of this directory, read when called.
+    pub fn iter(&self) -> DirIter<'a, IO> {
+        let entries = self.fs.fs.borrow().list_dir_path(&self.path);
+        DirIter {
+            fs: self.fs,
+            dir: self.path.clone(),
+            entries: match entries {
+                Ok(entries) => Ok(entries.into_iter()),
+                Err(e) => Err(Some(e)),
+            },
+        }
+    }
+}
+
+/// Iterator over a directory, from `Dir::iter`.
+pub struct DirIter<'a, IO: ReadWriteSeek> {
+    fs: &'a FileSystem<IO>,
+    dir: String,
+    /// The entries, or the listing error until it has been returned.
+    entries: core::result::Result<alloc::vec::IntoIter<RawEntry>, Option<Error>>,
+}
+
+impl<'a, IO: ReadWriteSeek> Iterator for DirIter<'a, IO> {
+    type Item = io::Result<DirEntry<'a, IO>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match &mut self.entries {
+            Ok(entries) => entries.next()?,
+            Err(e) => return e.take().map(|e| Err(e.into())),
+        };
+        let path = match self.dir.as_str() {
+            "/" => entry.name(),
+            dir => alloc::format!("{}/{}", dir, entry.name()),
+        };
+        Some(match self.fs.fs.borrow().metadata(&path) {
+            Ok(meta) => Ok(DirEntry {
+                fs: self.fs,
+                path,
+                entry,
+                meta,
+            }),
+            Err(e) => Err(e.into()),
+        })
+    }
+}
+
+/// An entry returned by `DirIter`.
+pub struct DirEntry<'a, IO: ReadWriteSeek> {
+    fs: &'a FileSystem<IO>,
+    path: String,
+    entry: RawEntry,
+    meta: Metadata,
+}
+
+impl<'a, IO: ReadWriteSeek> DirEntry<'a, IO> {
+    /// Name, honoring the NT lowercase flags.
+    pub fn file_name(&self) -> String {
+        self.entry.name()
+    }
+
+    /// Name as stored: 8 + 3 bytes, space-padded.
+    pub fn short_file_name_as_bytes(&self) -> &[u8] {
+        &self.entry.raw_name
+    }
+
+    /// True for a subdirectory.
+    pub fn is_dir(&self) -> bool {
+        self.entry.is_dir()
+    }
+
+    /// True for a file.
+    pub fn is_file(&self) -> bool {
+        !self.entry.is_dir()
+    }
+
+    /// Size in bytes (0 for a directory).
+    pub fn len(&self) -> u64 {
+        self.entry.file_size as u64
+    }
+
+    /// True if the size is 0.
+    pub fn is_empty(&self) -> bool {
+        self.entry.file_size == 0
+    }
+
+    /// Attribute byte (`dir::ATTR_*`).
+    pub fn attributes(&self) -> u8 {
+        self.entry.attr
+    }
+
+    /// Creation time.
+    pub fn created(&self) -> Timestamp {
+        self.meta.created
+    }
+
+    /// Last modification time.
+    pub fn modified(&self) -> Timestamp {
+        self.meta.modified
+    }
+
+    /// Last access date (the time part is not recorded).
+    pub fn accessed(&self) -> Timestamp {
+        self.meta.accessed
+    }
+
+    /// Open the file this entry names.
+    pub fn to_file(&self) -> io::Result<File<'a, IO>> {
+        self.fs.root_dir().open_file(&self.path)
+    }
+
+    /// Open the directory this entry names.
+    pub fn to_dir(&self) -> io::Result<Dir<'a, IO>> {
+        self.fs.root_dir().open_dir(&self.path)
+    }
+}
+
+/// An open file; closed, with its directory entry written, on drop.
+pub struct File<'a, IO: ReadWriteSeek> {
+    fs: &'a FileSystem<IO>,
+    /// `None` once closed.
+    handle: Option<FileHandle>,
+}
+
+impl<IO: ReadWriteSeek> File<'_, IO> {
+    fn with<T>(&mut self, f: impl FnOnce(&mut crate::file::File<'_, StreamDevice<IO>>) -> Result<T>) -> io::Result<T> {
+        let handle = self.handle.ok_or(Error::NotFound)?;
+        let mut fs = self.fs.fs.borrow_mut();
+        let mut file = fs.file(handle)?;
+        Ok(f(&mut file)?)
+    }
+
+    /// Cut the file off at the current position.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.with(|f| f.truncate())
+    }
+}
+
+impl<IO: ReadWriteSeek> Read for File<'_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with(|f| f.read(buf))
+    }
+}
+
+impl<IO: ReadWriteSeek> Write for File<'_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with(|f| f.write(buf))
+    }
+
+    /// Write the directory entry.
+    fn flush(&mut self) -> io::Result<()> {
+        self.with(|f| f.flush())
+    }
+}
+
+impl<IO: ReadWriteSeek> Seek for File<'_, IO> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.with(|f| {
+            let target = match pos {
+                SeekFrom::Start(n) => Some(n),
+                SeekFrom::Current(n) => (f.position() as u64).checked_add_signed(n),
+                SeekFrom::End(n) => (f.len() as u64).checked_add_signed(n),
+            };
+            let target = target.and_then(|t| u32::try_from(t).ok()).ok_or(Error::InvalidSeek)?;
+            f.seek(target).map(u64::from)
+        })
+    }
+}
+
+impl<IO: ReadWriteSeek> Drop for File<'_, IO> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.fs.fs.borrow_mut().close_handle(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use alloc::vec::Vec;
+    use std::io::Cursor;
+
+    #[test]
+    fn fatfs_style_session() {
+        let fs = FileSystem::new(Cursor::new(make_tiny_fat32_image()), FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+        let logs = root.create_dir("LOGS").unwrap();
+        assert!(root.create_dir("logs").is_ok());
+
+        let mut file = root.create_file("LOGS/BOOT.TXT").unwrap();
+        file.write_all(b"hello world").unwrap();
+        file.seek(SeekFrom::Start(5)).unwrap();
+        file.truncate().unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"!").unwrap();
+        drop(file);
+
+        // `create_file` keeps the content of an existing file.
+        let mut file = logs.create_file("boot.txt").unwrap();
+        let mut text = String::new();
+        file.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello!");
+        assert_eq!(file.seek(SeekFrom::Current(-1)).unwrap(), 5);
+        assert_eq!(file.seek(SeekFrom::Current(-6)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        drop(file);
+
+        logs.rename("BOOT.TXT", &logs, "OLD.TXT").unwrap();
+        let moved = root.rename("LOGS/OLD.TXT", &root, "OLD.TXT").unwrap_err();
+        assert_eq!(moved.kind(), io::ErrorKind::Unsupported);
+        let entries: Vec<_> = logs.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].file_name().as_str(), entries[0].len()), ("OLD.TXT", 6));
+        assert!(entries[0].is_file());
+        let mut data = Vec::new();
+        entries[0].to_file().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello!");
+
+        assert_eq!(root.open_file("LOGS").err().map(|e| e.kind()), Some(io::ErrorKind::IsADirectory));
+        assert_eq!(root.remove("LOGS").unwrap_err().kind(), io::ErrorKind::DirectoryNotEmpty);
+        logs.remove("OLD.TXT").unwrap();
+        root.remove("LOGS").unwrap();
+        assert_eq!(root.iter().count(), 0);
+        let stats = fs.stats().unwrap();
+        assert_eq!(stats.free_clusters() + 1, stats.total_clusters());
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn stream_errors_keep_their_kind() {
+        struct NoSpace;
+        impl Read for NoSpace {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+        impl Write for NoSpace {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from_raw_os_error(28))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for NoSpace {
+            fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+                Ok(0)
+            }
+        }
+
+        let mut dev = StreamDevice { io: RefCell::new(NoSpace) };
+        let mut buf = [0u8; 512];
+        assert_eq!(dev.read_sector(0, &mut buf), Err(Error::UnexpectedEof));
+        let e = dev.write_sector(0, &buf).unwrap_err();
+        assert_eq!(io::Error::from(e).raw_os_error(), Some(28));
+    }
+}
@@ -0,0 +1,163 @@
+//! Fault-injecting `BlockDevice` wrapper (`test-util` feature).
+//!
+//! Lets tests exercise error handling and crash safety: fail a given read or
+//! write, corrupt the data of a given read, or simulate a power cut after
+//! which writes are acknowledged but never stored.
+
+use core::cell::Cell;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+/// A `BlockDevice` that misbehaves on command.
+///
+/// Reads and writes are counted from 1; configure faults with the builder methods.
+pub struct FaultDevice<D: BlockDevice> {
+    inner: D,
+    reads: Cell<u64>,
+    writes: u64,
+    fail_read: Option<u64>,
+    fail_write: Option<u64>,
+    corrupt_read: Option<u64>,
+    power_cut_after: Option<u64>,
+}
+
+impl<D: BlockDevice> FaultDevice<D> {
+    /// Wrap `inner` with no faults configured.
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            reads: Cell::new(0),
+            writes: 0,
+            fail_read: None,
+            fail_write: None,
+            corrupt_read: None,
+            power_cut_after: None,
+        }
+    }
+
+    /// Fail the `n`-th read with `Error::Io`.
+    pub fn fail_read(mut self, n: u64) -> Self {
+        self.fail_read = Some(n);
+        self
+    }
+
+    /// Fail the `n`-th write with `Error::Io` (nothing is stored).
+    pub fn fail_write(mut self, n: u64) -> Self {
+        self.fail_write = Some(n);
+        self
+    }
+
+    /// Flip every bit of the data returned by the `n`-th read.
+    pub fn corrupt_read(mut self, n: u64) -> Self {
+        self.corrupt_read = Some(n);
+        self
+    }
+
+    /// Store only the first `n` writes; later writes (and flushes) report
+    /// success but are dropped, as if power was lost right after write `n`.
+    pub fn power_cut_after(mut self, n: u64) -> Self {
+        self.power_cut_after = Some(n);
+        self
+    }
+
+    /// Reads performed so far.
+    pub fn reads(&self) -> u64 {
+        self.reads.get()
+    }
+
+    /// Writes attempted so far.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// True once writes are being dropped.
+    pub fn power_lost(&self) -> bool {
+        self.power_cut_after.is_some_and(|n| self.writes > n)
+    }
+
+    /// Borrow the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the wrapped device, holding only what was persisted.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for FaultDevice<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        let n = self.reads.get() + 1;
+        self.reads.set(n);
+        if self.fail_read == Some(n) {
+            return Err(Error::Io);
+        }
+        self.inner.read_sector(lba, buf)?;
+        if self.corrupt_read == Some(n) {
+            buf.iter_mut().for_each(|b| *b = !*b);
+        }
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.writes += 1;
+        if self.fail_write == Some(self.writes) {
+            return Err(Error::Io);
+        }
+        if self.power_lost() {
+            return Ok(());
+        }
+        self.inner.write_sector(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.power_lost() {
+            return Ok(());
+        }
+        self.inner.flush()
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        if self.power_lost() {
+            return Ok(());
+        }
+        self.inner.trim(lba, count)
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.inner.is_write_protected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+
+    #[test]
+    fn injected_faults() {
+        let dev = FaultDevice::new(MemDevice::new(make_tiny_fat32_image())).fail_read(1);
+        assert_eq!(Fat32::mount(dev).err(), Some(Error::Io));
+
+        let dev = FaultDevice::new(MemDevice::new(make_tiny_fat32_image())).corrupt_read(1);
+        assert_eq!(Fat32::mount(dev).err(), Some(Error::InvalidBootSector));
+
+        let dev = FaultDevice::new(MemDevice::new(make_tiny_fat32_image())).fail_write(3);
+        let mut fs = Fat32::mount(dev).expect("mount");
+        assert_eq!(fs.write_file_root("A.TXT", b"a"), Err(Error::Io));
+
+        let dev = FaultDevice::new(MemDevice::new(make_tiny_fat32_image())).power_cut_after(0);
+        let mut fs = Fat32::mount(dev).expect("mount");
+        fs.write_file_root("A.TXT", b"a").expect("acknowledged");
+        assert!(fs.read_file_root("A.TXT").is_err());
+        assert!(fs.into_device().power_lost());
+    }
+}
@@ -0,0 +1,863 @@
+//! Open file handles with a read/write position (root directory, 8.3 names).
+//!
+//! `Fat32::open_file_root` returns a `File` borrowing the filesystem. To keep
+//! several files open at once, open them into the filesystem's open-file table
+//! with `Fat32::open_handle_root` and borrow a `File` view with `Fat32::file`
+//! whenever one is accessed.
+
+#[cfg(feature = "alloc")]
+use crate::device::AlignedBuf;
+use crate::device::{is_aligned, BlockDevice};
+use crate::dir::{nt_case_flags, DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY};
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, EOC_MIN};
+use crate::fs::Fat32;
+use crate::options::{CreateOptions, OpenMode};
+
+/// Index of an open file in the filesystem's open-file table.
+///
+/// Valid until `Fat32::close_handle`; the slot may then be reused by another file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle(usize);
+
+/// State of an open file, kept in the open-file table between accesses.
+#[derive(Clone)]
+pub(crate) struct OpenFile {
+    /// Sector and slot of the file's directory entry.
+    entry_lba: u64,
+    entry_slot: usize,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// Cached position in the chain: `cur_cluster` is the `cur_index`-th cluster (0 = none yet).
+    cur_cluster: u32,
+    cur_index: u32,
+    /// Data, size or first cluster changed since the entry was last written.
+    entry_dirty: bool,
+    /// The entry has the archive attribute; otherwise the first write marks
+    /// the entry dirty so that `flush` sets it.
+    archive: bool,
+    /// The entry has the read-only attribute: `write` and `truncate` fail.
+    read_only: bool,
+    /// Kept with the rest of the state so that handle views share it.
+    ahead: ReadAhead,
+}
+
+impl OpenFile {
+    fn new(entry_lba: u64, entry_slot: usize, first_cluster: u32, size: u32) -> Self {
+        Self {
+            entry_lba,
+            entry_slot,
+            first_cluster,
+            size,
+            pos: 0,
+            cur_cluster: 0,
+            cur_index: 0,
+            entry_dirty: false,
+            archive: true,
+            read_only: false,
+            ahead: ReadAhead::default(),
+        }
+    }
+
+    /// The state, moving the read-ahead buffer out of `self`.
+    #[cfg(feature = "alloc")]
+    fn take(&mut self) -> Self {
+        let ahead = core::mem::take(&mut self.ahead);
+        Self { ahead, ..self.clone() }
+    }
+
+    /// True if this file's directory entry is at (`lba`, `slot`).
+    #[cfg(feature = "alloc")]
+    pub(crate) fn is_entry(&self, lba: u64, slot: usize) -> bool {
+        self.entry_lba == lba && self.entry_slot == slot
+    }
+}
+
+/// An open file borrowing the filesystem.
+///
+/// Size and first cluster changes are written to the directory entry on
+/// `flush`, `close` or drop (where errors are ignored, so prefer `close`).
+/// A view returned by `Fat32::file` instead stores its state back into the
+/// open-file table on drop; its entry is written by `flush`, `Fat32::close_handle`
+/// or `Fat32::flush`.
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut Fat32<D>,
+    st: OpenFile,
+    #[cfg_attr(not(feature = "alloc"), allow(dead_code))]
+    handle: Option<FileHandle>,
+}
+
+/// Sectors prefetched by a file read sequentially (`MountOptions::read_ahead`).
+#[derive(Default)]
+struct ReadAhead {
+    /// Position right after the previous read.
+    next_pos: Option<u32>,
+    /// First sector held in `buf`.
+    #[cfg(feature = "alloc")]
+    lba: u64,
+    #[cfg(feature = "alloc")]
+    buf: AlignedBuf,
+}
+
+/// Clones start empty; the buffer is only a cache.
+impl Clone for ReadAhead {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl ReadAhead {
+    #[cfg(feature = "alloc")]
+    fn get(&self, lba: u64) -> Option<&[u8]> {
+        let i = usize::try_from(lba.checked_sub(self.lba)?).ok()?;
+        self.buf.get(i * 512..i * 512 + 512)
+    }
+
+    /// Without `alloc` nothing is ever prefetched.
+    #[cfg(not(feature = "alloc"))]
+    fn get(&self, _lba: u64) -> Option<&[u8]> {
+        None
+    }
+
+    fn clear(&mut self) {
+        #[cfg(feature = "alloc")]
+        self.buf.clear();
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Open an existing root file for reading and writing, positioned at the start.
+    pub fn open_file_root(&mut self, name: &str) -> Result<File<'_, D>> {
+        let st = self.open_root_state(name)?;
+        Ok(File::new(self, st, None))
+    }
+
+    /// Create an empty root file, truncating it if it already exists.
+    pub fn create_file_root(&mut self, name: &str) -> Result<File<'_, D>> {
+        self.create_file_root_with(name, &CreateOptions::default())
+    }
+
+    /// Like `create_file_root`, giving a newly created file the attributes in `options`.
+    pub fn create_file_root_with(&mut self, name: &str, options: &CreateOptions) -> Result<File<'_, D>> {
+        let st = self.create_state(self.bpb.root_cluster, name, options)?;
+        Ok(File::new(self, st, None))
+    }
+
+    /// Open an existing root file into the open-file table.
+    ///
+    /// Fails with `AlreadyOpen` if the file already has a handle.
+    #[cfg(feature = "alloc")]
+    pub fn open_handle_root(&mut self, name: &str) -> Result<FileHandle> {
+        let st = self.open_root_state(name)?;
+        Ok(self.insert_open_file(st))
+    }
+
+    /// Create (or truncate) a root file and add it to the open-file table.
+    #[cfg(feature = "alloc")]
+    pub fn create_handle_root(&mut self, name: &str) -> Result<FileHandle> {
+        let st = self.create_state(self.bpb.root_cluster, name, &CreateOptions::default())?;
+        Ok(self.insert_open_file(st))
+    }
+
+    /// Borrow the open file `handle` for reading, writing or seeking.
+    #[cfg(feature = "alloc")]
+    pub fn file(&mut self, handle: FileHandle) -> Result<File<'_, D>> {
+        let st = self
+            .open_files
+            .get_mut(handle.0)
+            .and_then(Option::as_mut)
+            .ok_or(Error::NotFound)?
+            .take();
+        Ok(File::new(self, st, Some(handle)))
+    }
+
+    /// Write the directory entry of `handle` if needed and remove it from the table.
+    #[cfg(feature = "alloc")]
+    pub fn close_handle(&mut self, handle: FileHandle) -> Result<()> {
+        let st = self
+            .open_files
+            .get_mut(handle.0)
+            .and_then(Option::take)
+            .ok_or(Error::NotFound)?;
+        if st.entry_dirty {
+            self.update_dir_entry(st.entry_lba, st.entry_slot, st.first_cluster, st.size)?;
+        }
+        Ok(())
+    }
+
+    /// Write the directory entries of every open handle whose size or chain changed.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn flush_open_files(&mut self) -> Result<()> {
+        for i in 0..self.open_files.len() {
+            let Some(st) = &self.open_files[i] else {
+                continue;
+            };
+            if st.entry_dirty {
+                let (lba, slot, first, size) = (st.entry_lba, st.entry_slot, st.first_cluster, st.size);
+                self.update_dir_entry(lba, slot, first, size)?;
+                if let Some(st) = &mut self.open_files[i] {
+                    st.entry_dirty = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "alloc")]
+    fn insert_open_file(&mut self, st: OpenFile) -> FileHandle {
+        match self.open_files.iter().position(Option::is_none) {
+            Some(i) => {
+                self.open_files[i] = Some(st);
+                FileHandle(i)
+            }
+            None => {
+                self.open_files.push(Some(st));
+                FileHandle(self.open_files.len() - 1)
+            }
+        }
+    }
+
+    /// Fail with `AlreadyOpen` if the entry at (`lba`, `slot`) has a handle.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn check_not_open(&self, lba: u64, slot: usize) -> Result<()> {
+        if self.open_files.iter().flatten().any(|st| st.is_entry(lba, slot)) {
+            return Err(Error::AlreadyOpen);
+        }
+        Ok(())
+    }
+
+    /// Without `alloc` there is no open-file table to check.
+    #[cfg(not(feature = "alloc"))]
+    pub(crate) fn check_not_open(&self, _lba: u64, _slot: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Open file `name` in the directory at `dir_cluster` as `mode` prescribes, into the open-file table.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn open_handle_in(&mut self, dir_cluster: u32, name: &str, mode: OpenMode) -> Result<FileHandle> {
+        let st = self.open_state_with(dir_cluster, name, mode)?;
+        Ok(self.insert_open_file(st))
+    }
+
+    /// Open root file `name` as `mode` prescribes.
+    pub fn open_file_root_with(&mut self, name: &str, mode: OpenMode) -> Result<File<'_, D>> {
+        let st = self.open_state_with(self.bpb.root_cluster, name, mode)?;
+        Ok(File::new(self, st, None))
+    }
+
+    /// Open file `name` in the directory at `dir_cluster` as `mode` prescribes.
+    pub(crate) fn open_state_with(&mut self, dir_cluster: u32, name: &str, mode: OpenMode) -> Result<OpenFile> {
+        let exists = self.find_entry(dir_cluster, &self.short_name(name)?)?.is_some();
+        match mode {
+            OpenMode::Open => self.open_state(dir_cluster, name),
+            OpenMode::CreateNew if exists => Err(Error::AlreadyExists),
+            OpenMode::CreateNew | OpenMode::Truncate => self.create_state(dir_cluster, name, &CreateOptions::default()),
+            OpenMode::Append if exists => {
+                let st = self.open_state(dir_cluster, name)?;
+                Ok(OpenFile { pos: st.size, ..st })
+            }
+            OpenMode::Append => self.create_state(dir_cluster, name, &CreateOptions::default()),
+        }
+    }
+
+    fn open_root_state(&mut self, name: &str) -> Result<OpenFile> {
+        self.open_state(self.bpb.root_cluster, name)
+    }
+
+    /// Look up file `name` in the directory at `dir_cluster` for opening.
+    pub(crate) fn open_state(&mut self, dir_cluster: u32, name: &str) -> Result<OpenFile> {
+        let short = self.short_name(name)?;
+        let (e, lba, slot) = self.find_entry(dir_cluster, &short)?.ok_or(Error::NotFound)?;
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        self.check_not_open(lba, slot)?;
+        let read_only = self.check_not_read_only(&e).is_err();
+        Ok(OpenFile {
+            archive: e.attr & ATTR_ARCHIVE != 0,
+            read_only,
+            ..OpenFile::new(lba, slot, e.first_cluster, e.file_size)
+        })
+    }
+
+    /// Create (or truncate) file `name` in the directory at `dir_cluster` for
+    /// opening; `options` only applies if the entry is new.
+    pub(crate) fn create_state(&mut self, dir_cluster: u32, name: &str, options: &CreateOptions) -> Result<OpenFile> {
+        self.atomic(|fs| fs.create_state_inner(dir_cluster, name, options))
+    }
+
+    fn create_state_inner(&mut self, dir_cluster: u32, name: &str, options: &CreateOptions) -> Result<OpenFile> {
+        let short = self.short_name(name)?;
+        self.mark_dirty()?;
+        let (lba, slot) = match self.find_entry(dir_cluster, &short)? {
+            Some((e, _, _)) if e.attr & ATTR_DIRECTORY != 0 => return Err(Error::IsADirectory),
+            Some((e, lba, slot)) => {
+                self.check_not_read_only(&e)?;
+                self.check_not_open(lba, slot)?;
+                // Detach the chain from the entry before freeing it.
+                self.update_dir_entry(lba, slot, 0, 0)?;
+                if e.first_cluster != 0 {
+                    self.free_chain(e.first_cluster)?;
+                }
+                (lba, slot)
+            }
+            None => {
+                let mut rec = DirEntry::build_short_entry(short, options.attr(), 0, 0);
+                rec[12] = nt_case_flags(name);
+                self.write_dir_entry_first_free(dir_cluster, &rec)?
+            }
+        };
+        Ok(OpenFile::new(lba, slot, 0, 0))
+    }
+}
+
+impl<'a, D: BlockDevice> File<'a, D> {
+    pub(crate) fn new(fs: &'a mut Fat32<D>, st: OpenFile, handle: Option<FileHandle>) -> Self {
+        Self { fs, st, handle }
+    }
+
+    /// File size in bytes.
+    pub fn len(&self) -> u32 {
+        self.st.size
+    }
+
+    /// True if the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.st.size == 0
+    }
+
+    /// Current read/write position.
+    pub fn position(&self) -> u32 {
+        self.st.pos
+    }
+
+    /// Move to absolute position `pos`; positions past the end are allowed and
+    /// zero-filled by the next write.
+    pub fn seek(&mut self, pos: u32) -> Result<u32> {
+        self.st.pos = pos;
+        Ok(pos)
+    }
+
+    /// Read up to `buf.len()` bytes at the current position; returns 0 at end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.st.pos >= self.st.size {
+            return Ok(0);
+        }
+        let n = buf.len().min((self.st.size - self.st.pos) as usize);
+        let sequential = self.fs.options().read_ahead && self.st.ahead.next_pos == Some(self.st.pos);
+        let mut done = 0;
+        while done < n {
+            let (lba, off) = self.locate(false)?;
+            // Whole clusters go straight into `buf` when it has the device's alignment.
+            let sectors = self.sectors_to_cluster_end(lba)?;
+            let whole = ((n - done) / 512) as u64;
+            if off == 0
+                && whole >= sectors
+                && self.st.ahead.get(lba).is_none()
+                && is_aligned(&buf[done..], self.fs.dev.alignment())
+            {
+                let len = self.contiguous_sectors(sectors, whole)? as usize * 512;
+                self.fs.read_sectors(Operation::ReadData, lba, &mut buf[done..done + len])?;
+                done += len;
+                self.st.pos += len as u32;
+                continue;
+            }
+            if sequential && self.st.ahead.get(lba).is_none() {
+                self.prefetch(lba, off)?;
+            }
+            let take = (512 - off).min(n - done);
+            match self.st.ahead.get(lba) {
+                Some(sector) => buf[done..done + take].copy_from_slice(&sector[off..off + take]),
+                None => {
+                    let mut sector = [0u8; 512];
+                    self.fs.read_sector(Operation::ReadData, lba, &mut sector)?;
+                    buf[done..done + take].copy_from_slice(&sector[off..off + take]);
+                }
+            }
+            done += take;
+            self.st.pos += take as u32;
+        }
+        self.st.ahead.next_pos = Some(self.st.pos);
+        Ok(n)
+    }
+
+    /// Fill the read-ahead buffer from `lba` (the sector holding the current
+    /// position, at offset `off`) to the end of its cluster, or of the next
+    /// cluster if that follows on disk, without going past the end of the file.
+    #[cfg(feature = "alloc")]
+    fn prefetch(&mut self, lba: u64, off: usize) -> Result<()> {
+        let spc = self.fs.bpb.sectors_per_cluster as u64;
+        let cluster = self.st.cur_cluster;
+        let mut end = cluster_to_lba(&self.fs.bpb, cluster)? + spc;
+        if self.fs.read_fat(cluster)? == cluster + 1 {
+            end += spc;
+        }
+        let left = (self.st.size - (self.st.pos - off as u32)).div_ceil(512) as u64;
+        let sectors = (end - lba).min(left) as usize;
+        self.st.ahead.buf.resize(sectors * 512, self.fs.dev.alignment());
+        if let Err(e) = self.fs.read_sectors(Operation::ReadData, lba, &mut self.st.ahead.buf) {
+            self.st.ahead.clear();
+            return Err(e);
+        }
+        self.st.ahead.lba = lba;
+        Ok(())
+    }
+
+    /// `mount_with` rejects `read_ahead` without `alloc`, so this is never reached.
+    #[cfg(not(feature = "alloc"))]
+    fn prefetch(&mut self, _lba: u64, _off: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write `buf` at the current position, growing the file as needed.
+    ///
+    /// Fails with `ReadOnlyFile` if the entry had the read-only attribute when opened.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.st.read_only {
+            return Err(Error::ReadOnlyFile);
+        }
+        self.fs.mark_dirty()?;
+        self.st.ahead.clear();
+        if !self.st.archive {
+            self.st.archive = true;
+            self.st.entry_dirty = true;
+        }
+        if self.st.pos.checked_add(buf.len() as u32).is_none() || buf.len() > u32::MAX as usize {
+            return Err(Error::NoSpace);
+        }
+        // Zero-fill a gap left by seeking past the end.
+        while self.st.pos > self.st.size {
+            let target = self.st.pos;
+            self.st.pos = self.st.size;
+            let zeros = [0u8; 512];
+            let take = ((target - self.st.size) as usize).min(512);
+            self.write_here(&zeros[..take])?;
+            self.st.pos = target;
+        }
+        self.write_here(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Cut the file off at the current position, freeing the clusters past it.
+    ///
+    /// Does nothing at or past the end. The shorter entry is written before
+    /// the clusters are freed, so an interrupted call only leaves a lost chain.
+    pub fn truncate(&mut self) -> Result<()> {
+        if self.st.pos >= self.st.size {
+            return Ok(());
+        }
+        if self.st.read_only {
+            return Err(Error::ReadOnlyFile);
+        }
+        self.fs.mark_dirty()?;
+        self.st.ahead.clear();
+        let cluster_bytes = self.fs.bpb.sectors_per_cluster as u32 * 512;
+        let keep = self.st.pos.div_ceil(cluster_bytes);
+        let cut = match keep {
+            0 => {
+                let first = self.st.first_cluster;
+                self.st.first_cluster = 0;
+                self.st.cur_cluster = 0;
+                self.st.cur_index = 0;
+                (first, None)
+            }
+            _ => {
+                let last = self.cluster_at(keep - 1, false)?;
+                (self.fs.read_fat(last)?, Some(last))
+            }
+        };
+        self.st.size = self.st.pos;
+        self.st.entry_dirty = true;
+        self.flush()?;
+        match cut {
+            (rest, last) if (2..EOC_MIN).contains(&rest) => self.fs.atomic(|fs| {
+                if let Some(last) = last {
+                    fs.write_fat(last, 0x0FFF_FFFF)?;
+                }
+                fs.free_chain(rest)
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the directory entry if the size or first cluster changed.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.st.entry_dirty {
+            self.fs
+                .update_dir_entry(self.st.entry_lba, self.st.entry_slot, self.st.first_cluster, self.st.size)?;
+            self.st.entry_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Flush and close the file (a view from `Fat32::file` keeps its handle open).
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Write `data` at `pos` (which must not be past the end).
+    fn write_here(&mut self, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let (lba, off) = self.locate(true)?;
+            let whole = ((data.len() - done) / 512) as u64;
+            let take = if off == 0 && whole > 0 {
+                // Whole sectors are written from `data` itself.
+                let len = whole.min(self.sectors_to_cluster_end(lba)?) as usize * 512;
+                self.fs.write_sectors_direct(Operation::WriteData, lba, &data[done..done + len])?;
+                len
+            } else {
+                self.write_partial(lba, off, &data[done..])?
+            };
+            done += take;
+            self.st.pos += take as u32;
+            if self.st.pos > self.st.size {
+                self.st.size = self.st.pos;
+                self.st.entry_dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the part of `data` that falls in sector `lba` from offset `off`;
+    /// returns the number of bytes written.
+    fn write_partial(&mut self, lba: u64, off: usize, data: &[u8]) -> Result<usize> {
+        let take = (512 - off).min(data.len());
+        let mut sector = [0u8; 512];
+        // Keep existing bytes of a partially overwritten sector; sectors past
+        // the end may hold stale data and start from zeros instead.
+        let sector_start = self.st.pos - off as u32;
+        if take < 512 && sector_start < self.st.size {
+            self.fs.read_sector(Operation::ReadData, lba, &mut sector)?;
+        }
+        sector[off..off + take].copy_from_slice(&data[..take]);
+        self.fs.write_sector(Operation::WriteData, lba, &sector)?;
+        Ok(take)
+    }
+
+    /// Sectors from `lba`, in the current cluster, to the end of that cluster.
+    fn sectors_to_cluster_end(&self, lba: u64) -> Result<u64> {
+        let spc = self.fs.bpb.sectors_per_cluster as u64;
+        Ok(cluster_to_lba(&self.fs.bpb, self.st.cur_cluster)? + spc - lba)
+    }
+
+    /// Extend a run of `sectors` ending the current cluster over the clusters
+    /// that follow it on disk, up to `max` sectors.
+    fn contiguous_sectors(&mut self, mut sectors: u64, max: u64) -> Result<u64> {
+        let spc = self.fs.bpb.sectors_per_cluster as u64;
+        let mut cluster = self.st.cur_cluster;
+        while sectors + spc <= max && self.fs.read_fat(cluster)? == cluster + 1 {
+            cluster += 1;
+            sectors += spc;
+        }
+        Ok(sectors)
+    }
+
+    /// Sector LBA and offset within it for the current position,
+    /// allocating clusters when `allocate` is set.
+    fn locate(&mut self, allocate: bool) -> Result<(u64, usize)> {
+        let cluster_bytes = (self.fs.bpb.sectors_per_cluster as u32) * 512;
+        let index = self.st.pos / cluster_bytes;
+        let cluster = self.cluster_at(index, allocate)?;
+        let in_cluster = self.st.pos % cluster_bytes;
+        let lba = cluster_to_lba(&self.fs.bpb, cluster)? + (in_cluster / 512) as u64;
+        Ok((lba, (in_cluster % 512) as usize))
+    }
+
+    /// Return the `index`-th cluster of the chain, walking from the cached position.
+    fn cluster_at(&mut self, index: u32, allocate: bool) -> Result<u32> {
+        if self.st.first_cluster == 0 {
+            if !allocate {
+                return Err(Error::UnexpectedEof);
+            }
+            self.st.first_cluster = self.fs.alloc_cluster(None)?;
+            self.st.entry_dirty = true;
+        }
+        if self.st.cur_cluster == 0 || index < self.st.cur_index {
+            self.st.cur_cluster = self.st.first_cluster;
+            self.st.cur_index = 0;
+        }
+        while self.st.cur_index < index {
+            let next = self.fs.read_fat(self.st.cur_cluster)?;
+            let next = if next >= EOC_MIN {
+                if !allocate {
+                    return Err(self.fs.record(
+                        Operation::ReadData,
+                        None,
+                        Some(self.st.cur_cluster),
+                        Error::UnexpectedEof,
+                    ));
+                }
+                self.fs.alloc_cluster(Some(self.st.cur_cluster))?
+            } else if next < 2 {
+                return Err(self.fs.record(Operation::ReadData, None, Some(self.st.cur_cluster), Error::Corrupt));
+            } else {
+                next
+            };
+            self.st.cur_cluster = next;
+            self.st.cur_index += 1;
+        }
+        Ok(self.st.cur_cluster)
+    }
+}
+
+impl<D: BlockDevice> Drop for File<'_, D> {
+    fn drop(&mut self) {
+        #[cfg(feature = "alloc")]
+        if let Some(h) = self.handle {
+            self.fs.open_files[h.0] = Some(self.st.take());
+            return;
+        }
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    #[cfg(feature = "alloc")]
+    use crate::options::MountOptions;
+    #[cfg(feature = "alloc")]
+    use alloc::vec::Vec;
+
+    #[test]
+    fn files_work_without_alloc() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let mut f = fs.create_file_root("LOG.TXT").expect("create");
+        for _ in 0..100 {
+            f.write(b"0123456789").expect("write");
+        }
+        f.seek(3).unwrap();
+        f.write(b"xx").unwrap();
+        f.close().expect("close");
+
+        let mut f = fs.open_file_root("LOG.TXT").expect("open");
+        let mut tail = [0u8; 8];
+        f.seek(995).unwrap();
+        assert_eq!(f.read(&mut tail), Ok(5));
+        assert_eq!(&tail[..5], b"56789");
+        f.close().unwrap();
+
+        let mut buf = [0u8; 1024];
+        assert_eq!(fs.read_file_into("LOG.TXT", &mut buf), Ok(1000));
+        assert_eq!(&buf[..10], b"012xx56789");
+        assert_eq!(fs.read_file_into("LOG.TXT", &mut buf[..4]), Ok(4));
+        assert_eq!(fs.read_file_into("NONE.TXT", &mut buf), Err(Error::NotFound));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn write_seek_and_read_back() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let mut f = fs.create_file_root("LOG.TXT").expect("create");
+        for _ in 0..300 {
+            f.write(b"0123456789").expect("write");
+        }
+        f.seek(5).unwrap();
+        f.write(b"xx").unwrap();
+        f.close().expect("close");
+
+        let data = fs.read_file_root("LOG.TXT").expect("read");
+        assert_eq!(data.len(), 3000);
+        assert_eq!(&data[..10], b"01234xx789");
+
+        let mut f = fs.open_file_root("LOG.TXT").expect("open");
+        let mut buf = [0u8; 4];
+        f.seek(2998).unwrap();
+        assert_eq!(f.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"89");
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn open_modes() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        assert_eq!(fs.open_file_root_with("A.TXT", OpenMode::Open).err(), Some(Error::NotFound));
+        fs.open_file_root_with("A.TXT", OpenMode::CreateNew).unwrap().write(b"one").unwrap();
+        assert_eq!(fs.open_file_root_with("A.TXT", OpenMode::CreateNew).err(), Some(Error::AlreadyExists));
+
+        let mut f = fs.open_file_root_with("A.TXT", OpenMode::Append).unwrap();
+        assert_eq!(f.position(), 3);
+        f.write(b"two").unwrap();
+        f.close().unwrap();
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"onetwo");
+
+        fs.open_file_root_with("A.TXT", OpenMode::Truncate).unwrap().write(b"3").unwrap();
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"3");
+        fs.open_file_root_with("B.TXT", OpenMode::Append).unwrap().write(b"b").unwrap();
+        assert_eq!(fs.read_file_root("B.TXT").unwrap(), b"b");
+
+        // Overwriting replaces the file instead of adding a second entry.
+        fs.write_file_root("A.TXT", &[4u8; 700]).unwrap();
+        assert_eq!(fs.list_root().unwrap().len(), 2);
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), [4u8; 700]);
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn truncate_at_position() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let free = fs.stats().unwrap().free_clusters;
+        let mut f = fs.create_file_root("A.BIN").unwrap();
+        f.write(&[7u8; 3000]).unwrap();
+        f.seek(1000).unwrap();
+        f.truncate().unwrap();
+        assert_eq!(f.len(), 1000);
+        f.seek(5000).unwrap();
+        f.truncate().unwrap();
+        assert_eq!(f.len(), 1000);
+        f.close().unwrap();
+        assert_eq!(fs.read_file_root("A.BIN").unwrap(), [7u8; 1000]);
+        assert_eq!(fs.stats().unwrap().free_clusters, free - 2);
+
+        let mut f = fs.open_file_root("A.BIN").unwrap();
+        f.truncate().unwrap();
+        f.write(b"new").unwrap();
+        f.close().unwrap();
+        assert_eq!(fs.read_file_root("A.BIN").unwrap(), b"new");
+        assert_eq!(fs.stats().unwrap().free_clusters, free - 1);
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn two_handles_open_at_once() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let data = fs.create_handle_root("DATA.BIN").expect("create data");
+        let index = fs.create_handle_root("INDEX.BIN").expect("create index");
+        assert_eq!(fs.open_handle_root("DATA.BIN"), Err(Error::AlreadyOpen));
+
+        for i in 0..100u8 {
+            fs.file(data).unwrap().write(&[i; 16]).unwrap();
+            let pos = fs.file(data).unwrap().position();
+            fs.file(index).unwrap().write(&pos.to_le_bytes()).unwrap();
+        }
+        fs.flush().unwrap();
+        assert_eq!(fs.read_file_root("DATA.BIN").unwrap().len(), 1600);
+
+        fs.close_handle(data).unwrap();
+        fs.close_handle(index).unwrap();
+        assert_eq!(fs.file(data).err(), Some(Error::NotFound));
+        let idx = fs.read_file_root("INDEX.BIN").unwrap();
+        assert_eq!(&idx[396..], &1600u32.to_le_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn sequential_reads_prefetch_clusters() {
+        /// Records the data-region reads as (lba, sectors).
+        struct Reads(MemDevice, core::cell::RefCell<Vec<(u64, usize)>>);
+        impl BlockDevice for Reads {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.read_sectors(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                self.0.write_sector(lba, buf)
+            }
+            fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+                self.1.borrow_mut().push((lba, buf.len() / 512));
+                self.0.read_sectors(lba, buf)
+            }
+        }
+
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        let img = crate::image::ImageBuilder::new(4096)
+            .sectors_per_cluster(4)
+            .file("SONG.RAW", &content)
+            .build()
+            .unwrap();
+        let options = MountOptions { read_ahead: true, ..MountOptions::default() };
+        let mut fs = Fat32::mount_with(Reads(MemDevice::new(img), Default::default()), options).expect("mount");
+        let first = cluster_to_lba(fs.bpb(), 3).unwrap();
+        let mut f = fs.open_file_root("SONG.RAW").unwrap();
+        f.fs.dev.1.borrow_mut().clear();
+        let mut out = Vec::new();
+        let mut buf = [0u8; 100];
+        loop {
+            let n = f.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, content);
+        let data: Vec<_> = f.fs.dev.1.borrow().iter().copied().filter(|&(lba, _)| lba >= first).collect();
+        // One sector for the first read, then clusters 3-4 and the two used sectors of cluster 5.
+        assert_eq!(data, [(first, 1), (first, 8), (first + 8, 2)]);
+        drop(f);
+
+        // A handle keeps its read-ahead between views.
+        let h = fs.open_handle_root("SONG.RAW").unwrap();
+        fs.dev.1.borrow_mut().clear();
+        out.clear();
+        while let n @ 1.. = fs.file(h).unwrap().read(&mut buf).unwrap() {
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, content);
+        let data: Vec<_> = fs.dev.1.borrow().iter().copied().filter(|&(lba, _)| lba >= first).collect();
+        assert_eq!(data, [(first, 1), (first, 8), (first + 8, 2)]);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn dma_aligned_transfers() {
+        /// Rejects buffers without 32-byte alignment, like a DMA engine would,
+        /// and records the sizes of multi-sector reads.
+        struct Dma(MemDevice, core::cell::RefCell<Vec<usize>>);
+        impl BlockDevice for Dma {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.read_sectors(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                self.write_sectors(lba, buf)
+            }
+            fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+                assert!(is_aligned(buf, 32), "misaligned read of sector {}", lba);
+                if buf.len() > 512 {
+                    self.1.borrow_mut().push(buf.len());
+                }
+                self.0.read_sectors(lba, buf)
+            }
+            fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+                assert!(is_aligned(buf, 32), "misaligned write of sector {}", lba);
+                self.0.write_sectors(lba, buf)
+            }
+            fn alignment(&self) -> usize {
+                32
+            }
+        }
+
+        let img = crate::image::ImageBuilder::new(4096).sectors_per_cluster(4).build().unwrap();
+        let mut fs = Fat32::mount(Dma(MemDevice::new(img), Default::default())).expect("mount");
+        let mut content = AlignedBuf::new(4 * 2048 + 1, 32);
+        content.iter_mut().enumerate().for_each(|(i, b)| *b = (i % 251) as u8);
+        let mut f = fs.create_file_root("DMA.BIN").unwrap();
+        f.write(&content[1..]).unwrap(); // misaligned: bounced
+        f.seek(0).unwrap();
+        f.write(&content[..2048]).unwrap(); // aligned: written in place
+        f.close().unwrap();
+        fs.write_file_root("COPY.BIN", &content[1..]).unwrap();
+
+        let mut f = fs.open_file_root("DMA.BIN").unwrap();
+        f.fs.dev.1.borrow_mut().clear();
+        let mut out = AlignedBuf::new(8192, 32);
+        assert_eq!(f.read(&mut out).unwrap(), 8192);
+        // Contiguous clusters are read straight into the caller's buffer.
+        assert_eq!(*f.fs.dev.1.borrow(), [8192]);
+        assert_eq!(out[..2048], content[..2048]);
+        assert_eq!(out[2048..], content[2049..]);
+        f.close().unwrap();
+
+        let mut odd = alloc::vec![0u8; 8193];
+        let mut f = fs.open_file_root("COPY.BIN").unwrap();
+        assert_eq!(f.read(&mut odd[1..]).unwrap(), 8192);
+        assert_eq!(odd[1..], content[1..]);
+    }
+}
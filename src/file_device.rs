@@ -0,0 +1,116 @@
+//! `BlockDevice` over a host file (`std` feature).
+//!
+//! Lets host tools and integration tests work directly on `.img` files
+//! produced by `mkfs.vfat` and friends.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+/// A disk image file accessed as 512-byte sectors.
+pub struct FileDevice {
+    file: File,
+    /// Opened with `open_read_only`; reported as write protection.
+    read_only: bool,
+}
+
+impl FileDevice {
+    /// Wrap an already opened file.
+    pub fn new(file: File) -> Self {
+        Self { file, read_only: false }
+    }
+
+    /// Open an existing image for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Open an existing image read-only. The device reports itself write
+    /// protected, so a `Fat32` on it fails with `WriteProtected` before writing.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            read_only: true,
+        })
+    }
+
+    /// Return the underlying file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        // `Read` and `Seek` are implemented for `&File`.
+        let mut f = &self.file;
+        f.seek(SeekFrom::Start(lba * 512))?;
+        f.read_exact(buf).map_err(Error::from)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let mut f = &self.file;
+        f.seek(SeekFrom::Start(lba * 512))?;
+        f.read_exact(buf).map_err(Error::from)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.write_sectors(lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(lba * 512))?;
+        self.file.write_all(buf).map_err(Error::from)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(Error::from)
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DeviceErrorKind;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+
+    #[test]
+    fn mount_image_file() {
+        let path = std::env::temp_dir().join(std::format!("fat32-file-device-{}.img", std::process::id()));
+        std::fs::write(&path, make_tiny_fat32_image()).unwrap();
+
+        let mut fs = Fat32::mount(FileDevice::open(&path).unwrap()).expect("mount");
+        fs.write_file_root("HOST.TXT", b"from host").expect("write");
+        fs.unmount().expect("unmount");
+
+        let mut fs = Fat32::mount(FileDevice::open_read_only(&path).unwrap()).expect("mount");
+        assert_eq!(fs.read_file_root("HOST.TXT").unwrap(), b"from host");
+        assert_eq!(fs.write_file_root("NEW.TXT", b"x"), Err(Error::WriteProtected));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn host_errors_keep_their_kind() {
+        let path = std::env::temp_dir().join(std::format!("fat32-file-device-err-{}.img", std::process::id()));
+        std::fs::write(&path, [0u8; 1024]).unwrap();
+        let mut dev = FileDevice::open_read_only(&path).unwrap();
+        let mut buf = [0u8; 512];
+        assert_eq!(dev.read_sector(2, &mut buf), Err(Error::UnexpectedEof));
+
+        // Writing through a read-only descriptor fails with EBADF.
+        let err = dev.write_sector(0, &buf).unwrap_err();
+        let Error::Device(d) = err else { panic!("{:?}", err) };
+        assert_eq!((d.kind, d.code), (DeviceErrorKind::Host, 9));
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(9));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! DOS-style wildcard search (`*` and `?`).
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+#[cfg(feature = "alloc")]
+use crate::device::BlockDevice;
+#[cfg(feature = "alloc")]
+use crate::dir::DirEntry;
+#[cfg(feature = "alloc")]
+use crate::error::Result;
+#[cfg(feature = "alloc")]
+use crate::fs::Fat32;
+#[cfg(feature = "alloc")]
+use crate::path::Path;
+
+/// True if `name` matches `pattern`, ignoring ASCII case.
+///
+/// `?` matches any one character and `*` any run of characters. As in DOS,
+/// a pattern ending in `.*` also matches names without an extension, so
+/// `*.*` matches everything.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    if glob(pattern, name) {
+        return true;
+    }
+    match pattern.strip_suffix(".*") {
+        Some(base) if !name.contains('.') => wildcard_match(base, name),
+        _ => false,
+    }
+}
+
+fn glob(p: &str, n: &str) -> bool {
+    // Iterative matcher over byte offsets that backtracks to the last `*` only.
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while let Some(nc) = n[ni..].chars().next() {
+        match p[pi..].chars().next() {
+            Some(pc) if pc == '?' || pc.eq_ignore_ascii_case(&nc) => {
+                pi += pc.len_utf8();
+                ni += nc.len_utf8();
+            }
+            Some('*') => {
+                star = Some((pi, ni));
+                pi += 1;
+            }
+            _ => {
+                let Some((sp, sn)) = star else {
+                    return false;
+                };
+                let sn = sn + n[sn..].chars().next().map_or(1, char::len_utf8);
+                pi = sp + 1;
+                ni = sn;
+                star = Some((sp, sn));
+            }
+        }
+    }
+    p[pi..].chars().all(|c| c == '*')
+}
+
+#[cfg(feature = "alloc")]
+impl<D: BlockDevice> Fat32<D> {
+    /// Entries of a directory whose names match the last component of
+    /// `path_pattern`, e.g. `"LOGS/*.LOG"` or `"DATA??.BIN"`.
+    ///
+    /// Only the last component may hold wildcards (see `wildcard_match`).
+    /// Names are the decoded 8.3 names (this crate does not read long names
+    /// yet); `.`, `..` and the volume label never match.
+    pub fn find(&self, path_pattern: &str) -> Result<Vec<DirEntry>> {
+        let (dir, pattern) = Path::new(path_pattern).split_last()?;
+        let cluster = self.resolve_dir(dir)?;
+        let mut out = self.dir_entries(cluster)?;
+        out.retain(|e| wildcard_match(pattern, &e.name()));
+        Ok(out)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn wildcard_rules() {
+        assert!(wildcard_match("*.LOG", "day1.log"));
+        assert!(wildcard_match("DATA??.BIN", "DATA01.BIN"));
+        assert!(!wildcard_match("DATA??.BIN", "DATA1.BIN"));
+        assert!(wildcard_match("*.*", "MAKEFILE"));
+        assert!(wildcard_match("READ*.*", "README"));
+        assert!(!wildcard_match("*.TXT", "TXT"));
+        assert!(wildcard_match("*A*B", "XAYAB"));
+        assert!(wildcard_match("?É*.TXT", "ÇÉÈÈ.txt"));
+        assert!(!wildcard_match("É?", "É"));
+    }
+
+    #[test]
+    fn find_in_subdirectory() {
+        let img = ImageBuilder::new(400)
+            .fats(1)
+            .file("LOGS/DAY1.LOG", b"1")
+            .file("LOGS/DAY2.LOG", b"2")
+            .file("LOGS/INDEX.TXT", b"i")
+            .dir("LOGS/OLD")
+            .build()
+            .unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let names = |p| fs.find(p).unwrap().iter().map(DirEntry::name).collect::<Vec<_>>();
+        assert_eq!(names("LOGS/*.LOG"), ["DAY1.LOG", "DAY2.LOG"]);
+        assert_eq!(names("/LOGS/*"), ["DAY1.LOG", "DAY2.LOG", "INDEX.TXT", "OLD"]);
+        assert_eq!(names("*"), ["LOGS"]);
+        assert_eq!(fs.find("NOPE/*").err(), Some(crate::error::Error::NotFound));
+    }
+}
@@ -0,0 +1,238 @@
+//! `BlockDevice` adapter for raw flash (SPI NOR and the like).
+//!
+//! Flash is programmed in pages and erased in larger blocks, and programming
+//! can only clear bits. `FlashDevice` hides that behind 512-byte sectors: it
+//! keeps the erase block being written in RAM and commits it with one erase
+//! and page programs when another block is touched or on `flush`.
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+/// A raw flash chip, addressed in bytes.
+pub trait Flash {
+    /// Bytes per program page (e.g. 256).
+    fn page_size(&self) -> usize;
+
+    /// Bytes per erase block (e.g. 4096 for a NOR sector).
+    fn erase_size(&self) -> usize;
+
+    /// Read `buf.len()` bytes at `offset`.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Program `data` at `offset`: within one page, clearing bits only.
+    fn program(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Erase the block starting at `offset` to all 0xFF.
+    fn erase(&mut self, offset: u64) -> Result<()>;
+}
+
+/// A `BlockDevice` on top of a `Flash` whose erase blocks are `BLOCK` bytes.
+///
+/// Writes to the cached block are held in RAM until `flush` (which
+/// `Fat32::sync` calls) or until a write goes to another block, so a power
+/// cut loses up to one erase block of acknowledged writes, and one cut
+/// during a commit can lose the whole block. A block is only erased when a
+/// write sets bits that are clear; other commits just program the changed
+/// pages.
+pub struct FlashDevice<F: Flash, const BLOCK: usize> {
+    flash: F,
+    /// Erase block held in `buf`, if any.
+    cached: Option<u64>,
+    dirty: bool,
+    buf: [u8; BLOCK],
+}
+
+impl<F: Flash, const BLOCK: usize> FlashDevice<F, BLOCK> {
+    /// Wrap `flash`; its erase size must be `BLOCK`, a multiple of both 512
+    /// and the page size, or this fails with `Error::InvalidInput`.
+    pub fn new(flash: F) -> Result<Self> {
+        let page = flash.page_size();
+        if flash.erase_size() != BLOCK || !BLOCK.is_multiple_of(512) || page == 0 || !BLOCK.is_multiple_of(page) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(Self {
+            flash,
+            cached: None,
+            dirty: false,
+            buf: [0xFF; BLOCK],
+        })
+    }
+
+    /// Borrow the flash chip.
+    pub fn inner(&self) -> &F {
+        &self.flash
+    }
+
+    /// Commit the cached block and return the flash chip.
+    pub fn into_inner(mut self) -> Result<F> {
+        self.commit()?;
+        Ok(self.flash)
+    }
+
+    /// Erase block and byte offset within it of sector `lba`.
+    fn locate(lba: u64) -> (u64, usize) {
+        let offset = lba * 512;
+        (offset / BLOCK as u64, (offset % BLOCK as u64) as usize)
+    }
+
+    /// Make `block` the cached one, committing the previous block first.
+    fn load(&mut self, block: u64) -> Result<()> {
+        if self.cached == Some(block) {
+            return Ok(());
+        }
+        self.commit()?;
+        self.cached = None;
+        self.flash.read(block * BLOCK as u64, &mut self.buf)?;
+        self.cached = Some(block);
+        Ok(())
+    }
+
+    /// Write the cached block back to flash if it changed.
+    fn commit(&mut self) -> Result<()> {
+        let Some(block) = self.cached.filter(|_| self.dirty) else {
+            return Ok(());
+        };
+        let base = block * BLOCK as u64;
+        let page = self.flash.page_size();
+        let mut erase = false;
+        for (i, data) in self.buf.chunks(page).enumerate() {
+            if self.compare(base + (i * page) as u64, data)?.1 {
+                erase = true;
+                break;
+            }
+        }
+        if erase {
+            self.flash.erase(base)?;
+        }
+        for (i, data) in self.buf.chunks(page).enumerate() {
+            let offset = base + (i * page) as u64;
+            let needed = if erase { data.iter().any(|&b| b != 0xFF) } else { self.compare(offset, data)?.0 };
+            if needed {
+                self.flash.program(offset, data)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Whether the flash at `offset` differs from `data`, and whether
+    /// writing `data` there needs a bit set (an erase).
+    fn compare(&self, offset: u64, data: &[u8]) -> Result<(bool, bool)> {
+        let (mut differs, mut needs_erase) = (false, false);
+        let mut old = [0u8; 64];
+        for (i, chunk) in data.chunks(old.len()).enumerate() {
+            let old = &mut old[..chunk.len()];
+            self.flash.read(offset + (i * 64) as u64, old)?;
+            differs |= old != chunk;
+            needs_erase |= old.iter().zip(chunk).any(|(&o, &n)| o & n != n);
+        }
+        Ok((differs, needs_erase))
+    }
+}
+
+impl<F: Flash, const BLOCK: usize> BlockDevice for FlashDevice<F, BLOCK> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        let (block, off) = Self::locate(lba);
+        if self.cached == Some(block) {
+            buf.copy_from_slice(&self.buf[off..off + 512]);
+            return Ok(());
+        }
+        self.flash.read(lba * 512, buf)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        let (block, off) = Self::locate(lba);
+        self.load(block)?;
+        if self.buf[off..off + 512] != *buf {
+            self.buf[off..off + 512].copy_from_slice(buf);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.commit()
+    }
+
+    /// Erases the blocks the range covers entirely.
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        let per_block = (BLOCK / 512) as u64;
+        let first = lba.div_ceil(per_block);
+        let end = (lba + count) / per_block;
+        for block in first..end {
+            if self.cached == Some(block) {
+                self.cached = None;
+                self.dirty = false;
+            }
+            self.flash.erase(block * BLOCK as u64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+    use alloc::vec::Vec;
+
+    /// NOR flash simulation with 256-byte pages and 4 KiB erase blocks.
+    struct Nor {
+        data: Vec<u8>,
+        erases: usize,
+    }
+
+    impl Flash for Nor {
+        fn page_size(&self) -> usize {
+            256
+        }
+
+        fn erase_size(&self) -> usize {
+            4096
+        }
+
+        fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let start = offset as usize;
+            buf.copy_from_slice(self.data.get(start..start + buf.len()).ok_or(Error::Io)?);
+            Ok(())
+        }
+
+        fn program(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            let start = offset as usize;
+            assert_eq!(start / 256, (start + data.len() - 1) / 256, "program crosses a page");
+            for (d, &n) in self.data[start..start + data.len()].iter_mut().zip(data) {
+                assert_eq!(*d & n, n, "program sets a bit");
+                *d = n;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, offset: u64) -> Result<()> {
+            assert_eq!(offset % 4096, 0);
+            self.data[offset as usize..offset as usize + 4096].fill(0xFF);
+            self.erases += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn filesystem_on_nor_flash() {
+        let mut data = make_tiny_fat32_image();
+        data.resize(104 * 1024, 0xFF);
+        let nor = Nor { data, erases: 0 };
+        let mismatched = FlashDevice::<_, 512>::new(Nor { data: Vec::new(), erases: 0 });
+        assert!(matches!(mismatched, Err(Error::InvalidInput)));
+        let mut fs = Fat32::mount(FlashDevice::<_, 4096>::new(nor).unwrap()).expect("mount");
+        fs.write_file_root("A.TXT", &[0x11u8; 3000]).unwrap();
+        fs.write_file_root("B.TXT", b"bee").unwrap();
+        let nor = fs.unmount().unwrap().into_inner().unwrap();
+        // Sector writes to one block are coalesced into a single commit.
+        assert!(nor.erases <= 6, "{} erases", nor.erases);
+
+        let fs = Fat32::mount(FlashDevice::<_, 4096>::new(nor).unwrap()).expect("remount");
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), [0x11u8; 3000]);
+        assert_eq!(fs.read_file_root("B.TXT").unwrap(), b"bee");
+        assert!(fs.check().unwrap().is_clean());
+    }
+}
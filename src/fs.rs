@@ -1,267 +1,2671 @@
-//! FAT32 high-level filesystem API (MVP).
-
-use alloc::vec::Vec;
-
-use crate::bpb::Bpb;
-use crate::device::BlockDevice;
-use crate::dir::{to_short_name_83, DirEntry};
-use crate::error::{Error, Result};
-use crate::fat::{cluster_to_lba, find_free_cluster, read_fat_entry, write_fat_entry, EOC_MIN};
-
-/// FAT32 filesystem handle.
-pub struct Fat32<D: BlockDevice> {
-    dev: D,
-    bpb: Bpb,
-}
-
-impl<D: BlockDevice> Fat32<D> {
-    /// Mount a FAT32 volume by reading and parsing sector 0.
-    pub fn mount(mut dev: D) -> Result<Self> {
-        let mut boot = [0u8; 512];
-        dev.read_sector(0, &mut boot)?;
-        let bpb = Bpb::parse(&boot)?;
-        Ok(Self { dev, bpb })
-    }
-
-    /// Return parsed BPB info.
-    pub fn bpb(&self) -> &Bpb {
-        &self.bpb
-    }
-
-    /// Read the root directory entries (8.3 only, skipping LFN in this MVP).
-    pub fn list_root(&self) -> Result<Vec<DirEntry>> {
-        let mut out = Vec::new();
-        let mut cluster = self.bpb.root_cluster;
-
-        loop {
-            let base_lba = cluster_to_lba(&self.bpb, cluster);
-            for s in 0..(self.bpb.sectors_per_cluster as u64) {
-                let mut buf = [0u8; 512];
-                self.dev.read_sector(base_lba + s, &mut buf)?;
-                for i in 0..16 {
-                    let mut rec = [0u8; 32];
-                    rec.copy_from_slice(&buf[i * 32..i * 32 + 32]);
-                    if let Some(e) = DirEntry::parse(&rec)? {
-                        // Skip deleted / LFN placeholders
-                        if e.attr == 0x0F || (e.first_cluster == 0 && e.file_size == 0 && e.raw_name == [0; 11]) {
-                            continue;
-                        }
-                        out.push(e);
-                    } else {
-                        return Ok(out);
-                    }
-                }
-            }
-
-            let next = read_fat_entry(&self.dev, &self.bpb, cluster)?;
-            if next >= EOC_MIN {
-                break;
-            }
-            if next < 2 {
-                return Err(Error::Corrupt);
-            }
-            cluster = next;
-        }
-
-        Ok(out)
-    }
-
-    /// Read a file by short name (8.3 only) from root directory.
-    pub fn read_file_root(&self, name: &str) -> Result<Vec<u8>> {
-        let target = to_short_name_83(name)?;
-        let entries = self.list_root()?;
-
-        let mut found = None;
-        for e in entries {
-            if e.raw_name == target {
-                found = Some(e);
-                break;
-            }
-        }
-        let e = found.ok_or(Error::NotFound)?;
-        if e.first_cluster < 2 {
-            return Err(Error::Corrupt);
-        }
-
-        let mut remaining = e.file_size as usize;
-        let mut data = Vec::with_capacity(remaining);
-        let mut cluster = e.first_cluster;
-
-        while remaining > 0 {
-            let base_lba = cluster_to_lba(&self.bpb, cluster);
-            for s in 0..(self.bpb.sectors_per_cluster as u64) {
-                let mut buf = [0u8; 512];
-                self.dev.read_sector(base_lba + s, &mut buf)?;
-                let take = remaining.min(512);
-                data.extend_from_slice(&buf[..take]);
-                remaining -= take;
-                if remaining == 0 {
-                    break;
-                }
-            }
-            if remaining == 0 {
-                break;
-            }
-            let next = read_fat_entry(&self.dev, &self.bpb, cluster)?;
-            if next >= EOC_MIN {
-                return Err(Error::Corrupt);
-            }
-            cluster = next;
-        }
-
-        Ok(data)
-    }
-
-    /// Create or overwrite a root file (8.3) and write `content` persistently.
-    ///
-    /// MVP limitations:
-    /// - allocates a new cluster chain (does not free old chains if overwriting)
-    /// - writes FAT #0 only (not mirrored to FAT #1 if present)
-    /// - writes into root directory only
-    pub fn write_file_root(&mut self, name: &str, content: &[u8]) -> Result<()> {
-        let short = to_short_name_83(name)?;
-        let clusters_needed = clusters_for_len(&self.bpb, content.len());
-        if clusters_needed == 0 {
-            return Err(Error::InvalidName);
-        }
-
-        // 1) Allocate cluster chain
-        let mut chain = Vec::with_capacity(clusters_needed);
-        let mut next_search = 2u32;
-        for _ in 0..clusters_needed {
-            let c = find_free_cluster(&self.dev, &self.bpb, next_search)?;
-            // Reserve quickly
-            write_fat_entry(&mut self.dev, &self.bpb, c, 0x0FFFFFFF)?;
-            chain.push(c);
-            next_search = c + 1;
-        }
-        // Link chain
-        for i in 0..chain.len() {
-            let cur = chain[i];
-            let val = if i + 1 < chain.len() { chain[i + 1] } else { 0x0FFFFFFF };
-            write_fat_entry(&mut self.dev, &self.bpb, cur, val)?;
-        }
-
-        // 2) Write data to clusters
-        let mut offset = 0usize;
-        for &cluster in &chain {
-            let base_lba = cluster_to_lba(&self.bpb, cluster);
-            for s in 0..(self.bpb.sectors_per_cluster as u64) {
-                let mut sector = [0u8; 512];
-                let remain = content.len().saturating_sub(offset);
-                if remain != 0 {
-                    let take = remain.min(512);
-                    sector[..take].copy_from_slice(&content[offset..offset + take]);
-                    offset += take;
-                }
-                self.dev.write_sector(base_lba + s, &sector)?;
-            }
-        }
-
-        // 3) Create directory entry in root (first free slot)
-        let first_cluster = chain[0];
-        let rec = DirEntry::build_short_file(short, first_cluster, content.len() as u32);
-        self.write_root_dir_entry_first_free(&rec)?;
-
-        Ok(())
-    }
-
-    fn write_root_dir_entry_first_free(&mut self, rec: &[u8; 32]) -> Result<()> {
-        let mut cluster = self.bpb.root_cluster;
-
-        loop {
-            let base_lba = cluster_to_lba(&self.bpb, cluster);
-
-            for s in 0..(self.bpb.sectors_per_cluster as u64) {
-                let lba = base_lba + s;
-                let mut buf = [0u8; 512];
-                self.dev.read_sector(lba, &mut buf)?;
-
-                for i in 0..16 {
-                    let first = buf[i * 32];
-                    if first == 0x00 || first == 0xE5 {
-                        buf[i * 32..i * 32 + 32].copy_from_slice(rec);
-                        self.dev.write_sector(lba, &buf)?;
-                        return Ok(());
-                    }
-                }
-            }
-
-            let next = read_fat_entry(&self.dev, &self.bpb, cluster)?;
-            if next >= EOC_MIN {
-                return Err(Error::DirFull);
-            }
-            if next < 2 {
-                return Err(Error::Corrupt);
-            }
-            cluster = next;
-        }
-    }
-
-    /// Consume the filesystem and return the underlying device (useful in tests).
-    pub fn into_device(self) -> D {
-        self.dev
-    }
-}
-
-fn clusters_for_len(bpb: &Bpb, len: usize) -> usize {
-    let bytes_per_cluster = (bpb.sectors_per_cluster as usize) * 512;
-    (len + bytes_per_cluster - 1) / bytes_per_cluster
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::device::MemDevice;
-
-    fn make_tiny_fat32_image() -> std::vec::Vec<u8> {
-        // Minimal FAT32-like image for tests.
-        let total_sectors = 200u32;
-        let mut img = vec![0u8; (total_sectors as usize) * 512];
-
-        // Boot sector @ LBA0
-        let bs = &mut img[0..512];
-        bs[510] = 0x55;
-        bs[511] = 0xAA;
-
-        // bytes_per_sector = 512
-        bs[11..13].copy_from_slice(&512u16.to_le_bytes());
-        // sectors_per_cluster = 1
-        bs[13] = 1;
-        // reserved_sectors = 32
-        bs[14..16].copy_from_slice(&32u16.to_le_bytes());
-        // num_fats = 1
-        bs[16] = 1;
-        // root_entry_count = 0
-        bs[17..19].copy_from_slice(&0u16.to_le_bytes());
-        // total_sectors_32
-        bs[32..36].copy_from_slice(&total_sectors.to_le_bytes());
-        // fat_size_32 = 1 sector
-        bs[36..40].copy_from_slice(&1u32.to_le_bytes());
-        // root_cluster = 2
-        bs[44..48].copy_from_slice(&2u32.to_le_bytes());
-        // fsinfo sector
-        bs[48..50].copy_from_slice(&1u16.to_le_bytes());
-
-        // FAT @ LBA = reserved = 32
-        let fat_lba = 32usize;
-        let fat = &mut img[fat_lba * 512..fat_lba * 512 + 512];
-        fat[0..4].copy_from_slice(&0x0FFFFFF8u32.to_le_bytes()); // cluster 0
-        fat[4..8].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes()); // cluster 1
-        fat[8..12].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes()); // cluster 2 root EOC
-
-        img
-    }
-
-    #[test]
-    fn mount_and_write_and_read() {
-        let img = make_tiny_fat32_image();
-        let dev = MemDevice::new(img);
-
-        let mut fs = Fat32::mount(dev).expect("mount");
-        fs.write_file_root("HELLO.TXT", b"abc").expect("write");
-
-        let data = fs.read_file_root("HELLO.TXT").expect("read");
-        assert_eq!(data, b"abc");
-    }
-}
+//! FAT32 high-level filesystem API (MVP).
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::ops::Range;
+
+use crate::bpb::{Bpb, BOOT_FLAGS_OFFSET, BOOT_FLAG_DIRTY, DEFAULT_BACKUP_BOOT_SECTOR};
+use crate::cancel::Cancel;
+#[cfg(feature = "alloc")]
+use crate::device::AlignedBuf;
+use crate::device::{is_aligned, BlockDevice, SectorBuf, SECTOR_BUF_ALIGN};
+use crate::dir::{nt_case_flags, to_short_name_83_with, DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LFN};
+use crate::error::{Error, ErrorContext, Operation, Result};
+#[cfg(feature = "alloc")]
+use crate::file::OpenFile;
+#[cfg(feature = "alloc")]
+use crate::fat::{compare_fats, FatMismatch};
+use crate::fat::{
+    cluster_to_lba, data_start_lba, fat_copy_lba, max_cluster, ChainIter, BAD_CLUSTER, EOC_MIN,
+    FAT1_CLEAN_SHUTDOWN,
+};
+use crate::fsinfo::FsInfo;
+use crate::metadata::ATTR_READ_ONLY;
+#[cfg(feature = "alloc")]
+use crate::options::CreateOptions;
+use crate::options::{AllocPolicy, ListOptions, MountOptions};
+use crate::path::{Components, Path};
+use crate::read_dir::ReadDir;
+
+/// A directory slot: cluster, sector and index within the sector.
+type Slot = (u32, u64, usize);
+
+/// Most records one entry takes: 20 long-name records and the short entry.
+const MAX_ENTRY_RECORDS: usize = 21;
+
+/// Consecutive directory slots, as found by `Fat32::find_free_slots`.
+struct SlotRun {
+    slots: [Slot; MAX_ENTRY_RECORDS],
+    len: usize,
+}
+
+impl SlotRun {
+    fn new() -> Self {
+        Self { slots: [(0, 0, 0); MAX_ENTRY_RECORDS], len: 0 }
+    }
+
+    fn push(&mut self, slot: Slot) {
+        self.slots[self.len] = slot;
+        self.len += 1;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl core::ops::Deref for SlotRun {
+    type Target = [Slot];
+
+    fn deref(&self) -> &[Slot] {
+        &self.slots[..self.len]
+    }
+}
+
+/// Bounce buffer size for transfers whose buffer lacks the device's alignment.
+#[cfg(feature = "alloc")]
+const BOUNCE_BYTES: usize = 16 * 512;
+
+/// Scratch buffer with `align` for a transfer of `len` bytes, at most
+/// `BOUNCE_BYTES` long.
+#[cfg(feature = "alloc")]
+fn bounce_buf(len: usize, align: usize) -> Result<AlignedBuf> {
+    Ok(AlignedBuf::new(len.min(BOUNCE_BYTES), align))
+}
+
+/// Scratch buffer with `align`: a single sector, since there is no heap to
+/// allocate a wider one from.
+#[cfg(not(feature = "alloc"))]
+fn bounce_buf(_len: usize, align: usize) -> Result<SectorBuf> {
+    if align > SECTOR_BUF_ALIGN {
+        return Err(Error::InvalidInput);
+    }
+    Ok(SectorBuf::default())
+}
+
+/// Zeros for clearing clusters a few sectors per device call, without a buffer.
+static ZEROS: ZeroSectors = ZeroSectors([0; 8 * 512]);
+
+/// Eight zero sectors, aligned like `SectorBuf`.
+#[repr(C, align(64))]
+struct ZeroSectors([u8; 8 * 512]);
+
+/// FAT32 filesystem handle.
+pub struct Fat32<D: BlockDevice> {
+    pub(crate) dev: D,
+    pub(crate) bpb: Bpb,
+    options: MountOptions,
+    /// The dirty flags were found set at mount time.
+    mounted_dirty: bool,
+    /// This session set the dirty flags and has not cleared them yet.
+    dirty: bool,
+    /// Context of the most recent error (see `last_error`).
+    last_error: Cell<Option<ErrorContext>>,
+    /// Open-file table (see `Fat32::open_handle_root`).
+    #[cfg(feature = "alloc")]
+    pub(crate) open_files: Vec<Option<OpenFile>>,
+    /// Metadata sectors written by the running transaction, not yet on disk.
+    #[cfg(feature = "alloc")]
+    pub(crate) staged: Vec<(u64, [u8; 512])>,
+    /// Nesting depth of `transaction`; metadata writes are staged while non-zero.
+    #[cfg(feature = "alloc")]
+    atomic_depth: u32,
+    /// FAT sectors updated but not yet written (`MountOptions::defer_fat_writes`).
+    #[cfg(feature = "alloc")]
+    fat_cache: FatCache,
+    /// Freed sector ranges to trim once the FAT that frees them is on the device.
+    #[cfg(feature = "alloc")]
+    pending_trims: Vec<(u64, u64)>,
+    /// Where `AllocPolicy::Rotate` looks for the next free cluster.
+    next_alloc: Cell<u32>,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Mount a FAT32 volume by reading and parsing sector 0.
+    pub fn mount(dev: D) -> Result<Self> {
+        Self::mount_with(dev, MountOptions::default())
+    }
+
+    /// Mount a FAT32 volume with explicit validation and behavior options.
+    pub fn mount_with(dev: D, options: MountOptions) -> Result<Self> {
+        Self::try_mount_with(dev, options).map_err(|(e, _)| e)
+    }
+
+    /// `mount_with`, handing the device back along with the error if mounting fails.
+    pub(crate) fn try_mount_with(dev: D, options: MountOptions) -> core::result::Result<Self, (Error, D)> {
+        let (bpb, boot_dirty) = match Self::read_boot_sector(&dev, &options) {
+            Ok(boot) => boot,
+            Err(e) => return Err((e, dev)),
+        };
+        let mut fs = Self {
+            dev,
+            bpb,
+            options,
+            mounted_dirty: false,
+            dirty: false,
+            last_error: Cell::new(None),
+            #[cfg(feature = "alloc")]
+            open_files: Vec::new(),
+            #[cfg(feature = "alloc")]
+            staged: Vec::new(),
+            #[cfg(feature = "alloc")]
+            atomic_depth: 0,
+            #[cfg(feature = "alloc")]
+            fat_cache: FatCache::default(),
+            #[cfg(feature = "alloc")]
+            pending_trims: Vec::new(),
+            next_alloc: Cell::new(2),
+        };
+        match fs.finish_mount(boot_dirty) {
+            Ok(()) => Ok(fs),
+            Err(e) => Err((e, fs.dev)),
+        }
+    }
+
+    /// Parse and check the boot sector; also returns its dirty flag.
+    fn read_boot_sector(dev: &D, options: &MountOptions) -> Result<(Bpb, bool)> {
+        #[cfg(not(feature = "alloc"))]
+        if options.journal || options.defer_fat_writes || options.read_ahead {
+            return Err(Error::InvalidInput);
+        }
+        let mut sector = SectorBuf::default();
+        dev.read_sector(0, &mut sector.0)?;
+        let boot = &sector.0;
+        let bpb = Bpb::parse_with(boot, options.strict_bpb)?;
+        if options.fat_to_use >= bpb.num_fats {
+            return Err(Error::InvalidFatIndex);
+        }
+        if bpb.sizes_disagree() {
+            fs_warn!("fat32: 16-bit size {} ignored, using {}", bpb.total_sectors_16, bpb.total_sectors_32);
+        }
+        if let Some(start) = options.partition_start.filter(|&s| s != bpb.hidden_sectors) {
+            if options.strict_bpb {
+                return Err(Error::InvalidBootSector);
+            }
+            fs_warn!("fat32: partition starts at {} but boot sector says {}", start, bpb.hidden_sectors);
+        }
+        Ok((bpb, boot[BOOT_FLAGS_OFFSET] & BOOT_FLAG_DIRTY != 0))
+    }
+
+    /// Replay the journal and check the FSInfo sector and root directory as `options` ask.
+    fn finish_mount(&mut self, boot_dirty: bool) -> Result<()> {
+        let bpb = self.bpb;
+        fs_debug!(
+            "fat32: mount spc={} reserved={} fats={}x{} root={} total={}",
+            bpb.sectors_per_cluster,
+            bpb.reserved_sectors,
+            bpb.num_fats,
+            bpb.fat_size_32,
+            bpb.root_cluster,
+            bpb.total_sectors_32
+        );
+        #[cfg(feature = "alloc")]
+        if self.options.journal {
+            self.replay_journal()?;
+        }
+        if self.options.verify_fsinfo {
+            self.verify_fsinfo()?;
+        }
+        if self.options.allocation == AllocPolicy::Rotate {
+            if let Some(info) = self.read_fsinfo()? {
+                if (2..=max_cluster(&self.bpb)).contains(&info.next_free) {
+                    self.next_alloc.set(info.next_free);
+                }
+            }
+        }
+        if !self.options.lazy {
+            let root = self.bpb.root_cluster;
+            if root > max_cluster(&self.bpb) {
+                return Err(self.record(Operation::ReadDir, None, Some(root), Error::Corrupt));
+            }
+            let next = self.read_fat(root)?;
+            if next == 0 || next == BAD_CLUSTER {
+                return Err(self.record(Operation::ReadDir, None, Some(root), Error::Corrupt));
+            }
+            let fat1 = self.read_fat(1)?;
+            self.mounted_dirty = fat1 & FAT1_CLEAN_SHUTDOWN == 0 || boot_dirty;
+            if self.mounted_dirty {
+                fs_warn!("fat32: volume was not cleanly unmounted");
+            }
+        }
+        Ok(())
+    }
+
+    /// Options this volume was mounted with.
+    pub fn options(&self) -> &MountOptions {
+        &self.options
+    }
+
+    /// Clusters of the chain starting at `first`, read from the FAT selected at mount.
+    pub fn chain(&self, first: u32) -> ChainIter<'_, D> {
+        ChainIter::in_fat(&self.dev, &self.bpb, self.options.fat_to_use, first)
+    }
+
+    /// The FSInfo sector, or `None` if the volume has none or it is invalid.
+    fn read_fsinfo(&self) -> Result<Option<FsInfo>> {
+        if self.bpb.fsinfo_sector == 0xFFFF {
+            return Ok(None);
+        }
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadFsInfo, self.bpb.fsinfo_sector as u64, &mut buf)?;
+        Ok(FsInfo::parse(&buf).ok())
+    }
+
+    /// Store the `AllocPolicy::Rotate` position as the FSInfo next-free hint.
+    fn write_next_free_hint(&mut self) -> Result<()> {
+        if let Some(mut info) = self.read_fsinfo()? {
+            if info.next_free != self.next_alloc.get() {
+                info.next_free = self.next_alloc.get();
+                self.write_sector(Operation::WriteFsInfo, self.bpb.fsinfo_sector as u64, &info.serialize())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the lead, struct and trail signatures of the FSInfo sector.
+    fn verify_fsinfo(&self) -> Result<()> {
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadFsInfo, self.bpb.fsinfo_sector as u64, &mut buf)?;
+        FsInfo::parse(&buf).map(|_| ())
+    }
+
+    /// Mount, restoring sector 0 from the backup boot sector (sector 6) if it is unusable.
+    pub fn mount_or_restore(mut dev: D) -> Result<Self> {
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot)?;
+        match Bpb::parse(&boot) {
+            Err(Error::InvalidBootSector) | Err(Error::NotFat32) => {
+                fs_warn!("fat32: boot sector unusable, restoring from backup");
+                dev.read_sector(DEFAULT_BACKUP_BOOT_SECTOR as u64, &mut boot)?;
+                Bpb::parse(&boot)?;
+                dev.write_sector(0, &boot)?;
+            }
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        Self::mount(dev)
+    }
+
+    /// Compare sector 0 with the backup boot sector (ignoring the volume flags byte).
+    ///
+    /// Returns `Ok(false)` when they differ, `Err(NotFound)` if the volume has no backup.
+    pub fn verify_backup_boot_sector(&self) -> Result<bool> {
+        if !self.bpb.has_backup_boot_sector() {
+            return Err(Error::NotFound);
+        }
+        let mut boot = [0u8; 512];
+        let mut backup = [0u8; 512];
+        self.read_sector(Operation::ReadBoot, 0, &mut boot)?;
+        self.read_sector(Operation::ReadBoot, self.bpb.backup_boot_sector as u64, &mut backup)?;
+        boot[BOOT_FLAGS_OFFSET] = 0;
+        backup[BOOT_FLAGS_OFFSET] = 0;
+        Ok(boot == backup)
+    }
+
+    /// Overwrite sector 0 with the backup boot sector, after checking the backup parses.
+    pub fn restore_boot_sector_from_backup(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if !self.bpb.has_backup_boot_sector() {
+            return Err(Error::NotFound);
+        }
+        let mut backup = [0u8; 512];
+        self.read_sector(Operation::ReadBoot, self.bpb.backup_boot_sector as u64, &mut backup)?;
+        let bpb = Bpb::parse(&backup)?;
+        if self.dirty {
+            backup[BOOT_FLAGS_OFFSET] |= BOOT_FLAG_DIRTY;
+        }
+        self.write_sector(Operation::WriteBoot, 0, &backup)?;
+        self.bpb = bpb;
+        Ok(())
+    }
+
+    /// True if the volume was not cleanly unmounted before this mount
+    /// (a host OS would suggest running a check). Always false for lazy mounts.
+    pub fn mounted_dirty(&self) -> bool {
+        self.mounted_dirty
+    }
+
+    /// Write pending directory entries of open handles and clear the
+    /// "volume dirty" flags set by the first write of this session.
+    ///
+    /// Call before power-down; a later write sets the flags again.
+    pub fn flush(&mut self) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        self.flush_open_files()?;
+        self.write_deferred_fat()?;
+        if self.dirty {
+            if self.options.allocation == AllocPolicy::Rotate {
+                self.write_next_free_hint()?;
+            }
+            self.set_dirty_flags(false)?;
+            self.write_deferred_fat()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Write the FAT sectors held back by `MountOptions::defer_fat_writes`, in LBA
+    /// order and one device call per run of consecutive sectors.
+    #[cfg(feature = "alloc")]
+    fn write_deferred_fat(&mut self) -> Result<()> {
+        let mut cache = core::mem::take(&mut self.fat_cache);
+        let mut done = 0;
+        let result = loop {
+            let Some(&lba) = cache.lbas.get(done) else {
+                break Ok(());
+            };
+            let run = cache.lbas[done..].iter().zip(lba..).take_while(|(l, want)| **l == *want).count();
+            let data = cache.data[done..done + run].as_flattened();
+            let written = self.write_sectors_direct(Operation::WriteFat, lba, data);
+            if written.is_err() {
+                break written;
+            }
+            done += run;
+        };
+        // Keep what was not written for the next attempt.
+        cache.lbas.drain(..done);
+        cache.data.drain(..done);
+        self.fat_cache = cache;
+        if result.is_ok() && self.atomic_depth == 0 {
+            self.issue_pending_trims();
+        }
+        result
+    }
+
+    /// Nothing is deferred without `alloc`.
+    #[cfg(not(feature = "alloc"))]
+    fn write_deferred_fat(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Durability point: `flush`, then `BlockDevice::flush` the device.
+    ///
+    /// Everything written before a successful `sync` survives power loss.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.dev.flush().map_err(|e| self.record(Operation::Flush, None, None, e))
+    }
+
+    /// Sync and return the underlying device.
+    pub fn unmount(mut self) -> Result<D> {
+        self.sync()?;
+        Ok(self.dev)
+    }
+
+    /// Set the "volume dirty" flags before the first mutation of this session.
+    ///
+    /// Every mutating operation calls this first, so it also enforces `read_only`
+    /// and `BlockDevice::is_write_protected`.
+    pub(crate) fn mark_dirty(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if !self.dirty {
+            self.set_dirty_flags(true)?;
+            // The dirty mark has to be on disk before anything it guards.
+            self.write_deferred_fat()?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnlyVolume);
+        }
+        if self.dev.is_write_protected() {
+            return Err(Error::WriteProtected);
+        }
+        Ok(())
+    }
+
+    /// Fail with `ReadOnlyFile` if `e` has the read-only attribute, unless
+    /// `MountOptions::ignore_read_only_attr` is set.
+    pub(crate) fn check_not_read_only(&self, e: &DirEntry) -> Result<()> {
+        if e.attr & ATTR_READ_ONLY != 0 && !self.options.ignore_read_only_attr {
+            return Err(Error::ReadOnlyFile);
+        }
+        Ok(())
+    }
+
+    /// Context of the most recent failed operation (operation, LBA, cluster).
+    ///
+    /// Not cleared by later successful calls.
+    pub fn last_error(&self) -> Option<ErrorContext> {
+        self.last_error.get()
+    }
+
+    /// Remember where `error` happened and hand it back for returning.
+    ///
+    /// For data-region sectors without an explicit cluster, the cluster is derived from `lba`.
+    pub(crate) fn record(&self, op: Operation, lba: Option<u64>, cluster: Option<u32>, error: Error) -> Error {
+        let data_start = data_start_lba(&self.bpb);
+        let cluster = cluster.or_else(|| {
+            let lba = lba.filter(|&l| l >= data_start)?;
+            Some(((lba - data_start) / self.bpb.sectors_per_cluster as u64) as u32 + 2)
+        });
+        let ctx = ErrorContext { error, op, lba, cluster };
+        fs_warn!("fat32: {}", ctx);
+        self.last_error.set(Some(ctx));
+        error
+    }
+
+    /// Read a sector, recording `op` as context on failure.
+    ///
+    /// Sectors staged by a running transaction are returned from memory.
+    pub(crate) fn read_sector(&self, op: Operation, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        self.read_staged_or_device(lba, buf).map_err(|e| self.record(op, Some(lba), None, e))
+    }
+
+    /// Read consecutive sectors with one device call, unless some are staged or deferred.
+    pub(crate) fn read_sectors(&self, op: Operation, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let count = (buf.len() / 512) as u64;
+        if self.held_in_memory(lba..lba + count) {
+            for (i, chunk) in buf.chunks_exact_mut(512).enumerate() {
+                let sector: &mut [u8; 512] = chunk.try_into().expect("512-byte chunk");
+                self.read_sector(op, lba + i as u64, sector)?;
+            }
+            return Ok(());
+        }
+        self.read_device(lba, buf).map_err(|e| self.record(op, Some(lba), None, e))
+    }
+
+    /// True if some sector in `range` is staged or deferred, so that the
+    /// device copy may be stale.
+    #[cfg(feature = "alloc")]
+    fn held_in_memory(&self, range: Range<u64>) -> bool {
+        self.staged.iter().any(|(l, _)| range.contains(l)) || self.fat_cache.overlaps(range)
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn held_in_memory(&self, _range: Range<u64>) -> bool {
+        false
+    }
+
+    fn read_staged_or_device(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if let Some((_, data)) = self.staged.iter().find(|(l, _)| *l == lba) {
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        self.read_cached_or_device(lba, buf)
+    }
+
+    /// Read a sector as committed outside any transaction: a deferred FAT
+    /// sector if there is one, the device otherwise.
+    fn read_cached_or_device(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if let Some(data) = self.fat_cache.get(lba) {
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        let align = self.dev.alignment();
+        if align > SECTOR_BUF_ALIGN {
+            return self.read_device(lba, buf);
+        }
+        if is_aligned(buf, align) {
+            return self.dev.read_sector(lba, buf);
+        }
+        let mut bounce = SectorBuf::default();
+        self.dev.read_sector(lba, &mut bounce.0)?;
+        *buf = bounce.0;
+        Ok(())
+    }
+
+    /// Read sectors from the device, through an aligned bounce buffer if `buf`
+    /// does not have the device's alignment.
+    fn read_device(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let align = self.dev.alignment();
+        if is_aligned(buf, align) {
+            return self.dev.read_sectors(lba, buf);
+        }
+        let mut bounce = bounce_buf(buf.len(), align)?;
+        let step = bounce.len();
+        for (i, chunk) in buf.chunks_mut(step).enumerate() {
+            let bounce = &mut bounce[..chunk.len()];
+            self.dev.read_sectors(lba + (i * step / 512) as u64, bounce)?;
+            chunk.copy_from_slice(bounce);
+        }
+        Ok(())
+    }
+
+    /// Write a sector, recording `op` as context on failure.
+    ///
+    /// Inside a transaction, FAT and directory writes are staged instead.
+    pub(crate) fn write_sector(&mut self, op: Operation, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if self.atomic_depth > 0 && matches!(op, Operation::WriteFat | Operation::WriteDir) {
+            return self.stage(lba, buf);
+        }
+        self.write_sector_direct(op, lba, buf)
+    }
+
+    /// Write a sector straight to the device, bypassing staging.
+    pub(crate) fn write_sector_direct(&mut self, op: Operation, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.write_sectors_direct(op, lba, buf)
+    }
+
+    /// Write consecutive sectors with one device call, bypassing staging.
+    pub(crate) fn write_sectors_direct(&mut self, op: Operation, lba: u64, buf: &[u8]) -> Result<()> {
+        self.write_device(lba, buf).map_err(|e| self.record(op, Some(lba), None, e))
+    }
+
+    /// Write to the device, reading the sectors back when `verify_writes` is set.
+    fn write_device(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let align = self.dev.alignment();
+        if is_aligned(buf, align) {
+            self.dev.write_sectors(lba, buf)?;
+        } else {
+            let mut bounce = bounce_buf(buf.len(), align)?;
+            let step = bounce.len();
+            for (i, chunk) in buf.chunks(step).enumerate() {
+                let bounce = &mut bounce[..chunk.len()];
+                bounce.copy_from_slice(chunk);
+                self.dev.write_sectors(lba + (i * step / 512) as u64, bounce)?;
+            }
+        }
+        // The written data was built on top of any deferred copy, which is now stale.
+        #[cfg(feature = "alloc")]
+        self.fat_cache.remove(lba..lba + (buf.len() / 512) as u64);
+        if self.options.verify_writes {
+            let mut check = bounce_buf(buf.len(), align)?;
+            let step = check.len();
+            for (i, chunk) in buf.chunks(step).enumerate() {
+                let check = &mut check[..chunk.len()];
+                self.dev.read_sectors(lba + (i * step / 512) as u64, check)?;
+                if *check != *chunk {
+                    return Err(Error::VerifyFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "alloc")]
+    fn stage(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        if let Some((_, data)) = self.staged.iter_mut().find(|(l, _)| *l == lba) {
+            *data = *buf;
+            return Ok(());
+        }
+        if self.options.journal && self.staged.len() >= self.journal_capacity()? {
+            return Err(Error::NoJournalSpace);
+        }
+        self.staged.push((lba, *buf));
+        Ok(())
+    }
+
+    /// Run `f` as one all-or-nothing group of operations.
+    ///
+    /// FAT and directory writes made inside `f` are staged in memory (reads see
+    /// them) and written when `f` returns `Ok`; on `Err` they are discarded and
+    /// open handles are restored, so e.g. deleting an old log and writing its
+    /// replacement either both happen or neither does. With
+    /// `MountOptions::journal` the final write goes through the journal and is
+    /// atomic across power loss as well.
+    ///
+    /// File data overwritten in place is not rolled back. Clusters freed inside
+    /// `f` are not reused before it commits. Nested calls join the outer one.
+    #[cfg(feature = "alloc")]
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.options.read_only {
+            // Nothing can be written, and `staged` may hold a journal replayed in memory.
+            return f(self);
+        }
+        let open_files = self.open_files.clone();
+        let dirty = self.dirty;
+        let trims = self.pending_trims.len();
+        self.atomic_depth += 1;
+        let result = f(self);
+        self.atomic_depth -= 1;
+        if self.atomic_depth > 0 {
+            return result;
+        }
+        let staged = core::mem::take(&mut self.staged);
+        let result = result.and_then(|v| self.commit_staged(&staged).map(|()| v));
+        if result.is_err() {
+            fs_debug!("fat32: dropping {} staged sectors", staged.len());
+            self.open_files = open_files;
+            // The dirty mark may only have been staged; set it again next time.
+            self.dirty = dirty;
+            // The clusters stay allocated, so their data must survive.
+            self.pending_trims.truncate(trims);
+        } else if self.fat_cache.is_empty() {
+            self.issue_pending_trims();
+        }
+        result
+    }
+
+    /// Run a single mutating operation as a transaction when journaling (or
+    /// already inside one); otherwise run `f` unchanged.
+    pub(crate) fn atomic<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        #[cfg(feature = "alloc")]
+        if self.options.journal || self.atomic_depth > 0 {
+            return self.transaction(f);
+        }
+        f(self)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn commit_staged(&mut self, staged: &[(u64, [u8; 512])]) -> Result<()> {
+        if staged.is_empty() {
+            return Ok(());
+        }
+        if self.options.journal {
+            return self.journal_commit(staged);
+        }
+        let data_start = data_start_lba(&self.bpb);
+        for (lba, data) in staged {
+            let op = if *lba < data_start { Operation::WriteFat } else { Operation::WriteDir };
+            self.write_sector_direct(op, *lba, data)?;
+        }
+        Ok(())
+    }
+
+    /// LBA of the sector holding the FAT entry for `cluster` in the active FAT.
+    fn fat_entry_lba(&self, cluster: u32) -> u64 {
+        fat_copy_lba(&self.bpb, self.options.fat_to_use) + (cluster as u64 * 4) / 512
+    }
+
+    /// Read the FAT entry for `cluster` from the FAT selected at mount.
+    pub(crate) fn read_fat(&self, cluster: u32) -> Result<u32> {
+        let lba = self.fat_entry_lba(cluster);
+        let off = ((cluster as usize) * 4) % 512;
+        let mut buf = [0u8; 512];
+        self.read_staged_or_device(lba, &mut buf)
+            .map_err(|e| self.record(Operation::ReadFat, Some(lba), Some(cluster), e))?;
+        Ok(u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) & 0x0FFFFFFF)
+    }
+
+    /// Write the FAT entry for `cluster` into the FAT selected at mount.
+    pub(crate) fn write_fat(&mut self, cluster: u32, value: u32) -> Result<()> {
+        fs_trace!("fat32: FAT[{}] = {:#x}", cluster, value);
+        let lba = self.fat_entry_lba(cluster);
+        let off = ((cluster as usize) * 4) % 512;
+        let mut buf = [0u8; 512];
+        self.read_staged_or_device(lba, &mut buf)
+            .map_err(|e| self.record(Operation::WriteFat, Some(lba), Some(cluster), e))?;
+        buf[off..off + 4].copy_from_slice(&(value & 0x0FFFFFFF).to_le_bytes());
+        self.write_fat_sector(lba, buf).map_err(|e| self.record(Operation::WriteFat, Some(lba), Some(cluster), e))
+    }
+
+    /// Stage, defer or write the FAT sector `buf` at `lba`.
+    fn write_fat_sector(&mut self, lba: u64, buf: [u8; 512]) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if self.atomic_depth > 0 {
+            return self.stage(lba, &buf);
+        }
+        #[cfg(feature = "alloc")]
+        if self.options.defer_fat_writes {
+            self.fat_cache.insert(lba, buf);
+            return Ok(());
+        }
+        self.write_device(lba, &buf)
+    }
+
+    /// Find a free cluster at or after `start_from` in the FAT selected at mount.
+    ///
+    /// Clusters freed by a running transaction stay reserved until it commits,
+    /// so a rollback never finds them overwritten.
+    pub(crate) fn find_free_cluster(&self, start_from: u32) -> Result<u32> {
+        for c in start_from.max(2)..=max_cluster(&self.bpb) {
+            if self.read_fat(c)? != 0 {
+                continue;
+            }
+            #[cfg(feature = "alloc")]
+            if !self.staged.is_empty() {
+                let mut buf = [0u8; 512];
+                let lba = self.fat_entry_lba(c);
+                self.read_cached_or_device(lba, &mut buf)
+                    .map_err(|e| self.record(Operation::ReadFat, Some(lba), Some(c), e))?;
+                let off = ((c as usize) * 4) % 512;
+                if u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) & 0x0FFFFFFF != 0 {
+                    continue;
+                }
+            }
+            return Ok(c);
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Call `f(cluster, value)` for every FAT entry in `2..=max`, one sector read at a time.
+    pub(crate) fn for_each_fat_entry(&self, max: u32, cancel: Cancel<'_>, mut f: impl FnMut(u32, u32)) -> Result<()> {
+        let mut buf = [0u8; 512];
+        let mut loaded = u64::MAX;
+        for c in 2..=max {
+            let sector = fat_copy_lba(&self.bpb, self.options.fat_to_use) + (c as u64 * 4) / 512;
+            if sector != loaded {
+                cancel.check()?;
+                self.read_sector(Operation::ReadFat, sector, &mut buf)?;
+                loaded = sector;
+            }
+            let off = ((c as usize) * 4) % 512;
+            let v = u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) & 0x0FFFFFFF;
+            f(c, v);
+        }
+        Ok(())
+    }
+
+    /// Find a free cluster for allocation, from `start` or, if `None`, where
+    /// the allocation policy says, wrapping around to cluster 2 at the end.
+    pub(crate) fn find_alloc_cluster(&self, start: Option<u32>) -> Result<u32> {
+        let start = start.unwrap_or_else(|| self.alloc_start());
+        let c = match self.find_free_cluster(start) {
+            Err(Error::NoSpace) if start > 2 => self.find_free_cluster(2)?,
+            r => r?,
+        };
+        self.note_allocated(c);
+        Ok(c)
+    }
+
+    /// First cluster a new chain is looked for from.
+    fn alloc_start(&self) -> u32 {
+        match self.options.allocation {
+            AllocPolicy::Pack => 2,
+            AllocPolicy::Rotate => self.next_alloc.get(),
+        }
+    }
+
+    fn note_allocated(&self, c: u32) {
+        let next = if c >= max_cluster(&self.bpb) { 2 } else { c + 1 };
+        self.next_alloc.set(next);
+    }
+
+    /// Update both dirty indicators: the clean-shutdown bit in FAT[1]
+    /// and the flags byte of the boot sector.
+    fn set_dirty_flags(&mut self, dirty: bool) -> Result<()> {
+        fs_debug!("fat32: set dirty flags = {}", dirty);
+        let fat1 = self.read_fat(1)?;
+        let fat1 = if dirty { fat1 & !FAT1_CLEAN_SHUTDOWN } else { fat1 | FAT1_CLEAN_SHUTDOWN };
+        self.write_fat(1, fat1)?;
+
+        let mut boot = [0u8; 512];
+        self.read_sector(Operation::ReadBoot, 0, &mut boot)?;
+        if dirty {
+            boot[BOOT_FLAGS_OFFSET] |= BOOT_FLAG_DIRTY;
+        } else {
+            boot[BOOT_FLAGS_OFFSET] &= !BOOT_FLAG_DIRTY;
+        }
+        self.write_sector(Operation::WriteBoot, 0, &boot)
+    }
+
+    /// Return parsed BPB info.
+    pub fn bpb(&self) -> &Bpb {
+        &self.bpb
+    }
+
+    /// Compare the other FAT copies against the active one and report divergent sector ranges.
+    ///
+    /// Writes only ever go to the active FAT (`MountOptions::fat_to_use`, FAT #0 by default),
+    /// so mirrors drift after every mutation until `resync_fat_copies` is called.
+    #[cfg(feature = "alloc")]
+    pub fn compare_fat_copies(&self) -> Result<Vec<FatMismatch>> {
+        compare_fats(&self.dev, &self.bpb, self.options.fat_to_use)
+    }
+
+    /// Overwrite every other FAT copy with FAT `source` (0 = primary).
+    ///
+    /// Use `source = 0` to refresh the mirrors, or a mirror index to restore a damaged primary.
+    /// Fails with `InvalidInput` inside a transaction, whose FAT changes are not on the
+    /// device yet; call it after the commit.
+    pub fn resync_fat_copies(&mut self, source: u8) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if self.atomic_depth > 0 {
+            return Err(Error::InvalidInput);
+        }
+        if source >= self.bpb.num_fats {
+            return Err(Error::InvalidFatIndex);
+        }
+        self.mark_dirty()?;
+        self.write_deferred_fat()?;
+        let mut a = [0u8; 512];
+        let mut b = [0u8; 512];
+        for copy in (0..self.bpb.num_fats).filter(|&i| i != source) {
+            for s in 0..self.bpb.fat_size_32 as u64 {
+                let lba = fat_copy_lba(&self.bpb, copy) + s;
+                self.read_sector(Operation::ReadFat, fat_copy_lba(&self.bpb, source) + s, &mut a)?;
+                self.read_sector(Operation::ReadFat, lba, &mut b)?;
+                if a != b {
+                    fs_trace!("fat32: resync FAT{} sector {} from FAT{}", copy, s, source);
+                    self.write_sector_direct(Operation::WriteFat, lba, &a)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the root directory entries (8.3 only, skipping LFN in this MVP).
+    ///
+    /// The volume label is not listed; use `list_root_with` to include it.
+    #[cfg(feature = "alloc")]
+    pub fn list_root(&self) -> Result<Vec<DirEntry>> {
+        self.list_dir(self.bpb.root_cluster)
+    }
+
+    /// Read the entries of the directory starting at `dir_cluster`, e.g. the
+    /// `first_cluster` of a directory entry, like `list_root` does for the root.
+    #[cfg(feature = "alloc")]
+    pub fn list_dir(&self, dir_cluster: u32) -> Result<Vec<DirEntry>> {
+        self.dir_entries(dir_cluster)
+    }
+
+    /// Read the entries of directory `path` (`/`-separated 8.3 components).
+    #[cfg(feature = "alloc")]
+    pub fn list_dir_path(&self, path: &str) -> Result<Vec<DirEntry>> {
+        self.dir_entries(self.resolve_dir(Path::new(path).components()?)?)
+    }
+
+    /// Read the root directory entries selected by `options`.
+    #[cfg(feature = "alloc")]
+    pub fn list_root_with(&self, options: &ListOptions) -> Result<Vec<DirEntry>> {
+        self.dir_entries_with(self.bpb.root_cluster, options)
+    }
+
+    /// Read the entries of the directory starting at `dir_cluster` with the
+    /// default `ListOptions`.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn dir_entries(&self, dir_cluster: u32) -> Result<Vec<DirEntry>> {
+        self.dir_entries_with(dir_cluster, &ListOptions::default())
+    }
+
+    /// Read the entries of the directory starting at `dir_cluster` selected by
+    /// `options`. LFN records are never returned themselves.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn dir_entries_with(&self, dir_cluster: u32, options: &ListOptions) -> Result<Vec<DirEntry>> {
+        ReadDir::new(self, dir_cluster, *options).collect()
+    }
+
+    /// Read a file by short name (8.3 only) from root directory.
+    #[cfg(feature = "alloc")]
+    pub fn read_file_root(&self, name: &str) -> Result<Vec<u8>> {
+        let target = self.short_name(name)?;
+        let entries = self.list_root()?;
+
+        let mut found = None;
+        for e in entries {
+            if e.raw_name == target {
+                found = Some(e);
+                break;
+            }
+        }
+        self.read_entry_data(&found.ok_or(Error::NotFound)?)
+    }
+
+    /// Read the whole content of the file described by `e`.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn read_entry_data(&self, e: &DirEntry) -> Result<Vec<u8>> {
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        if e.first_cluster < 2 {
+            return Err(self.record(Operation::ReadData, None, Some(e.first_cluster), Error::Corrupt));
+        }
+
+        // Whole sectors are read straight into `data`, one device call per
+        // run of contiguous clusters, and the tail is cut off at the end.
+        let cluster_bytes = self.bpb.sectors_per_cluster as usize * 512;
+        let mut data = alloc::vec![0u8; (e.file_size as usize).div_ceil(512) * 512];
+        let mut done = 0;
+        let mut cluster = e.first_cluster;
+
+        while done < data.len() {
+            let run_start = cluster;
+            let mut run_len = 1;
+            let mut next = None;
+            while done + run_len * cluster_bytes < data.len() {
+                let n = self.read_fat(cluster)?;
+                if n >= EOC_MIN {
+                    return Err(self.record(Operation::ReadData, None, Some(cluster), Error::UnexpectedEof));
+                }
+                if n != cluster + 1 {
+                    next = Some(n);
+                    break;
+                }
+                cluster = n;
+                run_len += 1;
+            }
+            let end = (done + run_len * cluster_bytes).min(data.len());
+            let lba = cluster_to_lba(&self.bpb, run_start)?;
+            self.read_sectors(Operation::ReadData, lba, &mut data[done..end])?;
+            done = end;
+            if let Some(n) = next {
+                cluster = n;
+            }
+        }
+
+        data.truncate(e.file_size as usize);
+        Ok(data)
+    }
+
+    /// Stream file `path` (`/`-separated 8.3 components) to `f`, one sector at a time.
+    ///
+    /// Each call gets the next slice of the file straight from the sector
+    /// buffer (the last one cut to the file size), so nothing is allocated
+    /// and nothing is copied twice.
+    pub fn read_file_with(&self, path: &str, mut f: impl FnMut(&[u8])) -> Result<()> {
+        let (e, _, _) = self.find_path(path)?;
+        if e.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        let mut remaining = e.file_size as usize;
+        let mut cluster = e.first_cluster;
+        let mut buf = [0u8; 512];
+        while remaining > 0 {
+            if !(2..EOC_MIN).contains(&cluster) {
+                return Err(self.record(Operation::ReadData, None, Some(cluster), Error::UnexpectedEof));
+            }
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                if remaining == 0 {
+                    break;
+                }
+                self.read_sector(Operation::ReadData, base_lba + s, &mut buf)?;
+                let take = remaining.min(512);
+                f(&buf[..take]);
+                remaining -= take;
+            }
+            if remaining > 0 {
+                cluster = self.read_fat(cluster)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the start of file `path` into `buf`, returning the bytes copied:
+    /// the file size, or `buf.len()` if the file is larger.
+    pub fn read_file_into(&self, path: &str, buf: &mut [u8]) -> Result<usize> {
+        self.read_chunks(path, 512)?.read_into(buf)
+    }
+
+    /// Create or overwrite a root file (8.3) and write `content` persistently.
+    ///
+    /// Data is written first, then the FAT chain (from its end), then the
+    /// directory entry, so an interrupted call leaves at most a lost chain.
+    ///
+    /// An existing file is replaced: its entry is pointed at the new chain and
+    /// the old chain freed last.
+    ///
+    /// MVP limitations:
+    /// - writes FAT #0 only (not mirrored to FAT #1 if present)
+    /// - writes into root directory only
+    #[cfg(feature = "alloc")]
+    pub fn write_file_root(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.write_file_root_with(name, content, &CreateOptions::default())
+    }
+
+    /// Like `write_file_root`, giving a newly created file the attributes in `options`.
+    #[cfg(feature = "alloc")]
+    pub fn write_file_root_with(&mut self, name: &str, content: &[u8], options: &CreateOptions) -> Result<()> {
+        let short = self.short_name(name)?;
+        let root = self.bpb.root_cluster;
+        let case = nt_case_flags(name);
+        self.atomic(|fs| fs.write_file_in(root, short, case, content, options))
+    }
+
+    /// Create a file named `short` holding `content` in the directory at `dir_cluster`.
+    ///
+    /// `case` holds the NT case flags for the entry (see `dir::nt_case_flags`);
+    /// `options` only applies if the entry is new.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn write_file_in(
+        &mut self,
+        dir_cluster: u32,
+        short: [u8; 11],
+        case: u8,
+        content: &[u8],
+        options: &CreateOptions,
+    ) -> Result<()> {
+        let clusters_needed = clusters_for_len(&self.bpb, content.len());
+        if clusters_needed == 0 {
+            return Err(Error::InvalidName);
+        }
+        let existing = self.find_entry(dir_cluster, &short)?;
+        if let Some((e, lba, slot)) = &existing {
+            if e.attr & ATTR_DIRECTORY != 0 {
+                return Err(Error::IsADirectory);
+            }
+            self.check_not_read_only(e)?;
+            self.check_not_open(*lba, *slot)?;
+        }
+        self.mark_dirty()?;
+
+        // 1) Pick free clusters
+        let chain = self.pick_free_clusters(clusters_needed)?;
+
+        // 2) Write data to clusters: whole sectors straight from `content`, one
+        // device call per run of contiguous clusters, then the zero-padded rest
+        let cluster_bytes = self.bpb.sectors_per_cluster as usize * 512;
+        let mut offset = 0usize;
+        let mut i = 0;
+        while i < chain.len() {
+            let run = 1 + chain[i + 1..].iter().zip(chain[i] + 1..).take_while(|(&c, want)| c == *want).count();
+            let lba = cluster_to_lba(&self.bpb, chain[i])?;
+            let end = offset + run * cluster_bytes;
+            let whole = (end.min(content.len()) - offset) / 512 * 512;
+            if whole > 0 {
+                self.write_sectors_direct(Operation::WriteData, lba, &content[offset..offset + whole])?;
+            }
+            for (s, pos) in (offset + whole..end).step_by(512).enumerate() {
+                let mut sector = [0u8; 512];
+                if pos < content.len() {
+                    let take = (content.len() - pos).min(512);
+                    sector[..take].copy_from_slice(&content[pos..pos + take]);
+                }
+                self.write_sector(Operation::WriteData, lba + (whole / 512 + s) as u64, &sector)?;
+            }
+            offset = end;
+            i += run;
+        }
+
+        // 3) Link the chain, then point the existing entry at it or create one
+        //    (first free slot); a failure releases the new clusters.
+        let first_cluster = chain[0];
+        let size = content.len() as u32;
+        let linked = self.link_chain(&chain).and_then(|()| match &existing {
+            Some((_, lba, slot)) => self.update_dir_entry(*lba, *slot, first_cluster, size),
+            None => {
+                let mut rec = DirEntry::build_short_entry(short, options.attr(), first_cluster, size);
+                rec[12] = case;
+                self.write_dir_entry_first_free(dir_cluster, &rec).map(|_| ())
+            }
+        });
+        if let Err(e) = linked {
+            self.release_clusters(&chain);
+            return Err(e);
+        }
+
+        if let Some((old, _, _)) = existing {
+            if old.first_cluster != 0 {
+                self.free_chain(old.first_cluster)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a root file (8.3).
+    ///
+    /// The entry is marked deleted before its chain is freed, so an interrupted
+    /// call leaves at most a lost chain.
+    pub fn remove_file_root(&mut self, name: &str) -> Result<()> {
+        self.atomic(|fs| fs.remove_file_root_inner(name))
+    }
+
+    fn remove_file_root_inner(&mut self, name: &str) -> Result<()> {
+        self.remove_file_in(self.bpb.root_cluster, name)
+    }
+
+    /// Delete file `name` from the directory at `dir_cluster` (call inside `atomic`).
+    pub(crate) fn remove_file_in(&mut self, dir_cluster: u32, name: &str) -> Result<()> {
+        let short = self.short_name(name)?;
+        let (e, lba, slot) = self.find_entry(dir_cluster, &short)?.ok_or(Error::NotFound)?;
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        self.check_not_read_only(&e)?;
+        self.check_not_open(lba, slot)?;
+        self.mark_dirty()?;
+
+        self.delete_entry(lba, slot)?;
+        if e.first_cluster != 0 {
+            self.free_chain(e.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the entry at (`lba`, `slot`) deleted.
+    pub(crate) fn delete_entry(&mut self, lba: u64, slot: usize) -> Result<()> {
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        buf[slot * 32] = 0xE5;
+        fs_debug!("fat32: delete entry lba {} slot {}", lba, slot);
+        self.write_sector(Operation::WriteDir, lba, &buf)
+    }
+
+    /// Delete file `path`, first overwriting its data with zeros.
+    ///
+    /// Every data cluster is overwritten before the directory entry, along
+    /// with the long-name records in front of it, is wiped and the chain freed.
+    /// Wear-levelling media (SD cards, USB sticks) may still keep old copies.
+    pub fn remove_file_secure(&mut self, path: &str) -> Result<()> {
+        self.remove_file_secure_with(path, 0)
+    }
+
+    /// `remove_file_secure`, overwriting the data with `pattern` instead of zeros.
+    pub fn remove_file_secure_with(&mut self, path: &str, pattern: u8) -> Result<()> {
+        let (parent, name) = Path::new(path).split_last()?;
+        let dir = self.resolve_dir(parent)?;
+        let (e, lba, slot) = self.find_entry(dir, &self.short_name(name)?)?.ok_or(Error::NotFound)?;
+        if e.attr & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        self.check_not_read_only(&e)?;
+        self.check_not_open(lba, slot)?;
+        self.mark_dirty()?;
+
+        let fill = [pattern; 512];
+        let max = max_cluster(&self.bpb);
+        let mut c = e.first_cluster;
+        let mut visited = 0;
+        while (2..=max).contains(&c) && visited < max {
+            let base_lba = cluster_to_lba(&self.bpb, c)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                self.write_sector(Operation::WriteData, base_lba + s, &fill)?;
+            }
+            c = self.read_fat(c)?;
+            visited += 1;
+        }
+        fs_debug!("fat32: overwrote {} clusters of {}", visited, path);
+
+        self.atomic(|fs| {
+            fs.wipe_dir_entry(dir, lba, slot)?;
+            if e.first_cluster != 0 {
+                fs.free_chain(e.first_cluster)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Overwrite every free cluster with zeros, then trim it if `trim` is set.
+    ///
+    /// For sanitizing a device before it is decommissioned, or making an
+    /// image compress well. Returns the number of clusters wiped.
+    ///
+    /// Deferred FAT writes are flushed first, so only clusters free on the
+    /// device are touched; inside a transaction the trims wait for the commit.
+    pub fn wipe_free_space(&mut self, trim: bool) -> Result<u32> {
+        self.wipe_free_space_cancellable(trim, Cancel::NEVER)
+    }
+
+    /// `wipe_free_space`, polling `cancel` before each cluster.
+    ///
+    /// Clusters wiped before cancellation stay zeroed but are not trimmed.
+    pub fn wipe_free_space_cancellable(&mut self, trim: bool, cancel: Cancel<'_>) -> Result<u32> {
+        self.check_writable()?;
+        // Only wipe clusters the FAT on the device shows free.
+        self.write_deferred_fat()?;
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let zero = [0u8; 512];
+        let mut wiped = 0;
+        // Contiguous run of wiped sectors not trimmed yet: (first LBA, count).
+        let mut run: Option<(u64, u64)> = None;
+        let mut c = 2;
+        loop {
+            let free = match self.find_free_cluster(c) {
+                Ok(free) => free,
+                Err(Error::NoSpace) => break,
+                Err(e) => return Err(e),
+            };
+            cancel.check()?;
+            let base_lba = cluster_to_lba(&self.bpb, free)?;
+            for s in 0..spc {
+                self.write_sector(Operation::WriteData, base_lba + s, &zero)?;
+            }
+            wiped += 1;
+            if trim {
+                run = match run {
+                    Some((start, count)) if start + count == base_lba => Some((start, count + spc)),
+                    Some((start, count)) => {
+                        self.trim_freed(start, count)?;
+                        Some((base_lba, spc))
+                    }
+                    None => Some((base_lba, spc)),
+                };
+            }
+            c = free + 1;
+        }
+        if let Some((start, count)) = run {
+            self.trim_freed(start, count)?;
+        }
+        fs_debug!("fat32: wiped {} free clusters", wiped);
+        Ok(wiped)
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        self.dev.trim(lba, count).map_err(|e| self.record(Operation::Trim, Some(lba), None, e))
+    }
+
+    /// Trim free sectors, or queue them while a transaction or deferred FAT
+    /// writes may still hand their clusters back: a rollback or power loss
+    /// would otherwise leave files pointing at erased data.
+    fn trim_freed(&mut self, lba: u64, count: u64) -> Result<()> {
+        #[cfg(feature = "alloc")]
+        if self.atomic_depth > 0 || !self.fat_cache.is_empty() {
+            self.pending_trims.push((lba, count));
+            return Ok(());
+        }
+        self.trim(lba, count)
+    }
+
+    /// Issue the trims queued by `trim_freed` now that the FAT is on the device.
+    #[cfg(feature = "alloc")]
+    fn issue_pending_trims(&mut self) {
+        for (lba, count) in core::mem::take(&mut self.pending_trims) {
+            self.discard(lba, count);
+        }
+    }
+
+    /// Zero the entry at `lba`/`slot` and the long-name records right before it,
+    /// leaving each marked deleted. The records may start in the previous
+    /// cluster of the directory chain beginning at `dir_cluster`.
+    fn wipe_dir_entry(&mut self, dir_cluster: u32, mut lba: u64, mut slot: usize) -> Result<()> {
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let mut cluster_start = lba - (lba - data_start_lba(&self.bpb)) % spc;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let mut changed = false;
+        let mut first = true;
+        loop {
+            let rec = &mut buf[slot * 32..slot * 32 + 32];
+            if !first && (rec[11] != ATTR_LFN || rec[0] == 0xE5) {
+                break;
+            }
+            rec.fill(0);
+            rec[0] = 0xE5;
+            changed = true;
+            first = false;
+            if slot > 0 {
+                slot -= 1;
+                continue;
+            }
+            self.write_sector(Operation::WriteDir, lba, &buf)?;
+            changed = false;
+            if lba == cluster_start {
+                let cluster = ((cluster_start - data_start_lba(&self.bpb)) / spc) as u32 + 2;
+                let Some(prev) = self.prev_cluster(dir_cluster, cluster)? else {
+                    break;
+                };
+                cluster_start = cluster_to_lba(&self.bpb, prev)?;
+                lba = cluster_start + spc;
+            }
+            lba -= 1;
+            slot = 15;
+            self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        }
+        if changed {
+            self.write_sector(Operation::WriteDir, lba, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// The cluster before `cluster` in the chain starting at `first`, if any.
+    fn prev_cluster(&self, first: u32, cluster: u32) -> Result<Option<u32>> {
+        let mut prev = None;
+        for c in self.chain(first) {
+            let c = c?;
+            if c == cluster {
+                return Ok(prev);
+            }
+            prev = Some(c);
+        }
+        Ok(None)
+    }
+
+    /// Store `rec` in the first free slot of the directory starting at `dir_cluster`.
+    ///
+    /// When every slot is used, the directory grows by one zeroed cluster
+    /// (up to the FAT limit of 65536 entries). Returns the sector and slot used.
+    pub(crate) fn write_dir_entry_first_free(&mut self, dir_cluster: u32, rec: &[u8; 32]) -> Result<(u64, usize)> {
+        self.write_dir_entries(dir_cluster, core::slice::from_ref(rec))
+    }
+
+    /// Store `recs` in consecutive slots of the directory starting at
+    /// `dir_cluster`, e.g. long-name records followed by their short entry.
+    ///
+    /// Takes the first run of free slots long enough, which may cross sector
+    /// and cluster boundaries. Without one, the directory grows by as many
+    /// zeroed clusters as needed (up to 65536 entries). The records are written
+    /// in order, so the last one lands last. Returns its sector and slot.
+    ///
+    /// Fails with `InvalidInput` for more records than one entry can take.
+    pub(crate) fn write_dir_entries(&mut self, dir_cluster: u32, recs: &[[u8; 32]]) -> Result<(u64, usize)> {
+        if recs.is_empty() || recs.len() > MAX_ENTRY_RECORDS {
+            return Err(Error::InvalidInput);
+        }
+        let (run, past_end) = self.find_free_slots(dir_cluster, recs.len())?;
+        let mut i = 0;
+        while i < run.len() {
+            let (cluster, lba, _) = run[i];
+            let mut buf = [0u8; 512];
+            self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+            while i < run.len() && run[i].1 == lba {
+                let slot = run[i].2;
+                buf[slot * 32..slot * 32 + 32].copy_from_slice(&recs[i]);
+                i += 1;
+            }
+            if i == run.len() && past_end {
+                // Everything past the old end marker is free; clear it so
+                // the directory still ends right after the new entries.
+                let last = run[i - 1].2;
+                buf[(last + 1) * 32..].fill(0);
+                if last == 15 {
+                    self.clear_dir_sector_after(cluster, lba)?;
+                }
+            }
+            self.write_sector(Operation::WriteDir, lba, &buf)?;
+        }
+        let (_, lba, slot) = run[run.len() - 1];
+        fs_debug!("fat32: {} dir entries ending at lba {} slot {}", recs.len(), lba, slot);
+        Ok((lba, slot))
+    }
+
+    /// Find `count` consecutive free slots as `(cluster, lba, slot)`, growing
+    /// the directory if needed. The flag is set when the run extends past the
+    /// end-of-directory marker into slots that may hold stale records.
+    fn find_free_slots(&mut self, dir_cluster: u32, count: usize) -> Result<(SlotRun, bool)> {
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let entries_per_cluster = spc as usize * 16;
+        let mut run = SlotRun::new();
+        let mut past_end = false;
+        let mut cluster = dir_cluster;
+        let mut clusters = 0usize;
+
+        loop {
+            clusters += 1;
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..spc {
+                let lba = base_lba + s;
+                let mut buf = [0u8; 512];
+                self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+                for i in 0..16 {
+                    let first = buf[i * 32];
+                    if past_end || first == 0x00 || first == 0xE5 {
+                        past_end |= first == 0x00;
+                        run.push((cluster, lba, i));
+                        if run.len() == count {
+                            return Ok((run, past_end));
+                        }
+                    } else {
+                        run.clear();
+                    }
+                }
+            }
+
+            let next = self.read_fat(cluster)?;
+            if next >= EOC_MIN {
+                break;
+            }
+            if next < 2 || clusters > max_cluster(&self.bpb) as usize {
+                return Err(self.record(Operation::WriteDir, None, Some(cluster), Error::Corrupt));
+            }
+            cluster = next;
+        }
+
+        // The run (possibly empty) reaches the end of the chain: continue it
+        // into new clusters.
+        let grow = (count - run.len()).div_ceil(entries_per_cluster);
+        if (clusters + grow) * entries_per_cluster > 65536 {
+            return Err(Error::DirFull);
+        }
+        for _ in 0..grow {
+            cluster = self.grow_dir(cluster)?;
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for k in 0..entries_per_cluster.min(count - run.len()) {
+                run.push((cluster, base_lba + (k / 16) as u64, k % 16));
+            }
+        }
+        // New clusters are zeroed, so the end marker follows the run already.
+        Ok((run, false))
+    }
+
+    /// Free 32-byte slots (never used or deleted) in the clusters directory
+    /// `path` currently owns.
+    ///
+    /// Once this reaches 0 the next new entry grows the directory by a
+    /// cluster, or fails with `DirFull` at the 65536-entry limit.
+    pub fn dir_free_slots(&self, path: &str) -> Result<u32> {
+        let first = self.resolve_dir(Path::new(path).components()?)?;
+        let max = max_cluster(&self.bpb);
+        let mut free = 0;
+        let mut cluster = first;
+        let mut clusters = 0;
+        while (2..=max).contains(&cluster) {
+            clusters += 1;
+            if clusters > max {
+                return Err(self.record(Operation::ReadDir, None, Some(first), Error::Corrupt));
+            }
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.read_sector(Operation::ReadDir, base_lba + s, &mut buf)?;
+                free += buf.chunks_exact(32).filter(|r| r[0] == 0x00 || r[0] == 0xE5).count() as u32;
+            }
+            cluster = self.read_fat(cluster)?;
+        }
+        Ok(free)
+    }
+
+    /// Zero the directory sector following `lba` (in `cluster` or the next
+    /// cluster of the chain) unless it already starts with an end marker.
+    fn clear_dir_sector_after(&mut self, cluster: u32, lba: u64) -> Result<()> {
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let next = if lba + 1 < cluster_to_lba(&self.bpb, cluster)? + spc {
+            lba + 1
+        } else {
+            match self.read_fat(cluster)? {
+                c if (2..EOC_MIN).contains(&c) => cluster_to_lba(&self.bpb, c)?,
+                _ => return Ok(()),
+            }
+        };
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, next, &mut buf)?;
+        if buf[0] != 0x00 {
+            self.write_sector(Operation::WriteDir, next, &[0u8; 512])?;
+        }
+        Ok(())
+    }
+
+    /// Append a zeroed cluster after `last` (the directory's final cluster)
+    /// and return it.
+    fn grow_dir(&mut self, last: u32) -> Result<u32> {
+        let new = self.find_alloc_cluster(Some(last + 1))?;
+        fs_debug!("fat32: grow directory cluster {} -> {}", last, new);
+        let grown = self
+            .write_fat(new, 0x0FFFFFFF)
+            .and_then(|()| self.zero_cluster(new))
+            .and_then(|()| self.write_fat(last, new));
+        if let Err(e) = grown {
+            self.release_clusters(&[new]);
+            return Err(e);
+        }
+        Ok(new)
+    }
+
+    /// Find the short entry named `name_83` in the directory starting at `dir_cluster`.
+    ///
+    /// Returns the parsed entry with the sector and slot holding it.
+    pub(crate) fn find_entry(&self, dir_cluster: u32, name_83: &[u8; 11]) -> Result<Option<(DirEntry, u64, usize)>> {
+        let mut cluster = dir_cluster;
+        loop {
+            let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+            for s in 0..(self.bpb.sectors_per_cluster as u64) {
+                let mut buf = [0u8; 512];
+                self.read_sector(Operation::ReadDir, base_lba + s, &mut buf)?;
+                for i in 0..16 {
+                    let mut rec = [0u8; 32];
+                    rec.copy_from_slice(&buf[i * 32..i * 32 + 32]);
+                    if rec[0] == 0x00 {
+                        return Ok(None);
+                    }
+                    if rec[0] == 0xE5 || rec[11] == ATTR_LFN || &rec[0..11] != name_83 {
+                        continue;
+                    }
+                    if let Some(e) = DirEntry::parse(&rec)? {
+                        let e = e.located(cluster, (s * 512) as u32 + i as u32 * 32);
+                        return Ok(Some((e, base_lba + s, i)));
+                    }
+                }
+            }
+
+            let next = self.read_fat(cluster)?;
+            if next >= EOC_MIN {
+                return Ok(None);
+            }
+            if next < 2 {
+                return Err(self.record(Operation::ReadDir, None, Some(cluster), Error::Corrupt));
+            }
+            cluster = next;
+        }
+    }
+
+    /// Rewrite the first cluster and size of the entry at (`lba`, `slot`) and
+    /// set its archive attribute.
+    pub(crate) fn update_dir_entry(&mut self, lba: u64, slot: usize, first_cluster: u32, size: u32) -> Result<()> {
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let rec = &mut buf[slot * 32..slot * 32 + 32];
+        rec[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        rec[26..28].copy_from_slice(&((first_cluster & 0xFFFF) as u16).to_le_bytes());
+        rec[28..32].copy_from_slice(&size.to_le_bytes());
+        rec[11] |= ATTR_ARCHIVE;
+        fs_debug!("fat32: update entry lba {} slot {} cluster {} size {}", lba, slot, first_cluster, size);
+        self.write_sector(Operation::WriteDir, lba, &buf)
+    }
+
+    /// Allocate a free cluster, mark it end-of-chain and link it after `prev` if given.
+    pub(crate) fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32> {
+        let c = self.find_alloc_cluster(prev.map(|p| p + 1))?;
+        fs_trace!("fat32: allocate cluster {}", c);
+        self.write_fat(c, 0x0FFFFFFF)?;
+        if let Some(p) = prev {
+            self.write_fat(p, c)?;
+        }
+        Ok(c)
+    }
+
+    /// Pick `count` free clusters without marking them, in search order: increasing,
+    /// except that an `AllocPolicy::Rotate` search may wrap around once.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn pick_free_clusters(&self, count: usize) -> Result<Vec<u32>> {
+        let mut chain: Vec<u32> = Vec::with_capacity(count);
+        let mut next_search = None;
+        let mut wrapped = false;
+        for _ in 0..count {
+            let c = self.find_alloc_cluster(next_search)?;
+            if let (Some(&first), Some(&last)) = (chain.first(), chain.last()) {
+                // Once the search wraps around, reaching `first` means every free cluster is taken.
+                wrapped |= c < last;
+                if wrapped && c >= first {
+                    return Err(Error::NoSpace);
+                }
+            }
+            fs_trace!("fat32: allocate cluster {}", c);
+            chain.push(c);
+            next_search = Some(c + 1);
+        }
+        Ok(chain)
+    }
+
+    /// Link `chain` in the FAT back to front, so every prefix written is a valid chain.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn link_chain(&mut self, chain: &[u32]) -> Result<()> {
+        for i in (0..chain.len()).rev() {
+            let val = if i + 1 < chain.len() { chain[i + 1] } else { 0x0FFFFFFF };
+            self.write_fat(chain[i], val)?;
+        }
+        Ok(())
+    }
+
+    /// Number of clusters in the chain starting at `first` (0 for cluster 0).
+    ///
+    /// Stops at the end marker or the first out-of-range value; fails with
+    /// `Corrupt` on a chain longer than the volume (a loop).
+    #[cfg(feature = "alloc")]
+    pub(crate) fn chain_len(&self, first: u32) -> Result<u32> {
+        let max = max_cluster(&self.bpb);
+        let mut len = 0;
+        let mut c = first;
+        while (2..=max).contains(&c) {
+            len += 1;
+            if len > max {
+                return Err(self.record(Operation::ReadFat, None, Some(first), Error::Corrupt));
+            }
+            c = self.read_fat(c)?;
+        }
+        Ok(len)
+    }
+
+    /// Mark the clusters of a chain that never got a directory entry free
+    /// again, after a failed write.
+    ///
+    /// Best effort: the caller is already returning an error, so a failure
+    /// here only leaves the clusters lost, as before.
+    pub(crate) fn release_clusters(&mut self, chain: &[u32]) {
+        for &c in chain {
+            let _ = self.write_fat(c, 0);
+        }
+    }
+
+    /// Mark every cluster of the chain starting at `first` free, trimming
+    /// the freed sectors under `MountOptions::discard`.
+    pub(crate) fn free_chain(&mut self, first: u32) -> Result<()> {
+        let max = max_cluster(&self.bpb);
+        let spc = self.bpb.sectors_per_cluster as u64;
+        // Contiguous run of freed sectors not trimmed yet: (first LBA, count).
+        let mut run: Option<(u64, u64)> = None;
+        let mut c = first;
+        while (2..=max).contains(&c) {
+            let next = self.read_fat(c)?;
+            self.write_fat(c, 0)?;
+            if self.options.discard {
+                let lba = cluster_to_lba(&self.bpb, c)?;
+                run = match run {
+                    Some((start, count)) if start + count == lba => Some((start, count + spc)),
+                    Some((start, count)) => {
+                        self.discard(start, count);
+                        Some((lba, spc))
+                    }
+                    None => Some((lba, spc)),
+                };
+            }
+            if next >= EOC_MIN {
+                break;
+            }
+            c = next;
+        }
+        if let Some((start, count)) = run {
+            self.discard(start, count);
+        }
+        Ok(())
+    }
+
+    /// Trim freed sectors (see `trim_freed`). Best effort: the clusters are
+    /// already free, so a device that fails the hint only keeps the stale data around.
+    fn discard(&mut self, lba: u64, count: u64) {
+        if self.trim_freed(lba, count).is_err() {
+            fs_warn!("fat32: discard of {} sectors at {} failed", count, lba);
+        }
+    }
+
+    /// Create directory `path` (8.3 components, see `Path`) and any missing
+    /// parents, like `std::fs::create_dir_all`.
+    ///
+    /// Components that already exist as directories are left alone; one that
+    /// exists as a file fails with `NotADirectory`.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        self.create_dir_all_in(path).map(|_| ())
+    }
+
+    /// `create_dir_all`, returning the first cluster of the last directory.
+    pub(crate) fn create_dir_all_in(&mut self, path: &str) -> Result<u32> {
+        self.create_dirs_in(Path::new(path).components()?)
+    }
+
+    /// Walk `comps` from the root, creating missing directories; returns the last one's cluster.
+    pub(crate) fn create_dirs_in(&mut self, comps: Components<'_>) -> Result<u32> {
+        let mut cluster = self.bpb.root_cluster;
+        for comp in comps {
+            let short = self.short_name(comp)?;
+            let case = nt_case_flags(comp);
+            cluster = match self.find_entry(cluster, &short)? {
+                Some((e, _, _)) if e.attr & ATTR_DIRECTORY != 0 => e.first_cluster,
+                Some(_) => return Err(Error::NotADirectory),
+                None => {
+                    self.mark_dirty()?;
+                    self.atomic(|fs| fs.create_dir_in(cluster, short, case))?
+                }
+            };
+        }
+        Ok(cluster)
+    }
+
+    /// Short name for `name` in the code page of `MountOptions::codepage`.
+    pub(crate) fn short_name(&self, name: &str) -> Result<[u8; 11]> {
+        to_short_name_83_with(name, &self.options.codepage)
+    }
+
+    /// Look up `path` (8.3 components, see `Path`) from the root.
+    ///
+    /// Returns the entry with its sector and slot. The root itself has no
+    /// entry, so an empty path fails with `InvalidName`.
+    pub(crate) fn find_path(&self, path: &str) -> Result<(DirEntry, u64, usize)> {
+        let (parent, name) = Path::new(path).split_last()?;
+        let dir = self.resolve_dir(parent)?;
+        self.find_entry(dir, &self.short_name(name)?)?.ok_or(Error::NotFound)
+    }
+
+    /// First cluster of the directory reached by walking `comps` from the root.
+    pub(crate) fn resolve_dir(&self, comps: Components<'_>) -> Result<u32> {
+        let mut cluster = self.bpb.root_cluster;
+        for comp in comps {
+            let (e, _, _) = self.find_entry(cluster, &self.short_name(comp)?)?.ok_or(Error::NotFound)?;
+            if !e.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            cluster = e.first_cluster;
+        }
+        Ok(cluster)
+    }
+
+    /// Remove the empty directory `path`.
+    ///
+    /// Fails with `DirectoryNotEmpty` if it holds anything besides `.` and `..`.
+    pub fn remove_dir(&mut self, path: &str) -> Result<()> {
+        let (e, lba, slot) = self.find_path(path)?;
+        self.remove_empty_dir(&e, lba, slot)
+    }
+
+    /// Remove the empty directory `e` whose entry is at (`lba`, `slot`).
+    pub(crate) fn remove_empty_dir(&mut self, e: &DirEntry, lba: u64, slot: usize) -> Result<()> {
+        if e.attr & ATTR_DIRECTORY == 0 {
+            return Err(Error::NotADirectory);
+        }
+        self.check_not_read_only(e)?;
+        if ReadDir::new(self, e.first_cluster, ListOptions::default()).next().transpose()?.is_some() {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        self.mark_dirty()?;
+        let first = e.first_cluster;
+        self.atomic(|fs| {
+            fs.delete_entry(lba, slot)?;
+            fs.free_chain(first)
+        })
+    }
+
+    /// Remove directory `path` with everything below it.
+    ///
+    /// The directory entry is deleted first; the chains of the contents are
+    /// freed afterwards, so an interrupted call leaves only lost chains.
+    /// Nothing is removed if any entry in the tree is read-only.
+    #[cfg(feature = "alloc")]
+    pub fn remove_dir_all(&mut self, path: &str) -> Result<()> {
+        let (e, lba, slot) = self.find_path(path)?;
+        if e.attr & ATTR_DIRECTORY == 0 {
+            return Err(Error::NotADirectory);
+        }
+        self.check_not_read_only(&e)?;
+
+        // Collect every chain of the tree before touching anything.
+        let max = max_cluster(&self.bpb);
+        let mut chains = Vec::new();
+        let mut dirs = alloc::vec![e.first_cluster];
+        while let Some(dir) = dirs.pop() {
+            chains.push(dir);
+            if chains.len() > max as usize {
+                return Err(self.record(Operation::ReadDir, None, Some(dir), Error::Corrupt));
+            }
+            for child in self.dir_entries(dir)? {
+                self.check_not_read_only(&child)?;
+                let lba = cluster_to_lba(&self.bpb, child.entry_cluster)? + (child.entry_offset / 512) as u64;
+                self.check_not_open(lba, (child.entry_offset % 512 / 32) as usize)?;
+                if child.attr & ATTR_DIRECTORY != 0 {
+                    dirs.push(child.first_cluster);
+                } else if child.first_cluster != 0 {
+                    chains.push(child.first_cluster);
+                }
+            }
+        }
+
+        self.mark_dirty()?;
+        self.atomic(|fs| fs.delete_entry(lba, slot))?;
+        fs_debug!("fat32: freeing {} chains below {}", chains.len(), path);
+        for first in chains {
+            self.free_chain(first)?;
+        }
+        Ok(())
+    }
+
+    /// Create an empty subdirectory `name_83` inside the directory at `parent_cluster`.
+    ///
+    /// Returns the first cluster of the new directory.
+    pub(crate) fn create_dir_in(&mut self, parent_cluster: u32, name_83: [u8; 11], case: u8) -> Result<u32> {
+        let cluster = self.find_alloc_cluster(None)?;
+        fs_debug!("fat32: create directory {:?} at cluster {}", name_83, cluster);
+        self.write_fat(cluster, 0x0FFFFFFF)?;
+        self.zero_cluster(cluster)?;
+
+        // `.` and `..` records; `..` points to cluster 0 when the parent is the root.
+        let dotdot = if parent_cluster == self.bpb.root_cluster { 0 } else { parent_cluster };
+        let mut buf = [0u8; 512];
+        buf[0..32].copy_from_slice(&DirEntry::build_short_entry(*b".          ", ATTR_DIRECTORY, cluster, 0));
+        buf[32..64].copy_from_slice(&DirEntry::build_short_entry(*b"..         ", ATTR_DIRECTORY, dotdot, 0));
+        self.write_sector(Operation::WriteDir, cluster_to_lba(&self.bpb, cluster)?, &buf)?;
+
+        let mut rec = DirEntry::build_short_entry(name_83, ATTR_DIRECTORY, cluster, 0);
+        rec[12] = case;
+        self.write_dir_entry_first_free(parent_cluster, &rec)?;
+        Ok(cluster)
+    }
+
+    /// Fill every sector of `cluster` with zeros.
+    pub(crate) fn zero_cluster(&mut self, cluster: u32) -> Result<()> {
+        let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+        // A freshly allocated cluster is unreferenced until the FAT links it,
+        // so it is zeroed in place rather than staged.
+        let sectors = self.bpb.sectors_per_cluster as usize;
+        let mut s = 0;
+        while s < sectors {
+            let n = (sectors - s).min(ZEROS.0.len() / 512);
+            self.write_sectors_direct(Operation::WriteDir, base_lba + s as u64, &ZEROS.0[..n * 512])?;
+            s += n;
+        }
+        Ok(())
+    }
+
+    /// Consume the filesystem and return the underlying device (useful in tests).
+    pub fn into_device(self) -> D {
+        self.dev
+    }
+}
+
+/// FAT sectors held back by `MountOptions::defer_fat_writes`, sorted by LBA
+/// with their data side by side, so a run of consecutive sectors can be
+/// written straight from `data`.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+struct FatCache {
+    lbas: Vec<u64>,
+    data: Vec<[u8; 512]>,
+}
+
+#[cfg(feature = "alloc")]
+impl FatCache {
+    fn get(&self, lba: u64) -> Option<&[u8; 512]> {
+        self.lbas.binary_search(&lba).ok().map(|i| &self.data[i])
+    }
+
+    fn insert(&mut self, lba: u64, buf: [u8; 512]) {
+        match self.lbas.binary_search(&lba) {
+            Ok(i) => self.data[i] = buf,
+            Err(i) => {
+                self.lbas.insert(i, lba);
+                self.data.insert(i, buf);
+            }
+        }
+    }
+
+    /// Index range of the cached sectors within `range`.
+    fn span(&self, range: Range<u64>) -> Range<usize> {
+        self.lbas.partition_point(|&l| l < range.start)..self.lbas.partition_point(|&l| l < range.end)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lbas.is_empty()
+    }
+
+    fn overlaps(&self, range: Range<u64>) -> bool {
+        !self.span(range).is_empty()
+    }
+
+    fn remove(&mut self, range: Range<u64>) {
+        let span = self.span(range);
+        self.lbas.drain(span.clone());
+        self.data.drain(span);
+    }
+}
+
+#[cfg(feature = "alloc")]
+pub(crate) fn clusters_for_len(bpb: &Bpb, len: usize) -> usize {
+    let bytes_per_cluster = (bpb.sectors_per_cluster as usize) * 512;
+    len.div_ceil(bytes_per_cluster)
+}
+
+/// Test images that need no `alloc` feature, so `--no-default-features` tests can use them.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::device::MemDevice;
+    use crate::mkfs::{format_with, FormatOptions};
+
+    /// 200-sector volume: 1 sector per cluster, one 2-sector FAT at LBA 32, root cluster 2 at LBA 34.
+    pub(crate) fn make_tiny_fat32_image() -> std::vec::Vec<u8> {
+        let mut dev = MemDevice::zeroed(200);
+        let options = FormatOptions {
+            sectors_per_cluster: Some(1),
+            num_fats: 1,
+            volume_id: Some(0x1234_5678),
+            allow_small: true,
+            ..FormatOptions::default()
+        };
+        format_with(&mut dev, 200, &options).expect("format");
+        dev.into_inner()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+pub(crate) mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fat::read_fat_entry;
+    use crate::fault_device::FaultDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::image::ImageBuilder;
+    use crate::options::OpenMode;
+    use crate::trace_device::TraceDevice;
+
+    #[test]
+    fn mount_and_write_and_read() {
+        let img = make_tiny_fat32_image();
+        let dev = MemDevice::new(img);
+
+        let mut fs = Fat32::mount(dev).expect("mount");
+        fs.write_file_root("HELLO.TXT", b"abc").expect("write");
+
+        let data = fs.read_file_root("HELLO.TXT").expect("read");
+        assert_eq!(data, b"abc");
+    }
+
+    #[test]
+    fn root_dir_grows_when_full() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        // One sector per cluster: 16 slots, the 17th entry needs a second cluster.
+        for i in 0..20u8 {
+            let name = [b'F', b'0' + i / 10, b'0' + i % 10];
+            let name = core::str::from_utf8(&name).unwrap();
+            fs.write_file_root(name, &[i]).expect("write");
+        }
+        assert_eq!(fs.list_root().unwrap().len(), 20);
+        assert_eq!(fs.read_file_root("F19").unwrap(), [19]);
+        assert!(read_fat_entry(&fs.dev, &fs.bpb, 2).unwrap() < EOC_MIN);
+    }
+
+    #[test]
+    fn dir_entries_take_a_contiguous_run() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        for i in 0..14u8 {
+            let name = [b'F', b'0' + i / 10, b'0' + i % 10];
+            fs.write_file_root(core::str::from_utf8(&name).unwrap(), &[i]).unwrap();
+        }
+        for name in ["F01", "F03", "F04", "F05"] {
+            fs.remove_file_root(name).unwrap();
+        }
+        let root = fs.bpb().root_cluster;
+        let rec = |name: &[u8; 11]| DirEntry::build_short_file(*name, 0, 0);
+
+        // Three slots fit the F03..F05 hole; F01's single slot is skipped.
+        let end = fs.write_dir_entries(root, &[rec(b"A1         "), rec(b"A2         "), rec(b"A3         ")]);
+        assert_eq!(end, Ok((34, 5)));
+
+        // Four slots do not fit before the end of the cluster: the run starts at
+        // slot 14 and continues into a new cluster.
+        let end = fs.write_dir_entries(root, &[rec(b"B1         "), rec(b"B2         "), rec(b"B3         "), rec(b"B4         ")]);
+        let second = read_fat_entry(&fs.dev, &fs.bpb, root).unwrap();
+        assert_eq!(end, Ok((cluster_to_lba(&fs.bpb, second).unwrap(), 1)));
+        let names: Vec<_> = fs.list_root().unwrap().iter().map(|e| e.name()).collect();
+        assert_eq!(names[..5], ["F00", "F02", "A1", "A2", "A3"]);
+        assert_eq!(names[12..], ["F13", "B1", "B2", "B3", "B4"]);
+    }
+
+    #[test]
+    fn list_options_select_entries() {
+        use crate::dir::{ATTR_ARCHIVE, ATTR_VOLUME_ID};
+        use crate::metadata::{ATTR_HIDDEN, ATTR_SYSTEM};
+
+        let mut img = make_tiny_fat32_image();
+        let root = 34 * 512;
+        let mut lfn = [0u8; 32];
+        lfn[0] = 0x41;
+        lfn[11] = ATTR_LFN;
+        let recs = [
+            DirEntry::build_short_entry(*b"VOL        ", ATTR_VOLUME_ID, 0, 0),
+            DirEntry::build_short_entry(*b"HID     TXT", ATTR_HIDDEN | ATTR_ARCHIVE, 0, 0),
+            DirEntry::build_short_entry(*b"SYS     TXT", ATTR_SYSTEM, 0, 0),
+            DirEntry::build_short_entry(*b"\xE5DEL    TXT", ATTR_ARCHIVE, 0, 0),
+            lfn,
+            DirEntry::build_short_entry(*b"LONGNA~1TXT", ATTR_ARCHIVE, 0, 0),
+            DirEntry::build_short_entry(*b"PLAIN   TXT", ATTR_ARCHIVE, 0, 0),
+        ];
+        for (i, rec) in recs.iter().enumerate() {
+            img[root + i * 32..root + i * 32 + 32].copy_from_slice(rec);
+        }
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let names = |o: ListOptions| fs.list_root_with(&o).unwrap().iter().map(DirEntry::name).collect::<Vec<_>>();
+
+        assert_eq!(names(ListOptions::default()), ["HID.TXT", "SYS.TXT", "LONGNA~1.TXT", "PLAIN.TXT"]);
+        let label = ListOptions {
+            include_volume_label: true,
+            ..ListOptions::default()
+        };
+        assert_eq!(names(label)[0], "VOL");
+        let visible = ListOptions {
+            include_hidden: false,
+            include_system: false,
+            ..ListOptions::default()
+        };
+        assert_eq!(names(visible), ["LONGNA~1.TXT", "PLAIN.TXT"]);
+        let long = ListOptions {
+            long_names_only: true,
+            ..visible
+        };
+        assert_eq!(names(long), ["LONGNA~1.TXT"]);
+        let deleted = ListOptions {
+            include_deleted: true,
+            ..visible
+        };
+        let all = fs.list_root_with(&deleted).unwrap();
+        assert_eq!(all[0].name(), "?DEL.TXT");
+        assert!(all[0].deleted && !all[1].deleted);
+    }
+
+    #[test]
+    fn create_dir_all_creates_missing_components_only() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.create_dir_all("A/B").unwrap();
+        let a = fs.create_dir_all_in("A").unwrap();
+        let c = fs.create_dir_all_in("/A/B/C/").unwrap();
+        assert_eq!(fs.create_dir_all_in("A/B/C").unwrap(), c);
+        assert_eq!(fs.list_root().unwrap().len(), 1);
+        assert!(fs.find_entry(a, b"B          ").unwrap().is_some());
+
+        fs.write_file_root("F.TXT", b"f").unwrap();
+        assert_eq!(fs.create_dir_all("F.TXT/X"), Err(Error::NotADirectory));
+        assert_eq!(fs.check().unwrap().directories, 4);
+    }
+
+    #[test]
+    fn reads_contiguous_clusters_in_one_call() {
+        struct Counting(MemDevice, Cell<u32>);
+        impl BlockDevice for Counting {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.0.read_sector(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                self.0.write_sector(lba, buf)
+            }
+            fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+                self.1.set(self.1.get() + 1);
+                self.0.read_sectors(lba, buf)
+            }
+        }
+
+        let mut fs = Fat32::mount(Counting(MemDevice::new(make_tiny_fat32_image()), Cell::new(0))).expect("mount");
+        let big: Vec<u8> = (0..2000u32).map(|i| (i % 251) as u8).collect();
+        fs.write_file_root("BIG.BIN", &big).unwrap();
+        assert_eq!(fs.read_file_root("BIG.BIN").unwrap(), big);
+        assert_eq!(fs.dev.1.replace(0), 1);
+
+        // A hole left by a removed file splits the next chain in two runs.
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        fs.write_file_root("B.TXT", b"b").unwrap();
+        fs.remove_file_root("A.TXT").unwrap();
+        fs.write_file_root("SPLIT.BIN", &big[..1100]).unwrap();
+        fs.dev.1.set(0);
+        assert_eq!(fs.read_file_root("SPLIT.BIN").unwrap(), &big[..1100]);
+        assert_eq!(fs.dev.1.get(), 2);
+    }
+
+    #[test]
+    fn read_file_with_streams_sectors() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let data: Vec<u8> = (0..1300u32).map(|i| i as u8).collect();
+        let sub = fs.create_dir_all_in("SUB").unwrap();
+        fs.write_file_in(sub, *b"DATA    BIN", 0, &data, &CreateOptions::default()).unwrap();
+
+        let mut chunks = Vec::new();
+        let mut out = Vec::new();
+        fs.read_file_with("SUB/DATA.BIN", |c| {
+            chunks.push(c.len());
+            out.extend_from_slice(c);
+        })
+        .unwrap();
+        assert_eq!(chunks, [512, 512, 276]);
+        assert_eq!(out, data);
+        assert_eq!(fs.read_file_with("SUB", |_| {}), Err(Error::IsADirectory));
+        let mut buf = [0u8; 2000];
+        assert_eq!(fs.read_file_into("SUB/DATA.BIN", &mut buf), Ok(1300));
+        assert_eq!(&buf[..1300], &data[..]);
+        assert_eq!(fs.read_file_into("SUB/DATA.BIN", &mut buf[..100]), Ok(100));
+        assert_eq!(fs.read_file_with("SUB/NONE.BIN", |_| {}), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn list_subdirectories() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let b = fs.create_dir_all_in("A/B").unwrap();
+        fs.write_file_in(b, *b"X       TXT", 0, b"x", &CreateOptions::default()).unwrap();
+        let a = fs.list_root().unwrap()[0].first_cluster;
+        let names = |v: Vec<DirEntry>| v.iter().map(DirEntry::name).collect::<Vec<_>>();
+        assert_eq!(names(fs.list_dir(a).unwrap()), ["B"]);
+        assert_eq!(names(fs.list_dir(b).unwrap()), ["X.TXT"]);
+        assert_eq!(names(fs.list_dir_path("A/B").unwrap()), ["X.TXT"]);
+        assert_eq!(names(fs.list_dir_path("/").unwrap()), ["A"]);
+        assert_eq!(fs.list_dir_path("A/B/X.TXT").err(), Some(Error::NotADirectory));
+    }
+
+    #[test]
+    fn remove_dir_and_remove_dir_all() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.create_dir_all("A/B/C").unwrap();
+        fs.create_dir_all("A/D").unwrap();
+        let b = fs.create_dir_all_in("A/B").unwrap();
+        fs.write_file_in(b, *b"DATA    BIN", 0, &[1u8; 1500], &CreateOptions::default()).unwrap();
+        fs.create_dir_all("E").unwrap();
+        let free_before = fs.check().unwrap();
+
+        assert_eq!(fs.remove_dir("A"), Err(Error::DirectoryNotEmpty));
+        assert_eq!(fs.remove_dir("A/B/DATA.BIN"), Err(Error::NotADirectory));
+        assert_eq!(fs.remove_dir("A/X"), Err(Error::NotFound));
+        fs.remove_dir("A/D").unwrap();
+        assert_eq!(fs.find_path("A/D").err(), Some(Error::NotFound));
+
+        // An open file anywhere in the tree blocks the removal before anything is deleted.
+        let h = fs.open_handle_in(b, "DATA.BIN", OpenMode::Open).unwrap();
+        assert_eq!(fs.remove_dir_all("A"), Err(Error::AlreadyOpen));
+        assert!(fs.find_path("A/B/DATA.BIN").is_ok());
+        fs.close_handle(h).unwrap();
+
+        fs.remove_dir_all("A").unwrap();
+        assert_eq!(fs.find_path("A").err(), Some(Error::NotFound));
+        let report = fs.check().unwrap();
+        assert!(report.is_clean());
+        assert_eq!((free_before.directories, report.directories), (6, 2));
+        // Everything but the root and E is free again.
+        assert_eq!(fs.find_free_cluster(2).unwrap(), 3);
+        assert_eq!(fs.find_free_cluster(5).unwrap(), 5);
+    }
+
+    #[test]
+    fn dir_free_slots_counts_unused_and_deleted() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        assert_eq!(fs.dir_free_slots("/").unwrap(), 16);
+        fs.create_dir_all("SUB").unwrap();
+        // `.` and `..` take two slots of the new directory.
+        assert_eq!(fs.dir_free_slots("SUB").unwrap(), 14);
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        fs.write_file_root("B.TXT", b"b").unwrap();
+        assert_eq!(fs.dir_free_slots("/").unwrap(), 13);
+        fs.remove_file_root("A.TXT").unwrap();
+        assert_eq!(fs.dir_free_slots("/").unwrap(), 14);
+        assert_eq!(fs.dir_free_slots("B.TXT"), Err(Error::NotADirectory));
+        assert_eq!(fs.dir_free_slots("NOPE"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn dirty_flag_set_on_write_and_cleared_on_unmount() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        assert!(!fs.mounted_dirty());
+        fs.write_file_root("A.TXT", b"a").expect("write");
+        // Simulate power loss: no unmount.
+        let fs = Fat32::mount(fs.into_device()).expect("mount");
+        assert!(fs.mounted_dirty());
+
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("A.TXT", b"a").expect("write");
+        let fs = Fat32::mount(fs.unmount().expect("unmount")).expect("mount");
+        assert!(!fs.mounted_dirty());
+    }
+
+    #[test]
+    fn interrupted_write_and_remove_never_break_chains() {
+        let mut base = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        base.write_file_root("OLD.TXT", &[1u8; 1200]).unwrap();
+        let base = base.unmount().unwrap().into_inner();
+
+        for writes in 0..16 {
+            let dev = FaultDevice::new(MemDevice::new(base.clone())).power_cut_after(writes);
+            let mut fs = Fat32::mount(dev).expect("mount");
+            let _ = fs.remove_file_root("OLD.TXT");
+            let _ = fs.write_file_root("NEW.TXT", &[2u8; 1500]);
+
+            let fs = Fat32::mount(fs.into_device().into_inner()).expect("remount");
+            let report = fs.check().unwrap();
+            assert_eq!((report.broken_chains, report.cross_links), (0, 0), "cut after {} writes", writes);
+            if let Ok(data) = fs.read_file_root("NEW.TXT") {
+                assert_eq!(data, [2u8; 1500]);
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_commits_or_discards_everything() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        // The dirty mark set by a rolled-back first write is set again by the next one.
+        let r = fs.transaction(|txn| txn.write_file_root("X.TXT", b"x").and(Err::<(), _>(Error::Io)));
+        assert_eq!(r, Err(Error::Io));
+        assert_ne!(fs.read_fat(1).unwrap() & FAT1_CLEAN_SHUTDOWN, 0);
+        fs.write_file_root("LOG.TXT", b"old").unwrap();
+        assert_eq!(fs.read_fat(1).unwrap() & FAT1_CLEAN_SHUTDOWN, 0);
+
+        let r: Result<()> = fs.transaction(|txn| {
+            txn.remove_file_root("LOG.TXT")?;
+            txn.write_file_root("LOG.TXT", &[3u8; 700])?;
+            assert_eq!(txn.read_file_root("LOG.TXT")?.len(), 700);
+            Err(Error::Io)
+        });
+        assert_eq!(r, Err(Error::Io));
+        assert_eq!(fs.read_file_root("LOG.TXT").unwrap(), b"old");
+        assert!(fs.check().unwrap().is_clean());
+
+        fs.transaction(|txn| {
+            txn.remove_file_root("LOG.TXT")?;
+            txn.write_file_root("LOG.TXT", b"new")
+        })
+        .unwrap();
+        let fs = Fat32::mount(fs.unmount().unwrap()).expect("remount");
+        assert_eq!(fs.read_file_root("LOG.TXT").unwrap(), b"new");
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn sync_flushes_device() {
+        struct Cached {
+            inner: MemDevice,
+            flushes: u32,
+        }
+        impl BlockDevice for Cached {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.inner.read_sector(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                self.inner.write_sector(lba, buf)
+            }
+            fn flush(&mut self) -> Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let mut dev = Cached {
+            inner: MemDevice::new(make_tiny_fat32_image()),
+            flushes: 0,
+        };
+        let mut fs = Fat32::mount(&mut dev).expect("mount");
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        fs.sync().unwrap();
+        assert!(!Fat32::mount(&mut fs.dev.inner).unwrap().mounted_dirty());
+        fs.unmount().unwrap();
+        assert_eq!(dev.flushes, 2);
+    }
+
+    #[test]
+    fn deferred_fat_writes_reach_disk_on_sync() {
+        let options = MountOptions {
+            defer_fat_writes: true,
+            ..MountOptions::default()
+        };
+        let mut dev = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        let mut fs = Fat32::mount_with(&mut dev, options).expect("mount");
+        let fat = fat_copy_lba(&fs.bpb, 0);
+        fs.write_file_root("BIG.BIN", &[4u8; 40 * 512]).unwrap();
+        fs.write_file_root("SMALL.TXT", b"small").unwrap();
+        assert_eq!(fs.read_file_root("BIG.BIN").unwrap().len(), 40 * 512);
+        fs.remove_file_root("SMALL.TXT").unwrap();
+        // Only the dirty mark went out so far.
+        let fat_writes = |dev: &TraceDevice<MemDevice>| dev.lbas().iter().filter(|&&l| (fat..fat + 2).contains(&l)).count();
+        assert_eq!(fat_writes(fs.dev), 1);
+
+        fs.sync().unwrap();
+        drop(fs);
+        // The chains, then the clean mark.
+        assert_eq!(fat_writes(&dev), 3);
+        let fs = Fat32::mount(dev.into_inner()).expect("remount");
+        assert!(!fs.mounted_dirty());
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.read_file_root("BIG.BIN").unwrap(), [4u8; 40 * 512]);
+    }
+
+    #[test]
+    fn writes_contiguous_sectors_in_one_call() {
+        /// Records the length of every multi-sector write.
+        struct Bulk(MemDevice, Vec<(u64, usize)>);
+        impl BlockDevice for Bulk {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.0.read_sector(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                self.0.write_sector(lba, buf)
+            }
+            fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+                if buf.len() > 512 {
+                    self.1.push((lba, buf.len() / 512));
+                }
+                self.0.write_sectors(lba, buf)
+            }
+        }
+
+        let options = MountOptions {
+            defer_fat_writes: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(Bulk(MemDevice::new(make_tiny_fat32_image()), Vec::new()), options).expect("mount");
+        let big: Vec<u8> = (0..140 * 512 - 100).map(|i| (i % 251) as u8).collect();
+        fs.write_file_root("BIG.BIN", &big).unwrap();
+        // Clusters 3..=142 in one run; the partial last sector goes on its own.
+        assert_eq!(fs.dev.1, [(35, 139)]);
+        fs.dev.1.clear();
+        fs.sync().unwrap();
+        // Both FAT sectors changed; the clean mark rewrites only the first.
+        assert_eq!(fs.dev.1, [(32, 2)]);
+
+        let fs = Fat32::mount(fs.unmount().unwrap().0).expect("remount");
+        assert_eq!(fs.read_file_root("BIG.BIN").unwrap(), big);
+        assert!(fs.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn verify_writes_detects_dropped_writes() {
+        /// Acknowledges writes to data sectors without storing them.
+        struct Marginal(MemDevice);
+        impl BlockDevice for Marginal {
+            fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+                self.0.read_sector(lba, buf)
+            }
+            fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+                if lba >= 33 {
+                    return Ok(());
+                }
+                self.0.write_sector(lba, buf)
+            }
+        }
+
+        let dev = Marginal(MemDevice::new(make_tiny_fat32_image()));
+        let opts = MountOptions {
+            verify_writes: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(dev, opts).expect("mount");
+        assert_eq!(fs.write_file_root("A.TXT", b"a"), Err(Error::VerifyFailed));
+        assert_eq!(fs.last_error().unwrap().op, Operation::WriteData);
+    }
+
+    #[test]
+    fn mount_over_borrowed_device() {
+        let mut dev = MemDevice::new(make_tiny_fat32_image());
+        let mut fs = Fat32::mount(&mut dev).expect("mount");
+        fs.write_file_root("A.TXT", b"a").expect("write");
+        fs.unmount().expect("unmount");
+
+        let fs = Fat32::mount(dev).expect("remount");
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"a");
+    }
+
+    #[test]
+    fn secure_remove_overwrites_data_and_long_name() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("KEEP.TXT", b"keep").unwrap();
+        fs.write_file_root("SECRET.TXT", &[0x5Au8; 1100]).unwrap();
+        let mut img = fs.unmount().unwrap().into_inner();
+
+        // Move the entry to slot 2 and put a long-name record in front of it.
+        let root = 34 * 512;
+        img.copy_within(root + 32..root + 64, root + 64);
+        img[root + 32..root + 64].fill(0);
+        img[root + 32] = 0x41;
+        img[root + 32 + 11] = ATTR_LFN;
+
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("remount");
+        fs.remove_file_secure_with("/SECRET.TXT", 0xA5).unwrap();
+        assert_eq!(fs.read_file_root("SECRET.TXT"), Err(Error::NotFound));
+        assert_eq!(fs.read_file_root("KEEP.TXT").unwrap(), b"keep");
+        assert!(fs.check().unwrap().is_clean());
+
+        let img = fs.unmount().unwrap().into_inner();
+        assert!(img[36 * 512..39 * 512].iter().all(|&b| b == 0xA5));
+        for slot in [1, 2] {
+            let rec = &img[root + slot * 32..root + slot * 32 + 32];
+            assert_eq!(rec[0], 0xE5);
+            assert!(rec[1..].iter().all(|&b| b == 0));
+        }
+
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("remount");
+        fs.create_dir_all("SUB").unwrap();
+        fs.open_dir("SUB").unwrap().create_file("KEY.BIN").unwrap().write(&[0x77u8; 500]).unwrap();
+        let first = fs.metadata("SUB/KEY.BIN").unwrap().first_cluster;
+        fs.remove_file_secure("SUB/KEY.BIN").unwrap();
+        assert!(!fs.exists("SUB/KEY.BIN").unwrap());
+        assert_eq!(fs.remove_file_secure("SUB"), Err(Error::IsADirectory));
+        let img = fs.unmount().unwrap().into_inner();
+        // One sector per cluster, cluster 2 at LBA 34.
+        let key = (first as usize + 32) * 512;
+        assert!(img[key..key + 512].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn secure_remove_wipes_long_name_in_previous_cluster() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("SECRET.TXT", &[0x5Au8; 600]).unwrap();
+        for i in 1..14u8 {
+            let name = [b'F', b'0' + i / 10, b'0' + i % 10];
+            fs.write_file_root(core::str::from_utf8(&name).unwrap(), &[i]).unwrap();
+        }
+
+        // Re-store the entry behind two long-name records in slots 14 and 15,
+        // so the short entry lands in slot 0 of the root's second cluster.
+        let root = fs.bpb().root_cluster;
+        let mut short = [0u8; 512];
+        fs.read_sector(Operation::ReadDir, 34, &mut short).unwrap();
+        let short: [u8; 32] = short[..32].try_into().unwrap();
+        let mut lfn = [[0u8; 32]; 2];
+        for (i, rec) in lfn.iter_mut().enumerate() {
+            rec[0] = if i == 0 { 0x42 } else { 0x01 };
+            rec[11] = ATTR_LFN;
+        }
+        let (lba, slot) = fs.write_dir_entries(root, &[lfn[0], lfn[1], short]).unwrap();
+        fs.delete_entry(34, 0).unwrap();
+        let second = read_fat_entry(&fs.dev, &fs.bpb, root).unwrap();
+        assert_eq!((lba, slot), (cluster_to_lba(&fs.bpb, second).unwrap(), 0));
+
+        fs.remove_file_secure("SECRET.TXT").unwrap();
+        assert!(!fs.exists("SECRET.TXT").unwrap());
+        assert!(fs.check().unwrap().is_clean());
+        let img = fs.unmount().unwrap().into_inner();
+        for at in [34 * 512 + 14 * 32, 34 * 512 + 15 * 32, lba as usize * 512] {
+            assert_eq!(img[at], 0xE5);
+            assert!(img[at + 1..at + 32].iter().all(|&b| b == 0));
+        }
+        assert_eq!(&img[34 * 512 + 13 * 32..][..11], b"F13        ");
+    }
+
+    #[test]
+    fn resync_refuses_transactions_and_records_errors() {
+        let mut fs = Fat32::mount(ImageBuilder::new(200).build_device().unwrap()).expect("mount");
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        assert_eq!(fs.transaction(|fs| fs.resync_fat_copies(0)), Err(Error::InvalidInput));
+        assert_eq!(fs.resync_fat_copies(2), Err(Error::InvalidFatIndex));
+        let img = fs.unmount().unwrap().into_inner();
+
+        let options = MountOptions { verify_writes: true, ..MountOptions::default() };
+        let mut fs = Fat32::mount_with(FaultDevice::new(MemDevice::new(img.clone())).fail_write(3), options).unwrap();
+        assert_eq!(fs.resync_fat_copies(0), Err(Error::Io));
+        let ctx = fs.last_error().unwrap();
+        assert_eq!(ctx.op, Operation::WriteFat);
+        assert_eq!(ctx.lba, Some(fat_copy_lba(&fs.bpb, 1)));
+
+        let mut fs = Fat32::mount_with(MemDevice::new(img), options).unwrap();
+        assert!(!fs.compare_fat_copies().unwrap().is_empty());
+        fs.resync_fat_copies(0).unwrap();
+        assert!(fs.compare_fat_copies().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wipe_free_space_zeroes_and_trims_free_clusters() {
+        let mut dev = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        let mut fs = Fat32::mount(&mut dev).expect("mount");
+        fs.write_file_root("KEEP.TXT", b"keep").unwrap();
+        fs.write_file_root("GONE.TXT", &[0x5Au8; 1100]).unwrap();
+        fs.remove_file_root("GONE.TXT").unwrap();
+        // 166 clusters, KEEP.TXT and the root use two.
+        assert_eq!(fs.wipe_free_space(true), Ok(164));
+        assert_eq!(fs.read_file_root("KEEP.TXT").unwrap(), b"keep");
+        fs.unmount().unwrap();
+
+        assert_eq!(dev.trims(), [(36, 164)]);
+        let img = dev.inner().as_slice();
+        assert!(img[36 * 512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rotating_allocation_spreads_and_wraps() {
+        let rotate = MountOptions {
+            allocation: AllocPolicy::Rotate,
+            ..MountOptions::default()
+        };
+        let first = |fs: &Fat32<MemDevice>, name| fs.find_path(name).unwrap().0.first_cluster;
+        let mut fs = Fat32::mount_with(MemDevice::new(make_tiny_fat32_image()), rotate).expect("mount");
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        fs.remove_file_root("A.TXT").unwrap();
+        fs.write_file_root("B.TXT", b"b").unwrap();
+        assert_eq!(first(&fs, "B.TXT"), 4);
+
+        // The position survives a remount through the FSInfo hint.
+        let mut fs = Fat32::mount_with(fs.unmount().unwrap(), rotate).expect("remount");
+        fs.write_file_root("C.TXT", b"c").unwrap();
+        assert_eq!(first(&fs, "C.TXT"), 5);
+
+        // Cycle through the whole volume; allocation wraps past the last cluster.
+        for _ in 0..200 {
+            fs.write_file_root("LOG.TXT", &[7u8; 1100]).unwrap();
+            fs.remove_file_root("LOG.TXT").unwrap();
+        }
+        fs.write_file_root("LOG.TXT", &[7u8; 1100]).unwrap();
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.read_file_root("LOG.TXT").unwrap(), [7u8; 1100]);
+
+        let mut fs = Fat32::mount(fs.unmount().unwrap()).expect("pack");
+        fs.write_file_root("D.TXT", b"d").unwrap();
+        assert_eq!(first(&fs, "D.TXT"), 3);
+    }
+
+    #[test]
+    fn discard_trims_freed_runs() {
+        let mut dev = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        let opts = MountOptions {
+            discard: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(&mut dev, opts).expect("mount");
+        for name in ["A.TXT", "B.TXT", "C.TXT"] {
+            fs.write_file_root(name, b"x").unwrap();
+        }
+        fs.remove_file_root("B.TXT").unwrap();
+        // Clusters 4, 6 and 7 (LBA 36, 38, 39).
+        fs.write_file_root("D.TXT", &[1u8; 1100]).unwrap();
+        fs.write_file_root("D.TXT", b"short").unwrap();
+        fs.unmount().unwrap();
+        assert_eq!(dev.trims(), [(36, 1), (36, 1), (38, 2)]);
+
+        let mut fs = Fat32::mount(&mut dev).expect("remount");
+        fs.remove_file_root("A.TXT").unwrap();
+        fs.unmount().unwrap();
+        assert_eq!(dev.trims().len(), 3, "no discard unless enabled");
+    }
+
+    #[test]
+    fn discard_waits_for_commit_and_deferred_fat() {
+        let mut dev = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        let opts = MountOptions {
+            discard: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(&mut dev, opts).expect("mount");
+        fs.write_file_root("OLD.LOG", &[5u8; 700]).unwrap();
+        let r: Result<()> = fs.transaction(|txn| {
+            txn.remove_file_root("OLD.LOG")?;
+            Err(Error::Io)
+        });
+        assert_eq!(r, Err(Error::Io));
+        assert_eq!(fs.read_file_root("OLD.LOG").unwrap(), [5u8; 700]);
+        fs.transaction(|txn| {
+            txn.remove_file_root("OLD.LOG")?;
+            assert!(txn.dev.trims().is_empty(), "trimmed before commit");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs.dev.trims(), [(35, 2)]);
+        fs.unmount().unwrap();
+
+        let opts = MountOptions {
+            defer_fat_writes: true,
+            ..opts
+        };
+        let mut fs = Fat32::mount_with(&mut dev, opts).expect("remount");
+        fs.write_file_root("NEW.LOG", b"n").unwrap();
+        fs.flush().unwrap();
+        fs.remove_file_root("NEW.LOG").unwrap();
+        assert_eq!(fs.dev.trims().len(), 1, "trimmed while the FAT was deferred");
+        fs.flush().unwrap();
+        assert_eq!(fs.dev.trims().len(), 2);
+    }
+
+    #[test]
+    fn restore_trashed_boot_sector_from_backup() {
+        let mut img = make_tiny_fat32_image();
+        img[50..52].copy_from_slice(&6u16.to_le_bytes());
+        img.copy_within(0..512, 6 * 512);
+        img[510] = 0; // trash the signature of sector 0
+
+        assert!(Fat32::mount(MemDevice::new(img.clone())).is_err());
+        let fs = Fat32::mount_or_restore(MemDevice::new(img)).expect("mount");
+        assert!(fs.verify_backup_boot_sector().unwrap());
+    }
+
+    #[test]
+    fn read_only_mount_rejects_writes() {
+        let opts = MountOptions {
+            read_only: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(MemDevice::new(make_tiny_fat32_image()), opts).expect("mount");
+        assert_eq!(fs.write_file_root("A.TXT", b"a"), Err(Error::ReadOnlyVolume));
+
+        let opts = MountOptions {
+            fat_to_use: 1,
+            ..MountOptions::default()
+        };
+        let res = Fat32::mount_with(MemDevice::new(make_tiny_fat32_image()), opts);
+        assert!(matches!(res, Err(Error::InvalidFatIndex)));
+    }
+
+    #[test]
+    fn write_protected_device_rejects_writes_up_front() {
+        let mut fs = Fat32::mount(MemDevice::read_only(make_tiny_fat32_image())).expect("mount");
+        assert_eq!(fs.write_file_root("A.TXT", b"a"), Err(Error::WriteProtected));
+        assert_eq!(fs.create_dir_all("LOGS"), Err(Error::WriteProtected));
+        // Refused before any device write, so no failure was recorded.
+        assert_eq!(fs.last_error(), None);
+        assert!(fs.list_root().unwrap().is_empty());
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn lenient_mount_uses_total_sectors_16() {
+        let mut img = make_tiny_fat32_image();
+        img[32..36].copy_from_slice(&0u32.to_le_bytes());
+        img[19..21].copy_from_slice(&200u16.to_le_bytes());
+        img[17] = 0xE0; // stale FAT16 root entry count
+
+        assert!(Fat32::mount(MemDevice::new(img.clone())).is_err());
+        let opts = MountOptions {
+            strict_bpb: false,
+            ..MountOptions::default()
+        };
+        let fs = Fat32::mount_with(MemDevice::new(img), opts).expect("lenient mount");
+        assert_eq!(fs.bpb().total_sectors_32, 200);
+    }
+
+    #[test]
+    fn partition_start_checked_against_hidden_sectors() {
+        let mut img = make_tiny_fat32_image();
+        img[28..32].copy_from_slice(&2048u32.to_le_bytes());
+        let mount = |start, strict_bpb| {
+            let opts = MountOptions {
+                partition_start: Some(start),
+                strict_bpb,
+                ..MountOptions::default()
+            };
+            Fat32::mount_with(MemDevice::new(img.clone()), opts).map(|fs| fs.bpb().absolute_lba(6))
+        };
+        assert_eq!(mount(2048, true), Ok(2054));
+        assert_eq!(mount(63, true), Err(Error::InvalidBootSector));
+        assert_eq!(mount(63, false), Ok(2054));
+    }
+
+    #[test]
+    fn last_error_reports_failing_data_sector() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("A.TXT", b"a").expect("write");
+        // Cut the image right after the root directory cluster (LBA 34).
+        let mut img = fs.into_device().into_inner();
+        img.truncate(35 * 512);
+
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        assert_eq!(fs.read_file_root("A.TXT"), Err(Error::Io));
+        let ctx = fs.last_error().expect("context");
+        assert_eq!(ctx.op, Operation::ReadData);
+        assert_eq!(ctx.lba, Some(35));
+        assert_eq!(ctx.cluster, Some(3));
+        assert_eq!(
+            std::format!("{}", ctx),
+            "device I/O error while reading file data (lba 35) (cluster 3)"
+        );
+    }
+
+    #[test]
+    fn inserting_at_end_marker_keeps_directory_terminated() {
+        let names: Vec<_> = (0..15).map(|i| std::format!("F{}", i)).collect();
+        let mut builder = ImageBuilder::new(4096).sectors_per_cluster(2);
+        for name in &names {
+            builder = builder.file(name, b"");
+        }
+        let mut dev = builder.build_device().unwrap();
+        // Stale records past the end marker, as left by another implementation.
+        let bpb = *Fat32::mount(&mut dev).unwrap().bpb();
+        let second = cluster_to_lba(&bpb, bpb.root_cluster).unwrap() + 1;
+        let mut junk = [0u8; 512];
+        junk[..32].copy_from_slice(&DirEntry::build_short_file(*b"JUNK1   TXT", 0, 0));
+        junk[32..64].copy_from_slice(&DirEntry::build_short_file(*b"JUNK2   TXT", 0, 0));
+        dev.write_sector(second, &junk).unwrap();
+
+        let mut fs = Fat32::mount(dev).expect("mount");
+        fs.write_file_root("LAST.TXT", b"x").unwrap();
+        assert_eq!(fs.list_root().unwrap().len(), 16);
+        fs.write_file_root("NEXT.TXT", b"y").unwrap();
+        let root = fs.list_root().unwrap();
+        assert_eq!(root.len(), 17);
+        assert_eq!(root[16].name(), "NEXT.TXT");
+    }
+
+    #[test]
+    fn failed_write_releases_its_clusters() {
+        let mut done = false;
+        for n in 1..20 {
+            let dev = FaultDevice::new(MemDevice::new(make_tiny_fat32_image())).fail_write(n);
+            let mut fs = Fat32::mount(dev).expect("mount");
+            let res = fs.write_file_root("A.BIN", &[3u8; 1100]);
+            let fs = Fat32::mount(fs.into_device().into_inner()).expect("remount");
+            let report = fs.check().unwrap();
+            assert!(report.is_clean(), "write {} failed: {:?}", n, report);
+            done |= res.is_ok();
+            if res.is_err() {
+                assert_eq!(fs.read_file_root("A.BIN"), Err(Error::NotFound));
+            }
+        }
+        assert!(done);
+    }
+}
@@ -0,0 +1,329 @@
+//! Volume consistency checker (fsck) with lost-chain repair.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::cancel::Cancel;
+use crate::device::BlockDevice;
+use crate::dir::{DirEntry, ATTR_DIRECTORY, ATTR_LFN, ATTR_VOLUME_ID};
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, max_cluster, BAD_CLUSTER, EOC_MIN};
+use crate::fs::Fat32;
+
+/// An allocated cluster chain that no directory entry references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LostChain {
+    /// First cluster of the chain.
+    pub first_cluster: u32,
+    /// Number of clusters in the chain.
+    pub clusters: u32,
+}
+
+/// What `Fat32::repair` does with lost chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostChainAction {
+    /// Mark every cluster of the chain free.
+    Free,
+    /// Attach each chain as `FOUNDnnn/FILEnnnn.CHK` in the root directory.
+    Recover,
+}
+
+/// Findings of a consistency check.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FsckReport {
+    /// Directories visited (including the root).
+    pub directories: u32,
+    /// Files visited.
+    pub files: u32,
+    /// Chains that end on a free, bad or out-of-range cluster.
+    pub broken_chains: u32,
+    /// Chains that run into a cluster already claimed by another chain (or loop).
+    pub cross_links: u32,
+    /// Allocated but unreferenced chains.
+    pub lost_chains: Vec<LostChain>,
+    /// Chains freed by `repair`.
+    pub chains_freed: u32,
+    /// Chains attached as `.CHK` files by `repair`.
+    pub chains_recovered: u32,
+}
+
+impl FsckReport {
+    /// True if no problem was found.
+    pub fn is_clean(&self) -> bool {
+        self.broken_chains == 0 && self.cross_links == 0 && self.lost_chains.is_empty()
+    }
+}
+
+/// One bit per cluster.
+struct Bitmap(Vec<u8>);
+
+impl Bitmap {
+    fn new(bits: u32) -> Self {
+        Self(vec![0u8; (bits as usize).div_ceil(8)])
+    }
+    fn get(&self, i: u32) -> bool {
+        self.0[(i / 8) as usize] & (1 << (i % 8)) != 0
+    }
+    fn set(&mut self, i: u32) {
+        self.0[(i / 8) as usize] |= 1 << (i % 8);
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Walk the directory tree and the FAT and report inconsistencies (read-only).
+    pub fn check(&self) -> Result<FsckReport> {
+        self.check_cancellable(Cancel::NEVER)
+    }
+
+    /// `check`, polling `cancel` before each directory cluster and FAT sector.
+    pub fn check_cancellable(&self, cancel: Cancel<'_>) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        self.scan(&mut report, cancel)?;
+        Ok(report)
+    }
+
+    /// Check the volume, then free or recover every lost chain according to `action`.
+    ///
+    /// Broken chains and cross links are reported but not repaired.
+    /// With `Recover`, chains that cannot be attached (e.g. no cluster left to grow
+    /// the `FOUNDnnn` directory) stay lost and are picked up by the next run.
+    pub fn repair(&mut self, action: LostChainAction) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        self.scan(&mut report, Cancel::NEVER)?;
+        if report.lost_chains.is_empty() {
+            return Ok(report);
+        }
+        fs_debug!("fat32: repairing {} lost chains ({:?})", report.lost_chains.len(), action);
+        self.mark_dirty()?;
+
+        match action {
+            LostChainAction::Free => {
+                for chain in &report.lost_chains {
+                    let mut c = chain.first_cluster;
+                    for _ in 0..chain.clusters {
+                        let next = self.read_fat(c)?;
+                        self.write_fat(c, 0)?;
+                        c = next;
+                    }
+                    report.chains_freed += 1;
+                }
+            }
+            LostChainAction::Recover => {
+                let found = self.create_found_dir()?;
+                let bytes_per_cluster = (self.bpb.sectors_per_cluster as u32) * 512;
+                for (i, chain) in report.lost_chains.iter().enumerate() {
+                    // Terminate the chain after its counted length (breaks lost loops).
+                    let mut last = chain.first_cluster;
+                    for _ in 1..chain.clusters {
+                        last = self.read_fat(last)?;
+                    }
+                    self.write_fat(last, 0x0FFFFFFF)?;
+
+                    let mut name = *b"FILE0000CHK";
+                    write_decimal(&mut name[4..8], i as u32);
+                    let size = chain.clusters.saturating_mul(bytes_per_cluster);
+                    let rec = DirEntry::build_short_file(name, chain.first_cluster, size);
+                    if self.write_dir_entry_first_free(found, &rec).is_err() {
+                        break;
+                    }
+                    report.chains_recovered += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Create the first unused `FOUNDnnn` directory in the root.
+    fn create_found_dir(&mut self) -> Result<u32> {
+        let root = self.bpb.root_cluster;
+        let mut name = *b"FOUND000   ";
+        for n in 0..1000 {
+            write_decimal(&mut name[5..8], n);
+            if self.find_entry(root, &name)?.is_none() {
+                return self.create_dir_in(self.bpb.root_cluster, name, 0);
+            }
+        }
+        Err(Error::DirFull)
+    }
+
+    fn scan(&self, report: &mut FsckReport, cancel: Cancel<'_>) -> Result<()> {
+        let max = max_cluster(&self.bpb);
+        let mut used = Bitmap::new(max + 1);
+
+        // 1) Mark everything reachable from the root directory.
+        let mut dirs = vec![self.bpb.root_cluster];
+        while let Some(dir) = dirs.pop() {
+            report.directories += 1;
+            let mut clusters = Vec::new();
+            self.mark_chain(dir, max, &mut used, report, &mut clusters)?;
+            for cluster in clusters {
+                cancel.check()?;
+                self.scan_dir_cluster(cluster, max, &mut used, report, &mut dirs)?;
+            }
+        }
+
+        // 2) Allocated clusters that were never reached are lost.
+        let mut lost = Bitmap::new(max + 1);
+        let mut pointed = Bitmap::new(max + 1);
+        self.for_each_fat_entry(max, cancel, |c, v| {
+            if v != 0 && v != BAD_CLUSTER && !used.get(c) {
+                lost.set(c);
+            }
+        })?;
+        self.for_each_fat_entry(max, cancel, |c, v| {
+            if lost.get(c) && (2..=max).contains(&v) && lost.get(v) {
+                pointed.set(v);
+            }
+        })?;
+
+        // 3) Group lost clusters into chains, heads first, then leftover loops.
+        let mut visited = Bitmap::new(max + 1);
+        for pass in 0..2 {
+            for c in 2..=max {
+                if !lost.get(c) || visited.get(c) || (pass == 0 && pointed.get(c)) {
+                    continue;
+                }
+                let mut len = 0u32;
+                let mut cur = c;
+                loop {
+                    visited.set(cur);
+                    len += 1;
+                    let next = self.read_fat(cur)?;
+                    if !(2..=max).contains(&next) || !lost.get(next) || visited.get(next) {
+                        break;
+                    }
+                    cur = next;
+                }
+                report.lost_chains.push(LostChain { first_cluster: c, clusters: len });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mark the chain starting at `first` as used, collecting its clusters into `out`.
+    fn mark_chain(
+        &self,
+        first: u32,
+        max: u32,
+        used: &mut Bitmap,
+        report: &mut FsckReport,
+        out: &mut Vec<u32>,
+    ) -> Result<()> {
+        let mut c = first;
+        loop {
+            if !(2..=max).contains(&c) {
+                report.broken_chains += 1;
+                return Ok(());
+            }
+            if used.get(c) {
+                report.cross_links += 1;
+                return Ok(());
+            }
+            used.set(c);
+            out.push(c);
+
+            let next = self.read_fat(c)?;
+            if next >= EOC_MIN {
+                return Ok(());
+            }
+            if next == 0 || next == BAD_CLUSTER {
+                report.broken_chains += 1;
+                return Ok(());
+            }
+            c = next;
+        }
+    }
+
+    fn scan_dir_cluster(
+        &self,
+        cluster: u32,
+        max: u32,
+        used: &mut Bitmap,
+        report: &mut FsckReport,
+        dirs: &mut Vec<u32>,
+    ) -> Result<()> {
+        let base_lba = cluster_to_lba(&self.bpb, cluster)?;
+        for s in 0..(self.bpb.sectors_per_cluster as u64) {
+            let mut buf = [0u8; 512];
+            self.read_sector(Operation::ReadDir, base_lba + s, &mut buf)?;
+            for rec in buf.chunks_exact(32) {
+                let attr = rec[11];
+                if rec[0] == 0x00 {
+                    return Ok(());
+                }
+                if rec[0] == 0xE5 || rec[0] == b'.' || attr == ATTR_LFN || attr & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                let hi = u16::from_le_bytes([rec[20], rec[21]]) as u32;
+                let lo = u16::from_le_bytes([rec[26], rec[27]]) as u32;
+                let first = (hi << 16) | lo;
+
+                if attr & ATTR_DIRECTORY != 0 {
+                    if first >= 2 && first <= max && !used.get(first) {
+                        dirs.push(first);
+                    } else {
+                        report.broken_chains += 1;
+                    }
+                    continue;
+                }
+                report.files += 1;
+                if first != 0 {
+                    let mut clusters = Vec::new();
+                    self.mark_chain(first, max, used, report, &mut clusters)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `n` as zero-padded ASCII decimal into `dst`.
+fn write_decimal(dst: &mut [u8], mut n: u32) {
+    for b in dst.iter_mut().rev() {
+        *b = b'0' + (n % 10) as u8;
+        n /= 10;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fat::{read_fat_entry, write_fat_entry};
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    fn image_with_lost_chain() -> MemDevice {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("KEEP.TXT", b"keep").expect("write");
+        // Orphan chain 20 -> 21 -> EOC, referenced by no directory entry.
+        write_fat_entry(&mut fs.dev, &fs.bpb, 20, 21).unwrap();
+        write_fat_entry(&mut fs.dev, &fs.bpb, 21, 0x0FFFFFFF).unwrap();
+        fs.into_device()
+    }
+
+    #[test]
+    fn repair_frees_or_recovers_lost_chains() {
+        let fs = Fat32::mount(image_with_lost_chain()).expect("mount");
+        let report = fs.check().expect("check");
+        assert_eq!(report.lost_chains, [LostChain { first_cluster: 20, clusters: 2 }]);
+
+        let mut fs = Fat32::mount(image_with_lost_chain()).expect("mount");
+        let report = fs.repair(LostChainAction::Free).expect("repair");
+        assert_eq!(report.chains_freed, 1);
+        assert_eq!(read_fat_entry(&fs.dev, &fs.bpb, 20).unwrap(), 0);
+        assert!(fs.check().unwrap().is_clean());
+
+        let mut fs = Fat32::mount(image_with_lost_chain()).expect("mount");
+        let report = fs.repair(LostChainAction::Recover).expect("repair");
+        assert_eq!(report.chains_recovered, 1);
+        assert!(fs.list_root().unwrap().iter().any(|e| &e.raw_name == b"FOUND000   "));
+        let after = fs.check().unwrap();
+        assert!(after.is_clean());
+        assert_eq!(after.directories, 2);
+        assert_eq!(fs.read_file_root("KEEP.TXT").unwrap(), b"keep");
+    }
+}
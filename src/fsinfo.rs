@@ -0,0 +1,79 @@
+//! FSInfo sector parsing and serialization.
+
+use crate::error::{Error, Result};
+
+const LEAD_SIG: u32 = 0x4161_5252;
+const STRUCT_SIG: u32 = 0x6141_7272;
+const TRAIL_SIG: u32 = 0xAA55_0000;
+
+/// Value of an FSInfo field that is not known.
+pub const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The FSInfo sector: free-space hints kept in the reserved area.
+///
+/// Both fields are hints only; `FSINFO_UNKNOWN` means "not computed".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FsInfo {
+    /// Last known number of free clusters.
+    pub free_count: u32,
+    /// Cluster to start looking for a free one from.
+    pub next_free: u32,
+}
+
+fn le_u32(buf: &[u8; 512], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+impl FsInfo {
+    /// FSInfo with both hints unknown.
+    pub const UNKNOWN: Self = Self {
+        free_count: FSINFO_UNKNOWN,
+        next_free: FSINFO_UNKNOWN,
+    };
+
+    /// Parse an FSInfo sector, checking its lead, struct and trail signatures.
+    pub fn parse(buf: &[u8; 512]) -> Result<Self> {
+        if le_u32(buf, 0) != LEAD_SIG || le_u32(buf, 484) != STRUCT_SIG || le_u32(buf, 508) != TRAIL_SIG {
+            return Err(Error::InvalidFsInfo);
+        }
+        Ok(Self {
+            free_count: le_u32(buf, 488),
+            next_free: le_u32(buf, 492),
+        })
+    }
+
+    /// `parse` on the first 512 bytes of `data`; shorter input is `InvalidFsInfo`.
+    pub fn parse_bytes(data: &[u8]) -> Result<Self> {
+        Self::parse(data.first_chunk::<512>().ok_or(Error::InvalidFsInfo)?)
+    }
+
+    /// The FSInfo sector, with signatures and zeroed reserved bytes.
+    pub fn serialize(&self) -> [u8; 512] {
+        let mut buf = [0u8; 512];
+        buf[0..4].copy_from_slice(&LEAD_SIG.to_le_bytes());
+        buf[484..488].copy_from_slice(&STRUCT_SIG.to_le_bytes());
+        buf[488..492].copy_from_slice(&self.free_count.to_le_bytes());
+        buf[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+        buf[508..512].copy_from_slice(&TRAIL_SIG.to_le_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_and_signatures() {
+        let info = FsInfo {
+            free_count: 1234,
+            next_free: 7,
+        };
+        let mut buf = info.serialize();
+        assert_eq!(FsInfo::parse(&buf), Ok(info));
+        assert_eq!(&buf[0..4], b"RRaA");
+        buf[510] = 0;
+        assert_eq!(FsInfo::parse(&buf), Err(Error::InvalidFsInfo));
+    }
+}
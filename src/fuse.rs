@@ -0,0 +1,563 @@
+//! FUSE adapter (`fuse` feature): mount a volume on a Linux or macOS host.
+//!
+//! `mount(fs, mountpoint)` serves a `Fat32` through the kernel's FUSE driver
+//! until the mount point is unmounted, so ordinary tools (`ls`, `cp`, a file
+//! manager) can inspect and change an image or a card in a USB reader:
+//!
+//! ```ignore
+//! let fs = Fat32::mount(FileDevice::open("card.img")?)?;
+//! fat32::fuse::mount(fs, "/mnt/card")?;
+//! ```
+//!
+//! Names are 8.3, as everywhere else in the crate. FAT has no inode numbers,
+//! so the adapter hands them out per path as the kernel looks entries up;
+//! they are only stable for the life of the mount. Times are read as UTC.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use std::ffi::OsStr;
+#[cfg(feature = "fuse")]
+use std::io;
+#[cfg(feature = "fuse")]
+use std::path::Path as HostPath;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "fuse")]
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyWrite, Request, TimeOrNow,
+};
+#[cfg(feature = "fuse")]
+use libc::c_int;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+use crate::fs::Fat32;
+use crate::metadata::Timestamp;
+use crate::options::OpenMode;
+
+/// How long the kernel may cache attributes and lookups.
+#[cfg(feature = "fuse")]
+const TTL: Duration = Duration::from_secs(1);
+
+/// Inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// A `fuser::Filesystem` backed by a `Fat32`.
+pub struct FuseFs<D: BlockDevice> {
+    fs: Fat32<D>,
+    /// Path of inode `ROOT_INO + i`, upper-cased; the root is `/`.
+    paths: Vec<String>,
+}
+
+/// What the adapter reports for an inode; the `Filesystem` impl adds the
+/// caller's uid and gid to make a `fuser::FileAttr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attr {
+    ino: u64,
+    size: u64,
+    is_dir: bool,
+    perm: u16,
+    accessed: SystemTime,
+    modified: SystemTime,
+    created: SystemTime,
+}
+
+impl<D: BlockDevice> FuseFs<D> {
+    /// Serve `fs`.
+    pub fn new(fs: Fat32<D>) -> Self {
+        Self {
+            fs,
+            paths: alloc::vec![String::from("/")],
+        }
+    }
+
+    /// Return the filesystem.
+    pub fn into_inner(self) -> Fat32<D> {
+        self.fs
+    }
+
+    /// Path of inode `ino`.
+    fn path(&self, ino: u64) -> Result<String> {
+        let i = ino.checked_sub(ROOT_INO).ok_or(Error::NotFound)?;
+        self.paths.get(i as usize).cloned().ok_or(Error::NotFound)
+    }
+
+    /// Inode number of `path`, assigning a new one on first use.
+    fn inode(&mut self, path: &str) -> u64 {
+        let i = match self.paths.iter().position(|p| p == path) {
+            Some(i) => i,
+            None => {
+                self.paths.push(path.to_string());
+                self.paths.len() - 1
+            }
+        };
+        i as u64 + ROOT_INO
+    }
+
+    /// Path of entry `name` in directory `parent`.
+    fn child(&self, parent: u64, name: &OsStr) -> Result<String> {
+        let name = name.to_str().ok_or(Error::InvalidName)?.to_ascii_uppercase();
+        let dir = self.path(parent)?;
+        Ok(if dir == "/" { name } else { format!("{}/{}", dir, name) })
+    }
+
+    /// Attributes of `path`, reported as inode `ino`.
+    fn attr(&self, ino: u64, path: &str) -> Result<Attr> {
+        let (is_dir, size, created, modified, accessed, read_only) = if path == "/" {
+            let zero = Timestamp::default();
+            (true, 0, zero, zero, zero, false)
+        } else {
+            let m = self.fs.metadata(path)?;
+            (m.is_dir(), m.size as u64, m.created, m.modified, m.accessed, m.is_read_only())
+        };
+        let mut perm = if is_dir { 0o755 } else { 0o644 };
+        if read_only || self.fs.options().read_only {
+            perm &= 0o555;
+        }
+        Ok(Attr {
+            ino,
+            size,
+            is_dir,
+            perm,
+            accessed: system_time(accessed),
+            modified: system_time(modified),
+            created: system_time(created),
+        })
+    }
+
+    /// Attributes of inode `ino`.
+    fn getattr_ino(&self, ino: u64) -> Result<Attr> {
+        self.attr(ino, &self.path(ino)?)
+    }
+
+    /// Look up `path`, assigning it an inode.
+    fn entry(&mut self, path: &str) -> Result<Attr> {
+        let ino = self.inode(path);
+        self.attr(ino, path)
+    }
+
+    /// Look up `name` in directory `parent`.
+    fn lookup_child(&mut self, parent: u64, name: &OsStr) -> Result<Attr> {
+        let path = self.child(parent, name)?;
+        self.entry(&path)
+    }
+
+    /// Resize inode `ino`; only truncation to zero is supported, other sizes
+    /// fail with `InvalidSeek`.
+    fn set_size(&mut self, ino: u64, size: Option<u64>) -> Result<Attr> {
+        let path = self.path(ino)?;
+        match size {
+            Some(0) => {
+                let (dir, name) = split(&path);
+                self.fs.open_dir(dir)?.open_file_with(name, OpenMode::Truncate)?.close()?;
+            }
+            Some(n) if n != self.fs.metadata(&path)?.size as u64 => return Err(Error::InvalidSeek),
+            _ => {}
+        }
+        self.attr(ino, &path)
+    }
+
+    /// Create directory `name` in `parent`.
+    fn make_dir(&mut self, parent: u64, name: &OsStr) -> Result<Attr> {
+        let path = self.child(parent, name)?;
+        let (dir, name) = split(&path);
+        self.fs.open_dir(dir)?.create_dir(name)?;
+        self.entry(&path)
+    }
+
+    /// Create empty file `name` in `parent`.
+    fn make_file(&mut self, parent: u64, name: &OsStr) -> Result<Attr> {
+        let path = self.child(parent, name)?;
+        let (dir, name) = split(&path);
+        self.fs.open_dir(dir)?.create_file(name)?.close()?;
+        self.entry(&path)
+    }
+
+    /// Remove file `name` from `parent`.
+    fn remove_file(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child(parent, name)?;
+        if self.fs.metadata(&path)?.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        let (dir, name) = split(&path);
+        self.fs.open_dir(dir)?.remove(name)
+    }
+
+    /// Remove empty directory `name` from `parent`.
+    fn remove_dir(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child(parent, name)?;
+        self.fs.remove_dir(&path)
+    }
+
+    /// Rename `name` to `newname` within directory `parent`.
+    fn rename_child(&mut self, parent: u64, name: &OsStr, newname: &OsStr) -> Result<()> {
+        let from = self.child(parent, name)?;
+        let to = self.child(parent, newname)?;
+        let (dir, old) = split(&from);
+        let new = split(&to).1;
+        self.fs.open_dir(dir)?.rename(old, new)?;
+        self.moved(&from, &to);
+        Ok(())
+    }
+
+    /// Up to `size` bytes of inode `ino` from `offset`.
+    fn read_at(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+        let path = self.path(ino)?;
+        let (dir, name) = split(&path);
+        let mut dir = self.fs.open_dir(dir)?;
+        let mut file = dir.open_file(name)?;
+        let offset = u32::try_from(offset).map_err(|_| Error::InvalidSeek)?;
+        if offset >= file.len() {
+            return Ok(Vec::new());
+        }
+        file.seek(offset)?;
+        let mut buf = alloc::vec![0u8; size.min(file.len() - offset) as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            match file.read(&mut buf[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buf.truncate(done);
+        Ok(buf)
+    }
+
+    /// Write `data` to inode `ino` at `offset`.
+    fn write_at(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<usize> {
+        let path = self.path(ino)?;
+        let (dir, name) = split(&path);
+        let mut dir = self.fs.open_dir(dir)?;
+        let mut file = dir.open_file(name)?;
+        file.seek(u32::try_from(offset).map_err(|_| Error::InvalidSeek)?)?;
+        let n = file.write(data)?;
+        file.close()?;
+        Ok(n)
+    }
+
+    /// Entries of directory `ino` as `(ino, is_dir, name)`, with `.` and `..` first.
+    fn list(&mut self, ino: u64) -> Result<Vec<(u64, bool, String)>> {
+        let path = self.path(ino)?;
+        let parent = match path.as_str() {
+            "/" => "/",
+            _ => split(&path).0,
+        };
+        let mut out = alloc::vec![(ino, true, String::from(".")), (self.inode(parent), true, String::from(".."))];
+        let mut children = Vec::new();
+        for e in self.fs.open_dir(&path)?.iter() {
+            let e = e?;
+            let name = e.name();
+            let upper = name.to_ascii_uppercase();
+            let full = if path == "/" { upper } else { format!("{}/{}", path, upper) };
+            children.push((full, e.is_dir(), name));
+        }
+        for (full, is_dir, name) in children {
+            out.push((self.inode(&full), is_dir, name));
+        }
+        Ok(out)
+    }
+
+    /// Point inodes at and below `from` to the same place under `to`.
+    fn moved(&mut self, from: &str, to: &str) {
+        for p in &mut self.paths {
+            if p == from {
+                *p = to.to_string();
+            } else if let Some(rest) = p.strip_prefix(from).filter(|r| r.starts_with('/')) {
+                *p = format!("{}{}", to, rest);
+            }
+        }
+    }
+
+    /// `attr` as a `fuser::FileAttr` owned by the caller of `req`.
+    #[cfg(feature = "fuse")]
+    fn file_attr(&self, req: &Request<'_>, attr: Attr) -> FileAttr {
+        let kind = if attr.is_dir { FileType::Directory } else { FileType::RegularFile };
+        FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.size.div_ceil(512),
+            atime: attr.accessed,
+            mtime: attr.modified,
+            ctime: attr.modified,
+            crtime: attr.created,
+            kind,
+            perm: attr.perm,
+            nlink: if attr.is_dir { 2 } else { 1 },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: self.fs.bpb().sectors_per_cluster as u32 * 512,
+            flags: 0,
+        }
+    }
+}
+
+/// Thin glue: each callback forwards to the matching method above and turns
+/// its `Result` into a reply.
+#[cfg(feature = "fuse")]
+impl<D: BlockDevice> Filesystem for FuseFs<D> {
+    fn destroy(&mut self) {
+        if let Err(e) = self.fs.sync() {
+            fs_warn!("fat32: sync on unmount failed: {}", e);
+        }
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_child(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &self.file_attr(req, attr), 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.getattr_ino(ino) {
+            Ok(attr) => reply.attr(&TTL, &self.file_attr(req, attr)),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    /// Only truncation to zero is supported; mode, owner and time changes are ignored.
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.set_size(ino, size) {
+            Ok(attr) => reply.attr(&TTL, &self.file_attr(req, attr)),
+            Err(Error::InvalidSeek) => reply.error(libc::EOPNOTSUPP),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+        match self.make_dir(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &self.file_attr(req, attr), 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_file(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_dir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    /// Renames within one directory; moves between directories fail with
+    /// `EXDEV`, which makes `mv` fall back to copy and delete.
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if parent != newparent {
+            return reply.error(libc::EXDEV);
+        }
+        match self.rename_child(parent, name, newname) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_at(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write_at(ino, offset, data) {
+            Ok(n) => reply.written(n as u32),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.fs.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.fs.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let entries = match self.list(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(errno(e)),
+        };
+        for (i, (ino, is_dir, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            let kind = if is_dir { FileType::Directory } else { FileType::RegularFile };
+            if reply.add(ino, i as i64 + 1, kind, &name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.make_file(parent, name) {
+            Ok(attr) => reply.created(&TTL, &self.file_attr(req, attr), 0, 0, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+}
+
+/// Serve `fs` at `mountpoint` until it is unmounted, then sync it.
+#[cfg(feature = "fuse")]
+pub fn mount<D: BlockDevice, P: AsRef<HostPath>>(fs: Fat32<D>, mountpoint: P) -> io::Result<()> {
+    let mut options = alloc::vec![MountOption::FSName(String::from("fat32")), MountOption::NoExec];
+    if fs.options().read_only {
+        options.push(MountOption::RO);
+    }
+    fuser::mount2(FuseFs::new(fs), mountpoint, &options)
+}
+
+/// The errno FUSE reports for `e`.
+#[cfg(feature = "fuse")]
+pub fn errno(e: Error) -> c_int {
+    match e {
+        Error::NotFound => libc::ENOENT,
+        Error::AlreadyExists => libc::EEXIST,
+        Error::InvalidName | Error::InvalidInput => libc::EINVAL,
+        Error::NotADirectory => libc::ENOTDIR,
+        Error::IsADirectory => libc::EISDIR,
+        Error::DirectoryNotEmpty => libc::ENOTEMPTY,
+        Error::NoSpace | Error::DirFull | Error::NoJournalSpace => libc::ENOSPC,
+        Error::ReadOnlyVolume | Error::WriteProtected => libc::EROFS,
+        Error::ReadOnlyFile => libc::EACCES,
+        Error::AlreadyOpen => libc::EBUSY,
+        Error::InvalidSeek => libc::EFBIG,
+        _ => libc::EIO,
+    }
+}
+
+/// Split `path` into its directory (`/` for the root) and last component.
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("/", path),
+    }
+}
+
+/// `ts` as a host time, taking FAT local time as UTC; unset is the epoch.
+fn system_time(ts: Timestamp) -> SystemTime {
+    let secs = ts.to_naive_date_time().map_or(0, |dt| dt.and_utc().timestamp());
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    fn name(s: &str) -> &OsStr {
+        OsStr::new(s)
+    }
+
+    #[test]
+    fn callbacks_work_on_inodes() {
+        let mut fuse = FuseFs::new(Fat32::mount(MemDevice::new(make_tiny_fat32_image())).unwrap());
+        assert!(fuse.getattr_ino(ROOT_INO).unwrap().is_dir);
+        assert_eq!(fuse.lookup_child(ROOT_INO, name("none.txt")), Err(Error::NotFound));
+
+        let logs = fuse.make_dir(ROOT_INO, name("logs")).unwrap();
+        let file = fuse.make_file(logs.ino, name("a.log")).unwrap();
+        assert_eq!((file.size, file.perm, file.is_dir), (0, 0o644, false));
+        assert_eq!(fuse.write_at(file.ino, 0, b"hello world").unwrap(), 11);
+        assert_eq!(fuse.read_at(file.ino, 6, 100).unwrap(), b"world");
+        assert_eq!(fuse.read_at(file.ino, 20, 4).unwrap(), b"");
+        // Lookups are case-insensitive and hand back the same inode.
+        assert_eq!(fuse.lookup_child(logs.ino, name("A.LOG")).unwrap().ino, file.ino);
+
+        let names: Vec<_> = fuse.list(logs.ino).unwrap().into_iter().map(|(ino, _, n)| (ino, n)).collect();
+        assert_eq!(names[..2], [(logs.ino, String::from(".")), (ROOT_INO, String::from(".."))]);
+        assert_eq!(names[2..], [(file.ino, String::from("A.LOG"))]);
+
+        // Renaming the directory keeps the inodes under it valid.
+        fuse.rename_child(ROOT_INO, name("logs"), name("old")).unwrap();
+        assert_eq!(fuse.read_at(file.ino, 0, 5).unwrap(), b"hello");
+        assert_eq!(fuse.set_size(file.ino, Some(3)), Err(Error::InvalidSeek));
+        assert_eq!(fuse.set_size(file.ino, Some(0)).unwrap().size, 0);
+
+        assert_eq!(fuse.remove_file(ROOT_INO, name("old")), Err(Error::IsADirectory));
+        assert_eq!(fuse.remove_dir(ROOT_INO, name("old")), Err(Error::DirectoryNotEmpty));
+        fuse.remove_file(logs.ino, name("a.log")).unwrap();
+        fuse.remove_dir(ROOT_INO, name("old")).unwrap();
+        assert_eq!(fuse.getattr_ino(file.ino), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn timestamps_map_to_host_times() {
+        let t = system_time(Timestamp::new(2024, 3, 1, 12, 30, 10));
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1_709_296_210);
+        assert_eq!(system_time(Timestamp::new(1980, 1, 1, 0, 0, 0)), UNIX_EPOCH + Duration::from_secs(315_532_800));
+        assert_eq!(system_time(Timestamp::default()), UNIX_EPOCH);
+        assert_eq!(split("A/B/C.TXT"), ("A/B", "C.TXT"));
+        assert_eq!(split("C.TXT"), ("/", "C.TXT"));
+    }
+}
@@ -0,0 +1,142 @@
+//! In-memory FAT32 image builder for tests (`test-util` feature).
+
+use alloc::vec::Vec;
+
+use crate::device::MemDevice;
+use crate::dir::{nt_case_flags, to_short_name_83, DirEntry};
+use crate::error::Result;
+use crate::fat::sync_fats;
+use crate::fs::Fat32;
+use crate::mkfs::{self, FormatOptions};
+use crate::options::CreateOptions;
+use crate::path::Path;
+
+enum Item<'a> {
+    Dir(&'a str),
+    File(&'a str, &'a [u8]),
+}
+
+/// Builds a formatted FAT32 image, optionally pre-populated with
+/// directories and files (8.3 names, `/`-separated paths).
+///
+/// ```ignore
+/// let img = ImageBuilder::new(4096)
+///     .sectors_per_cluster(2)
+///     .dir("LOGS")
+///     .file("LOGS/DAY1.TXT", b"hello")
+///     .build()?;
+/// ```
+pub struct ImageBuilder<'a> {
+    total_sectors: u32,
+    sectors_per_cluster: u8,
+    num_fats: u8,
+    items: Vec<Item<'a>>,
+}
+
+impl<'a> ImageBuilder<'a> {
+    /// An image of `total_sectors` 512-byte sectors, one sector per cluster, two FATs.
+    pub fn new(total_sectors: u32) -> Self {
+        Self {
+            total_sectors,
+            sectors_per_cluster: 1,
+            num_fats: 2,
+            items: Vec::new(),
+        }
+    }
+
+    /// Cluster size in sectors (power of two).
+    pub fn sectors_per_cluster(mut self, n: u8) -> Self {
+        self.sectors_per_cluster = n;
+        self
+    }
+
+    /// Number of FAT copies.
+    pub fn fats(mut self, n: u8) -> Self {
+        self.num_fats = n;
+        self
+    }
+
+    /// Create directory `path`, along with any missing parents.
+    pub fn dir(mut self, path: &'a str) -> Self {
+        self.items.push(Item::Dir(path));
+        self
+    }
+
+    /// Create file `path` holding `content`, along with any missing parent directories.
+    pub fn file(mut self, path: &'a str, content: &'a [u8]) -> Self {
+        self.items.push(Item::File(path, content));
+        self
+    }
+
+    /// Format and populate the image.
+    pub fn build(self) -> Result<Vec<u8>> {
+        let mut dev = MemDevice::zeroed(self.total_sectors as usize);
+        let options = FormatOptions {
+            sectors_per_cluster: Some(self.sectors_per_cluster),
+            num_fats: self.num_fats,
+            volume_id: Some(0x1234_5678),
+            allow_small: true,
+            ..FormatOptions::default()
+        };
+        mkfs::format_with(&mut dev, self.total_sectors, &options)?;
+        let mut fs = Fat32::mount(dev)?;
+        for item in &self.items {
+            match *item {
+                Item::Dir(path) => fs.create_dir_all(path)?,
+                Item::File(path, content) => {
+                    let (parent, name) = Path::new(path).split_last()?;
+                    let dir = fs.create_dirs_in(parent)?;
+                    let short = to_short_name_83(name)?;
+                    let case = nt_case_flags(name);
+                    if content.is_empty() {
+                        let mut rec = DirEntry::build_short_file(short, 0, 0);
+                        rec[12] = case;
+                        fs.write_dir_entry_first_free(dir, &rec)?;
+                    } else {
+                        fs.write_file_in(dir, short, case, content, &CreateOptions::default())?;
+                    }
+                }
+            }
+        }
+        let bpb = *fs.bpb();
+        let mut dev = fs.unmount()?;
+        // The filesystem only writes the active FAT; mirror it like a real formatter would.
+        sync_fats(&mut dev, &bpb, 0)?;
+        Ok(dev.into_inner())
+    }
+
+    /// Build and wrap the image in a `MemDevice`.
+    pub fn build_device(self) -> Result<MemDevice> {
+        Ok(MemDevice::new(self.build()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_populated_image() {
+        let img = ImageBuilder::new(8192)
+            .sectors_per_cluster(4)
+            .dir("EMPTY")
+            .file("README.TXT", b"top")
+            .file("LOGS/2024/DAY1.TXT", &[9u8; 5000])
+            .build()
+            .unwrap();
+
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        assert_eq!(fs.bpb().sectors_per_cluster, 4);
+        assert!(fs.compare_fat_copies().unwrap().is_empty());
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.read_file_root("README.TXT").unwrap(), b"top");
+        let names: Vec<_> = fs.list_root().unwrap().iter().map(|e| e.raw_name).collect();
+        assert_eq!(names, [*b"EMPTY      ", *b"README  TXT", *b"LOGS       "]);
+
+        let logs = fs.find_entry(fs.bpb().root_cluster, b"LOGS       ").unwrap().unwrap().0;
+        let year = fs.find_entry(logs.first_cluster, b"2024       ").unwrap().unwrap().0;
+        let day = fs.find_entry(year.first_cluster, b"DAY1    TXT").unwrap().unwrap().0;
+        assert_eq!(day.file_size, 5000);
+        assert_eq!(fs.check().unwrap().files, 2);
+    }
+}
@@ -0,0 +1,186 @@
+//! Trait adapters for `File`: `embedded-io` traits and, with `std`, `std::io` traits.
+
+#[cfg(feature = "embedded-io")]
+mod embedded {
+    use crate::checksum::{Digest, HashingReader};
+    use crate::device::BlockDevice;
+    use crate::error::{DeviceErrorKind, Error};
+    use crate::file::File;
+    use embedded_io::{ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+
+    impl embedded_io::Error for Error {
+        fn kind(&self) -> ErrorKind {
+            match self {
+                Error::NotFound => ErrorKind::NotFound,
+                Error::AlreadyExists => ErrorKind::AlreadyExists,
+                Error::InvalidName | Error::InvalidFatIndex | Error::InvalidSeek | Error::InvalidInput => {
+                    ErrorKind::InvalidInput
+                }
+                Error::ReadOnlyVolume | Error::WriteProtected | Error::ReadOnlyFile => ErrorKind::PermissionDenied,
+                Error::NoSpace | Error::DirFull => ErrorKind::OutOfMemory,
+                Error::Corrupt | Error::UnexpectedEof | Error::InvalidFsInfo | Error::VerifyFailed => {
+                    ErrorKind::InvalidData
+                }
+                Error::UnsupportedSectorSize => ErrorKind::Unsupported,
+                Error::Cancelled => ErrorKind::Interrupted,
+                Error::Device(d) => match d.kind {
+                    DeviceErrorKind::Timeout => ErrorKind::TimedOut,
+                    DeviceErrorKind::Removed => ErrorKind::NotConnected,
+                    _ => ErrorKind::Other,
+                },
+                _ => ErrorKind::Other,
+            }
+        }
+    }
+
+    impl<D: BlockDevice> ErrorType for File<'_, D> {
+        type Error = Error;
+    }
+
+    impl<D: BlockDevice> Read for File<'_, D> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            File::read(self, buf)
+        }
+    }
+
+    impl<D: BlockDevice, H: Digest> ErrorType for HashingReader<'_, '_, D, H> {
+        type Error = Error;
+    }
+
+    impl<D: BlockDevice, H: Digest> Read for HashingReader<'_, '_, D, H> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            HashingReader::read(self, buf)
+        }
+    }
+
+    impl<D: BlockDevice> Write for File<'_, D> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            File::write(self, buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            File::flush(self)
+        }
+    }
+
+    impl<D: BlockDevice> Seek for File<'_, D> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+            let target = match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::End(d) => self.len() as i64 + d,
+                SeekFrom::Current(d) => self.position() as i64 + d,
+            };
+            let target = u32::try_from(target).map_err(|_| Error::InvalidSeek)?;
+            File::seek(self, target).map(u64::from)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod host {
+    use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    use crate::checksum::{Digest, HashingReader};
+    use crate::device::BlockDevice;
+    use crate::error::{DeviceError, DeviceErrorKind, Error};
+    use crate::file::File;
+
+    impl From<Error> for io::Error {
+        fn from(e: Error) -> Self {
+            let kind = match e {
+                Error::NotFound => ErrorKind::NotFound,
+                Error::AlreadyExists => ErrorKind::AlreadyExists,
+                Error::InvalidName | Error::InvalidFatIndex | Error::InvalidSeek | Error::InvalidInput => {
+                    ErrorKind::InvalidInput
+                }
+                Error::ReadOnlyVolume | Error::WriteProtected | Error::ReadOnlyFile => ErrorKind::PermissionDenied,
+                Error::NoSpace | Error::DirFull => ErrorKind::StorageFull,
+                Error::Corrupt | Error::InvalidFsInfo | Error::VerifyFailed => ErrorKind::InvalidData,
+                Error::UnexpectedEof => ErrorKind::UnexpectedEof,
+                Error::NotADirectory => ErrorKind::NotADirectory,
+                Error::IsADirectory => ErrorKind::IsADirectory,
+                Error::AlreadyOpen => ErrorKind::ResourceBusy,
+                Error::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+                Error::Cancelled => ErrorKind::Interrupted,
+                Error::Device(d) => match d.kind {
+                    DeviceErrorKind::Host if d.code != 0 => return io::Error::from_raw_os_error(d.code as i32),
+                    DeviceErrorKind::Timeout => ErrorKind::TimedOut,
+                    DeviceErrorKind::Removed => ErrorKind::NotConnected,
+                    _ => ErrorKind::Other,
+                },
+                Error::UnsupportedSectorSize => ErrorKind::Unsupported,
+                _ => ErrorKind::Other,
+            };
+            io::Error::new(kind, e)
+        }
+    }
+
+    /// For host-backed `BlockDevice`s: OS errors become `DeviceErrorKind::Host`
+    /// with the error number, so `io::Error::from` gives back the same kind.
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            let kind = match e.kind() {
+                ErrorKind::UnexpectedEof => return Error::UnexpectedEof,
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => return Error::WriteProtected,
+                ErrorKind::TimedOut => DeviceErrorKind::Timeout,
+                _ => DeviceErrorKind::Host,
+            };
+            Error::Device(DeviceError::new(kind, e.raw_os_error().unwrap_or(0) as u32))
+        }
+    }
+
+    impl<D: BlockDevice> Read for File<'_, D> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Ok(File::read(self, buf)?)
+        }
+    }
+
+    impl<D: BlockDevice, H: Digest> Read for HashingReader<'_, '_, D, H> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Ok(HashingReader::read(self, buf)?)
+        }
+    }
+
+    impl<D: BlockDevice> Write for File<'_, D> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(File::write(self, buf)?)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(File::flush(self)?)
+        }
+    }
+
+    impl<D: BlockDevice> Seek for File<'_, D> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let target = match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::End(d) => self.len() as i64 + d,
+                SeekFrom::Current(d) => self.position() as i64 + d,
+            };
+            let target = u32::try_from(target).map_err(|_| Error::InvalidSeek)?;
+            Ok(File::seek(self, target)? as u64)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::{BufRead, BufReader};
+
+        use super::*;
+        use crate::device::MemDevice;
+        use crate::fs::fixtures::make_tiny_fat32_image;
+        use crate::fs::Fat32;
+
+        #[test]
+        fn io_copy_and_buf_reader() {
+            let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+            let mut f = fs.create_file_root("LINES.TXT").unwrap();
+            io::copy(&mut &b"one\ntwo\nthree\n"[..], &mut f).unwrap();
+            Seek::seek(&mut f, SeekFrom::Start(0)).unwrap();
+
+            let lines: std::vec::Vec<std::string::String> = BufReader::new(f).lines().map(|l| l.unwrap()).collect();
+            assert_eq!(lines, ["one", "two", "three"]);
+        }
+    }
+}
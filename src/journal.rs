@@ -0,0 +1,291 @@
+//! Redo journal for metadata updates (`MountOptions::journal`).
+//!
+//! While an operation runs, FAT and directory sector writes are staged in
+//! memory (see `Fat32::transaction`). On success the staged sectors are written to
+//! a journal in the reserved area, committed by a single header write, then
+//! copied to their home locations and the header cleared, with a device flush
+//! between each of these stages so a write-back cache cannot reorder them. Mounting replays a
+//! committed journal and drops an uncommitted one, so an operation either
+//! happened completely or not at all.
+//!
+//! Layout, starting at `JOURNAL_START_SECTOR`:
+//! - header: magic, sector count, checksum, then the target LBA of each sector
+//! - one sector image per entry
+//!
+//! Reserved sectors may hold boot code (Windows keeps some in sector 12), so
+//! the area is only used if its header carries the magic or every sector of
+//! it is zero; the first journaled mount then claims it with an empty header.
+
+use alloc::vec::Vec;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Operation, Result};
+use crate::fs::Fat32;
+
+/// First reserved sector used by the journal (after the backup boot sectors 6..=8).
+pub const JOURNAL_START_SECTOR: u16 = 9;
+
+const MAGIC: &[u8; 8] = b"FATJRNL1";
+/// Target LBAs that fit in the header after magic, count and checksum.
+const MAX_ENTRIES: usize = (512 - 16) / 8;
+
+/// Header of a journal holding nothing.
+fn empty_header() -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[0..8].copy_from_slice(MAGIC);
+    header
+}
+
+fn checksum(sectors: &[(u64, [u8; 512])]) -> u32 {
+    // FNV-1a over every target LBA and sector image.
+    let mut h = 0x811C9DC5u32;
+    for (lba, data) in sectors {
+        for &b in lba.to_le_bytes().iter().chain(data.iter()) {
+            h = (h ^ b as u32).wrapping_mul(0x01000193);
+        }
+    }
+    h
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Number of sectors one journaled operation may change, or an error if
+    /// the reserved area has no room for a journal.
+    pub(crate) fn journal_capacity(&self) -> Result<usize> {
+        let start = JOURNAL_START_SECTOR;
+        let reserved = self.bpb.reserved_sectors;
+        let fsinfo = self.bpb.fsinfo_sector;
+        let backup = self.bpb.backup_boot_sector;
+        let collides = (start..reserved).contains(&fsinfo)
+            || (self.bpb.has_backup_boot_sector() && backup + 3 > start && backup < reserved);
+        if reserved < start + 2 || collides {
+            return Err(Error::NoJournalSpace);
+        }
+        Ok(((reserved - start - 1) as usize).min(MAX_ENTRIES))
+    }
+
+    /// Flush the device so every stage of a commit is on the media before the next starts.
+    fn journal_barrier(&mut self) -> Result<()> {
+        self.dev.flush().map_err(|e| self.record(Operation::Flush, None, None, e))
+    }
+
+    /// Write `sectors` through the journal: journal copy, commit, home copy, clear.
+    pub(crate) fn journal_commit(&mut self, sectors: &[(u64, [u8; 512])]) -> Result<()> {
+        if sectors.len() > self.journal_capacity()? {
+            return Err(Error::NoJournalSpace);
+        }
+        let start = JOURNAL_START_SECTOR as u64;
+        for (i, (_, data)) in sectors.iter().enumerate() {
+            self.write_sector_direct(Operation::WriteJournal, start + 1 + i as u64, data)?;
+        }
+        self.journal_barrier()?;
+
+        let mut header = [0u8; 512];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&(sectors.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&checksum(sectors).to_le_bytes());
+        for (i, (lba, _)) in sectors.iter().enumerate() {
+            header[16 + i * 8..24 + i * 8].copy_from_slice(&lba.to_le_bytes());
+        }
+        fs_debug!("fat32: journal commit {} sectors", sectors.len());
+        self.write_sector_direct(Operation::WriteJournal, start, &header)?;
+        self.journal_barrier()?;
+
+        for (lba, data) in sectors {
+            self.write_sector_direct(Operation::WriteJournal, *lba, data)?;
+        }
+        self.journal_barrier()?;
+        self.write_sector_direct(Operation::WriteJournal, start, &empty_header())
+    }
+
+    /// Make sure the journal area belongs to the journal, claiming it if it is
+    /// all zeros; anything else there fails with `NoJournalSpace`.
+    pub(crate) fn claim_journal(&mut self) -> Result<()> {
+        let capacity = self.journal_capacity()?;
+        let start = JOURNAL_START_SECTOR as u64;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadJournal, start, &mut buf)?;
+        if &buf[0..8] == MAGIC {
+            return Ok(());
+        }
+        for lba in start..=start + capacity as u64 {
+            self.read_sector(Operation::ReadJournal, lba, &mut buf)?;
+            if buf.iter().any(|&b| b != 0) {
+                fs_warn!("fat32: reserved sector {} is in use, not journaling", lba);
+                return Err(Error::NoJournalSpace);
+            }
+        }
+        if self.check_writable().is_err() {
+            // Nothing will be journaled; leave the area unclaimed.
+            return Ok(());
+        }
+        self.write_sector_direct(Operation::WriteJournal, start, &empty_header())
+    }
+
+    /// Sectors of a committed but not yet applied journal (empty if none).
+    ///
+    /// A torn or otherwise invalid journal is ignored: it was never committed.
+    pub(crate) fn journal_pending(&self) -> Result<Vec<(u64, [u8; 512])>> {
+        let capacity = self.journal_capacity()?;
+        let start = JOURNAL_START_SECTOR as u64;
+        let mut header = [0u8; 512];
+        self.read_sector(Operation::ReadJournal, start, &mut header)?;
+        let count = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        if &header[0..8] != MAGIC || count == 0 || count > capacity {
+            return Ok(Vec::new());
+        }
+
+        let total = self.bpb.total_sectors_32 as u64;
+        let mut sectors = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut lba = [0u8; 8];
+            lba.copy_from_slice(&header[16 + i * 8..24 + i * 8]);
+            let lba = u64::from_le_bytes(lba);
+            if lba < self.bpb.reserved_sectors as u64 || lba >= total {
+                return Ok(Vec::new());
+            }
+            let mut data = [0u8; 512];
+            self.read_sector(Operation::ReadJournal, start + 1 + i as u64, &mut data)?;
+            sectors.push((lba, data));
+        }
+        let sum = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        if sum != checksum(&sectors) {
+            fs_warn!("fat32: ignoring journal with bad checksum");
+            return Ok(Vec::new());
+        }
+        Ok(sectors)
+    }
+
+    /// Finish an interrupted commit found at mount.
+    ///
+    /// On a read-only mount the journal is only applied in memory.
+    pub(crate) fn replay_journal(&mut self) -> Result<()> {
+        self.claim_journal()?;
+        let sectors = self.journal_pending()?;
+        if sectors.is_empty() {
+            return Ok(());
+        }
+        fs_warn!("fat32: replaying journal of {} sectors", sectors.len());
+        if self.options().read_only {
+            self.staged = sectors;
+            return Ok(());
+        }
+        for (lba, data) in &sectors {
+            self.write_sector_direct(Operation::WriteJournal, *lba, data)?;
+        }
+        self.journal_barrier()?;
+        self.write_sector_direct(Operation::WriteJournal, JOURNAL_START_SECTOR as u64, &empty_header())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fault_device::FaultDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::options::MountOptions;
+
+
+    fn journaled() -> MountOptions {
+        MountOptions {
+            journal: true,
+            ..MountOptions::default()
+        }
+    }
+
+    #[test]
+    fn interrupted_operations_are_all_or_nothing() {
+        let mut base = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        base.write_file_root("OLD.TXT", &[1u8; 1200]).unwrap();
+        let base = base.unmount().unwrap().into_inner();
+
+        let mut completed = false;
+        for writes in 0..40 {
+            let dev = FaultDevice::new(MemDevice::new(base.clone())).power_cut_after(writes);
+            let mut fs = Fat32::mount_with(dev, journaled()).expect("mount");
+            fs.remove_file_root("OLD.TXT").unwrap();
+            fs.write_file_root("NEW.TXT", &[2u8; 1500]).unwrap();
+
+            let fs = Fat32::mount_with(fs.into_device().into_inner(), journaled()).expect("remount");
+            assert!(fs.check().unwrap().is_clean(), "cut after {} writes", writes);
+            match fs.read_file_root("NEW.TXT") {
+                Ok(data) => assert_eq!(data, [2u8; 1500]),
+                Err(e) => assert_eq!(e, Error::NotFound),
+            }
+            if fs.read_file_root("NEW.TXT").is_ok() {
+                assert_eq!(fs.read_file_root("OLD.TXT"), Err(Error::NotFound));
+                completed = true;
+            }
+        }
+        assert!(completed);
+    }
+
+    /// Records writes as `Some(lba)` and flushes as `None`.
+    struct EventLog {
+        inner: MemDevice,
+        events: Vec<Option<u64>>,
+    }
+
+    impl BlockDevice for EventLog {
+        fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+            self.inner.read_sector(lba, buf)
+        }
+
+        fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+            self.events.push(Some(lba));
+            self.inner.write_sector(lba, buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.events.push(None);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commit_stages_are_flushed_in_order() {
+        let mut dev = EventLog {
+            inner: MemDevice::new(make_tiny_fat32_image()),
+            events: Vec::new(),
+        };
+        let mut fs = Fat32::mount_with(&mut dev, journaled()).expect("mount");
+        fs.write_file_root("A.TXT", b"a").unwrap();
+        drop(fs);
+        dev.events.clear();
+        let mut fs = Fat32::mount_with(&mut dev, journaled()).expect("mount");
+        fs.remove_file_root("A.TXT").unwrap();
+        drop(fs);
+
+        // The last commit: images, flush, header, flush, home copies, flush, clear.
+        let start = JOURNAL_START_SECTOR as u64;
+        let header = Some(start);
+        let events = &dev.events;
+        let clear = events.iter().rposition(|&e| e == header).unwrap();
+        let commit = events[..clear].iter().rposition(|&e| e == header).unwrap();
+        assert_eq!(events[clear - 1], None);
+        assert_eq!((events[commit - 1], events[commit + 1]), (None, None));
+        assert!(events[commit + 2..clear - 1].iter().all(|&e| e.is_some_and(|lba| lba > start + 8)));
+        let images = events[..commit - 1].iter().rev().take_while(|e| e.is_some());
+        assert!(images.count() > 0);
+    }
+
+    #[test]
+    fn reserved_sectors_in_use_are_left_alone() {
+        let mut img = make_tiny_fat32_image();
+        let boot_code = 12 * 512;
+        img[boot_code..boot_code + 4].copy_from_slice(&[0xFA, 0x33, 0xC9, 0x8E]);
+        let fs = Fat32::mount_with(MemDevice::new(img.clone()), journaled());
+        assert!(matches!(fs, Err(Error::NoJournalSpace)));
+
+        img[boot_code..boot_code + 4].fill(0);
+        let fs = Fat32::mount_with(MemDevice::new(img), journaled()).expect("mount");
+        let img = fs.unmount().unwrap().into_inner();
+        let header = JOURNAL_START_SECTOR as usize * 512;
+        assert_eq!(&img[header..header + 8], MAGIC);
+        // Once claimed, old sector images in the area no longer matter.
+        let mut img = img;
+        img[boot_code] = 0xFA;
+        let mut fs = Fat32::mount_with(MemDevice::new(img), journaled()).expect("remount");
+        fs.write_file_root("A.TXT", b"a").unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+//! Volume address math (boot sector fields to sector numbers).
+//!
+//! Depends only on a parsed `Bpb`, so boot loaders that just need to locate
+//! a file's sectors can use it without the filesystem layer. These functions
+//! are also re-exported from `fat`.
+
+use crate::bpb::Bpb;
+use crate::error::{Error, Result};
+
+/// Compute LBA of FAT region start.
+pub fn fat_start_lba(bpb: &Bpb) -> u64 {
+    bpb.reserved_sectors as u64
+}
+
+/// Compute LBA of the first sector of FAT copy `index` (0 = primary).
+pub fn fat_copy_lba(bpb: &Bpb, index: u8) -> u64 {
+    fat_start_lba(bpb) + (index as u64) * (bpb.fat_size_32 as u64)
+}
+
+/// Compute LBA of data region start.
+pub fn data_start_lba(bpb: &Bpb) -> u64 {
+    fat_start_lba(bpb) + (bpb.num_fats as u64) * (bpb.fat_size_32 as u64)
+}
+
+/// Convert cluster number to first sector LBA.
+///
+/// Fails with `Corrupt` for clusters 0 and 1, which have no data sectors.
+pub fn cluster_to_lba(bpb: &Bpb, cluster: u32) -> Result<u64> {
+    // Cluster numbers start at 2.
+    let index = cluster.checked_sub(2).ok_or(Error::Corrupt)?;
+    Ok(data_start_lba(bpb) + (index as u64) * (bpb.sectors_per_cluster as u64))
+}
+
+/// Highest valid cluster number, bounded by both the data region and the FAT size.
+pub fn max_cluster(bpb: &Bpb) -> u32 {
+    let data_sectors = (bpb.total_sectors_32 as u64).saturating_sub(data_start_lba(bpb));
+    let by_data = data_sectors / (bpb.sectors_per_cluster as u64) + 1;
+    let by_fat = (bpb.fat_size_32 as u64) * 128 - 1;
+    by_data.min(by_fat).min(0x0FFFFFF6) as u32
+}
+
+/// Number of data clusters (cluster numbers 2 to `max_cluster`).
+pub fn cluster_count(bpb: &Bpb) -> u32 {
+    max_cluster(bpb) - 1
+}
+
+/// 32-byte directory records per sector.
+pub fn dir_entries_per_sector(bpb: &Bpb) -> u32 {
+    bpb.bytes_per_sector as u32 / 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    #[test]
+    fn tiny_image_layout() {
+        let img = make_tiny_fat32_image();
+        let bpb = Bpb::parse(img[..512].try_into().unwrap()).unwrap();
+        assert_eq!(fat_start_lba(&bpb), 32);
+        assert_eq!(fat_copy_lba(&bpb, 0), 32);
+        assert_eq!(data_start_lba(&bpb), 34);
+        assert_eq!(cluster_to_lba(&bpb, 2), Ok(34));
+        assert_eq!(cluster_to_lba(&bpb, 10), Ok(42));
+        assert_eq!(cluster_to_lba(&bpb, 1), Err(Error::Corrupt));
+        assert_eq!((max_cluster(&bpb), cluster_count(&bpb)), (167, 166));
+        assert_eq!(dir_entries_per_sector(&bpb), 16);
+    }
+}
@@ -1,16 +1,90 @@
 #![no_std]
 
+#[cfg(any(test, feature = "alloc"))]
 extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
 
-#[cfg(not(test))]
-mod allocator;
+// Bring your own allocator: with `alloc` the crate allocates but never defines
+// a `#[global_allocator]`, which is the application's to pick. Without `alloc`
+// the heap-backed APIs are compiled out and nothing allocates.
+#[macro_use]
+mod trace;
 
+#[cfg(feature = "async")]
+pub mod asynch;
 pub mod bpb;
+#[cfg(feature = "heapless")]
+pub mod bounded;
+pub mod cancel;
+pub mod checksum;
+pub mod chunks;
+#[cfg(feature = "alloc")]
+mod clone;
+pub mod codepage;
+#[cfg(any(all(test, feature = "alloc"), feature = "test-util"))]
+pub mod compat;
+#[cfg(feature = "alloc")]
+mod copy;
 pub mod device;
 pub mod dir;
+pub mod dir_handle;
+#[cfg(feature = "embassy")]
+pub mod embassy;
 pub mod error;
 pub mod fat;
+#[cfg(feature = "std")]
+pub mod fatfs;
+#[cfg(any(all(test, feature = "alloc"), feature = "test-util"))]
+pub mod fault_device;
+pub mod file;
+#[cfg(feature = "std")]
+pub mod file_device;
+pub mod find;
+pub mod flash_device;
 pub mod fs;
+#[cfg(feature = "alloc")]
+pub mod fsck;
+pub mod fsinfo;
+// Only the `fuser` glue needs the `fuse` feature; tests cover the rest without libfuse.
+#[cfg(any(feature = "fuse", all(test, feature = "std", feature = "chrono")))]
+pub mod fuse;
+#[cfg(any(all(test, feature = "alloc"), feature = "test-util"))]
+pub mod image;
+mod io;
+#[cfg(feature = "alloc")]
+pub mod journal;
+pub mod layout;
+pub mod metadata;
+pub mod mkfs;
+pub mod options;
+pub mod path;
+#[cfg(any(all(test, feature = "alloc"), feature = "test-util"))]
+pub mod random_volume;
+pub mod read_dir;
+pub mod remap_device;
+#[cfg(feature = "alloc")]
+mod replace;
+#[cfg(feature = "alloc")]
+mod resize;
+pub mod retry_device;
+#[cfg(feature = "embedded-hal")]
+pub mod sd_spi;
+#[cfg(feature = "alloc")]
+pub mod sdmmc;
+pub mod shared;
+pub mod stats;
+#[cfg(any(all(test, feature = "alloc"), feature = "test-util"))]
+pub mod trace_device;
+pub mod volume_id;
 
-pub use crate::error::{Error, Result};
+pub use crate::cancel::Cancel;
+pub use crate::dir_handle::Dir;
+pub use crate::error::{DeviceError, DeviceErrorKind, Error, ErrorContext, Operation, Result};
+pub use crate::file::{File, FileHandle};
 pub use crate::fs::Fat32;
+pub use crate::metadata::{DirSize, Metadata, Timestamp};
+pub use crate::options::{AllocPolicy, CreateOptions, ListOptions, MountOptions, OpenMode};
+pub use crate::shared::SharedFat32;
+pub use crate::stats::{ClusterRun, ClusterState, FsStats};
+pub use crate::volume_id::VolumeIdSource;
@@ -0,0 +1,480 @@
+//! Stat-style lookups (`Fat32::metadata`, `Fat32::exists`) and timestamp and
+//! attribute updates.
+
+use crate::device::BlockDevice;
+use crate::dir::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_VOLUME_ID};
+use crate::error::{Error, Operation, Result};
+#[cfg(feature = "alloc")]
+use crate::fat::max_cluster;
+use crate::fs::Fat32;
+use crate::path::Path;
+
+/// Read-only attribute bit.
+pub const ATTR_READ_ONLY: u8 = 0x01;
+/// Hidden attribute bit.
+pub const ATTR_HIDDEN: u8 = 0x02;
+/// System attribute bit.
+pub const ATTR_SYSTEM: u8 = 0x04;
+
+/// A FAT date and time as stored on disk (local time, 2-second resolution).
+///
+/// `date` packs year-since-1980, month and day; `time` packs hour, minute
+/// and second / 2. All zero means "not set".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Timestamp {
+    pub date: u16,
+    pub time: u16,
+}
+
+impl Timestamp {
+    /// Pack a date and time; `year` must be 1980..=2107 and `second` is rounded down to even.
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        let date = (year.saturating_sub(1980).min(127) << 9) | ((month as u16 & 0xF) << 5) | (day as u16 & 0x1F);
+        let time = ((hour as u16 & 0x1F) << 11) | ((minute as u16 & 0x3F) << 5) | ((second as u16 / 2) & 0x1F);
+        Self { date, time }
+    }
+
+    /// Year (1980..=2107).
+    pub fn year(&self) -> u16 {
+        1980 + (self.date >> 9)
+    }
+
+    /// Month (1..=12).
+    pub fn month(&self) -> u8 {
+        ((self.date >> 5) & 0xF) as u8
+    }
+
+    /// Day of month (1..=31).
+    pub fn day(&self) -> u8 {
+        (self.date & 0x1F) as u8
+    }
+
+    /// Hour (0..=23).
+    pub fn hour(&self) -> u8 {
+        (self.time >> 11) as u8
+    }
+
+    /// Minute (0..=59).
+    pub fn minute(&self) -> u8 {
+        ((self.time >> 5) & 0x3F) as u8
+    }
+
+    /// Second (even, 0..=58).
+    pub fn second(&self) -> u8 {
+        ((self.time & 0x1F) * 2) as u8
+    }
+
+    /// Pack a date and time, clamping dates outside 1980..=2107 to the nearest end.
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    fn clamped(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        match year {
+            ..1980 => Self::new(1980, 1, 1, 0, 0, 0),
+            2108.. => Self::new(2107, 12, 31, 23, 59, 58),
+            y => Self::new(y as u16, month, day, hour, minute, second),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp {
+    /// As a `chrono` date and time; `None` if unset or not a valid date.
+    pub fn to_naive_date_time(&self) -> Option<chrono::NaiveDateTime> {
+        let date = chrono::NaiveDate::from_ymd_opt(self.year() as i32, self.month() as u32, self.day() as u32)?;
+        date.and_hms_opt(self.hour() as u32, self.minute() as u32, self.second() as u32)
+    }
+}
+
+/// Rounds the second down to even and clamps dates outside 1980..=2107.
+#[cfg(feature = "chrono")]
+impl From<chrono::NaiveDateTime> for Timestamp {
+    fn from(dt: chrono::NaiveDateTime) -> Self {
+        use chrono::{Datelike, Timelike};
+        let (month, day) = (dt.month() as u8, dt.day() as u8);
+        Self::clamped(dt.year(), month, day, dt.hour() as u8, dt.minute() as u8, dt.second() as u8)
+    }
+}
+
+#[cfg(feature = "time")]
+impl Timestamp {
+    /// As a `time` date and time; `None` if unset or not a valid date.
+    pub fn to_primitive_date_time(&self) -> Option<time::PrimitiveDateTime> {
+        let month = time::Month::try_from(self.month()).ok()?;
+        let date = time::Date::from_calendar_date(self.year() as i32, month, self.day()).ok()?;
+        let time = time::Time::from_hms(self.hour(), self.minute(), self.second()).ok()?;
+        Some(time::PrimitiveDateTime::new(date, time))
+    }
+}
+
+/// Rounds the second down to even and clamps dates outside 1980..=2107.
+#[cfg(feature = "time")]
+impl From<time::PrimitiveDateTime> for Timestamp {
+    fn from(dt: time::PrimitiveDateTime) -> Self {
+        Self::clamped(dt.year(), dt.month().into(), dt.day(), dt.hour(), dt.minute(), dt.second())
+    }
+}
+
+/// Everything stored in a file's or directory's entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    /// File size in bytes (0 for directories).
+    pub size: u32,
+    /// Attribute bits (`ATTR_*`).
+    pub attr: u8,
+    /// First cluster of the data (0 for an empty file).
+    pub first_cluster: u32,
+    /// Creation date and time (2-second resolution).
+    pub created: Timestamp,
+    /// Last write date and time (2-second resolution).
+    pub modified: Timestamp,
+    /// Last access date (FAT keeps no access time; `time` is always 0).
+    pub accessed: Timestamp,
+    /// Sector holding the directory entry.
+    pub entry_lba: u64,
+    /// Slot (0..16) of the entry within that sector.
+    pub entry_slot: usize,
+}
+
+impl Metadata {
+    /// Decode the 32-byte entry stored at `entry_lba`/`entry_slot`.
+    pub fn from_record(rec: &[u8; 32], entry_lba: u64, entry_slot: usize) -> Self {
+        let u16_at = |o: usize| u16::from_le_bytes([rec[o], rec[o + 1]]);
+        Self {
+            size: u32::from_le_bytes([rec[28], rec[29], rec[30], rec[31]]),
+            attr: rec[11],
+            first_cluster: ((u16_at(20) as u32) << 16) | u16_at(26) as u32,
+            created: Timestamp {
+                date: u16_at(16),
+                time: u16_at(14),
+            },
+            modified: Timestamp {
+                date: u16_at(24),
+                time: u16_at(22),
+            },
+            accessed: Timestamp {
+                date: u16_at(18),
+                time: 0,
+            },
+            entry_lba,
+            entry_slot,
+        }
+    }
+
+    /// True for a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// True for a regular file.
+    pub fn is_file(&self) -> bool {
+        self.attr & (ATTR_DIRECTORY | ATTR_VOLUME_ID) == 0
+    }
+
+    /// True if the read-only attribute is set.
+    pub fn is_read_only(&self) -> bool {
+        self.attr & ATTR_READ_ONLY != 0
+    }
+}
+
+/// Totals of a directory tree, from `Fat32::dir_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirSize {
+    /// Files in the tree.
+    pub files: u32,
+    /// Subdirectories in the tree (not counting the directory itself).
+    pub dirs: u32,
+    /// Sum of the file sizes.
+    pub bytes: u64,
+    /// Space taken on disk: every cluster of every file and directory,
+    /// including the directory itself and slack at the end of chains.
+    pub allocated: u64,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Entry details of `path` (`/`-separated 8.3 components).
+    ///
+    /// The root directory has no entry and fails with `InvalidName`.
+    pub fn metadata(&self, path: &str) -> Result<Metadata> {
+        let (_, lba, slot) = self.find_path(path)?;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let mut rec = [0u8; 32];
+        rec.copy_from_slice(&buf[slot * 32..slot * 32 + 32]);
+        Ok(Metadata::from_record(&rec, lba, slot))
+    }
+
+    /// Set the creation, modification and access times of `path`, leaving
+    /// those passed as `None` unchanged (only the date of `accessed` is stored).
+    ///
+    /// The root directory has no entry and fails with `InvalidName`.
+    pub fn set_times(
+        &mut self,
+        path: &str,
+        created: Option<Timestamp>,
+        modified: Option<Timestamp>,
+        accessed: Option<Timestamp>,
+    ) -> Result<()> {
+        let (_, lba, slot) = self.find_path(path)?;
+        self.mark_dirty()?;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let rec = &mut buf[slot * 32..slot * 32 + 32];
+        if let Some(t) = created {
+            // Creation time's 10 ms refinement; `Timestamp` has none.
+            rec[13] = 0;
+            rec[14..16].copy_from_slice(&t.time.to_le_bytes());
+            rec[16..18].copy_from_slice(&t.date.to_le_bytes());
+        }
+        if let Some(t) = accessed {
+            rec[18..20].copy_from_slice(&t.date.to_le_bytes());
+        }
+        if let Some(t) = modified {
+            rec[22..24].copy_from_slice(&t.time.to_le_bytes());
+            rec[24..26].copy_from_slice(&t.date.to_le_bytes());
+        }
+        self.write_sector(Operation::WriteDir, lba, &buf)
+    }
+
+    /// Set or clear the read-only attribute of `path`.
+    ///
+    /// While it is set, writing, truncating and deleting the entry fail with
+    /// `ReadOnlyFile` (see `MountOptions::ignore_read_only_attr`); renaming is
+    /// still allowed. Files already open keep the access they were opened with.
+    pub fn set_readonly(&mut self, path: &str, read_only: bool) -> Result<()> {
+        match read_only {
+            true => self.update_attr(path, ATTR_READ_ONLY, 0),
+            false => self.update_attr(path, 0, ATTR_READ_ONLY),
+        }
+    }
+
+    /// Clear the archive attribute of `path`, as backup tools do once it is saved.
+    ///
+    /// Writing, truncating or replacing the file sets it again.
+    pub fn clear_archive(&mut self, path: &str) -> Result<()> {
+        self.update_attr(path, 0, ATTR_ARCHIVE)
+    }
+
+    /// Set the `set` and clear the `clear` attribute bits of the entry for `path`.
+    fn update_attr(&mut self, path: &str, set: u8, clear: u8) -> Result<()> {
+        let (_, lba, slot) = self.find_path(path)?;
+        self.mark_dirty()?;
+        let mut buf = [0u8; 512];
+        self.read_sector(Operation::ReadDir, lba, &mut buf)?;
+        let attr = &mut buf[slot * 32 + 11];
+        *attr = (*attr & !clear) | set;
+        self.write_sector(Operation::WriteDir, lba, &buf)
+    }
+
+    /// Walk the tree below directory `path` (the root for `/`) and total it up.
+    #[cfg(feature = "alloc")]
+    pub fn dir_size(&self, path: &str) -> Result<DirSize> {
+        let top = self.resolve_dir(Path::new(path).components()?)?;
+        let cluster_bytes = self.bpb.sectors_per_cluster as u64 * 512;
+        let max = max_cluster(&self.bpb);
+        let mut size = DirSize::default();
+        let mut dirs = alloc::vec![top];
+        while let Some(dir) = dirs.pop() {
+            if size.dirs > max {
+                return Err(self.record(Operation::ReadDir, None, Some(dir), Error::Corrupt));
+            }
+            size.allocated += self.chain_len(dir)? as u64 * cluster_bytes;
+            for e in self.dir_entries(dir)? {
+                if e.is_dir() {
+                    size.dirs += 1;
+                    dirs.push(e.first_cluster);
+                } else {
+                    size.files += 1;
+                    size.bytes += e.file_size as u64;
+                    size.allocated += self.chain_len(e.first_cluster)? as u64 * cluster_bytes;
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// True if `path` names an existing file or directory (the root always exists).
+    ///
+    /// Stops at the first missing component instead of listing whole directories.
+    pub fn exists(&self, path: &str) -> Result<bool> {
+        if Path::new(path).is_root()? {
+            return Ok(true);
+        }
+        match self.find_path(path) {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound) | Err(Error::NotADirectory) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+    use crate::options::MountOptions;
+
+    #[test]
+    fn metadata_and_exists() {
+        let mut img = ImageBuilder::new(200).fats(1).file("LOGS/A.TXT", b"hello").build().unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let m = fs.metadata("LOGS/A.TXT").unwrap();
+        let (lba, slot) = (m.entry_lba, m.entry_slot);
+        img = fs.unmount().unwrap().into_inner();
+        let rec = lba as usize * 512 + slot * 32;
+        img[rec + 22..rec + 26].copy_from_slice(&[0x2E, 0x7B, 0x4F, 0x59]);
+        img[rec + 11] |= ATTR_READ_ONLY;
+
+        let fs = Fat32::mount(MemDevice::new(img)).expect("remount");
+        let m = fs.metadata("/LOGS/A.TXT").unwrap();
+        assert_eq!((m.size, m.is_file(), m.is_read_only()), (5, true, true));
+        let t = m.modified;
+        assert_eq!((t.year(), t.month(), t.day()), (2024, 10, 15));
+        assert_eq!((t.hour(), t.minute(), t.second()), (15, 25, 28));
+        assert_eq!(Timestamp::new(2024, 10, 15, 15, 25, 29), t);
+        assert!(fs.metadata("LOGS").unwrap().is_dir());
+        assert_eq!(fs.metadata("/"), Err(Error::InvalidName));
+
+        assert_eq!(fs.exists("LOGS/A.TXT"), Ok(true));
+        assert_eq!(fs.exists("/"), Ok(true));
+        assert_eq!(fs.exists("LOGS/B.TXT"), Ok(false));
+        assert_eq!(fs.exists("LOGS/A.TXT/X"), Ok(false));
+        assert_eq!(fs.exists("bad name"), Err(Error::InvalidName));
+    }
+
+    #[test]
+    fn set_times_updates_entry() {
+        let img = ImageBuilder::new(200).fats(1).file("LOGS/A.TXT", b"hello").build().unwrap();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let before = fs.metadata("LOGS/A.TXT").unwrap();
+        let t1 = Timestamp::new(2001, 2, 3, 4, 5, 6);
+        fs.set_times("LOGS/A.TXT", None, Some(t1), None).unwrap();
+        let m = fs.metadata("LOGS/A.TXT").unwrap();
+        assert_eq!((m.created, m.modified, m.accessed), (before.created, t1, before.accessed));
+
+        let t2 = Timestamp::new(2030, 12, 31, 23, 59, 58);
+        fs.set_times("LOGS", Some(t1), Some(t2), Some(t2)).unwrap();
+        let m = fs.metadata("LOGS").unwrap();
+        assert_eq!((m.created, m.modified), (t1, t2));
+        assert_eq!(m.accessed, Timestamp { date: t2.date, time: 0 });
+        assert_eq!(fs.set_times("/", None, Some(t1), None), Err(Error::InvalidName));
+
+        let img = fs.unmount().unwrap().into_inner();
+        let options = MountOptions {
+            read_only: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(MemDevice::new(img), options).expect("remount");
+        assert_eq!(fs.metadata("LOGS/A.TXT").unwrap().modified, t1);
+        assert_eq!(fs.set_times("LOGS/A.TXT", None, Some(t2), None), Err(Error::ReadOnlyVolume));
+    }
+
+    #[test]
+    fn read_only_attribute_is_enforced() {
+        let img = ImageBuilder::new(200).fats(1).file("A.TXT", b"hello").file("LOGS/B.TXT", b"b").build().unwrap();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        fs.set_readonly("A.TXT", true).unwrap();
+        fs.set_readonly("LOGS/B.TXT", true).unwrap();
+        assert!(fs.metadata("A.TXT").unwrap().is_read_only());
+
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"hello");
+        assert_eq!(fs.write_file_root("A.TXT", b"x"), Err(Error::ReadOnlyFile));
+        assert_eq!(fs.open_file_root("A.TXT").unwrap().write(b"x"), Err(Error::ReadOnlyFile));
+        assert_eq!(fs.create_file_root("A.TXT").err(), Some(Error::ReadOnlyFile));
+        assert_eq!(fs.remove_file_root("A.TXT"), Err(Error::ReadOnlyFile));
+        assert_eq!(fs.remove_dir_all("LOGS"), Err(Error::ReadOnlyFile));
+        fs.open_dir("/").unwrap().rename("A.TXT", "C.TXT").unwrap();
+        assert_eq!(fs.read_file_root("C.TXT").unwrap(), b"hello");
+
+        fs.set_readonly("LOGS/B.TXT", false).unwrap();
+        fs.remove_dir_all("LOGS").unwrap();
+
+        let img = fs.unmount().unwrap().into_inner();
+        let options = MountOptions {
+            ignore_read_only_attr: true,
+            ..MountOptions::default()
+        };
+        let mut fs = Fat32::mount_with(MemDevice::new(img), options).expect("remount");
+        fs.write_file_root("C.TXT", b"bye").unwrap();
+        assert!(fs.metadata("C.TXT").unwrap().is_read_only());
+        fs.remove_file_root("C.TXT").unwrap();
+    }
+
+    #[test]
+    fn modification_sets_archive_bit() {
+        let img = ImageBuilder::new(200).fats(1).file("A.TXT", b"hello").build().unwrap();
+        let mut fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        let archived = |fs: &Fat32<MemDevice>| fs.metadata("A.TXT").unwrap().attr & ATTR_ARCHIVE != 0;
+        assert!(archived(&fs));
+        fs.clear_archive("A.TXT").unwrap();
+        assert!(!archived(&fs));
+
+        fs.open_file_root("A.TXT").unwrap().close().unwrap();
+        assert_eq!(fs.read_file_root("A.TXT").unwrap(), b"hello");
+        assert!(!archived(&fs));
+        // Overwriting in place leaves the size alone but still counts.
+        let mut f = fs.open_file_root("A.TXT").unwrap();
+        f.write(b"J").unwrap();
+        f.close().unwrap();
+        assert!(archived(&fs));
+
+        fs.clear_archive("A.TXT").unwrap();
+        fs.write_file_root("A.TXT", b"new").unwrap();
+        assert!(archived(&fs));
+        fs.clear_archive("A.TXT").unwrap();
+        fs.create_file_root("A.TXT").unwrap().close().unwrap();
+        assert!(archived(&fs));
+        assert_eq!(fs.metadata("A.TXT").unwrap().size, 0);
+    }
+
+    #[test]
+    fn dir_size_totals_subtree() {
+        let img = ImageBuilder::new(400)
+            .fats(1)
+            .file("LOGS/A.TXT", b"hello")
+            .file("LOGS/OLD/B.BIN", &[0u8; 1300])
+            .file("LOGS/EMPTY.TXT", b"")
+            .file("TOP.TXT", b"top")
+            .build()
+            .unwrap();
+        let fs = Fat32::mount(MemDevice::new(img)).expect("mount");
+        // LOGS, OLD and A.TXT take a cluster each, B.BIN three.
+        let expected = DirSize {
+            files: 3,
+            dirs: 1,
+            bytes: 1305,
+            allocated: 6 * 512,
+        };
+        assert_eq!(fs.dir_size("LOGS").unwrap(), expected);
+        assert_eq!(fs.dir_size("/").unwrap().allocated, 8 * 512);
+        assert_eq!(fs.dir_size("TOP.TXT"), Err(Error::NotADirectory));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_conversions() {
+        let dt = chrono::NaiveDate::from_ymd_opt(2024, 2, 29).unwrap().and_hms_opt(23, 59, 59).unwrap();
+        let ts = Timestamp::from(dt);
+        assert_eq!(ts, Timestamp::new(2024, 2, 29, 23, 59, 58));
+        assert_eq!(ts.to_naive_date_time(), Some(dt - chrono::TimeDelta::seconds(1)));
+        assert_eq!(Timestamp::default().to_naive_date_time(), None);
+        assert_eq!(Timestamp::new(2023, 2, 30, 0, 0, 0).to_naive_date_time(), None);
+        let early = chrono::NaiveDate::from_ymd_opt(1970, 6, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert_eq!(Timestamp::from(early), Timestamp::new(1980, 1, 1, 0, 0, 0));
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_conversions() {
+        let date = time::Date::from_calendar_date(2107, time::Month::December, 31).unwrap();
+        let dt = date.with_hms(23, 59, 58).unwrap();
+        let ts = Timestamp::from(dt);
+        assert_eq!(ts, Timestamp::new(2107, 12, 31, 23, 59, 58));
+        assert_eq!(ts.to_primitive_date_time(), Some(dt));
+        assert_eq!(Timestamp::default().to_primitive_date_time(), None);
+        assert_eq!(Timestamp::from(dt.replace_year(2200).unwrap()), ts);
+    }
+}
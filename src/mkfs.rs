@@ -0,0 +1,310 @@
+//! FAT32 formatter.
+
+use crate::bpb::{BootSectorBuilder, BOOT_CODE, DEFAULT_BACKUP_BOOT_SECTOR};
+use crate::cancel::Cancel;
+use crate::device::BlockDevice;
+use crate::dir::{DirEntry, ATTR_VOLUME_ID};
+use crate::error::{Error, Result};
+use crate::fsinfo::FsInfo;
+use crate::volume_id::VolumeIdSource;
+
+/// Reserved sectors before the first FAT.
+pub const RESERVED_SECTORS: u16 = 32;
+/// FSInfo sector written by `format`.
+pub const FSINFO_SECTOR: u16 = 1;
+/// Most data clusters a FAT32 volume may have (cluster numbers stay below 0x0FFFFFF7).
+pub const MAX_CLUSTERS: u32 = 0x0FFF_FFF5;
+/// Fewest data clusters the specification allows a FAT32 volume; hosts
+/// count clusters to tell FAT16 from FAT32.
+pub const MIN_CLUSTERS: u32 = 65525;
+
+/// Parameters for `format_with`.
+///
+/// `FormatOptions::default()` matches `format` with automatic cluster size
+/// and two FATs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FormatOptions {
+    /// Volume label, space-padded. Anything but `NO NAME` is also written
+    /// as a label entry in the root directory.
+    pub label: [u8; 11],
+    /// Cluster size in sectors (power of two, at most 128), or `None` to
+    /// pick it from the volume size like Windows does.
+    pub sectors_per_cluster: Option<u8>,
+    /// Number of FAT copies.
+    pub num_fats: u8,
+    /// Sectors before the first FAT; at least 8 to hold the backup boot
+    /// sector and FSInfo at 6 and 7.
+    pub reserved_sectors: u16,
+    /// Volume serial number, or `None` to derive one from the volume size,
+    /// the boot sector being replaced and (with `std`) the current time.
+    /// Set it from a `VolumeIdSource` with `volume_id_from`.
+    pub volume_id: Option<u32>,
+    /// Start LBA of the partition being formatted (0 for a whole device).
+    pub hidden_sectors: u32,
+    /// Keep the x86 boot code (`bpb::BOOT_CODE`) of the boot sector already
+    /// on the device, if it has one, so a bootloader such as syslinux
+    /// still starts after reformatting.
+    pub keep_boot_code: bool,
+    /// Format volumes with fewer than `MIN_CLUSTERS` clusters, such as small
+    /// test images. Some hosts mistake those for FAT16 and will not mount them.
+    pub allow_small: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            label: *b"NO NAME    ",
+            sectors_per_cluster: None,
+            num_fats: 2,
+            reserved_sectors: RESERVED_SECTORS,
+            volume_id: None,
+            hidden_sectors: 0,
+            keep_boot_code: false,
+            allow_small: false,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Take the volume serial number from `source`.
+    pub fn volume_id_from<S: VolumeIdSource>(mut self, mut source: S) -> Self {
+        self.volume_id = Some(source.next_volume_id());
+        self
+    }
+}
+
+/// Cluster size Windows picks for a FAT32 volume of `total_sectors`.
+pub fn auto_sectors_per_cluster(total_sectors: u32) -> u8 {
+    match total_sectors {
+        0..=532_480 => 1,          // up to 260 MiB: 512 bytes
+        532_481..=16_777_216 => 8, // up to 8 GiB: 4 KiB
+        16_777_217..=33_554_432 => 16,
+        33_554_433..=67_108_864 => 32,
+        _ => 64,
+    }
+}
+
+/// Sectors per FAT for a volume of `total_sectors`, by the formula from the
+/// Microsoft FAT specification (slightly generous).
+pub(crate) fn fat_sectors(total_sectors: u32, reserved: u32, sectors_per_cluster: u32, fats: u32) -> u32 {
+    (total_sectors.saturating_sub(reserved)).div_ceil((256 * sectors_per_cluster + fats) / 2)
+}
+
+/// Write an empty FAT32 volume of `total_sectors` sectors to `dev`.
+///
+/// Lays out 32 reserved sectors (boot sector, FSInfo, backups at 6/7),
+/// `num_fats` FATs sized for the data area, and an empty root directory in
+/// cluster 2. Only the reserved area, the FATs and the root cluster are written.
+pub fn format<D: BlockDevice>(dev: &mut D, total_sectors: u32, sectors_per_cluster: u8, num_fats: u8) -> Result<()> {
+    format_cancellable(dev, total_sectors, sectors_per_cluster, num_fats, Cancel::NEVER)
+}
+
+/// `format`, polling `cancel` before each sector it zeroes.
+///
+/// A cancelled format leaves the device without a valid volume: the old boot
+/// sector may already be gone.
+pub fn format_cancellable<D: BlockDevice>(
+    dev: &mut D,
+    total_sectors: u32,
+    sectors_per_cluster: u8,
+    num_fats: u8,
+    cancel: Cancel<'_>,
+) -> Result<()> {
+    let options = FormatOptions {
+        sectors_per_cluster: Some(sectors_per_cluster),
+        num_fats,
+        ..FormatOptions::default()
+    };
+    format_with_cancellable(dev, total_sectors, &options, cancel)
+}
+
+/// Write an empty FAT32 volume of `total_sectors` sectors laid out by `options`.
+///
+/// Fails with `Error::InvalidBootSector` for a cluster size, FAT count or
+/// reserved area FAT32 cannot have or for more than `MAX_CLUSTERS` clusters,
+/// and with `Error::NoSpace` if not even one data cluster fits. Volumes
+/// below `MIN_CLUSTERS` clusters also fail with `InvalidBootSector` unless
+/// `FormatOptions::allow_small` is set.
+pub fn format_with<D: BlockDevice>(dev: &mut D, total_sectors: u32, options: &FormatOptions) -> Result<()> {
+    format_with_cancellable(dev, total_sectors, options, Cancel::NEVER)
+}
+
+/// `format_with`, polling `cancel` before each sector it zeroes.
+pub fn format_with_cancellable<D: BlockDevice>(
+    dev: &mut D,
+    total_sectors: u32,
+    options: &FormatOptions,
+    cancel: Cancel<'_>,
+) -> Result<()> {
+    let sectors_per_cluster = options.sectors_per_cluster.unwrap_or_else(|| auto_sectors_per_cluster(total_sectors));
+    let num_fats = options.num_fats;
+    let min_reserved = DEFAULT_BACKUP_BOOT_SECTOR + FSINFO_SECTOR + 1;
+    if !sectors_per_cluster.is_power_of_two() || num_fats == 0 || options.reserved_sectors < min_reserved {
+        return Err(Error::InvalidBootSector);
+    }
+    let spc = sectors_per_cluster as u32;
+    let fats = num_fats as u32;
+    let reserved = options.reserved_sectors as u32;
+
+    let fat_size = fat_sectors(total_sectors, reserved, spc, fats);
+    let data_start = fats.checked_mul(fat_size).and_then(|s| s.checked_add(reserved)).ok_or(Error::NoSpace)?;
+    if total_sectors < data_start.saturating_add(spc) {
+        return Err(Error::NoSpace);
+    }
+    let clusters = (total_sectors - data_start) / spc;
+    if clusters > MAX_CLUSTERS || (clusters < MIN_CLUSTERS && !options.allow_small) {
+        return Err(Error::InvalidBootSector);
+    }
+    fs_debug!("fat32: format {} sectors, {} clusters, FAT {} sectors", total_sectors, clusters, fat_size);
+
+    let mut old = [0u8; 512];
+    if options.keep_boot_code || options.volume_id.is_none() {
+        dev.read_sector(0, &mut old)?;
+    }
+    let volume_id = options.volume_id.unwrap_or_else(|| derive_volume_id(total_sectors, &old));
+    let mut builder = BootSectorBuilder::new(total_sectors, fat_size)
+        .sectors_per_cluster(sectors_per_cluster)
+        .reserved_sectors(options.reserved_sectors)
+        .num_fats(num_fats)
+        .fsinfo_sector(FSINFO_SECTOR)
+        .hidden_sectors(options.hidden_sectors)
+        .volume_id(volume_id)
+        .volume_label(options.label);
+    if options.keep_boot_code && old[510..512] == [0x55, 0xAA] {
+        builder = builder.boot_code(old[BOOT_CODE].try_into().expect("420 bytes"));
+    }
+    let boot = builder.build()?;
+
+    let zero = [0u8; 512];
+    for lba in 0..data_start + spc {
+        cancel.check()?;
+        dev.write_sector(lba as u64, &zero)?;
+    }
+
+    let fsinfo = FsInfo { free_count: clusters - 1, next_free: 3 }.serialize();
+
+    let backup = DEFAULT_BACKUP_BOOT_SECTOR as u64;
+    dev.write_sector(0, &boot)?;
+    dev.write_sector(FSINFO_SECTOR as u64, &fsinfo)?;
+    dev.write_sector(backup, &boot)?;
+    dev.write_sector(backup + FSINFO_SECTOR as u64, &fsinfo)?;
+
+    // FAT[0] = media, FAT[1] = clean shutdown, FAT[2] = root directory (end of chain).
+    let mut fat = [0u8; 512];
+    fat[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+    fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+    fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+    for i in 0..fats {
+        dev.write_sector((reserved + i * fat_size) as u64, &fat)?;
+    }
+    if options.label != *b"NO NAME    " {
+        let mut root = [0u8; 512];
+        root[..32].copy_from_slice(&DirEntry::build_short_entry(options.label, ATTR_VOLUME_ID, 0, 0));
+        dev.write_sector(data_start as u64, &root)?;
+    }
+    dev.flush()
+}
+
+/// Serial number for a volume formatted without `FormatOptions::volume_id`:
+/// an FNV-1a hash of the size, the old boot sector and, with `std`, the time.
+fn derive_volume_id(total_sectors: u32, old_boot: &[u8; 512]) -> u32 {
+    let mut h = 0x811C9DC5u32;
+    let mut mix = |bytes: &[u8]| {
+        for &b in bytes {
+            h = (h ^ b as u32).wrapping_mul(0x01000193);
+        }
+    };
+    mix(&total_sectors.to_le_bytes());
+    mix(old_boot);
+    #[cfg(feature = "std")]
+    if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        mix(&now.as_nanos().to_le_bytes());
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::Fat32;
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn format_with_options() {
+        let mut dev = MemDevice::zeroed(4096);
+        let options = FormatOptions {
+            label: *b"DATA       ",
+            sectors_per_cluster: Some(4),
+            num_fats: 1,
+            reserved_sectors: 16,
+            volume_id: Some(0xDEAD_BEEF),
+            hidden_sectors: 63,
+            keep_boot_code: false,
+            allow_small: true,
+        };
+        format_with(&mut dev, 4096, &options).unwrap();
+        let fs = Fat32::mount(dev).unwrap();
+        let bpb = *fs.bpb();
+        assert_eq!((bpb.sectors_per_cluster, bpb.num_fats, bpb.reserved_sectors), (4, 1, 16));
+        assert_eq!(bpb.hidden_sectors, 63);
+        let stats = fs.stats().unwrap();
+        assert_eq!((stats.label, stats.serial), (*b"DATA       ", 0xDEAD_BEEF));
+        assert!(fs.check().unwrap().is_clean());
+        assert!(fs.list_root().unwrap().is_empty());
+
+        let mut dev = MemDevice::zeroed(1);
+        for options in [
+            FormatOptions { sectors_per_cluster: Some(3), ..options },
+            FormatOptions { num_fats: 0, ..options },
+            FormatOptions { reserved_sectors: 7, ..options },
+            FormatOptions { sectors_per_cluster: Some(1), ..options },
+        ] {
+            assert_eq!(format_with(&mut dev, u32::MAX, &options), Err(Error::InvalidBootSector));
+        }
+        assert_eq!(auto_sectors_per_cluster(u32::MAX), 64);
+        assert_eq!(format_with(&mut dev, u32::MAX, &FormatOptions::default()), Err(Error::Io));
+    }
+
+    #[test]
+    fn small_volumes_need_opt_in_and_serials_differ() {
+        let small = FormatOptions { allow_small: true, ..FormatOptions::default() };
+        let mut dev = MemDevice::zeroed(4096);
+        assert_eq!(format(&mut dev, 4096, 1, 2), Err(Error::InvalidBootSector));
+        assert_eq!(format_with(&mut dev, 4096, &FormatOptions::default()), Err(Error::InvalidBootSector));
+        format_with(&mut dev, 4096, &small).unwrap();
+        let first = Fat32::mount(&mut dev).unwrap().bpb().volume_id;
+        // The old boot sector goes into the new serial.
+        format_with(&mut dev, 4096, &small).unwrap();
+        assert_ne!(Fat32::mount(&mut dev).unwrap().bpb().volume_id, first);
+
+        // 66600 sectors leave just over MIN_CLUSTERS one-sector clusters.
+        let mut dev = MemDevice::zeroed(66_600);
+        format(&mut dev, 66_600, 1, 2).unwrap();
+        assert!(Fat32::mount(dev).unwrap().stats().unwrap().total_clusters >= MIN_CLUSTERS);
+    }
+    #[test]
+    fn reformat_keeps_boot_code() {
+        let mut dev = MemDevice::zeroed(4096);
+        format_with(&mut dev, 4096, &FormatOptions { allow_small: true, ..FormatOptions::default() }).unwrap();
+        let mut boot = [0u8; 512];
+        dev.read_sector(0, &mut boot).unwrap();
+        boot[BOOT_CODE].fill(0xCC);
+        dev.write_sector(0, &boot).unwrap();
+
+        let options = FormatOptions {
+            keep_boot_code: true,
+            allow_small: true,
+            sectors_per_cluster: Some(2),
+            ..FormatOptions::default()
+        };
+        format_with(&mut dev, 4096, &options).unwrap();
+        let mut backup = [0u8; 512];
+        dev.read_sector(0, &mut boot).unwrap();
+        dev.read_sector(DEFAULT_BACKUP_BOOT_SECTOR as u64, &mut backup).unwrap();
+        assert_eq!(boot, backup);
+        assert!(boot[BOOT_CODE].iter().all(|&b| b == 0xCC));
+        assert_eq!(Fat32::mount(dev).unwrap().bpb().sectors_per_cluster, 2);
+    }
+}
@@ -0,0 +1,205 @@
+//! Mount-time, listing and file creation options.
+
+use crate::codepage::Codepage;
+use crate::dir::{DirEntry, ATTR_ARCHIVE};
+use crate::metadata::{ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM};
+
+/// Options accepted by `Fat32::mount_with`.
+///
+/// `MountOptions::default()` matches `Fat32::mount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct MountOptions {
+    /// Refuse every mutating operation with `Error::ReadOnlyVolume`.
+    pub read_only: bool,
+    /// Reject boot sectors with any deviation from the FAT32 layout.
+    ///
+    /// When false, recoverable deviations are accepted (see `Bpb::parse_with`).
+    pub strict_bpb: bool,
+    /// Check the FSInfo sector signatures at mount time.
+    pub verify_fsinfo: bool,
+    /// FAT copy used for all FAT reads and writes (0 = primary).
+    pub fat_to_use: u8,
+    /// Only read the boot sector at mount; skip the root chain check and dirty-flag read.
+    pub lazy: bool,
+    /// Make file creation, deletion and `Fat32::transaction` groups atomic across
+    /// power loss through a journal in the reserved sectors (see the `journal`
+    /// module); a pending journal is replayed at mount.
+    ///
+    /// Needs the `alloc` feature: without it `mount_with` fails with
+    /// `Error::InvalidInput`.
+    pub journal: bool,
+    /// Read back every written sector and fail with `Error::VerifyFailed` if it differs.
+    ///
+    /// Doubles the I/O of writes; meant for marginal SD cards.
+    pub verify_writes: bool,
+    /// Keep updated FAT sectors in memory and write each once, on the next
+    /// `Fat32::flush`, `sync` or `unmount`, instead of once per changed entry.
+    ///
+    /// Until then a power loss can leave directory entries pointing at
+    /// clusters the on-disk FAT still shows free, and `Fat32::chain` and
+    /// `compare_fat_copies` (which read the device) do not see the updates.
+    /// Dropping the handle without flushing discards them. Needs `alloc`,
+    /// like `journal`.
+    pub defer_fat_writes: bool,
+    /// Let a file read sequentially fetch the rest of the current cluster,
+    /// and the next one when it follows on disk, with one device call.
+    ///
+    /// Each open file, including every entry of the open-file table, buffers
+    /// up to two clusters. Off by default, and only accepted with `alloc`.
+    pub read_ahead: bool,
+    /// Start LBA of the partition the device covers, if known.
+    ///
+    /// Compared with the boot sector's `hidden_sectors`: a mismatch fails the
+    /// mount with `Error::InvalidBootSector` under `strict_bpb` and is only
+    /// logged otherwise.
+    pub partition_start: Option<u32>,
+    /// Pass the sectors of clusters freed by deleting, truncating or
+    /// overwriting a file to `BlockDevice::trim`, so flash devices can erase
+    /// them ahead of time. Their old content cannot be recovered afterwards.
+    ///
+    /// Inside a transaction, or while `defer_fat_writes` holds FAT updates,
+    /// the trims wait until the FAT that frees the clusters reaches the
+    /// device; a rolled-back transaction trims nothing.
+    pub discard: bool,
+    /// Where new clusters are looked for.
+    pub allocation: AllocPolicy,
+    /// Let writes, truncation and deletion go through entries with the
+    /// read-only attribute instead of failing with `Error::ReadOnlyFile`.
+    pub ignore_read_only_attr: bool,
+    /// OEM code page used to turn names given to lookups, creation and
+    /// renames into short names.
+    pub codepage: Codepage,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            strict_bpb: true,
+            verify_fsinfo: false,
+            fat_to_use: 0,
+            lazy: false,
+            journal: false,
+            verify_writes: false,
+            defer_fat_writes: false,
+            read_ahead: false,
+            partition_start: None,
+            discard: false,
+            allocation: AllocPolicy::Pack,
+            ignore_read_only_attr: false,
+            codepage: Codepage::Ascii,
+        }
+    }
+}
+
+/// Which entries `Fat32::list_root_with` returns.
+///
+/// `ListOptions::default()` matches `Fat32::list_root`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ListOptions {
+    /// Include entries with the hidden attribute.
+    pub include_hidden: bool,
+    /// Include entries with the system attribute.
+    pub include_system: bool,
+    /// Include the volume label record (root directory only).
+    pub include_volume_label: bool,
+    /// Include the `.` and `..` records of subdirectories.
+    pub include_dot_entries: bool,
+    /// Include deleted entries, with `DirEntry::deleted` set and the lost
+    /// first name byte shown as `?`.
+    pub include_deleted: bool,
+    /// Only include entries that have a long file name.
+    pub long_names_only: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            include_hidden: true,
+            include_system: true,
+            include_volume_label: false,
+            include_dot_entries: false,
+            include_deleted: false,
+            long_names_only: false,
+        }
+    }
+}
+
+impl ListOptions {
+    /// True if `e` (preceded by long-name records if `long_name`) is selected.
+    pub(crate) fn includes(&self, e: &DirEntry, long_name: bool) -> bool {
+        (self.include_hidden || e.attr & ATTR_HIDDEN == 0)
+            && (self.include_system || e.attr & ATTR_SYSTEM == 0)
+            && (self.include_volume_label || !e.is_volume_label())
+            && (self.include_dot_entries || !e.is_dot_entry())
+            && (!self.long_names_only || long_name)
+    }
+}
+
+/// Attributes for a file created by `Fat32::write_file_root_with`,
+/// `Fat32::create_file_root_with`, `Fat32::write_file_atomic_with` or
+/// `Dir::create_file_with`; an existing file keeps its own.
+///
+/// `CreateOptions::default()` gives a plain file (archive attribute only).
+/// Files created through an `OpenMode` (`open_file_with`,
+/// `open_file_root_with`, `create_handle_root`, the `fatfs` layer) always get
+/// the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct CreateOptions {
+    /// Set the hidden attribute: left out of normal listings on Windows and of
+    /// listings here with `ListOptions::include_hidden` off.
+    pub hidden: bool,
+    /// Set the system attribute, marking an operating system file.
+    pub system: bool,
+    /// Set the read-only attribute; the handle returned by `Dir::create_file_with`
+    /// can still write.
+    pub read_only: bool,
+}
+
+impl CreateOptions {
+    /// Attribute byte of the new entry.
+    pub(crate) fn attr(&self) -> u8 {
+        let mut attr = ATTR_ARCHIVE;
+        if self.hidden {
+            attr |= ATTR_HIDDEN;
+        }
+        if self.system {
+            attr |= ATTR_SYSTEM;
+        }
+        if self.read_only {
+            attr |= ATTR_READ_ONLY;
+        }
+        attr
+    }
+}
+
+/// Where `Fat32` starts looking for free clusters (`MountOptions::allocation`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum AllocPolicy {
+    /// Always from cluster 2, packing data at the start of the volume.
+    Pack,
+    /// From just after the last cluster allocated, wrapping around at the
+    /// end, so repeated appends and deletes spread writes over the whole
+    /// data area instead of wearing out the first flash blocks. The position
+    /// is kept in the FSInfo next-free hint across mounts.
+    Rotate,
+}
+
+/// How opening a file treats an existing or missing file, for
+/// `Fat32::open_file_root_with` and `Dir::open_file_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum OpenMode {
+    /// Open an existing file at the start; `NotFound` if it is missing.
+    Open,
+    /// Create a new empty file; `AlreadyExists` if it exists.
+    CreateNew,
+    /// Create the file, or empty an existing one and free its clusters.
+    Truncate,
+    /// Open the file, creating it if missing, positioned at the end.
+    Append,
+}
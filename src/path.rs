@@ -0,0 +1,135 @@
+//! `/`-separated volume paths.
+//!
+//! All paths are absolute: a leading `/` is optional, empty and `.`
+//! components are ignored and `..` drops the previous component.
+
+use crate::error::{Error, Result};
+
+/// Longest name a component may have (the long file name limit).
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// A borrowed path on the volume, e.g. `"LOGS/2024/DAY1.TXT"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Path<'a>(&'a str);
+
+impl<'a> Path<'a> {
+    /// Wrap `s`; nothing is checked until the components are taken.
+    pub fn new(s: &'a str) -> Self {
+        Self(s)
+    }
+
+    /// The path as given.
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    /// Normalized components, outermost first (none for the root).
+    ///
+    /// Fails with `InvalidName` if a component is longer than
+    /// `MAX_COMPONENT_LEN` or `..` climbs above the root.
+    pub fn components(&self) -> Result<Components<'a>> {
+        let mut depth = 0usize;
+        for comp in self.0.split('/') {
+            match comp {
+                "" | "." => {}
+                ".." => depth = depth.checked_sub(1).ok_or(Error::InvalidName)?,
+                _ if comp.len() > MAX_COMPONENT_LEN => return Err(Error::InvalidName),
+                _ => depth += 1,
+            }
+        }
+        Ok(Components { rest: self.0, left: depth })
+    }
+
+    /// True if the path names the root directory.
+    pub fn is_root(&self) -> Result<bool> {
+        Ok(self.components()?.len() == 0)
+    }
+
+    /// Split into the parent's components and the last name.
+    ///
+    /// Fails with `InvalidName` for the root, which has no name.
+    pub fn split_last(&self) -> Result<(Components<'a>, &'a str)> {
+        let mut comps = self.components()?;
+        let name = comps.clone().last().ok_or(Error::InvalidName)?;
+        comps.left -= 1;
+        Ok((comps, name))
+    }
+}
+
+/// Iterator over the normalized components of a `Path`, from `Path::components`.
+///
+/// Walks the string itself, so no allocation is needed.
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    /// Part of the path not looked at yet.
+    rest: &'a str,
+    /// Components still to yield.
+    left: usize,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while self.left > 0 && !self.rest.is_empty() {
+            let (comp, rest) = self.rest.split_once('/').unwrap_or((self.rest, ""));
+            self.rest = rest;
+            if !matches!(comp, "" | "." | "..") && !cancelled(rest) {
+                self.left -= 1;
+                return Some(comp);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl ExactSizeIterator for Components<'_> {}
+
+/// True if a `..` in `rest` drops the component just before it.
+fn cancelled(rest: &str) -> bool {
+    let mut depth = 0usize;
+    for comp in rest.split('/') {
+        match comp {
+            "" | "." => {}
+            ".." if depth == 0 => return true,
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+impl<'a> From<&'a str> for Path<'a> {
+    fn from(s: &'a str) -> Self {
+        Self::new(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_components() {
+        let comps = |p| Path::new(p).components().map(Iterator::collect::<std::vec::Vec<_>>);
+        assert_eq!(comps("/A//B/./C/").unwrap(), ["A", "B", "C"]);
+        assert_eq!(comps("A/B/../C").unwrap(), ["A", "C"]);
+        assert_eq!(comps("A/B/C/../../D/./E/..").unwrap(), ["A", "D"]);
+        assert!(Path::new("/").is_root().unwrap());
+        assert!(Path::new("A/..").is_root().unwrap());
+        assert_eq!(comps(".."), Err(Error::InvalidName));
+        assert_eq!(comps("A/../.."), Err(Error::InvalidName));
+        let long = "X".repeat(256);
+        assert_eq!(comps(&long), Err(Error::InvalidName));
+
+        let (parent, name) = Path::new("A/B/../C/D.TXT").split_last().unwrap();
+        assert_eq!((parent.collect::<std::vec::Vec<_>>(), name), (std::vec!["A", "C"], "D.TXT"));
+        let (parent, name) = Path::new("A/B.TXT/..").split_last().unwrap();
+        assert_eq!((parent.len(), name), (0, "A"));
+        assert!(Path::new("/./").split_last().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Randomized FAT32 volumes for property tests (`test-util` feature).
+//!
+//! `RandomVolume::generate(seed)` formats an image with a random geometry
+//! (size, cluster size, FAT count, reserved sectors), then creates
+//! directories and writes and deletes files at random so that later files
+//! are fragmented across the holes. It records what the volume should
+//! contain, so a test can check that mounting, listing and reading give it
+//! back:
+//!
+//! ```ignore
+//! for seed in 0..100 {
+//!     let vol = RandomVolume::generate(seed)?;
+//!     let fs = Fat32::mount(MemDevice::new(vol.image.clone()))?;
+//!     assert!(fs.check()?.is_clean(), "seed {}", seed);
+//!     for (path, content) in &vol.files {
+//!         let mut read = Vec::new();
+//!         fs.read_file_with(path, |d| read.extend_from_slice(d))?;
+//!         assert_eq!(read, *content, "seed {}", seed);
+//!     }
+//! }
+//! ```
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::device::MemDevice;
+use crate::error::Result;
+use crate::fat::sync_fats;
+use crate::fs::Fat32;
+use crate::mkfs::{self, FormatOptions};
+
+/// A generated volume and what it holds.
+pub struct RandomVolume {
+    /// Seed the volume was generated from.
+    pub seed: u64,
+    /// Volume size in sectors.
+    pub total_sectors: u32,
+    /// Cluster size in sectors.
+    pub sectors_per_cluster: u8,
+    /// Number of FAT copies (all identical).
+    pub num_fats: u8,
+    /// Directories created, as `/`-separated paths, parents first.
+    pub dirs: Vec<String>,
+    /// Files left on the volume, as `/`-separated paths, with their content.
+    pub files: Vec<(String, Vec<u8>)>,
+    /// The disk image.
+    pub image: Vec<u8>,
+}
+
+/// xorshift64*: small, deterministic and good enough to pick test shapes.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `0..n` (n > 0).
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+impl RandomVolume {
+    /// Generate the volume for `seed`; the same seed always gives the same image.
+    pub fn generate(seed: u64) -> Result<Self> {
+        // A zero state would stay zero.
+        let mut rng = Rng(seed ^ 0x9E37_79B9_7F4A_7C15 | 1);
+        let sectors_per_cluster = 1u8 << rng.below(5);
+        let num_fats = 1 + rng.below(2) as u8;
+        let total_sectors = 300 + rng.below(4000) as u32 + 64 * sectors_per_cluster as u32;
+        let options = FormatOptions {
+            sectors_per_cluster: Some(sectors_per_cluster),
+            num_fats,
+            reserved_sectors: 8 + rng.below(32) as u16,
+            volume_id: Some(rng.next() as u32),
+            allow_small: true,
+            ..FormatOptions::default()
+        };
+        let mut dev = MemDevice::zeroed(total_sectors as usize);
+        mkfs::format_with(&mut dev, total_sectors, &options)?;
+        let mut fs = Fat32::mount(dev)?;
+
+        let mut dirs = Vec::new();
+        for i in 0..rng.below(6) {
+            let name = match rng.below(dirs.len() as u64 + 1) as usize {
+                0 => format!("D{}", i),
+                n => format!("{}/D{}", dirs[n - 1], i),
+            };
+            fs.create_dir_all(&name)?;
+            dirs.push(name);
+        }
+
+        let cluster_bytes = sectors_per_cluster as u64 * 512;
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+        for i in 0..8 + rng.below(32) {
+            if !files.is_empty() && rng.below(3) == 0 {
+                let (path, _) = files.swap_remove(rng.below(files.len() as u64) as usize);
+                let (dir, name) = split(&path);
+                fs.open_dir(dir)?.remove(name)?;
+                continue;
+            }
+            // Mostly small files, some a few clusters long, sizes often on a sector boundary.
+            let limit = fs.stats()?.free_bytes() / 4;
+            let mut len = match rng.below(4) {
+                0 => rng.below(600),
+                1 => rng.below(4) * 512,
+                _ => rng.below(cluster_bytes * 5),
+            };
+            len = len.min(limit);
+            let content: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            let dir = match rng.below(dirs.len() as u64 + 1) as usize {
+                0 => String::new(),
+                n => dirs[n - 1].clone(),
+            };
+            let name = format!("F{}.BIN", i);
+            let mut handle = fs.open_dir(if dir.is_empty() { "/" } else { &dir })?;
+            let mut file = handle.create_file(&name)?;
+            let mut done = 0;
+            while done < content.len() {
+                let n = (1 + rng.below(3 * 512) as usize).min(content.len() - done);
+                file.write(&content[done..done + n])?;
+                done += n;
+            }
+            file.close()?;
+            let path = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+            files.push((path, content));
+        }
+
+        let bpb = *fs.bpb();
+        let mut dev = fs.unmount()?;
+        // The filesystem only writes the active FAT; mirror it like `ImageBuilder` does.
+        sync_fats(&mut dev, &bpb, 0)?;
+        Ok(Self {
+            seed,
+            total_sectors,
+            sectors_per_cluster,
+            num_fats,
+            dirs,
+            files,
+            image: dev.into_inner(),
+        })
+    }
+}
+
+/// Split `path` into its directory (`/` for the root) and file name.
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("/", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat::ChainIter;
+
+    #[test]
+    fn random_volumes_round_trip() {
+        let mut fragmented = 0;
+        for seed in 0..40 {
+            let vol = RandomVolume::generate(seed).unwrap();
+            let fs = Fat32::mount(MemDevice::new(vol.image.clone())).expect("mount");
+            let bpb = fs.bpb();
+            assert_eq!((bpb.sectors_per_cluster, bpb.num_fats), (vol.sectors_per_cluster, vol.num_fats));
+            assert!(fs.compare_fat_copies().unwrap().is_empty(), "seed {}", seed);
+            let report = fs.check().unwrap();
+            assert!(report.is_clean(), "seed {}: {:?}", seed, report);
+            assert_eq!(report.files as usize, vol.files.len(), "seed {}", seed);
+            assert_eq!(report.directories as usize, vol.dirs.len() + 1, "seed {}", seed);
+
+            let mut used = 0;
+            for (path, content) in &vol.files {
+                let mut read = Vec::new();
+                fs.read_file_with(path, |d| read.extend_from_slice(d)).unwrap();
+                assert!(read == *content, "seed {}: {} differs", seed, path);
+                let first = fs.metadata(path).unwrap().first_cluster;
+                if first != 0 {
+                    let chain: Vec<u32> = ChainIter::new(&fs.dev, bpb, first).map(|c| c.unwrap()).collect();
+                    fragmented += chain.windows(2).any(|w| w[1] != w[0] + 1) as u32;
+                }
+                used += (content.len() as u64).div_ceil(bpb.sectors_per_cluster as u64 * 512);
+            }
+            let listed: usize = core::iter::once("/")
+                .chain(vol.dirs.iter().map(String::as_str))
+                .map(|d| fs.list_dir_path(d).unwrap().iter().filter(|e| !e.is_dir()).count())
+                .sum();
+            assert_eq!(listed, vol.files.len(), "seed {}", seed);
+            let stats = fs.stats().unwrap();
+            assert!(stats.total_clusters - stats.free_clusters >= used as u32, "seed {}", seed);
+        }
+        assert!(fragmented > 10, "only {} fragmented files", fragmented);
+    }
+}
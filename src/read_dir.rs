@@ -0,0 +1,160 @@
+//! Directory iteration without allocating.
+
+use crate::device::BlockDevice;
+use crate::dir::{DirEntry, ATTR_LFN};
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, EOC_MIN};
+use crate::fs::Fat32;
+use crate::options::ListOptions;
+use crate::path::Path;
+
+/// Iterator over the entries of one directory, from `Fat32::read_dir`.
+///
+/// Holds a single sector buffer; entries are parsed as the iterator
+/// advances. Iteration stops after the first error.
+pub struct ReadDir<'a, D: BlockDevice> {
+    fs: &'a Fat32<D>,
+    options: ListOptions,
+    cluster: u32,
+    /// Next record to parse within `cluster`, counted from 0.
+    slot: u32,
+    buf: [u8; 512],
+    /// The previous record was a live LFN record, i.e. the next entry has a long name.
+    after_lfn: bool,
+    done: bool,
+}
+
+impl<'a, D: BlockDevice> ReadDir<'a, D> {
+    pub(crate) fn new(fs: &'a Fat32<D>, dir_cluster: u32, options: ListOptions) -> Self {
+        Self {
+            fs,
+            options,
+            cluster: dir_cluster,
+            slot: 0,
+            buf: [0u8; 512],
+            after_lfn: false,
+            done: false,
+        }
+    }
+
+    fn advance(&mut self) -> Result<Option<DirEntry>> {
+        let slots_per_cluster = self.fs.bpb.sectors_per_cluster as u32 * 16;
+        loop {
+            if self.slot == slots_per_cluster {
+                let next = self.fs.read_fat(self.cluster)?;
+                if next >= EOC_MIN {
+                    return Ok(None);
+                }
+                if next < 2 {
+                    return Err(self.fs.record(Operation::ReadDir, None, Some(self.cluster), Error::Corrupt));
+                }
+                self.cluster = next;
+                self.slot = 0;
+            }
+            let i = (self.slot % 16) as usize;
+            if i == 0 {
+                let lba = cluster_to_lba(&self.fs.bpb, self.cluster)? + (self.slot / 16) as u64;
+                self.fs.read_sector(Operation::ReadDir, lba, &mut self.buf)?;
+            }
+            let offset = self.slot * 32;
+            self.slot += 1;
+
+            let mut rec = [0u8; 32];
+            rec.copy_from_slice(&self.buf[i * 32..i * 32 + 32]);
+            if rec[0] == 0x00 {
+                return Ok(None);
+            }
+            let deleted = rec[0] == 0xE5;
+            let long_name = core::mem::replace(&mut self.after_lfn, rec[11] == ATTR_LFN && !deleted);
+            if rec[11] == ATTR_LFN || (deleted && !self.options.include_deleted) {
+                continue;
+            }
+            if deleted {
+                // The first name byte is gone; show it as `?` like DOS undelete tools.
+                rec[0] = b'?';
+            }
+            let Some(mut e) = DirEntry::parse(&rec)? else {
+                continue;
+            };
+            e.deleted = deleted;
+            if self.options.includes(&e, long_name) {
+                return Ok(Some(e.located(self.cluster, offset)));
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Result<DirEntry>> {
+        if self.done {
+            return None;
+        }
+        let r = self.advance().transpose();
+        if !matches!(r, Some(Ok(_))) {
+            self.done = true;
+        }
+        r
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Iterate over directory `path` (the root for `/`) with the default `ListOptions`.
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir<'_, D>> {
+        self.read_dir_with(path, &ListOptions::default())
+    }
+
+    /// Iterate over the entries of directory `path` selected by `options`.
+    pub fn read_dir_with(&self, path: &str, options: &ListOptions) -> Result<ReadDir<'_, D>> {
+        let cluster = self.resolve_dir(Path::new(path).components()?)?;
+        Ok(ReadDir::new(self, cluster, *options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    #[cfg(feature = "alloc")]
+    use crate::image::ImageBuilder;
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn iterates_across_clusters() {
+        let mut builder = ImageBuilder::new(300).dir("SUB");
+        let names: std::vec::Vec<std::string::String> = (0..20).map(|i| std::format!("SUB/F{}.TXT", i)).collect();
+        for name in &names {
+            builder = builder.file(name, b"x");
+        }
+        let fs = Fat32::mount(MemDevice::new(builder.build().unwrap())).expect("mount");
+
+        // `.` and `..` plus 20 files span two one-sector clusters.
+        let listed: std::vec::Vec<_> = fs.read_dir("SUB").unwrap().map(|e| e.unwrap().name()).collect();
+        assert_eq!(listed.len(), 20);
+        assert_eq!(listed[0], "F0.TXT");
+        assert_eq!(listed[19], "F19.TXT");
+        assert_eq!(fs.read_dir("/").unwrap().count(), 1);
+        assert!(matches!(fs.read_dir("SUB/F0.TXT"), Err(Error::NotADirectory)));
+    }
+
+    #[test]
+    fn lists_without_alloc() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.create_dir_all("SUB").unwrap();
+        for name in ["A.TXT", "B.TXT"] {
+            fs.create_file_root(name).unwrap().close().unwrap();
+        }
+
+        let mut names = [[0u8; 11]; 4];
+        let mut n = 0;
+        for entry in fs.read_dir("/").unwrap() {
+            names[n] = entry.unwrap().raw_name;
+            n += 1;
+        }
+        assert_eq!(names[..n], [*b"SUB        ", *b"A       TXT", *b"B       TXT"]);
+        assert_eq!(fs.read_dir("SUB").unwrap().count(), 0);
+        assert!(matches!(fs.read_dir("A.TXT"), Err(Error::NotADirectory)));
+    }
+}
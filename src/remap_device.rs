@@ -0,0 +1,202 @@
+//! Bad-sector remapping `BlockDevice` wrapper.
+//!
+//! Raw NOR/NAND-backed devices without their own flash translation layer
+//! expose worn-out sectors directly. `RemapDevice` keeps a small table of
+//! known-bad LBAs, stored in a sector of its own, and redirects each of them
+//! to a spare sector that follows the table.
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+const MAGIC: [u8; 4] = *b"RMAP";
+
+/// Most sectors one remap table can redirect.
+pub const MAX_REMAPS: usize = 63;
+
+/// A `BlockDevice` that redirects known-bad sectors to spare sectors.
+///
+/// The table lives at `table_lba` and spare `i` at `table_lba + 1 + i`;
+/// the filesystem must not use that area (format a volume that ends before
+/// `table_lba`). A write that fails with `Error::Io` or `Error::Device`
+/// marks the sector bad and is retried on a spare; a failing read is
+/// returned as is, as its data is lost.
+pub struct RemapDevice<D: BlockDevice> {
+    inner: D,
+    table_lba: u64,
+    capacity: usize,
+    bad: [u64; MAX_REMAPS],
+    count: usize,
+}
+
+impl<D: BlockDevice> RemapDevice<D> {
+    /// Wrap `inner` with `spares` spare sectors after the table at
+    /// `table_lba`, loading the table stored there (a sector without one
+    /// starts an empty table).
+    pub fn new(inner: D, table_lba: u64, spares: u64) -> Result<Self> {
+        let mut dev = Self {
+            inner,
+            table_lba,
+            capacity: spares.min(MAX_REMAPS as u64) as usize,
+            bad: [0; MAX_REMAPS],
+            count: 0,
+        };
+        let mut table = [0u8; 512];
+        dev.inner.read_sector(table_lba, &mut table)?;
+        if table[0..4] == MAGIC {
+            let count = u32::from_le_bytes([table[4], table[5], table[6], table[7]]) as usize;
+            if count > dev.capacity {
+                return Err(Error::Corrupt);
+            }
+            for (i, slot) in dev.bad[..count].iter_mut().enumerate() {
+                let off = 8 + i * 8;
+                *slot = u64::from_le_bytes(table[off..off + 8].try_into().expect("8 bytes"));
+            }
+            dev.count = count;
+        }
+        Ok(dev)
+    }
+
+    /// Bad sectors remapped so far, in the order they were found.
+    pub fn remapped(&self) -> &[u64] {
+        &self.bad[..self.count]
+    }
+
+    /// Sector actually accessed for `lba`.
+    pub fn target(&self, lba: u64) -> u64 {
+        match self.remapped().iter().position(|&b| b == lba) {
+            Some(i) => self.table_lba + 1 + i as u64,
+            None => lba,
+        }
+    }
+
+    /// Redirect `lba` to a spare sector, copying over what can still be
+    /// read of it (zeroes otherwise). Returns the spare.
+    ///
+    /// Fails with `Error::NoSpace` once every spare is in use.
+    pub fn mark_bad(&mut self, lba: u64) -> Result<u64> {
+        if self.target(lba) != lba {
+            return Ok(self.target(lba));
+        }
+        let mut data = [0u8; 512];
+        if self.inner.read_sector(lba, &mut data).is_err() {
+            data = [0u8; 512];
+        }
+        self.remap_with(lba, &data)
+    }
+
+    /// Borrow the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the wrapped device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Put `data` in the next spare, then record `lba` in the table.
+    fn remap_with(&mut self, lba: u64, data: &[u8; 512]) -> Result<u64> {
+        if self.count == self.capacity {
+            return Err(Error::NoSpace);
+        }
+        let spare = self.table_lba + 1 + self.count as u64;
+        self.inner.write_sector(spare, data)?;
+        self.bad[self.count] = lba;
+        self.count += 1;
+        fs_warn!("fat32: sector {} remapped to {}", lba, spare);
+
+        let mut table = [0u8; 512];
+        table[0..4].copy_from_slice(&MAGIC);
+        table[4..8].copy_from_slice(&(self.count as u32).to_le_bytes());
+        for (i, b) in self.remapped().iter().enumerate() {
+            table[8 + i * 8..16 + i * 8].copy_from_slice(&b.to_le_bytes());
+        }
+        if let Err(e) = self.inner.write_sector(self.table_lba, &table) {
+            self.count -= 1;
+            return Err(e);
+        }
+        Ok(spare)
+    }
+
+    /// True if no sector in `lba..lba + count` is remapped.
+    fn unmapped(&self, lba: u64, count: u64) -> bool {
+        !self.remapped().iter().any(|&b| (lba..lba + count).contains(&b))
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for RemapDevice<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        self.inner.read_sector(self.target(lba), buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        if self.unmapped(lba, buf.len() as u64 / 512) {
+            return self.inner.read_sectors(lba, buf);
+        }
+        for (i, chunk) in buf.chunks_exact_mut(512).enumerate() {
+            self.read_sector(lba + i as u64, chunk.try_into().expect("512-byte chunk"))?;
+        }
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        match self.inner.write_sector(self.target(lba), buf) {
+            Err(Error::Io | Error::Device(_)) if self.target(lba) == lba => self.remap_with(lba, buf).map(|_| ()),
+            r => r,
+        }
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        if self.unmapped(lba, buf.len() as u64 / 512) && self.inner.write_sectors(lba, buf).is_ok() {
+            return Ok(());
+        }
+        for (i, chunk) in buf.chunks_exact(512).enumerate() {
+            self.write_sector(lba + i as u64, chunk.try_into().expect("512-byte chunk"))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        if self.unmapped(lba, count) {
+            return self.inner.trim(lba, count);
+        }
+        Ok(())
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.inner.is_write_protected()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fault_device::FaultDevice;
+
+    #[test]
+    fn failed_writes_move_to_spares_and_survive_reopen() {
+        // Sectors 0..16 for data, table at 16, two spares.
+        let dev = FaultDevice::new(MemDevice::zeroed(19)).fail_write(2);
+        let mut dev = RemapDevice::new(dev, 16, 2).unwrap();
+        dev.write_sector(3, &[1u8; 512]).unwrap();
+        dev.write_sector(5, &[2u8; 512]).unwrap(); // fails on the device, lands in spare 17
+        assert_eq!(dev.remapped(), [5]);
+        assert_eq!(dev.mark_bad(3), Ok(18));
+
+        let mut dev = RemapDevice::new(dev.into_inner(), 16, 2).unwrap();
+        let mut buf = [0u8; 512 * 3];
+        dev.read_sectors(3, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[512], buf[1024]), (1, 0, 2));
+        assert_eq!((dev.target(3), dev.target(4), dev.target(5)), (18, 4, 17));
+        assert_eq!(dev.mark_bad(7), Err(Error::NoSpace));
+    }
+}
@@ -0,0 +1,215 @@
+//! Growing a volume onto a larger device: `Fat32::grow`.
+
+use alloc::vec::Vec;
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Operation, Result};
+use crate::fat::{cluster_to_lba, data_start_lba, fat_copy_lba, max_cluster};
+use crate::fs::Fat32;
+use crate::fsinfo::FsInfo;
+use crate::mkfs::fat_sectors;
+use crate::stats::ClusterState;
+
+/// Sectors moved per device call while relocating the data area.
+const CHUNK_SECTORS: u64 = 64;
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Extend the volume to `total_sectors`, e.g. so a small factory image
+    /// fills the card it was written to on first boot.
+    ///
+    /// The new clusters are added at the end and start free. When the FATs
+    /// cannot map them, every FAT copy is enlarged and each used cluster moves
+    /// up by the added FAT sectors; that rewrites most of the volume and is
+    /// not safe against power loss. The boot sector, FSInfo and their backups
+    /// are updated.
+    ///
+    /// Fails with `NoSpace` for a smaller `total_sectors` (shrinking is not
+    /// supported), with `AlreadyOpen` while file handles are open, and with
+    /// the device's error if sector `total_sectors - 1` cannot be read.
+    pub fn grow(&mut self, total_sectors: u32) -> Result<()> {
+        let old = self.bpb;
+        if total_sectors < old.total_sectors_32 {
+            return Err(Error::NoSpace);
+        }
+        if total_sectors == old.total_sectors_32 {
+            return Ok(());
+        }
+        if self.open_files.iter().any(Option::is_some) {
+            return Err(Error::AlreadyOpen);
+        }
+        let mut probe = [0u8; 512];
+        self.read_sector(Operation::ReadData, total_sectors as u64 - 1, &mut probe)?;
+        self.sync()?;
+        self.mark_dirty()?;
+
+        let spc = old.sectors_per_cluster as u32;
+        let fats = old.num_fats as u32;
+        let fat_size = fat_sectors(total_sectors, old.reserved_sectors as u32, spc, fats).max(old.fat_size_32);
+        let mut new = old;
+        new.total_sectors_32 = total_sectors;
+        new.total_sectors_16 = 0;
+        new.fat_size_32 = fat_size;
+        let shift = data_start_lba(&new) - data_start_lba(&old);
+        fs_debug!("fat32: grow to {} sectors, FAT {} -> {} sectors", total_sectors, old.fat_size_32, fat_size);
+
+        if shift > 0 {
+            self.move_used_clusters(shift)?;
+            self.move_fats(fat_size)?;
+        }
+        self.bpb = new;
+        self.clear_fat_entries(max_cluster(&old) + 1, max_cluster(&new))?;
+        self.write_grown_boot_sectors(total_sectors, fat_size)?;
+        self.sync()
+    }
+
+    /// Copy every used cluster `shift` sectors up, last first so that no
+    /// cluster is overwritten before it has been copied.
+    fn move_used_clusters(&mut self, shift: u64) -> Result<()> {
+        let spc = self.bpb.sectors_per_cluster as u64;
+        let mut buf = Vec::new();
+        for run in self.cluster_map()?.iter().rev().filter(|r| r.state == ClusterState::Used) {
+            let start = cluster_to_lba(&self.bpb, run.first)?;
+            let mut end = start + run.count as u64 * spc;
+            while end > start {
+                let n = (end - start).min(CHUNK_SECTORS);
+                end -= n;
+                buf.resize(n as usize * 512, 0);
+                self.read_sectors(Operation::ReadData, end, &mut buf)?;
+                self.write_sectors_direct(Operation::WriteData, end + shift, &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move each FAT copy to its place for FATs of `fat_size` sectors, last copy
+    /// and last sector first, zeroing the added sectors.
+    fn move_fats(&mut self, fat_size: u32) -> Result<()> {
+        let old = self.bpb;
+        let mut new = old;
+        new.fat_size_32 = fat_size;
+        for i in (0..old.num_fats).rev() {
+            let (from, to) = (fat_copy_lba(&old, i), fat_copy_lba(&new, i));
+            for s in (0..fat_size as u64).rev() {
+                let mut buf = [0u8; 512];
+                if s < old.fat_size_32 as u64 {
+                    self.read_sector(Operation::ReadFat, from + s, &mut buf)?;
+                }
+                self.write_sector_direct(Operation::WriteFat, to + s, &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark clusters `first..=last` free in every FAT copy (the FAT slack
+    /// past the old last cluster may hold anything).
+    fn clear_fat_entries(&mut self, first: u32, last: u32) -> Result<()> {
+        for i in 0..self.bpb.num_fats {
+            let fat = fat_copy_lba(&self.bpb, i);
+            let mut c = first;
+            while c <= last {
+                let lba = fat + (c as u64 * 4) / 512;
+                let mut buf = [0u8; 512];
+                self.read_sector(Operation::ReadFat, lba, &mut buf)?;
+                while c <= last && fat + (c as u64 * 4) / 512 == lba {
+                    let off = (c as usize * 4) % 512;
+                    buf[off..off + 4].fill(0);
+                    c += 1;
+                }
+                self.write_sector_direct(Operation::WriteFat, lba, &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the new size in the boot sector, FSInfo and their backups.
+    fn write_grown_boot_sectors(&mut self, total_sectors: u32, fat_size: u32) -> Result<()> {
+        let free = self.stats()?.free_clusters;
+        let backup = self.bpb.backup_boot_sector as u64;
+        let mut bases = alloc::vec![0];
+        if self.bpb.has_backup_boot_sector() {
+            bases.push(backup);
+        }
+        for base in bases {
+            let mut boot = [0u8; 512];
+            self.read_sector(Operation::ReadBoot, base, &mut boot)?;
+            boot[19..21].fill(0); // a stale 16-bit size would contradict the new one
+            boot[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+            boot[36..40].copy_from_slice(&fat_size.to_le_bytes());
+            self.write_sector(Operation::WriteBoot, base, &boot)?;
+
+            if self.bpb.fsinfo_sector != 0xFFFF {
+                let lba = base + self.bpb.fsinfo_sector as u64;
+                let mut buf = [0u8; 512];
+                self.read_sector(Operation::ReadFsInfo, lba, &mut buf)?;
+                let mut fsinfo = FsInfo::parse(&buf).unwrap_or(FsInfo::UNKNOWN);
+                fsinfo.free_count = free;
+                self.write_sector(Operation::WriteFsInfo, lba, &fsinfo.serialize())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    fn image_on_device(sectors: usize) -> MemDevice {
+        let mut img = ImageBuilder::new(4096)
+            .file("APP.BIN", &[7u8; 5000])
+            .file("CFG/NET.INI", b"dhcp=1")
+            .build()
+            .unwrap();
+        img.resize(sectors * 512, 0xEE);
+        MemDevice::new(img)
+    }
+
+    #[test]
+    fn grow_relocates_data_when_the_fat_must_grow() {
+        let mut fs = Fat32::mount(image_on_device(20000)).expect("mount");
+        let before = *fs.bpb();
+        assert_eq!(fs.grow(3000), Err(Error::NoSpace));
+        assert_eq!(fs.grow(30000), Err(Error::Io));
+        fs.grow(20000).unwrap();
+        assert!(fs.bpb().fat_size_32 > before.fat_size_32);
+
+        // Twice the size of the original volume.
+        let big = alloc::vec![3u8; 4096 * 512 * 2];
+        let mut fs = Fat32::mount(fs.unmount().unwrap()).expect("remount");
+        assert_eq!(fs.bpb().total_sectors_32, 20000);
+        assert_eq!(fs.verify_backup_boot_sector(), Ok(true));
+        assert_eq!(fs.read_file_root("APP.BIN").unwrap(), [7u8; 5000]);
+        let mut buf = [0u8; 16];
+        assert_eq!(fs.read_file_into("CFG/NET.INI", &mut buf), Ok(6));
+        assert!(fs.check().unwrap().is_clean());
+        assert!(fs.compare_fat_copies().unwrap().is_empty());
+
+        let stats = fs.stats().unwrap();
+        assert!(stats.free_bytes() > big.len() as u64);
+        fs.write_file_root("BIG.BIN", &big).unwrap();
+        assert_eq!(fs.read_file_root("BIG.BIN").unwrap().len(), big.len());
+    }
+
+    #[test]
+    fn grow_within_fat_slack_moves_nothing() {
+        let mut fs = Fat32::mount(image_on_device(4100)).expect("mount");
+        let before = *fs.bpb();
+        let clusters = fs.stats().unwrap().total_clusters;
+        fs.grow(4100).unwrap();
+        assert_eq!(fs.bpb().fat_size_32, before.fat_size_32);
+        assert_eq!(fs.stats().unwrap().total_clusters, clusters + 4);
+
+        let fs = Fat32::mount_with(
+            fs.unmount().unwrap(),
+            crate::options::MountOptions {
+                verify_fsinfo: true,
+                ..Default::default()
+            },
+        )
+        .expect("remount");
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.read_file_root("APP.BIN").unwrap(), [7u8; 5000]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Retrying `BlockDevice` wrapper.
+//!
+//! SPI SD cards in particular report the odd CRC error on a perfectly good
+//! sector; retrying a few times turns those into successes instead of fatal
+//! `Error::Io`s.
+
+use crate::device::BlockDevice;
+use crate::error::{Error, Result};
+
+/// A `BlockDevice` that retries sector reads, writes and flushes failing with
+/// `Error::Io` or a transient `Error::Device` (CRC error, timeout).
+///
+/// Other errors (e.g. `WriteProtected`, a removed card) are returned at once. Before retry
+/// `n` (counted from 1) the delay hook is called with `n`, e.g. to sleep
+/// `n * 2` ms for a linear backoff; by default there is no delay.
+pub struct RetryDevice<D: BlockDevice, F: Fn(u32) = fn(u32)> {
+    inner: D,
+    retries: u32,
+    delay: F,
+}
+
+impl<D: BlockDevice> RetryDevice<D> {
+    /// Wrap `inner`, retrying each failed access up to `retries` times.
+    pub fn new(inner: D, retries: u32) -> Self {
+        Self {
+            inner,
+            retries,
+            delay: |_| {},
+        }
+    }
+}
+
+impl<D: BlockDevice, F: Fn(u32)> RetryDevice<D, F> {
+    /// Call `delay(n)` before retry `n`.
+    pub fn with_delay<G: Fn(u32)>(self, delay: G) -> RetryDevice<D, G> {
+        RetryDevice {
+            inner: self.inner,
+            retries: self.retries,
+            delay,
+        }
+    }
+
+    /// Borrow the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the wrapped device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn retry<T>(retries: u32, delay: &F, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < retries && is_transient(e) => {
+                    attempt += 1;
+                    fs_debug!("fat32: I/O error, retry {}/{}", attempt, retries);
+                    delay(attempt);
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+fn is_transient(e: Error) -> bool {
+    match e {
+        Error::Io => true,
+        Error::Device(d) => d.is_transient(),
+        _ => false,
+    }
+}
+
+impl<D: BlockDevice, F: Fn(u32)> BlockDevice for RetryDevice<D, F> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        Self::retry(self.retries, &self.delay, || self.inner.read_sector(lba, buf))
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        Self::retry(self.retries, &self.delay, || self.inner.read_sectors(lba, buf))
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        let inner = &mut self.inner;
+        Self::retry(self.retries, &self.delay, || inner.write_sector(lba, buf))
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let inner = &mut self.inner;
+        Self::retry(self.retries, &self.delay, || inner.write_sectors(lba, buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let inner = &mut self.inner;
+        Self::retry(self.retries, &self.delay, || inner.flush())
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        self.inner.trim(lba, count)
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.inner.is_write_protected()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fault_device::FaultDevice;
+    use crate::error::{DeviceError, DeviceErrorKind};
+    use core::cell::{Cell, RefCell};
+
+    #[test]
+    fn retries_transient_io_errors() {
+        let dev = FaultDevice::new(MemDevice::zeroed(4)).fail_read(1).fail_write(1);
+        let delays = RefCell::new(std::vec::Vec::new());
+        let mut dev = RetryDevice::new(dev, 2).with_delay(|n| delays.borrow_mut().push(n));
+        dev.write_sector(1, &[7u8; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        dev.read_sector(1, &mut buf).unwrap();
+        assert_eq!(buf, [7u8; 512]);
+        assert_eq!(*delays.borrow(), [1, 1]);
+        assert_eq!((dev.inner().reads(), dev.inner().writes()), (2, 2));
+
+        // Out of range keeps failing: two retries, then the error.
+        delays.borrow_mut().clear();
+        assert_eq!(dev.read_sector(9, &mut buf), Err(Error::Io));
+        assert_eq!(*delays.borrow(), [1, 2]);
+
+        let mut ro = RetryDevice::new(MemDevice::read_only(std::vec![0u8; 512]), 3);
+        assert_eq!(ro.write_sector(0, &buf), Err(Error::WriteProtected));
+    }
+
+    /// Fails every access with the given device error.
+    struct Broken(DeviceError, Cell<u32>);
+
+    impl BlockDevice for Broken {
+        fn read_sector(&self, _: u64, _: &mut [u8; 512]) -> Result<()> {
+            self.1.set(self.1.get() + 1);
+            Err(Error::Device(self.0))
+        }
+
+        fn write_sector(&mut self, _: u64, _: &[u8; 512]) -> Result<()> {
+            Err(Error::Device(self.0))
+        }
+    }
+
+    #[test]
+    fn retries_only_transient_device_errors() {
+        let mut buf = [0u8; 512];
+        let crc = DeviceError::new(DeviceErrorKind::Crc, 0x08);
+        let dev = RetryDevice::new(Broken(crc, Cell::new(0)), 3);
+        assert_eq!(dev.read_sector(0, &mut buf), Err(Error::Device(crc)));
+        assert_eq!(dev.inner().1.get(), 4);
+
+        let removed = DeviceError::new(DeviceErrorKind::Removed, 0);
+        let dev = RetryDevice::new(Broken(removed, Cell::new(0)), 3);
+        assert_eq!(dev.read_sector(0, &mut buf), Err(Error::Device(removed)));
+        assert_eq!(dev.inner().1.get(), 1);
+        assert_eq!(std::format!("{}", Error::Device(crc)), "device error: CRC error (code 0x8)");
+    }
+}
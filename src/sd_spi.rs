@@ -0,0 +1,598 @@
+//! SD card over SPI (`embedded-hal` feature).
+//!
+//! `SdSpiDevice` implements `BlockDevice` on any `embedded_hal::spi::SpiDevice`:
+//! it runs the SPI-mode init sequence (CMD0, CMD8, ACMD41, CMD58), turns on
+//! CRC checking and reads and writes single blocks with CMD17 and CMD24,
+//! keeping chip select low for each command and each block.
+//!
+//! ```ignore
+//! let spi = ExclusiveDevice::new(spi_bus, cs, Delay)?; // SPI clock <= 400 kHz
+//! let sd = SdSpiDevice::new(spi, &mut Delay)?;
+//! // Raise the SPI clock to up to 25 MHz here.
+//! let mut fs = Fat32::mount(sd)?;
+//! ```
+
+use core::cell::{Cell, RefCell};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::{Operation, SpiDevice};
+
+use crate::device::BlockDevice;
+use crate::error::{DeviceError, DeviceErrorKind, Error, Result};
+
+const CMD0: u8 = 0; // GO_IDLE_STATE
+const CMD8: u8 = 8; // SEND_IF_COND
+const CMD16: u8 = 16; // SET_BLOCKLEN
+const CMD17: u8 = 17; // READ_SINGLE_BLOCK
+const CMD24: u8 = 24; // WRITE_BLOCK
+const CMD55: u8 = 55; // APP_CMD
+const CMD58: u8 = 58; // READ_OCR
+const CMD59: u8 = 59; // CRC_ON_OFF
+const ACMD41: u8 = 41; // SD_SEND_OP_COND
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const DATA_START_TOKEN: u8 = 0xFE;
+const OCR_CCS: u32 = 0x4000_0000;
+
+/// Bytes clocked while waiting for a data token or for the card to stop
+/// being busy: about 300 ms at 25 MHz.
+const POLL_BYTES: u32 = 1_000_000;
+
+/// Bytes clocked after a command frame; R1 arrives within the first 8.
+const R1_WINDOW: usize = 8;
+/// Bytes clocked after the read delay, where the data token should turn up.
+const TOKEN_WINDOW: usize = 16;
+/// First step of the read delay, and its cap (the SDHC read timeout).
+const READ_DELAY_STEP_NS: u32 = 10_000;
+const MAX_READ_DELAY_NS: u32 = 100_000_000;
+
+/// An SD / SDHC / SDXC card on an SPI bus.
+///
+/// Every command (frame and response) is one `SpiDevice` transaction and so is
+/// every data block, so chip select stays low while the card is talking. A read
+/// takes its command and block in a single transaction with an
+/// `Operation::DelayNs` in between; `new` times a read of sector 0 to size that
+/// delay. A read that still finds the card unready drains the block, doubles
+/// the delay and fails with a transient `DeviceErrorKind::Timeout`, so a
+/// `RetryDevice` on top hides it. A write sends the command first and the block
+/// only once the card has accepted it, then polls the busy signal in separate
+/// transactions, which the SD spec allows.
+///
+/// `SpiDevice` cannot clock the bus with chip select high, which the card
+/// expects for 74 clocks after power-up; if your card does not initialize,
+/// send ten 0xFF bytes on the bus with CS high before building the `SpiDevice`.
+///
+/// Errors: SPI bus failures are `Error::Device` with `DeviceErrorKind::Host`;
+/// card errors are `Error::Device` with the R1 response or data token as
+/// `code`, `DeviceErrorKind::Crc` for CRC mismatches and
+/// `DeviceErrorKind::Timeout` for a card that stops answering.
+pub struct SdSpiDevice<S: SpiDevice> {
+    spi: RefCell<S>,
+    /// SDHC/SDXC cards are addressed in blocks, older cards in bytes.
+    high_capacity: bool,
+    /// Wait between a read command and its data, in nanoseconds.
+    read_delay: Cell<u32>,
+}
+
+impl<S: SpiDevice> SdSpiDevice<S> {
+    /// Initialize the card; run the SPI clock at 400 kHz or less until this returns.
+    pub fn new<T: DelayNs>(spi: S, delay: &mut T) -> Result<Self> {
+        let mut spi = spi;
+        let high_capacity = init(&mut spi, delay)?;
+        fs_debug!("fat32: SD card ready, high capacity: {}", high_capacity);
+        let sd = Self {
+            spi: RefCell::new(spi),
+            high_capacity,
+            read_delay: Cell::new(0),
+        };
+        // Grow the read delay until a whole block fits one transaction.
+        let mut block = [0; 512];
+        loop {
+            let read_delay = sd.read_delay.get();
+            match sd.read_sector(0, &mut block) {
+                Err(Error::Device(e)) if e.kind == DeviceErrorKind::Timeout && sd.read_delay.get() > read_delay => {}
+                r => break r?,
+            }
+        }
+        Ok(sd)
+    }
+
+    /// True for SDHC/SDXC cards.
+    pub fn high_capacity(&self) -> bool {
+        self.high_capacity
+    }
+
+    /// Return the SPI device.
+    pub fn into_inner(self) -> S {
+        self.spi.into_inner()
+    }
+
+    /// Command argument addressing sector `lba`.
+    fn address(&self, lba: u64) -> Result<u32> {
+        let addr = if self.high_capacity { lba } else { lba * 512 };
+        u32::try_from(addr).map_err(|_| Error::Io)
+    }
+
+    /// Double the read delay after a read the card was not ready for.
+    fn slow_down(&self) {
+        let read_delay = (self.read_delay.get() * 2).clamp(READ_DELAY_STEP_NS, MAX_READ_DELAY_NS);
+        fs_debug!("fat32: SD read delay now {} ns", read_delay);
+        self.read_delay.set(read_delay);
+    }
+}
+
+impl<S: SpiDevice> BlockDevice for SdSpiDevice<S> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        let addr = self.address(lba)?;
+        let spi = &mut *self.spi.borrow_mut();
+        wait_for(spi, |b| b == 0xFF)?;
+        let frame = frame(CMD17, addr);
+        let mut head = [0xFF; R1_WINDOW + TOKEN_WINDOW];
+        let mut tail = [0xFF; TOKEN_WINDOW + 2];
+        buf.fill(0xFF);
+        let (r1_window, token_window) = head.split_at_mut(R1_WINDOW);
+        spi.transaction(&mut [
+            Operation::Write(&frame),
+            Operation::TransferInPlace(r1_window),
+            Operation::DelayNs(self.read_delay.get()),
+            Operation::TransferInPlace(token_window),
+            Operation::TransferInPlace(buf),
+            Operation::TransferInPlace(&mut tail),
+        ])
+        .map_err(spi_error)?;
+        let at = r1_index(&head[..R1_WINDOW])?;
+        card_status(head[at])?;
+
+        // The token can be anywhere in head, buf or tail, with the block right behind it.
+        let head = &head[at + 1..];
+        let total = head.len() + 512 + tail.len();
+        let Some(token_at) = (0..total).find(|&i| stream_byte(head, buf, &tail, i) != 0xFF) else {
+            self.slow_down();
+            let token = wait_for(spi, |b| b != 0xFF)?;
+            if token != DATA_START_TOKEN {
+                return Err(device_error(DeviceErrorKind::Other, token));
+            }
+            skip(spi, 514)?;
+            return Err(device_error(DeviceErrorKind::Timeout, token));
+        };
+        let token = stream_byte(head, buf, &tail, token_at);
+        if token != DATA_START_TOKEN {
+            return Err(device_error(DeviceErrorKind::Other, token));
+        }
+        let start = token_at + 1;
+        if start + 514 > total {
+            self.slow_down();
+            skip(spi, start + 514 - total)?;
+            return Err(device_error(DeviceErrorKind::Timeout, token));
+        }
+        let crc = [stream_byte(head, buf, &tail, start + 512), stream_byte(head, buf, &tail, start + 513)];
+        if start <= head.len() {
+            let n = head.len() - start;
+            buf.copy_within(..512 - n, n);
+            buf[..n].copy_from_slice(&head[start..]);
+        } else {
+            let n = start - head.len();
+            buf.copy_within(n.., 0);
+            buf[512 - n..].copy_from_slice(&tail[..n]);
+        }
+        if u16::from_be_bytes(crc) != crc16(buf) {
+            return Err(device_error(DeviceErrorKind::Crc, 0));
+        }
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        let addr = self.address(lba)?;
+        let spi = self.spi.get_mut();
+        card_status(command(spi, CMD24, addr)?)?;
+        let crc = crc16(buf).to_be_bytes();
+        let mut response = [0xFF; 8];
+        spi.transaction(&mut [
+            Operation::Write(&[0xFF, DATA_START_TOKEN]),
+            Operation::Write(buf),
+            Operation::Write(&crc),
+            Operation::TransferInPlace(&mut response),
+        ])
+        .map_err(spi_error)?;
+        // Data response token: xxx0sss1, sss = 010 accepted, 101 CRC error, 110 write error.
+        let Some(&token) = response.iter().find(|&&b| b != 0xFF) else {
+            return Err(device_error(DeviceErrorKind::Timeout, 0xFF));
+        };
+        match token & 0x1F {
+            0x05 => {}
+            0x0B => return Err(device_error(DeviceErrorKind::Crc, token & 0x1F)),
+            code => return Err(device_error(DeviceErrorKind::Other, code)),
+        }
+        // The card holds the line low while it programs the block.
+        if response[response.len() - 1] != 0xFF {
+            wait_for(spi, |b| b == 0xFF)?;
+        }
+        Ok(())
+    }
+}
+
+fn device_error(kind: DeviceErrorKind, code: u8) -> Error {
+    Error::Device(DeviceError::new(kind, code as u32))
+}
+
+/// SPI bus failures carry no card status; the bus error kind goes to the log.
+fn spi_error<E: embedded_hal::spi::Error>(e: E) -> Error {
+    fs_debug!("fat32: SPI error: {:?}", e.kind());
+    device_error(DeviceErrorKind::Host, 0)
+}
+
+/// Fail unless R1 reports no error (idle allowed only during init).
+fn card_status(r1: u8) -> Result<()> {
+    match r1 {
+        0 => Ok(()),
+        r1 if r1 & 0x08 != 0 => Err(device_error(DeviceErrorKind::Crc, r1)),
+        r1 => Err(device_error(DeviceErrorKind::Other, r1)),
+    }
+}
+
+/// Run the SPI-mode init sequence; returns whether the card is block addressed.
+fn init<S: SpiDevice, T: DelayNs>(spi: &mut S, delay: &mut T) -> Result<bool> {
+    spi.write(&[0xFF; 10]).map_err(spi_error)?;
+    let mut idle = false;
+    for _ in 0..10 {
+        if command(spi, CMD0, 0)? == R1_IDLE {
+            idle = true;
+            break;
+        }
+        delay.delay_ms(10);
+    }
+    if !idle {
+        return Err(device_error(DeviceErrorKind::Timeout, 0xFF));
+    }
+    if command(spi, CMD59, 1)? != R1_IDLE {
+        return Err(device_error(DeviceErrorKind::Other, CMD59));
+    }
+
+    // CMD8 is unknown to version 1 cards; version 2 cards echo the check pattern.
+    let (r1, r7) = command_with_data(spi, CMD8, 0x1AA)?;
+    let v2 = r1 & R1_ILLEGAL_COMMAND == 0;
+    if v2 && (r7[2] & 0x0F != 0x01 || r7[3] != 0xAA) {
+        return Err(device_error(DeviceErrorKind::Other, r7[3]));
+    }
+
+    let hcs = if v2 { OCR_CCS } else { 0 };
+    let mut ready = false;
+    for _ in 0..1000 {
+        command(spi, CMD55, 0)?;
+        match command(spi, ACMD41, hcs)? {
+            0 => {
+                ready = true;
+                break;
+            }
+            R1_IDLE => delay.delay_ms(1),
+            r1 => return Err(device_error(DeviceErrorKind::Other, r1)),
+        }
+    }
+    if !ready {
+        return Err(device_error(DeviceErrorKind::Timeout, R1_IDLE));
+    }
+
+    let mut high_capacity = false;
+    if v2 {
+        let (r1, ocr) = command_with_data(spi, CMD58, 0)?;
+        card_status(r1)?;
+        high_capacity = u32::from_be_bytes(ocr) & OCR_CCS != 0;
+    }
+    if !high_capacity {
+        card_status(command(spi, CMD16, 512)?)?;
+    }
+    Ok(high_capacity)
+}
+
+/// Frame for command `cmd` with `arg`, CRC included.
+fn frame(cmd: u8, arg: u32) -> [u8; 6] {
+    let mut frame = [0x40 | cmd, 0, 0, 0, 0, 0];
+    frame[1..5].copy_from_slice(&arg.to_be_bytes());
+    frame[5] = crc7(&frame[..5]) << 1 | 1;
+    frame
+}
+
+/// Send command `cmd` with `arg` and return its R1 response.
+fn command<S: SpiDevice>(spi: &mut S, cmd: u8, arg: u32) -> Result<u8> {
+    command_with_data(spi, cmd, arg).map(|(r1, _)| r1)
+}
+
+/// Send command `cmd` with `arg` in one transaction; returns R1 and the four
+/// bytes after it (the rest of an R3 or R7 response).
+fn command_with_data<S: SpiDevice>(spi: &mut S, cmd: u8, arg: u32) -> Result<(u8, [u8; 4])> {
+    if cmd != CMD0 {
+        wait_for(spi, |b| b == 0xFF)?;
+    }
+    let mut response = [0xFF; R1_WINDOW + 4];
+    spi.transaction(&mut [Operation::Write(&frame(cmd, arg)), Operation::TransferInPlace(&mut response)])
+        .map_err(spi_error)?;
+    let at = r1_index(&response[..R1_WINDOW])?;
+    let mut data = [0; 4];
+    data.copy_from_slice(&response[at + 1..at + 5]);
+    Ok((response[at], data))
+}
+
+/// Position of R1 in `window`: the first byte with the top bit clear.
+fn r1_index(window: &[u8]) -> Result<usize> {
+    window
+        .iter()
+        .position(|&b| b & 0x80 == 0)
+        .ok_or(device_error(DeviceErrorKind::Timeout, 0xFF))
+}
+
+/// Byte `i` of `head`, `buf` and `tail` read back to back.
+fn stream_byte(head: &[u8], buf: &[u8; 512], tail: &[u8], i: usize) -> u8 {
+    match i.checked_sub(head.len()) {
+        None => head[i],
+        Some(i) if i < 512 => buf[i],
+        Some(i) => tail[i - 512],
+    }
+}
+
+fn read_byte<S: SpiDevice>(spi: &mut S) -> Result<u8> {
+    let mut b = [0xFF];
+    spi.transfer_in_place(&mut b).map_err(spi_error)?;
+    Ok(b[0])
+}
+
+/// Clock out and drop `n` bytes the card is still sending.
+fn skip<S: SpiDevice>(spi: &mut S, mut n: usize) -> Result<()> {
+    let mut scratch = [0xFF; 64];
+    while n > 0 {
+        let len = n.min(scratch.len());
+        scratch.fill(0xFF);
+        spi.transfer_in_place(&mut scratch[..len]).map_err(spi_error)?;
+        n -= len;
+    }
+    Ok(())
+}
+
+/// Clock bytes until one satisfies `done`, returning it.
+fn wait_for<S: SpiDevice>(spi: &mut S, done: impl Fn(u8) -> bool) -> Result<u8> {
+    for _ in 0..POLL_BYTES {
+        let b = read_byte(spi)?;
+        if done(b) {
+            return Ok(b);
+        }
+    }
+    Err(device_error(DeviceErrorKind::Timeout, 0xFF))
+}
+
+/// CRC-7 of a command frame (polynomial x^7 + x^3 + 1).
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        let mut b = byte;
+        for _ in 0..8 {
+            crc <<= 1;
+            if (b ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+            b <<= 1;
+        }
+    }
+    crc & 0x7F
+}
+
+/// CRC-16/XMODEM of a data block.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use embedded_hal::spi::{ErrorKind, ErrorType};
+
+    use super::*;
+
+    /// Card end of the SPI protocol, one byte per clock.
+    struct MockCard {
+        sectors: Vec<[u8; 512]>,
+        /// Clocks from the R1 of a read to its data token; a 1 us delay counts as one clock.
+        latency: u64,
+        clock: u64,
+        out: VecDeque<u8>,
+        frame: Vec<u8>,
+        app_command: bool,
+        idle_polls: u32,
+        /// Clock at which the pending read is ready, and its sector.
+        read: Option<(u64, usize)>,
+        write: Option<usize>,
+        data: Option<(usize, Vec<u8>)>,
+        busy_until: u64,
+        /// Transactions that ended while the card was still sending or receiving.
+        cut: u32,
+        corrupt: bool,
+        fail: bool,
+    }
+
+    impl MockCard {
+        fn new(latency: u64) -> Self {
+            Self {
+                sectors: vec![[0; 512]; 8],
+                latency,
+                clock: 0,
+                out: VecDeque::new(),
+                frame: Vec::new(),
+                app_command: false,
+                idle_polls: 2,
+                read: None,
+                write: None,
+                data: None,
+                busy_until: 0,
+                cut: 0,
+                corrupt: false,
+                fail: false,
+            }
+        }
+
+        fn exchange(&mut self, mosi: u8) -> u8 {
+            self.clock += 1;
+            let miso = if let Some(b) = self.out.pop_front() {
+                b
+            } else if self.clock <= self.busy_until {
+                0
+            } else if let Some((_, lba)) = self.read.filter(|&(ready, _)| self.clock >= ready) {
+                self.read = None;
+                let mut block = self.sectors[lba];
+                let crc = crc16(&block).to_be_bytes();
+                block[0] ^= self.corrupt as u8;
+                self.out.extend(block);
+                self.out.extend(crc);
+                DATA_START_TOKEN
+            } else {
+                0xFF
+            };
+            self.receive(mosi);
+            miso
+        }
+
+        fn receive(&mut self, b: u8) {
+            if let Some((lba, data)) = &mut self.data {
+                data.push(b);
+                if data.len() == 514 {
+                    let (lba, data) = (*lba, core::mem::take(data));
+                    self.data = None;
+                    assert_eq!(crc16(&data[..512]).to_be_bytes(), data[512..]);
+                    self.sectors[lba].copy_from_slice(&data[..512]);
+                    self.out.push_back(0x05);
+                    self.busy_until = self.clock + 20;
+                }
+            } else if let Some(lba) = self.write {
+                if b == DATA_START_TOKEN {
+                    self.write = None;
+                    self.data = Some((lba, Vec::new()));
+                }
+            } else if !self.frame.is_empty() || b & 0xC0 == 0x40 {
+                self.frame.push(b);
+                if self.frame.len() == 6 {
+                    let frame = core::mem::take(&mut self.frame);
+                    self.command(&frame);
+                }
+            }
+        }
+
+        fn command(&mut self, frame: &[u8]) {
+            assert_eq!(frame[5], crc7(&frame[..5]) << 1 | 1);
+            let arg = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+            let app_command = core::mem::take(&mut self.app_command);
+            let r1 = if self.idle_polls > 0 { R1_IDLE } else { 0 };
+            self.out.push_back(0xFF);
+            match frame[0] & 0x3F {
+                CMD0 | CMD59 => self.out.push_back(R1_IDLE),
+                CMD8 => self.out.extend([R1_IDLE, 0, 0, 0x01, 0xAA]),
+                CMD55 => {
+                    self.app_command = true;
+                    self.out.push_back(r1);
+                }
+                ACMD41 if app_command => {
+                    self.idle_polls -= 1;
+                    self.out.push_back(if self.idle_polls > 0 { R1_IDLE } else { 0 });
+                }
+                CMD58 => self.out.extend([r1, 0xC0, 0xFF, 0x80, 0x00]),
+                CMD17 => {
+                    self.out.push_back(0);
+                    self.read = Some((self.clock + 2 + self.latency, arg as usize));
+                }
+                CMD24 => {
+                    self.out.push_back(0);
+                    self.write = Some(arg as usize);
+                }
+                _ => self.out.push_back(R1_ILLEGAL_COMMAND),
+            }
+        }
+    }
+
+    impl ErrorType for MockCard {
+        type Error = ErrorKind;
+    }
+
+    impl SpiDevice for MockCard {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> core::result::Result<(), ErrorKind> {
+            if self.fail {
+                return Err(ErrorKind::Overrun);
+            }
+            for op in operations {
+                match op {
+                    Operation::Read(buf) => buf.iter_mut().for_each(|b| *b = self.exchange(0xFF)),
+                    Operation::Write(buf) => buf.iter().for_each(|&b| {
+                        self.exchange(b);
+                    }),
+                    Operation::Transfer(read, write) => {
+                        read.iter_mut().zip(write.iter()).for_each(|(r, &w)| *r = self.exchange(w))
+                    }
+                    Operation::TransferInPlace(buf) => buf.iter_mut().for_each(|b| *b = self.exchange(*b)),
+                    Operation::DelayNs(ns) => self.clock += u64::from(*ns) / 1000,
+                }
+            }
+            // Chip select goes high: busy is fine, anything else is cut short.
+            if !self.out.is_empty() || !self.frame.is_empty() || self.read.is_some() || self.data.is_some() {
+                self.cut += 1;
+            }
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    #[test]
+    fn commands_and_blocks_keep_chip_select() {
+        let mut sd = SdSpiDevice::new(MockCard::new(40), &mut NoDelay).unwrap();
+        assert!(sd.high_capacity());
+        // Sizing the read delay in `new` cut a block short once; nothing else may.
+        sd.spi.get_mut().cut = 0;
+
+        let block: [u8; 512] = core::array::from_fn(|i| i as u8 ^ 0xA5);
+        sd.write_sector(3, &block).unwrap();
+        assert_eq!(sd.spi.get_mut().sectors[3], block);
+        // Token in the R1 window, in the token window and inside `buf`.
+        let mut buf = [0; 512];
+        for latency in [0, 20, 40] {
+            sd.spi.get_mut().latency = latency;
+            sd.read_sector(3, &mut buf).unwrap();
+            assert_eq!(buf, block);
+        }
+        assert_eq!(sd.spi.get_mut().cut, 0);
+
+        // A slower card fails transiently until the delay has caught up.
+        sd.spi.get_mut().latency = 2000;
+        let mut failures = 0;
+        while let Err(e) = sd.read_sector(3, &mut buf) {
+            assert!(matches!(e, Error::Device(d) if d.is_transient()));
+            failures += 1;
+            assert!(failures < 10);
+        }
+        assert_eq!(buf, block);
+
+        sd.spi.get_mut().corrupt = true;
+        let e = sd.read_sector(3, &mut buf).unwrap_err();
+        assert!(matches!(e, Error::Device(d) if d.kind == DeviceErrorKind::Crc));
+        sd.spi.get_mut().fail = true;
+        let e = sd.write_sector(3, &block).unwrap_err();
+        assert_eq!(e, Error::Device(DeviceError::new(DeviceErrorKind::Host, 0)));
+    }
+
+    #[test]
+    fn command_and_data_crcs() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]) << 1 | 1, 0x95); // CMD0
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xAA]) << 1 | 1, 0x87); // CMD8
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(&[0xFF; 512]), 0x7FA1);
+    }
+}
@@ -0,0 +1,440 @@
+//! `embedded-sdmmc`-style API over `Fat32`.
+//!
+//! `VolumeManager` mirrors the handle-based API of the `embedded-sdmmc`
+//! crate (`open_volume`, `open_root_dir`, `open_file_in_dir`, `read`,
+//! `write`, `close_file`, ...), so code written against it can move to this
+//! crate by changing its imports:
+//!
+//! ```ignore
+//! let mut mgr = VolumeManager::new(sdcard);
+//! let volume = mgr.open_volume(VolumeIdx(0))?;
+//! let root = mgr.open_root_dir(volume)?;
+//! let file = mgr.open_file_in_dir(root, "LOG.TXT", Mode::ReadWriteCreateOrAppend)?;
+//! mgr.write(file, b"boot\n")?;
+//! mgr.close_file(file)?;
+//! mgr.close_dir(root)?;
+//! mgr.close_volume(volume)?;
+//! ```
+//!
+//! Differences: errors are this crate's `Error`, directory entries are
+//! `dir::DirEntry`, only one volume can be open at a time, and there is no
+//! time source as the crate does not stamp entries.
+
+use alloc::vec::Vec;
+
+use crate::bpb::Bpb;
+use crate::device::BlockDevice;
+use crate::dir::DirEntry;
+use crate::error::{Error, Result};
+use crate::file::{File as FatFile, FileHandle};
+use crate::fs::Fat32;
+use crate::options::{MountOptions, OpenMode};
+
+/// Which volume to open: partition `n` of the MBR, or the whole device for
+/// index 0 when it is not partitioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeIdx(pub usize);
+
+/// An open volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume(());
+
+/// An open directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Directory(usize);
+
+/// An open file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct File(usize);
+
+/// How `open_file_in_dir` opens a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Existing file, read only.
+    ReadOnly,
+    /// Existing file, positioned at the end.
+    ReadWriteAppend,
+    /// Existing file, emptied.
+    ReadWriteTruncate,
+    /// New file; `AlreadyExists` if there is one.
+    ReadWriteCreate,
+    /// New or emptied file.
+    ReadWriteCreateOrTruncate,
+    /// New file, or existing one positioned at the end.
+    ReadWriteCreateOrAppend,
+}
+
+/// A device seen from the start of one of its partitions.
+struct Partition<D> {
+    inner: D,
+    start: u64,
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        self.inner.read_sector(self.start + lba, buf)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        self.inner.write_sector(self.start + lba, buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_sectors(self.start + lba, buf)
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        self.inner.write_sectors(self.start + lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        self.inner.trim(self.start + lba, count)
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.inner.is_write_protected()
+    }
+}
+
+/// Start sector of volume `idx` on `dev`.
+fn volume_start<D: BlockDevice>(dev: &D, idx: usize) -> Result<u64> {
+    let mut sector = [0u8; 512];
+    dev.read_sector(0, &mut sector)?;
+    if idx == 0 && Bpb::parse(&sector).is_ok() {
+        return Ok(0);
+    }
+    if sector[510..] != [0x55, 0xAA] || idx >= 4 {
+        return Err(Error::NotFound);
+    }
+    let entry = &sector[446 + idx * 16..446 + idx * 16 + 16];
+    if entry[4] == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64)
+}
+
+/// Handle-based access to the volumes of a block device.
+///
+/// `open_volume` mounts the device and keeps it for another try if that
+/// fails. `close_volume` or `free` unmounts cleanly.
+pub struct VolumeManager<D: BlockDevice> {
+    dev: Option<D>,
+    fs: Option<Fat32<Partition<D>>>,
+    /// First cluster of each open directory.
+    dirs: Vec<Option<u32>>,
+    files: Vec<Option<(FileHandle, Mode)>>,
+}
+
+impl<D: BlockDevice> VolumeManager<D> {
+    /// Manage the volumes of `dev`.
+    pub fn new(dev: D) -> Self {
+        Self {
+            dev: Some(dev),
+            fs: None,
+            dirs: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Mount volume `idx`; fails with `AlreadyOpen` while another is open.
+    pub fn open_volume(&mut self, idx: VolumeIdx) -> Result<Volume> {
+        if self.fs.is_some() {
+            return Err(Error::AlreadyOpen);
+        }
+        let dev = self.dev.take().ok_or(Error::NotFound)?;
+        let start = match volume_start(&dev, idx.0) {
+            Ok(start) => start,
+            Err(e) => {
+                self.dev = Some(dev);
+                return Err(e);
+            }
+        };
+        // Like embedded-sdmmc, trust the partition table over the boot sector's hidden sectors.
+        match Fat32::try_mount_with(Partition { inner: dev, start }, MountOptions::default()) {
+            Ok(fs) => self.fs = Some(fs),
+            Err((e, part)) => {
+                self.dev = Some(part.inner);
+                return Err(e);
+            }
+        }
+        Ok(Volume(()))
+    }
+
+    /// Unmount the volume; fails with `AlreadyOpen` while files or directories are open.
+    pub fn close_volume(&mut self, _volume: Volume) -> Result<()> {
+        if self.has_open_handles() {
+            return Err(Error::AlreadyOpen);
+        }
+        let fs = self.fs.take().ok_or(Error::NotFound)?;
+        self.dev = Some(fs.unmount()?.inner);
+        Ok(())
+    }
+
+    /// True while any file or directory is open.
+    pub fn has_open_handles(&self) -> bool {
+        self.dirs.iter().any(Option::is_some) || self.files.iter().any(Option::is_some)
+    }
+
+    /// Unmount the open volume, if any, and return the device.
+    pub fn free(mut self) -> Result<D> {
+        match self.fs.take() {
+            Some(fs) => Ok(fs.unmount()?.inner),
+            None => self.dev.ok_or(Error::NotFound),
+        }
+    }
+
+    /// Open the root directory of `volume`.
+    pub fn open_root_dir(&mut self, _volume: Volume) -> Result<Directory> {
+        let root = self.fs()?.bpb().root_cluster;
+        Ok(Directory(insert(&mut self.dirs, root)))
+    }
+
+    /// Open subdirectory `name` of `parent`.
+    pub fn open_dir(&mut self, parent: Directory, name: &str) -> Result<Directory> {
+        let e = self.find_directory_entry(parent, name)?;
+        if !e.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        // `..` of a first-level directory records the root as cluster 0.
+        let cluster = match e.first_cluster {
+            0 => self.fs()?.bpb().root_cluster,
+            c => c,
+        };
+        Ok(Directory(insert(&mut self.dirs, cluster)))
+    }
+
+    /// Close `dir`.
+    pub fn close_dir(&mut self, dir: Directory) -> Result<()> {
+        self.dirs.get_mut(dir.0).and_then(Option::take).map(|_| ()).ok_or(Error::NotFound)
+    }
+
+    /// Call `f` with each entry of `dir`.
+    pub fn iterate_dir(&mut self, dir: Directory, mut f: impl FnMut(&DirEntry)) -> Result<()> {
+        let cluster = self.dir(dir)?;
+        for e in self.fs()?.list_dir(cluster)? {
+            f(&e);
+        }
+        Ok(())
+    }
+
+    /// Entry `name` of `dir`.
+    pub fn find_directory_entry(&mut self, dir: Directory, name: &str) -> Result<DirEntry> {
+        let cluster = self.dir(dir)?;
+        let fs = self.fs()?;
+        let short = fs.short_name(name)?;
+        Ok(fs.find_entry(cluster, &short)?.ok_or(Error::NotFound)?.0)
+    }
+
+    /// Create subdirectory `name` in `dir`.
+    pub fn make_dir_in_dir(&mut self, dir: Directory, name: &str) -> Result<()> {
+        let cluster = self.dir(dir)?;
+        self.fs()?.dir_at(cluster).create_dir(name).map(|_| ())
+    }
+
+    /// Delete file `name` from `dir`; it must not be open.
+    pub fn delete_file_in_dir(&mut self, dir: Directory, name: &str) -> Result<()> {
+        if self.find_directory_entry(dir, name)?.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        let cluster = self.dir(dir)?;
+        self.fs()?.dir_at(cluster).remove(name)
+    }
+
+    /// Open file `name` in `dir` as `mode` prescribes.
+    pub fn open_file_in_dir(&mut self, dir: Directory, name: &str, mode: Mode) -> Result<File> {
+        let exists = match self.find_directory_entry(dir, name) {
+            Ok(_) => true,
+            Err(Error::NotFound) => false,
+            Err(e) => return Err(e),
+        };
+        let open = match mode {
+            Mode::ReadWriteAppend | Mode::ReadWriteTruncate if !exists => return Err(Error::NotFound),
+            Mode::ReadOnly => OpenMode::Open,
+            Mode::ReadWriteAppend | Mode::ReadWriteCreateOrAppend => OpenMode::Append,
+            Mode::ReadWriteTruncate | Mode::ReadWriteCreateOrTruncate => OpenMode::Truncate,
+            Mode::ReadWriteCreate => OpenMode::CreateNew,
+        };
+        let cluster = self.dir(dir)?;
+        let handle = self.fs()?.open_handle_in(cluster, name, open)?;
+        Ok(File(insert(&mut self.files, (handle, mode))))
+    }
+
+    /// Read from `file` at its position; returns 0 at the end.
+    pub fn read(&mut self, file: File, buf: &mut [u8]) -> Result<usize> {
+        self.file(file)?.0.read(buf)
+    }
+
+    /// Write all of `buf` to `file` at its position.
+    pub fn write(&mut self, file: File, buf: &[u8]) -> Result<()> {
+        let (mut f, mode) = self.file(file)?;
+        if mode == Mode::ReadOnly {
+            return Err(Error::ReadOnlyFile);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            done += f.write(&buf[done..])?;
+        }
+        Ok(())
+    }
+
+    /// True if `file` is positioned at (or past) its end.
+    pub fn file_eof(&mut self, file: File) -> Result<bool> {
+        let f = self.file(file)?.0;
+        Ok(f.position() >= f.len())
+    }
+
+    /// Length of `file` in bytes.
+    pub fn file_length(&mut self, file: File) -> Result<u32> {
+        Ok(self.file(file)?.0.len())
+    }
+
+    /// Position of `file`.
+    pub fn file_offset(&mut self, file: File) -> Result<u32> {
+        Ok(self.file(file)?.0.position())
+    }
+
+    /// Move `file` to `offset`; positions past the end fail with `InvalidSeek`.
+    pub fn file_seek_from_start(&mut self, file: File, offset: u32) -> Result<()> {
+        let mut f = self.file(file)?.0;
+        if offset > f.len() {
+            return Err(Error::InvalidSeek);
+        }
+        f.seek(offset).map(|_| ())
+    }
+
+    /// Move `file` by `offset` from its position.
+    pub fn file_seek_from_current(&mut self, file: File, offset: i32) -> Result<()> {
+        let pos = self.file_offset(file)?;
+        let target = pos.checked_add_signed(offset).ok_or(Error::InvalidSeek)?;
+        self.file_seek_from_start(file, target)
+    }
+
+    /// Move `file` to `offset` bytes before its end.
+    pub fn file_seek_from_end(&mut self, file: File, offset: u32) -> Result<()> {
+        let len = self.file_length(file)?;
+        self.file_seek_from_start(file, len.checked_sub(offset).ok_or(Error::InvalidSeek)?)
+    }
+
+    /// Close `file`, writing its directory entry.
+    pub fn close_file(&mut self, file: File) -> Result<()> {
+        let (handle, _) = self.files.get_mut(file.0).and_then(Option::take).ok_or(Error::NotFound)?;
+        self.fs()?.close_handle(handle)
+    }
+
+    fn fs(&mut self) -> Result<&mut Fat32<Partition<D>>> {
+        self.fs.as_mut().ok_or(Error::NotFound)
+    }
+
+    fn dir(&self, dir: Directory) -> Result<u32> {
+        self.dirs.get(dir.0).copied().flatten().ok_or(Error::NotFound)
+    }
+
+    fn file(&mut self, file: File) -> Result<(FatFile<'_, Partition<D>>, Mode)> {
+        let (handle, mode) = self.files.get(file.0).copied().flatten().ok_or(Error::NotFound)?;
+        Ok((self.fs()?.file(handle)?, mode))
+    }
+}
+
+/// Put `value` in the first free slot of `table`, returning its index.
+fn insert<T>(table: &mut Vec<Option<T>>, value: T) -> usize {
+    match table.iter().position(Option::is_none) {
+        Some(i) => {
+            table[i] = Some(value);
+            i
+        }
+        None => {
+            table.push(Some(value));
+            table.len() - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::image::ImageBuilder;
+
+    #[test]
+    fn embedded_sdmmc_call_sequence() {
+        // An MBR with one FAT32 (LBA) partition starting at sector 8.
+        let mut disk = alloc::vec![0u8; 8 * 512];
+        disk[446 + 4] = 0x0C;
+        disk[446 + 8] = 8;
+        disk[510] = 0x55;
+        disk[511] = 0xAA;
+        disk.extend(ImageBuilder::new(4096).file("CONFIG.TXT", b"speed=9").build().unwrap());
+
+        let mut mgr = VolumeManager::new(MemDevice::new(disk));
+        assert_eq!(mgr.open_volume(VolumeIdx(1)), Err(Error::NotFound));
+        let volume = mgr.open_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(mgr.open_volume(VolumeIdx(0)), Err(Error::AlreadyOpen));
+        let root = mgr.open_root_dir(volume).unwrap();
+
+        let cfg = mgr.open_file_in_dir(root, "config.txt", Mode::ReadOnly).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(mgr.read(cfg, &mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"speed=9");
+        assert_eq!(mgr.file_eof(cfg), Ok(true));
+        mgr.file_seek_from_end(cfg, 1).unwrap();
+        assert_eq!(mgr.read(cfg, &mut buf), Ok(1));
+        assert_eq!(buf[0], b'9');
+        assert_eq!(mgr.write(cfg, b"x"), Err(Error::ReadOnlyFile));
+        assert_eq!(mgr.file_seek_from_start(cfg, 8), Err(Error::InvalidSeek));
+        mgr.close_file(cfg).unwrap();
+
+        mgr.make_dir_in_dir(root, "LOGS").unwrap();
+        let logs = mgr.open_dir(root, "LOGS").unwrap();
+        assert_eq!(mgr.open_file_in_dir(logs, "BOOT.LOG", Mode::ReadWriteAppend), Err(Error::NotFound));
+        for _ in 0..2 {
+            let log = mgr.open_file_in_dir(logs, "BOOT.LOG", Mode::ReadWriteCreateOrAppend).unwrap();
+            mgr.write(log, b"boot\n").unwrap();
+            mgr.close_file(log).unwrap();
+        }
+        let mut names = Vec::new();
+        mgr.iterate_dir(logs, |e| names.push((e.name(), e.file_size))).unwrap();
+        assert_eq!(names, [(alloc::string::String::from("BOOT.LOG"), 10)]);
+        assert_eq!(mgr.delete_file_in_dir(root, "LOGS"), Err(Error::IsADirectory));
+        mgr.delete_file_in_dir(logs, "BOOT.LOG").unwrap();
+
+        assert_eq!(mgr.close_volume(volume), Err(Error::AlreadyOpen));
+        mgr.close_dir(logs).unwrap();
+        mgr.close_dir(root).unwrap();
+        mgr.close_volume(volume).unwrap();
+        let disk = mgr.free().unwrap().into_inner();
+        let fs = Fat32::mount(MemDevice::new(disk[8 * 512..].to_vec())).expect("mount partition");
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.list_dir_path("LOGS").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn failed_open_keeps_the_device() {
+        // Partition 0 (sector 1) holds no filesystem; partition 1 starts at sector 8.
+        let mut disk = alloc::vec![0u8; 8 * 512];
+        for (i, start) in [1u8, 8].into_iter().enumerate() {
+            disk[446 + i * 16 + 4] = 0x0C;
+            disk[446 + i * 16 + 8] = start;
+        }
+        disk[510] = 0x55;
+        disk[511] = 0xAA;
+        disk.extend(ImageBuilder::new(4096).file("A.TXT", b"a").build().unwrap());
+
+        let mut mgr = VolumeManager::new(MemDevice::new(disk));
+        assert!(mgr.open_volume(VolumeIdx(0)).is_err());
+        let volume = mgr.open_volume(VolumeIdx(1)).unwrap();
+        let root = mgr.open_root_dir(volume).unwrap();
+        assert!(mgr.find_directory_entry(root, "A.TXT").is_ok());
+        mgr.close_dir(root).unwrap();
+        mgr.close_volume(volume).unwrap();
+        assert!(mgr.open_volume(VolumeIdx(0)).is_err());
+        assert_eq!(mgr.free().unwrap().as_slice().len(), (8 + 4096) * 512);
+    }
+}
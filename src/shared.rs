@@ -0,0 +1,100 @@
+//! Filesystem handle shareable between tasks or threads.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use lock_api::{Mutex, MutexGuard, RawMutex};
+
+use crate::device::BlockDevice;
+#[cfg(feature = "alloc")]
+use crate::dir::DirEntry;
+use crate::error::Result;
+use crate::fs::Fat32;
+
+/// A `Fat32` behind a mutex; every operation locks it for its whole duration.
+///
+/// `R` is any `lock_api::RawMutex`: the default spinlock works everywhere, and
+/// firmware can plug in a critical-section or RTOS mutex instead. Put it in a
+/// `static` (or share a `&SharedFat32`) to use one volume from several tasks.
+pub struct SharedFat32<D: BlockDevice, R: RawMutex = spin::Mutex<()>> {
+    inner: Mutex<R, Fat32<D>>,
+}
+
+impl<D: BlockDevice, R: RawMutex> SharedFat32<D, R> {
+    /// Wrap a mounted filesystem.
+    pub fn new(fs: Fat32<D>) -> Self {
+        Self { inner: Mutex::new(fs) }
+    }
+
+    /// Lock the filesystem for a sequence of operations (e.g. an open `File`).
+    pub fn lock(&self) -> MutexGuard<'_, R, Fat32<D>> {
+        self.inner.lock()
+    }
+
+    /// Run `f` with exclusive access to the filesystem.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Fat32<D>) -> T) -> T {
+        f(&mut self.inner.lock())
+    }
+
+    /// See `Fat32::list_root`.
+    #[cfg(feature = "alloc")]
+    pub fn list_root(&self) -> Result<Vec<DirEntry>> {
+        self.inner.lock().list_root()
+    }
+
+    /// See `Fat32::read_file_root`.
+    #[cfg(feature = "alloc")]
+    pub fn read_file_root(&self, name: &str) -> Result<Vec<u8>> {
+        self.inner.lock().read_file_root(name)
+    }
+
+    /// See `Fat32::write_file_root`.
+    #[cfg(feature = "alloc")]
+    pub fn write_file_root(&self, name: &str, content: &[u8]) -> Result<()> {
+        self.inner.lock().write_file_root(name, content)
+    }
+
+    /// See `Fat32::flush`.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.lock().flush()
+    }
+
+    /// See `Fat32::sync`.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.lock().sync()
+    }
+
+    /// Return the wrapped filesystem.
+    pub fn into_inner(self) -> Fat32<D> {
+        self.inner.into_inner()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use std::format;
+    use std::thread;
+
+    #[test]
+    fn writers_on_several_threads() {
+        let fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let shared: SharedFat32<_> = SharedFat32::new(fs);
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                let shared = &shared;
+                s.spawn(move || {
+                    let name = format!("T{}.TXT", i);
+                    shared.write_file_root(&name, name.as_bytes()).expect("write");
+                });
+            }
+        });
+
+        assert_eq!(shared.list_root().unwrap().len(), 4);
+        assert_eq!(shared.read_file_root("T2.TXT").unwrap(), b"T2.TXT");
+        shared.with(|fs| fs.flush()).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Volume properties: `Fat32::stats` and the cluster map of `Fat32::cluster_map`.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::cancel::Cancel;
+use crate::device::BlockDevice;
+use crate::dir::ATTR_VOLUME_ID;
+use crate::error::Result;
+use crate::fat::{max_cluster, BAD_CLUSTER};
+use crate::fs::Fat32;
+use crate::layout::cluster_count;
+use crate::options::ListOptions;
+
+/// Size and identity of a mounted volume, from `Fat32::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FsStats {
+    /// Volume size in bytes.
+    pub total_bytes: u64,
+    /// Cluster size in bytes.
+    pub cluster_size: u32,
+    /// Data clusters on the volume.
+    pub total_clusters: u32,
+    /// Clusters marked free in the FAT.
+    pub free_clusters: u32,
+    /// Clusters marked bad in the FAT.
+    pub bad_clusters: u32,
+    /// Number of FAT copies.
+    pub fats: u8,
+    /// First cluster of the root directory.
+    pub root_cluster: u32,
+    /// Volume label as stored (space-padded): the root directory's label
+    /// entry if there is one, else the boot sector field.
+    pub label: [u8; 11],
+    /// Volume serial number from the boot sector.
+    pub serial: u32,
+}
+
+impl FsStats {
+    /// Bytes available in free clusters.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters as u64 * self.cluster_size as u64
+    }
+}
+
+/// What the FAT says about a cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClusterState {
+    /// Available for allocation.
+    Free,
+    /// Part of a chain (or reserved).
+    Used,
+    /// Marked bad; never allocated.
+    Bad,
+}
+
+impl ClusterState {
+    fn of(fat_value: u32) -> Self {
+        match fat_value {
+            0 => ClusterState::Free,
+            BAD_CLUSTER => ClusterState::Bad,
+            _ => ClusterState::Used,
+        }
+    }
+}
+
+/// Consecutive clusters in the same state, from `Fat32::cluster_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClusterRun {
+    /// First cluster of the run.
+    pub first: u32,
+    /// Clusters in the run.
+    pub count: u32,
+    /// State shared by the run's clusters.
+    pub state: ClusterState,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Collect the volume's size, free space and identity in one call.
+    ///
+    /// Free and bad clusters are counted by reading the whole active FAT, not
+    /// taken from the FSInfo hint.
+    pub fn stats(&self) -> Result<FsStats> {
+        let bpb = self.bpb;
+        let (mut free, mut bad) = (0, 0);
+        self.for_each_fat_entry(max_cluster(&bpb), Cancel::NEVER, |_, v| match ClusterState::of(v) {
+            ClusterState::Free => free += 1,
+            ClusterState::Bad => bad += 1,
+            ClusterState::Used => {}
+        })?;
+
+        let mut label = bpb.volume_label;
+        let with_label = ListOptions {
+            include_volume_label: true,
+            ..ListOptions::default()
+        };
+        for e in self.read_dir_with("/", &with_label)? {
+            let e = e?;
+            if e.attr & ATTR_VOLUME_ID != 0 {
+                label = e.raw_name;
+                break;
+            }
+        }
+
+        Ok(FsStats {
+            total_bytes: bpb.total_sectors_32 as u64 * 512,
+            cluster_size: bpb.sectors_per_cluster as u32 * 512,
+            total_clusters: cluster_count(&bpb),
+            free_clusters: free,
+            bad_clusters: bad,
+            fats: bpb.num_fats,
+            root_cluster: bpb.root_cluster,
+            label,
+            serial: bpb.volume_id,
+        })
+    }
+
+    /// Run-length map of the data clusters, in cluster order from cluster 2,
+    /// for drawing fragmentation views or planning a defragmenter.
+    ///
+    /// The runs cover every cluster; adjacent runs differ in state.
+    #[cfg(feature = "alloc")]
+    pub fn cluster_map(&self) -> Result<Vec<ClusterRun>> {
+        let mut runs: Vec<ClusterRun> = Vec::new();
+        self.for_each_fat_entry(max_cluster(&self.bpb), Cancel::NEVER, |c, v| {
+            let state = ClusterState::of(v);
+            match runs.last_mut() {
+                Some(run) if run.state == state => run.count += 1,
+                _ => runs.push(ClusterRun { first: c, count: 1, state }),
+            }
+        })?;
+        Ok(runs)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::dir::DirEntry;
+    use crate::fs::fixtures::make_tiny_fat32_image;
+
+    #[test]
+    fn stats_count_clusters_and_read_identity() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        let stats = fs.stats().unwrap();
+        assert_eq!(stats.total_bytes, 200 * 512);
+        assert_eq!((stats.total_clusters, stats.free_clusters, stats.bad_clusters), (166, 165, 0));
+        assert_eq!((stats.fats, stats.root_cluster, stats.cluster_size), (1, 2, 512));
+        assert_eq!(&stats.label, b"NO NAME    ");
+        assert_eq!(stats.serial, 0x1234_5678);
+
+        fs.write_file_root("A.BIN", &[1u8; 1500]).unwrap();
+        fs.write_fat(100, BAD_CLUSTER).unwrap();
+        let root = fs.bpb().root_cluster;
+        fs.write_dir_entry_first_free(root, &DirEntry::build_short_entry(*b"CAMERA     ", ATTR_VOLUME_ID, 0, 0))
+            .unwrap();
+        let stats = fs.stats().unwrap();
+        assert_eq!((stats.free_clusters, stats.bad_clusters), (161, 1));
+        assert_eq!(stats.free_bytes(), 161 * 512);
+        assert_eq!(&stats.label, b"CAMERA     ");
+    }
+
+    #[test]
+    fn cluster_map_is_run_length_encoded() {
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("A.BIN", &[1u8; 1500]).unwrap();
+        fs.write_file_root("B.TXT", b"b").unwrap();
+        fs.remove_file_root("A.BIN").unwrap();
+        fs.write_fat(10, BAD_CLUSTER).unwrap();
+
+        let run = |first, count, state| ClusterRun { first, count, state };
+        let map = fs.cluster_map().unwrap();
+        assert_eq!(
+            map,
+            [
+                run(2, 1, ClusterState::Used),
+                run(3, 3, ClusterState::Free),
+                run(6, 1, ClusterState::Used),
+                run(7, 3, ClusterState::Free),
+                run(10, 1, ClusterState::Bad),
+                run(11, 157, ClusterState::Free),
+            ]
+        );
+        assert_eq!(map.iter().map(|r| r.count).sum::<u32>(), fs.stats().unwrap().total_clusters);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn reports_round_trip_through_json() {
+        use crate::fsck::FsckReport;
+        use crate::metadata::Metadata;
+
+        let mut fs = Fat32::mount(MemDevice::new(make_tiny_fat32_image())).expect("mount");
+        fs.write_file_root("A.TXT", b"json").unwrap();
+        let stats = fs.stats().unwrap();
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains("\"free_clusters\""));
+        assert_eq!(serde_json::from_str::<FsStats>(&json).unwrap(), stats);
+
+        let meta = fs.metadata("A.TXT").unwrap();
+        assert_eq!(serde_json::from_str::<Metadata>(&serde_json::to_string(&meta).unwrap()).unwrap(), meta);
+        let report = fs.check().unwrap();
+        let back: FsckReport = serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert_eq!((back.files, back.is_clean()), (1, true));
+        let bpb = serde_json::to_value(fs.bpb()).unwrap();
+        assert_eq!(bpb["root_cluster"], 2);
+    }
+}
@@ -0,0 +1,30 @@
+//! Logging macros, backed by the `log` crate when the `log` feature is enabled.
+//!
+//! Without the feature the arguments are type-checked but never evaluated.
+
+#[cfg(feature = "log")]
+macro_rules! fs_trace {
+    ($($arg:tt)*) => { log::trace!($($arg)*) };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! fs_trace {
+    ($($arg:tt)*) => {{ if false { let _ = format_args!($($arg)*); } }};
+}
+
+#[cfg(feature = "log")]
+macro_rules! fs_debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! fs_debug {
+    ($($arg:tt)*) => {{ if false { let _ = format_args!($($arg)*); } }};
+}
+
+#[cfg(feature = "log")]
+macro_rules! fs_warn {
+    ($($arg:tt)*) => { log::warn!($($arg)*) };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! fs_warn {
+    ($($arg:tt)*) => {{ if false { let _ = format_args!($($arg)*); } }};
+}
@@ -0,0 +1,161 @@
+//! Write-recording `BlockDevice` wrapper (`test-util` feature).
+//!
+//! Lets tests assert on exactly which sectors an operation wrote and in what
+//! order, e.g. that a directory entry is only written after the FAT chain it
+//! points to.
+
+use alloc::vec::Vec;
+
+use crate::device::BlockDevice;
+use crate::error::Result;
+
+/// One write seen by a `TraceDevice`.
+#[derive(Debug, Clone)]
+pub struct TracedWrite {
+    /// Sector written.
+    pub lba: u64,
+    /// Sector content before the write.
+    pub before: [u8; 512],
+    /// Data written.
+    pub after: [u8; 512],
+}
+
+impl TracedWrite {
+    /// True if the write changed the sector.
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+}
+
+/// A `BlockDevice` that records every successful write (and flush and trim).
+pub struct TraceDevice<D: BlockDevice> {
+    inner: D,
+    writes: Vec<TracedWrite>,
+    flushes: usize,
+    trims: Vec<(u64, u64)>,
+}
+
+impl<D: BlockDevice> TraceDevice<D> {
+    /// Wrap `inner` with an empty trace.
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            writes: Vec::new(),
+            flushes: 0,
+            trims: Vec::new(),
+        }
+    }
+
+    /// Writes recorded so far, oldest first.
+    pub fn writes(&self) -> &[TracedWrite] {
+        &self.writes
+    }
+
+    /// Sectors written so far, in order (with repeats).
+    pub fn lbas(&self) -> Vec<u64> {
+        self.writes.iter().map(|w| w.lba).collect()
+    }
+
+    /// Index of the last write to `lba`, if any.
+    pub fn last_write_to(&self, lba: u64) -> Option<usize> {
+        self.writes.iter().rposition(|w| w.lba == lba)
+    }
+
+    /// Flushes seen so far.
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+
+    /// Trims seen so far, as `(lba, count)`.
+    pub fn trims(&self) -> &[(u64, u64)] {
+        &self.trims
+    }
+
+    /// Forget the recorded writes, flushes and trims, e.g. after setting up a test volume.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.flushes = 0;
+        self.trims.clear();
+    }
+
+    /// Borrow the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the wrapped device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for TraceDevice<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; 512]) -> Result<()> {
+        self.inner.read_sector(lba, buf)
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_sectors(lba, buf)
+    }
+
+    fn write_sector(&mut self, lba: u64, buf: &[u8; 512]) -> Result<()> {
+        let mut before = [0u8; 512];
+        // A sector that cannot be read back is recorded as all zeroes.
+        let _ = self.inner.read_sector(lba, &mut before);
+        self.inner.write_sector(lba, buf)?;
+        self.writes.push(TracedWrite { lba, before, after: *buf });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        self.flushes += 1;
+        Ok(())
+    }
+
+    fn trim(&mut self, lba: u64, count: u64) -> Result<()> {
+        self.inner.trim(lba, count)?;
+        self.trims.push((lba, count));
+        Ok(())
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.inner.is_write_protected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fat::{cluster_to_lba, fat_start_lba};
+    use crate::fs::fixtures::make_tiny_fat32_image;
+    use crate::fs::Fat32;
+
+    #[test]
+    fn write_file_orders_data_fat_then_entry() {
+        let mut dev = TraceDevice::new(MemDevice::new(make_tiny_fat32_image()));
+        let mut fs = Fat32::mount(&mut dev).expect("mount");
+        fs.write_file_root("A.TXT", b"first").unwrap();
+        let bpb = *fs.bpb();
+        fs.unmount().unwrap().clear();
+
+        let mut fs = Fat32::mount(&mut dev).expect("remount");
+        fs.write_file_root("B.TXT", &[7u8; 1100]).unwrap();
+        drop(fs);
+
+        let root = cluster_to_lba(&bpb, bpb.root_cluster).unwrap();
+        let entry = dev.last_write_to(root).expect("entry written");
+        assert_eq!(entry, dev.writes().len() - 1, "directory entry written last");
+        assert!(dev.writes()[entry].changed());
+        let fat = dev.last_write_to(fat_start_lba(&bpb)).expect("FAT written");
+        let data: Vec<_> = dev.lbas().iter().enumerate().filter(|(_, &l)| l > root).map(|(i, _)| i).collect();
+        assert_eq!(data.len(), 3);
+        assert!(data.iter().all(|&i| i < fat), "data written before the chain");
+        assert_eq!(dev.flushes(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Volume serial number sources, for `mkfs::FormatOptions::volume_id_from`
+//! and `Fat32::set_volume_id`.
+//!
+//! `no_std` targets have no ambient entropy, so the application supplies one:
+//! a hardware RNG, an RTC reading, a counter kept in flash. Giving every
+//! device of a product the same serial makes hosts that cache by serial
+//! (e.g. Windows) mix them up.
+
+use crate::device::BlockDevice;
+use crate::error::{Operation, Result};
+use crate::fs::Fat32;
+use crate::metadata::Timestamp;
+
+/// Produces volume serial numbers.
+pub trait VolumeIdSource {
+    /// A new volume serial number.
+    fn next_volume_id(&mut self) -> u32;
+}
+
+/// A fixed serial number.
+impl VolumeIdSource for u32 {
+    fn next_volume_id(&mut self) -> u32 {
+        *self
+    }
+}
+
+/// A closure, e.g. `|| rng.next_u32()`.
+impl<F: FnMut() -> u32> VolumeIdSource for F {
+    fn next_volume_id(&mut self) -> u32 {
+        self()
+    }
+}
+
+/// The current time from an RTC, combined the way DOS `FORMAT` did: date
+/// in the high half, time in the low half.
+impl VolumeIdSource for Timestamp {
+    fn next_volume_id(&mut self) -> u32 {
+        (self.date as u32) << 16 | self.time as u32
+    }
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Give the volume a new serial number from `source`, in the boot
+    /// sector and its backup. Returns the number written.
+    ///
+    /// A boot sector without an extended boot signature is marked as
+    /// holding a serial number (0x28), leaving the label and type bytes alone.
+    pub fn set_volume_id<S: VolumeIdSource>(&mut self, mut source: S) -> Result<u32> {
+        self.check_writable()?;
+        let id = source.next_volume_id();
+        let mut lbas = [Some(0), None];
+        if self.bpb.has_backup_boot_sector() {
+            lbas[1] = Some(self.bpb.backup_boot_sector as u64);
+        }
+        for lba in lbas.into_iter().flatten() {
+            let mut boot = [0u8; 512];
+            self.read_sector(Operation::ReadBoot, lba, &mut boot)?;
+            if !matches!(boot[66], 0x28 | 0x29) {
+                boot[66] = 0x28;
+            }
+            boot[67..71].copy_from_slice(&id.to_le_bytes());
+            self.write_sector(Operation::WriteBoot, lba, &boot)?;
+        }
+        if !matches!(self.bpb.boot_signature, 0x28 | 0x29) {
+            self.bpb.boot_signature = 0x28;
+        }
+        self.bpb.volume_id = id;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::mkfs::{format_with, FormatOptions};
+
+    #[test]
+    fn formatter_and_set_volume_id_use_the_source() {
+        let mut next = 0xAB00_0000u32;
+        let mut counter = || {
+            next += 1;
+            next
+        };
+        let mut dev = MemDevice::zeroed(4096);
+        let options = FormatOptions { allow_small: true, ..FormatOptions::default() }.volume_id_from(&mut counter);
+        format_with(&mut dev, 4096, &options).unwrap();
+
+        let mut fs = Fat32::mount(dev).unwrap();
+        assert_eq!(fs.bpb().volume_id, 0xAB00_0001);
+        assert_eq!(fs.set_volume_id(&mut counter), Ok(0xAB00_0002));
+        assert_eq!(fs.set_volume_id(0x0102_0304), Ok(0x0102_0304));
+        assert_eq!(fs.verify_backup_boot_sector(), Ok(true));
+        let fs = Fat32::mount(fs.unmount().unwrap()).unwrap();
+        assert_eq!(fs.stats().unwrap().serial, 0x0102_0304);
+
+        let mut rtc = Timestamp::new(2024, 5, 17, 12, 30, 0);
+        assert_ne!(rtc.next_volume_id(), Timestamp::new(2024, 5, 17, 12, 30, 2).next_volume_id());
+    }
+}